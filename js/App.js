@@ -1093,6 +1093,13 @@ function urlToImage(url) {
 async function main() {
     let compiler = await import("../pkg/compiler.js");
 
+    // the compiler documents its host functions, references.json only what isn't one (show_image)
+    let generated_references = compiler.library_references();
+    let documented = new Set(generated_references.map((f) => f.name));
+    references.image_processing_functions = references.image_processing_functions
+        .filter((f) => !documented.has(f.name))
+        .concat(generated_references);
+
     function processImageInput() {
         let names = [...observableStateStore.imageInputFiles.keys()];
         for (const [name, data] of observableStateStore.imageInputFiles) {
@@ -1119,9 +1126,7 @@ async function main() {
         let output = compiler.code_to_wasm(observableStateStore.code, image_names);
         let output_wasm_buffer = new Uint8Array(output[0]);
         let output_textures_info = output[1]; // image_name, image_id
-        let output_materials_info = output[2]; // position in mem, [material_name, channel_name, material_type]
         // console.log(output)
-        // console.log("output_materials_info", output_materials_info)
 
         if (observableStateStore.consoleMessage.length != console_message_len_before) {
             loadElement.classList.remove("is-active");
@@ -1132,64 +1137,27 @@ async function main() {
 
         print_wat(output_wasm_buffer);
 
-        let wasmImportObject = {
-            env: {
-                logger: function (arg) {
-                    console.log(arg);
-                },
-                resize: function (img_id, width, height) {
-                    return compiler.resize_(img_id, width, height)
-                },
-                darken: function (img_id, value) {
-                    return compiler.darken(img_id, value)
-                },
-                lighten: function (img_id, value) {
-                    return compiler.lighten_(img_id, value)
-                },
-                blank_image: function (r, g, b, a, width, height) {
-                    return compiler.blank_image(r, g, b, a, width, height)
-                },
-                grayscale: function (img_id) {
-                    return compiler.grayscale_(img_id)
-                },
-                invert_color: function (img_id) {
-                    return compiler.invert_color(img_id)
-                },
-                flip_horizontal: function (img_id) {
-                    return compiler.flip_horizontal(img_id)
-                },
-                flip_vertical: function (img_id) {
-                    return compiler.flip_vertical(img_id)
-                },
-                mask_channel_r: function (img_id) {
-                    return compiler.mask_channel_r(img_id)
-                },
-                mask_channel_g: function (img_id) {
-                    return compiler.mask_channel_g(img_id)
-                },
-                mask_channel_b: function (img_id) {
-                    return compiler.mask_channel_b(img_id)
-                },
-                blur: function (img_id) {
-                    return compiler.blur(img_id)
-                },
-                blur_gaussian: function (img_id) {
-                    return compiler.blur_gaussian(img_id)
-                },
-                noise_perlin: function (img_id, radius) {
-                    return compiler.noise_perlin(img_id, radius)
-                },
-                sharpen: function (img_id) {
-                    return compiler.sharpen_(img_id)
-                },
-                overlay: function (img_id_1, img_id_2) {
-                    return compiler.overlay(img_id_1, img_id_2)
-                },
-                multiply: function (img_id_1, img_id_2) {
-                    return compiler.multiply(img_id_1, img_id_2)
-                },
+        // the import object is generated from the host function registry of the compiler
+        let hostAbi = compiler.library_host_abi();
+        let wasmImportObject = {};
+        for (const f of hostAbi.functions) {
+            if (!(f.module in wasmImportObject)) {
+                wasmImportObject[f.module] = {};
             }
-        };
+            wasmImportObject[f.module][f.name] = function (...args) {
+                return compiler.library_call(f.name, Int32Array.from(args));
+            };
+        }
+
+        let abiErrors = compiler.host_abi_errors(output_wasm_buffer, hostAbi);
+        if (abiErrors.length > 0) {
+            for (const e of abiErrors) {
+                observableStateStore.addConsoleMessage(time_now() + " ✗ " + e);
+            }
+            loadElement.classList.remove("is-active");
+            return;
+        }
+
         let { _, instance } = await WebAssembly.instantiate(output_wasm_buffer, wasmImportObject);
         observableStateStore.addConsoleMessage(time_now() + " ✓ Wasm module instantiated.");
 
@@ -1197,8 +1165,9 @@ async function main() {
         // console.log(wasm_memory.slice(0, 200))
         observableStateStore.addConsoleMessage(time_now() + " ✓ Wasm module executed.");
 
-        var wasm_memory = new Uint32Array(instance.exports.mem.buffer)
-        process_export(output_textures_info, wasm_memory, output_materials_info);
+        // name, type, resolution and image ids of every material, decoded from manifest()
+        let manifest = compiler.decode_manifest(new Uint8Array(instance.exports.mem.buffer), instance.exports.manifest());
+        process_export(output_textures_info, manifest);
 
         observableStateStore.addConsoleMessage(time_now() + " ✓ Export finished.");
 
@@ -1217,7 +1186,7 @@ async function main() {
         }
     }
 
-    function process_export(output_textures_info, wasm_memory, output_materials_info) {
+    function process_export(output_textures_info, manifest) {
         // single images
         let export_info = {}
         for (let [name, id] of Object.entries(output_textures_info)) {
@@ -1226,13 +1195,18 @@ async function main() {
         export_images("textures", compiler.library_export(export_info), "");
 
         // materials
-        for (let [offset, names] of Object.entries(output_materials_info)) {
-            observableStateStore.addMaterialInstanceType(names[0], names[2]);
-            if (wasm_memory[offset] < 2147483647) {
-                export_info = {}
-                export_info[names[1]] = wasm_memory[offset];
-                export_images(names[0], compiler.library_export(export_info));
-            }
+        // mismatched sizes, missing required channels or implausible values are reported before exporting
+        for (const d of compiler.library_validate_materials(manifest)) {
+            let channel = d.channel ? "." + d.channel : "";
+            let mark = d.severity == "error" ? " ✗ " : " ! ";
+            observableStateStore.addConsoleMessage(time_now() + mark + d.material + channel + ": " + d.message);
+        }
+        // empty channels are filled with their default color and packed textures (e.g. orm) are added,
+        // pass fill_defaults: false or pack_channels: false to skip them
+        let material_images = compiler.library_export_materials(manifest, { fill_defaults: true, pack_channels: true });
+        for (const material of manifest.materials) {
+            observableStateStore.addMaterialInstanceType(material.name, material.material_type);
+            export_images(material.name, material_images[material.name]);
         }
     }
 
@@ -0,0 +1,282 @@
+use super::compiler::{ImportInfo, MemoryLayout};
+use super::diagnostic::to_js_value;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Error, Formatter};
+use wasm_bindgen::prelude::*;
+
+// bump whenever the meaning of an import, the memory layout or the exports change
+pub const ABI_VERSION: u32 = 5;
+pub const ABI_SECTION_NAME: &str = "coocoo_abi";
+
+// contract between a compiled module and its host, stored in the "coocoo_abi" custom section
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AbiMetadata {
+    pub compiler_version: String,
+    pub abi_version: u32,
+    pub imports: Vec<ImportInfo>,
+    pub textures: Vec<String>,
+    pub materials: Vec<MaterialExport>,
+    pub layout: MemoryLayout,
+    #[serde(default)]
+    pub tests: Vec<String>, // exported as "test:<name>", missing before abi 5
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaterialExport {
+    pub name: String,
+    pub material_type: String,
+}
+
+impl AbiMetadata {
+    pub fn new(
+        imports: Vec<ImportInfo>,
+        textures: Vec<String>,
+        materials: Vec<MaterialExport>,
+        layout: MemoryLayout,
+        tests: Vec<String>,
+    ) -> Self {
+        AbiMetadata {
+            compiler_version: env!("CARGO_PKG_VERSION").to_string(),
+            abi_version: ABI_VERSION,
+            imports,
+            textures,
+            materials,
+            layout,
+            tests,
+        }
+    }
+
+    pub fn to_custom_section(&self) -> walrus::RawCustomSection {
+        walrus::RawCustomSection {
+            name: ABI_SECTION_NAME.to_string(),
+            data: serde_json::to_vec(self).unwrap(),
+        }
+    }
+
+    pub fn from_wasm(wasm: &[u8]) -> Result<AbiMetadata, String> {
+        let mut module = match walrus::Module::from_buffer(wasm) {
+            Ok(module) => module,
+            Err(e) => return Err(format!("failed to parse module: {}", e)),
+        };
+        match module.customs.remove_raw(ABI_SECTION_NAME) {
+            Some(section) => match serde_json::from_slice(&section.data) {
+                Ok(metadata) => Ok(metadata),
+                Err(e) => Err(format!("malformed {:?} section: {}", ABI_SECTION_NAME, e)),
+            },
+            None => Err(format!(
+                "module has no {:?} section, it was not compiled by coocoo or is too old",
+                ABI_SECTION_NAME
+            )),
+        }
+    }
+}
+
+// what a host provides: the abi version it implements and its function table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostAbi {
+    pub abi_version: u32,
+    pub functions: Vec<ImportInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AbiMismatch {
+    Version {
+        module: u32,
+        host: u32,
+    },
+    MissingFunction {
+        module: String,
+        name: String,
+    },
+    Signature {
+        name: String,
+        expected: (Vec<String>, Vec<String>),
+        found: (Vec<String>, Vec<String>),
+    },
+}
+
+impl Display for AbiMismatch {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        match self {
+            Self::Version { module, host } => write!(
+                fmt,
+                "module requires host abi version {} but the host implements version {}",
+                module, host
+            ),
+            Self::MissingFunction { module, name } => write!(
+                fmt,
+                "host does not provide function {:?} in namespace {:?}",
+                name, module
+            ),
+            Self::Signature {
+                name,
+                expected,
+                found,
+            } => write!(
+                fmt,
+                "function {:?} should have signature ({}) -> ({}) but the host provides ({}) -> ({})",
+                name,
+                expected.0.join(", "),
+                expected.1.join(", "),
+                found.0.join(", "),
+                found.1.join(", ")
+            ),
+        }
+    }
+}
+
+// check a host function table against the metadata of a compiled module
+pub fn check_host_abi(metadata: &AbiMetadata, host: &HostAbi) -> Result<(), Vec<AbiMismatch>> {
+    let mut mismatches: Vec<AbiMismatch> = vec![];
+    if metadata.abi_version != host.abi_version {
+        mismatches.push(AbiMismatch::Version {
+            module: metadata.abi_version,
+            host: host.abi_version,
+        });
+    }
+
+    for required in &metadata.imports {
+        match host
+            .functions
+            .iter()
+            .find(|f| f.module == required.module && f.name == required.name)
+        {
+            Some(provided) => {
+                if provided.params != required.params || provided.results != required.results {
+                    mismatches.push(AbiMismatch::Signature {
+                        name: required.name.clone(),
+                        expected: (required.params.clone(), required.results.clone()),
+                        found: (provided.params.clone(), provided.results.clone()),
+                    });
+                }
+            }
+            None => mismatches.push(AbiMismatch::MissingFunction {
+                module: required.module.clone(),
+                name: required.name.clone(),
+            }),
+        }
+    }
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(mismatches)
+    }
+}
+
+// returns a list of error messages, empty when the host can run the module
+#[wasm_bindgen]
+pub fn host_abi_errors(wasm: Vec<u8>, host: &JsValue) -> JsValue {
+    let host: HostAbi = match host.into_serde() {
+        Ok(host) => host,
+        Err(e) => return to_js_value(&vec![format!("malformed host function table: {}", e)]),
+    };
+    let errors: Vec<String> = match AbiMetadata::from_wasm(&wasm) {
+        Ok(metadata) => match check_host_abi(&metadata, &host) {
+            Ok(()) => vec![],
+            Err(mismatches) => mismatches.iter().map(|m| m.to_string()).collect(),
+        },
+        Err(e) => vec![e],
+    };
+    to_js_value(&errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use walrus::ValType;
+
+    fn import(name: &str, params: Vec<ValType>, results: Vec<ValType>) -> ImportInfo {
+        ImportInfo::new(&name.to_string(), &params, &results)
+    }
+
+    fn metadata() -> AbiMetadata {
+        AbiMetadata::new(
+            vec![
+                import("invert_color", vec![ValType::I32], vec![ValType::I32]),
+                import("blur", vec![ValType::I32, ValType::I32], vec![ValType::I32]),
+            ],
+            vec![],
+            vec![],
+            MemoryLayout {
+                align: 2,
+                pages: 1,
+                records: vec![],
+            },
+            vec![],
+        )
+    }
+
+    #[test]
+    fn matching_hosts_pass() {
+        let metadata = metadata();
+        let host = HostAbi {
+            abi_version: ABI_VERSION,
+            functions: metadata.imports.clone(),
+        };
+        assert_eq!(check_host_abi(&metadata, &host), Ok(()));
+    }
+
+    #[test]
+    fn every_mismatch_is_reported() {
+        let host = HostAbi {
+            abi_version: ABI_VERSION - 1,
+            functions: vec![import("blur", vec![ValType::I32], vec![ValType::I32])],
+        };
+        let mismatches = check_host_abi(&metadata(), &host).unwrap_err();
+        assert_eq!(
+            mismatches,
+            vec![
+                AbiMismatch::Version {
+                    module: ABI_VERSION,
+                    host: ABI_VERSION - 1,
+                },
+                AbiMismatch::MissingFunction {
+                    module: "env".to_string(),
+                    name: "invert_color".to_string(),
+                },
+                AbiMismatch::Signature {
+                    name: "blur".to_string(),
+                    expected: (
+                        vec!["i32".to_string(), "i32".to_string()],
+                        vec!["i32".to_string()]
+                    ),
+                    found: (vec!["i32".to_string()], vec!["i32".to_string()]),
+                },
+            ]
+        );
+        assert_eq!(
+            mismatches[2].to_string(),
+            "function \"blur\" should have signature (i32, i32) -> (i32) but the host provides \
+             (i32) -> (i32)"
+        );
+    }
+
+    #[test]
+    fn functions_of_another_namespace_are_missing() {
+        let metadata = metadata();
+        let mut functions = metadata.imports.clone();
+        functions[0].module = "host".to_string();
+        let host = HostAbi {
+            abi_version: ABI_VERSION,
+            functions,
+        };
+        assert_eq!(
+            check_host_abi(&metadata, &host),
+            Err(vec![AbiMismatch::MissingFunction {
+                module: "env".to_string(),
+                name: "invert_color".to_string(),
+            }])
+        );
+    }
+
+    #[test]
+    fn metadata_survives_the_custom_section() {
+        let mut module = walrus::Module::default();
+        module.customs.add(metadata().to_custom_section());
+        let read = AbiMetadata::from_wasm(&module.emit_wasm()).unwrap();
+        assert_eq!(read.abi_version, ABI_VERSION);
+        assert_eq!(read.imports.len(), 2);
+        assert!(AbiMetadata::from_wasm(&walrus::Module::default().emit_wasm()).is_err());
+    }
+}
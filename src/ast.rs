@@ -1,5 +1,6 @@
-use super::compiler::{Memory, MemoryValue};
+use super::compiler::{material_shape, Memory, MemoryValue, Shape, UNALLOCATED};
 use super::log_rule;
+use super::material_ops::{compile_operation, MATERIAL_OPERATIONS};
 use super::symbol::*;
 use std::fmt::{Debug, Error, Formatter};
 use walrus::ir::*;
@@ -17,7 +18,7 @@ pub trait Compile {
         builder: &mut InstrSeqBuilder,
         symbol_table: &mut SymbolTable,
         memories: &mut Memory,
-    ) -> Result<(), &'static str>;
+    ) -> Result<(), String>;
 }
 
 #[derive(Copy, Clone)]
@@ -26,6 +27,13 @@ pub enum Opcode {
     Div,
     Add,
     Sub,
+    // comparisons give 1 or 0
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
 }
 
 impl Debug for Opcode {
@@ -36,6 +44,12 @@ impl Debug for Opcode {
             Div => write!(fmt, "/"),
             Add => write!(fmt, "+"),
             Sub => write!(fmt, "-"),
+            Eq => write!(fmt, "=="),
+            Ne => write!(fmt, "!="),
+            Lt => write!(fmt, "<"),
+            Gt => write!(fmt, ">"),
+            Le => write!(fmt, "<="),
+            Ge => write!(fmt, ">="),
         }
     }
 }
@@ -47,7 +61,7 @@ impl Compile for Opcode {
         builder: &mut InstrSeqBuilder,
         _symbol_table: &mut SymbolTable,
         _memories: &mut Memory,
-    ) -> Result<(), &'static str> {
+    ) -> Result<(), String> {
         use self::Opcode::*;
         match *self {
             Mul => {
@@ -66,6 +80,30 @@ impl Compile for Opcode {
                 builder.binop(BinaryOp::I32Sub);
                 return Ok(());
             }
+            Eq => {
+                builder.binop(BinaryOp::I32Eq);
+                return Ok(());
+            }
+            Ne => {
+                builder.binop(BinaryOp::I32Ne);
+                return Ok(());
+            }
+            Lt => {
+                builder.binop(BinaryOp::I32LtS);
+                return Ok(());
+            }
+            Gt => {
+                builder.binop(BinaryOp::I32GtS);
+                return Ok(());
+            }
+            Le => {
+                builder.binop(BinaryOp::I32LeS);
+                return Ok(());
+            }
+            Ge => {
+                builder.binop(BinaryOp::I32GeS);
+                return Ok(());
+            }
         };
     }
 }
@@ -77,6 +115,7 @@ pub enum Expr {
     Variable(String),
     Op(Box<Expr>, Opcode, Box<Expr>),
     Call(String, Vec<Box<Expr>>),
+    Pack(Vec<(String, Box<Expr>)>), // pack(r: image, g: image, ...)
     Error,
 }
 
@@ -92,11 +131,65 @@ impl Debug for Expr {
                 "Function {{ name:{:?}, parameters: {:?}}}",
                 identifier, exprs
             ),
+            Self::Pack(ref channels) => write!(fmt, "Pack {{ channels: {:?} }}", channels),
             Self::Error => write!(fmt, "error"),
         }
     }
 }
 
+impl Expr {
+    // rewrite built-ins with special syntax into plain calls
+    pub fn lower(&self) -> Result<Box<Expr>, String> {
+        match self {
+            Self::Op(ref l, op, ref r) => Ok(Box::new(Self::Op(l.lower()?, *op, r.lower()?))),
+            Self::Call(ref identifier, ref exprs) => {
+                let mut lowered: Vec<Box<Expr>> = vec![];
+                for expr in exprs {
+                    lowered.push(expr.lower()?);
+                }
+                Ok(Box::new(Self::Call(identifier.clone(), lowered)))
+            }
+            Self::Pack(ref channels) => {
+                let mut lowered: Vec<(String, Box<Expr>)> = vec![];
+                for (channel, expr) in channels {
+                    lowered.push((channel.clone(), expr.lower()?));
+                }
+                Ok(Box::new(Expr::pack_call(&lowered)?))
+            }
+            _ => Ok(Box::new(self.clone())),
+        }
+    }
+
+    // pack(g: a, r: b) becomes the library call pack(b, a, -1, -1)
+    pub fn pack_call(channels: &Vec<(String, Box<Expr>)>) -> Result<Expr, String> {
+        let mut args: Vec<Box<Expr>> = vec![Box::new(Expr::Number(-1)); 4];
+        let mut assigned: Vec<&String> = vec![];
+        for (channel, expr) in channels {
+            let index = match channel.as_str() {
+                "r" => 0,
+                "g" => 1,
+                "b" => 2,
+                "a" => 3,
+                _ => {
+                    return Err(format!(
+                        "pack() takes the channels r, g, b and a, not {:?}",
+                        channel
+                    ))
+                }
+            };
+            if assigned.contains(&channel) {
+                return Err(format!("channel {:?} is packed twice", channel));
+            }
+            assigned.push(channel);
+            args[index] = expr.clone();
+        }
+        if assigned.is_empty() {
+            return Err("pack() needs at least one channel".to_string());
+        }
+        Ok(Expr::Call("pack".to_string(), args))
+    }
+}
+
 impl Compile for Expr {
     fn compile(
         &self,
@@ -104,7 +197,7 @@ impl Compile for Expr {
         builder: &mut InstrSeqBuilder,
         symbol_table: &mut SymbolTable,
         memories: &mut Memory,
-    ) -> Result<(), &'static str> {
+    ) -> Result<(), String> {
         use self::Expr::*;
         match *self {
             Number(n) => {
@@ -132,42 +225,37 @@ impl Compile for Expr {
             Variable(ref identifier) => {
                 if let Some(attr) = symbol_table.lookup(identifier) {
                     match attr {
-                        Attribute::Image(local_id, _) => {
+                        Attribute::Image(local_id, _) | Attribute::Number(local_id) => {
                             builder.local_get(*local_id);
                             return Ok(());
                         }
                         Attribute::Material(_, _, _) => {}
                         _ => {
-                            log(&format!(
-                                "Error: variable {:?} is neither an image nor a number.",
+                            return Err(format!(
+                                "variable {:?} is neither an image nor a number.",
                                 identifier
                             ));
-                            return Err("Error");
                         }
                     }
                 } else {
-                    log(&format!("Error:  variable {:?} doesn't exist.", identifier));
-                    return Err("Error");
+                    return Err(format!("variable {:?} doesn't exist.", identifier));
                 }
             }
             Call(ref identifier, ref exprs) => {
                 if symbol_table.lookup(identifier).is_none() {
-                    log(&format!(
-                        "Error: function {:?} doesn't exist. Please try using an existing function from the library.",
+                    return Err(format!("function {:?} doesn't exist. Please try using an existing function from the library.",
                         identifier
                     ));
-                    return Err("Error");
                 };
                 match symbol_table.lookup(identifier).unwrap().clone() {
                     Attribute::Func(func_id, arguments, _) => {
                         if exprs.len() != arguments.len() {
-                            log(&format!(
-                                "Error: function {:?} should take {:?} parameters instead of {:?} ",
+                            return Err(format!(
+                                "function {:?} should take {:?} parameters instead of {:?} ",
                                 identifier,
                                 arguments.len(),
                                 exprs.len()
                             ));
-                            return Ok(());
                         }
                         for i in 0..exprs.len() {
                             let expr = &*exprs[i];
@@ -196,23 +284,20 @@ impl Compile for Expr {
                                                     }
                                                 }
                                                 _ => {
-                                                    log(&format!(
-                                                        "Error: {:?} has a wrong type",
+                                                    return Err(format!(
+                                                        "{:?} has a wrong type",
                                                         expr
                                                     ));
-                                                    return Err("Error");
                                                 }
                                             }
                                         } else {
-                                            log(&format!("Error: {:?} has a wrong type", expr));
-                                            return Err("Error");
+                                            return Err(format!("{:?} has a wrong type", expr));
                                         }
                                     } else {
-                                        log(&format!(
-                                            "Error: Variable {:?} does not exist",
+                                        return Err(format!(
+                                            "Variable {:?} does not exist",
                                             var_ident
                                         ));
-                                        return Err("Error");
                                     }
                                 }
                                 _ => {}
@@ -238,6 +323,8 @@ pub enum Statement {
     Assignment(Vec<String>, Box<Expr>),
     Block(Vec<Statement>),
     Call(String, Vec<Box<Expr>>),
+    MaterialType(String, Vec<String>), // type name, channel names
+    Test(String, Vec<(Statement, usize, usize)>), // name, statements with their byte range
 }
 
 impl Debug for Statement {
@@ -259,10 +346,70 @@ impl Debug for Statement {
             Self::Call(ref identifier, ref exprs) => {
                 write!(fmt, "function{:?}({:?})", identifier, exprs)
             }
+            Self::MaterialType(ref identifier, ref channels) => {
+                write!(
+                    fmt,
+                    "material type: {:?}, channels: {:?}",
+                    identifier, channels
+                )
+            }
+            Self::Test(ref name, ref statements) => {
+                write!(fmt, "test: {:?}, statements: {:?}", name, statements)
+            }
+        }
+    }
+}
+
+impl Statement {
+    pub fn lower(&self) -> Result<Statement, String> {
+        match self {
+            Self::Declare(ref identifier, ref var_type, Some(ref expr)) => Ok(Self::Declare(
+                identifier.clone(),
+                var_type.clone(),
+                Some(expr.lower()?),
+            )),
+            Self::Assignment(ref identifiers, ref expr) => {
+                Ok(Self::Assignment(identifiers.clone(), expr.lower()?))
+            }
+            Self::Call(ref identifier, ref exprs) => {
+                let mut lowered: Vec<Box<Expr>> = vec![];
+                for expr in exprs {
+                    lowered.push(expr.lower()?);
+                }
+                Ok(Self::Call(identifier.clone(), lowered))
+            }
+            Self::Block(ref statements) => {
+                let mut lowered: Vec<Statement> = vec![];
+                for statement in statements {
+                    lowered.push(statement.lower()?);
+                }
+                Ok(Self::Block(lowered))
+            }
+            Self::Test(ref name, ref statements) => {
+                let mut lowered: Vec<(Statement, usize, usize)> = vec![];
+                for (statement, l, r) in statements {
+                    lowered.push((statement.lower()?, *l, *r));
+                }
+                Ok(Self::Test(name.clone(), lowered))
+            }
+            _ => Ok(self.clone()),
         }
     }
 }
 
+// give a material a record of the given shape, its previous record (if any) is released
+fn reallocate(
+    memories: &mut Memory,
+    identifier: &String,
+    offset: u32,
+    shape: Shape,
+) -> Result<u32, String> {
+    if memories.shape(offset).is_some() {
+        memories.free(offset);
+    }
+    memories.alloc(identifier, shape)
+}
+
 impl Compile for Statement {
     fn compile(
         &self,
@@ -270,15 +417,12 @@ impl Compile for Statement {
         builder: &mut InstrSeqBuilder,
         symbol_table: &mut SymbolTable,
         memories: &mut Memory,
-    ) -> Result<(), &'static str> {
+    ) -> Result<(), String> {
         use self::Statement::*;
         match &*self {
             Declare(ref identifier, ref var_type, ref expr) => {
                 if identifier.parse::<i32>().is_ok() {
-                    log(&format!(
-                        "Error: please use a non-numeric name for a variable."
-                    ));
-                    return Err("Error");
+                    return Err("please use a non-numeric name for a variable.".to_string());
                 }
 
                 match var_type.as_str() {
@@ -296,21 +440,19 @@ impl Compile for Statement {
                                                 builder.local_set(local_id);
                                             }
                                             _ => {
-                                                log(&format!(
-                                                    "Error: {:?} is not a number.",
+                                                symbol_table.remove(identifier);
+                                                return Err(format!(
+                                                    "{:?} is not a number.",
                                                     var_right_ident
                                                 ));
-                                                symbol_table.remove(identifier);
-                                                return Err("Error");
                                             }
                                         }
                                     } else {
-                                        log(&format!(
-                                            "Error: {:?} does not exist.",
+                                        symbol_table.remove(identifier);
+                                        return Err(format!(
+                                            "{:?} does not exist.",
                                             var_right_ident
                                         ));
-                                        symbol_table.remove(identifier);
-                                        return Err("Error");
                                     }
                                     symbol_table.insert(
                                         identifier.to_string(),
@@ -328,12 +470,11 @@ impl Compile for Statement {
                                     }
                                 }
                                 _ => {
-                                    log(&format!(
-                                        "Error: {:?} and {:?} has different type.",
+                                    symbol_table.remove(identifier);
+                                    return Err(format!(
+                                        "{:?} and {:?} has different type.",
                                         identifier, expr
                                     ));
-                                    symbol_table.remove(identifier);
-                                    return Err("Error");
                                 }
                             }
                         }
@@ -347,8 +488,7 @@ impl Compile for Statement {
                             match expr {
                                 Expr::Variable(right_ident) => {
                                     if symbol_table.lookup(&right_ident).is_none() {
-                                        log(&format!("Error: {:?} does not exist.", right_ident));
-                                        return Err("Error");
+                                        return Err(format!("{:?} does not exist.", right_ident));
                                     }
                                     match symbol_table.lookup(&right_ident).unwrap().clone() {
                                         Attribute::Image(right_local_id, right_image) => {
@@ -363,18 +503,16 @@ impl Compile for Statement {
                                             );
                                         }
                                         _ => {
-                                            log(&format!(
-                                                "Error: {:?} is not an image.",
+                                            return Err(format!(
+                                                "{:?} is not an image.",
                                                 right_ident
                                             ));
-                                            return Err("Error");
                                         }
                                     }
                                 }
                                 Expr::Call(right_ident, _) => {
                                     if symbol_table.lookup(&right_ident).is_none() {
-                                        log(&format!("Error: {:?} does not exist.", right_ident));
-                                        return Err("Error");
+                                        return Err(format!("{:?} does not exist.", right_ident));
                                     }
                                     match symbol_table.lookup(&right_ident).unwrap().clone() {
                                         Attribute::Func(_, _, _) => {
@@ -394,21 +532,19 @@ impl Compile for Statement {
                                             }
                                         }
                                         _ => {
-                                            log(&format!(
-                                                "Error: {:?} is not a function.",
+                                            return Err(format!(
+                                                "{:?} is not a function.",
                                                 right_ident
                                             ));
-                                            return Err("Error");
                                         }
                                     }
                                 }
                                 _ => {
-                                    log(&format!(
-                                        "Error: {:?} and {:?} has different type.",
+                                    symbol_table.remove(identifier);
+                                    return Err(format!(
+                                        "{:?} and {:?} has different type.",
                                         identifier, expr
                                     ));
-                                    symbol_table.remove(identifier);
-                                    return Err("Error");
                                 }
                             }
                         }
@@ -418,11 +554,10 @@ impl Compile for Statement {
                         return Ok(());
                     }
                     "M" | "Material" | "m" | "material" => {
-                        let (mem_id, offset) =
-                            memories.store(builder, None, vec![MemoryValue::i32(i32::MAX); 32]);
+                        // the record is allocated once the material type is known
                         symbol_table.insert(
                             identifier.clone(),
-                            Attribute::Material(mem_id, offset, "".to_string()),
+                            Attribute::Material(memories.id, UNALLOCATED, "".to_string()),
                         );
 
                         if let Some(expression) = expr {
@@ -443,48 +578,40 @@ impl Compile for Statement {
                         }
                     }
                     _ => {
-                        log(&format!(
-                            "Error: type {:?} not supported.",
-                            var_type.as_str()
-                        ));
-                        return Err("Error");
+                        return Err(format!("type {:?} not supported.", var_type.as_str()));
                     }
                 }
             }
             Assignment(ref identifiers, ref expr) => {
                 if identifiers.len() == 2 {
                     if symbol_table.lookup(&identifiers[0]).is_none() {
-                        return Err("Error");
+                        return Err(format!("{:?} does not exist.", identifiers[0]));
                     }
                     match symbol_table.lookup(&identifiers[0]).unwrap().clone() {
                         Attribute::Material(_, material_offset, material_type) => {
                             let channel_name = &identifiers[1];
 
-                            if let Ok(channel_index) = symbol_table
-                                .library_tracker
-                                .material_info
-                                .find_channel_index(&material_type, channel_name)
-                            {
+                            if let (Some(channel_offset), Some(width_offset), Some(height_offset)) = (
+                                memories.field(material_offset, &["channels", channel_name]),
+                                memories.field(material_offset, &["width"]),
+                                memories.field(material_offset, &["height"]),
+                            ) {
                                 let expr = &**expr;
                                 match expr {
                                     Expr::Variable(right_ident) => {
                                         if symbol_table.lookup(&right_ident).is_none() {
-                                            log(&format!(
-                                                "Error: {:?} does not exist.",
+                                            return Err(format!(
+                                                "{:?} does not exist.",
                                                 right_ident
                                             ));
-                                            return Err("Error");
                                         }
                                         match symbol_table.lookup(&right_ident).unwrap().clone() {
-                                            Attribute::Image(_, right_image_info) => {
+                                            Attribute::Image(right_local_id, _) => {
                                                 // store (resized) image in material
-                                                builder.i32_const(
-                                                    (material_offset
-                                                        + (3 + channel_index) * u32::pow(2, ALIGN))
-                                                        as i32,
-                                                );
+                                                builder.i32_const(channel_offset as i32);
 
-                                                builder.i32_const(right_image_info.unwrap().id);
+                                                // the id is only known at runtime when the image came from a call
+                                                builder.local_get(right_local_id);
 
                                                 // implicit resize image before assigning it to a channel
                                                 match symbol_table
@@ -493,15 +620,8 @@ impl Compile for Statement {
                                                     .clone()
                                                 {
                                                     Attribute::Func(resize_func_id, _, _) => {
-                                                        memories.load(
-                                                            builder,
-                                                            material_offset + u32::pow(2, ALIGN),
-                                                        );
-                                                        memories.load(
-                                                            builder,
-                                                            material_offset
-                                                                + 2 * u32::pow(2, ALIGN),
-                                                        );
+                                                        memories.load(builder, width_offset);
+                                                        memories.load(builder, height_offset);
                                                         builder.call(resize_func_id);
                                                     }
                                                     _ => {}
@@ -517,11 +637,10 @@ impl Compile for Statement {
                                                 );
                                             }
                                             _ => {
-                                                log(&format!(
-                                                    "Error: {:?} is not an image.",
+                                                return Err(format!(
+                                                    "{:?} is not an image.",
                                                     right_ident
                                                 ));
-                                                return Err("Error");
                                             }
                                         }
                                     }
@@ -530,17 +649,9 @@ impl Compile for Statement {
                                             Attribute::Func(_, _, returns) => {
                                                 if returns == vec![walrus::ValType::I32] {
                                                     // store (resized) image in material
-                                                    builder.i32_const(
-                                                        (material_offset
-                                                            + (3 + channel_index)
-                                                                * u32::pow(2, ALIGN))
-                                                            as i32,
-                                                    );
+                                                    builder.i32_const(channel_offset as i32);
 
                                                     // get returned image id
-                                                    // let offset = material_offset
-                                                    //     + (3 + channel_index) * u32::pow(2, ALIGN);
-                                                    // builder.i32_const(offset as i32);
                                                     let call_compile_result = expr.compile(
                                                         module,
                                                         builder,
@@ -558,16 +669,8 @@ impl Compile for Statement {
                                                         .clone()
                                                     {
                                                         Attribute::Func(resize_func_id, _, _) => {
-                                                            memories.load(
-                                                                builder,
-                                                                material_offset
-                                                                    + u32::pow(2, ALIGN),
-                                                            );
-                                                            memories.load(
-                                                                builder,
-                                                                material_offset
-                                                                    + 2 * u32::pow(2, ALIGN),
-                                                            );
+                                                            memories.load(builder, width_offset);
+                                                            memories.load(builder, height_offset);
                                                             builder.call(resize_func_id);
                                                         }
                                                         _ => {}
@@ -588,40 +691,40 @@ impl Compile for Statement {
                                                 }
                                             }
                                             _ => {
-                                                log(&format!(
-                                            "Error: {:?} doesn't exist. Please use another function",
-                                            func_ident
-                                        ));
+                                                return Err(format!(
+                                                    "{:?} doesn't exist. Please use another function",
+                                                    func_ident
+                                                ));
                                             }
                                         }
                                     }
                                     _ => {}
                                 }
                             } else {
-                                log(&format!("Error: Can't find channel {:?} of material type {:?} for variable {:?}",channel_name, material_type, identifiers[0]));
+                                return Err(format!(
+                                    "Can't find channel {:?} of material type {:?} for variable {:?}",
+                                    channel_name, material_type, identifiers[0]
+                                ));
                             }
                         }
                         _ => {
-                            log(&format!("Error: Please use a material."));
+                            return Err("Please use a material.".to_string());
                         }
                     }
                 } else if identifiers.len() == 1 {
                     let identifier = &identifiers[0];
                     let expr = &**expr;
                     if symbol_table.lookup(identifier).is_none() {
-                        log(&format!(
-                        "Error: {:?} doesn't exist. Please decalre or define it. Example: var image0:Image; or var image1:Image = file_001;",
+                        return Err(format!("{:?} doesn't exist. Please decalre or define it. Example: var image0:Image; or var image1:Image = file_001;",
                         identifier
                     ));
-                        return Err("Error");
                     }
 
                     match symbol_table.lookup(identifier).unwrap().clone() {
                         Attribute::Number(left_local_id) => match expr {
                             Expr::Variable(right_ident) => {
                                 if symbol_table.lookup(&right_ident).is_none() {
-                                    log(&format!("Error: {:?} does not exist.", right_ident));
-                                    return Err("Error");
+                                    return Err(format!("{:?} does not exist.", right_ident));
                                 }
                                 match symbol_table.lookup(&right_ident) {
                                     Some(Attribute::Number(right_local_id)) => {
@@ -629,8 +732,7 @@ impl Compile for Statement {
                                         builder.local_set(left_local_id);
                                     }
                                     _ => {
-                                        log(&format!("Error: {:?} is not a number.", expr));
-                                        return Err("Error");
+                                        return Err(format!("{:?} is not a number.", expr));
                                     }
                                 }
                             }
@@ -644,18 +746,16 @@ impl Compile for Statement {
                                 };
                             }
                             _ => {
-                                log(&format!(
-                                    "Error: value of {:?} should be as a number",
+                                return Err(format!(
+                                    "value of {:?} should be as a number",
                                     identifier,
                                 ));
-                                return Err("Error");
                             }
                         },
                         Attribute::Image(left_local_id, _) => match expr {
                             Expr::Variable(right_ident) => {
                                 if symbol_table.lookup(&right_ident).is_none() {
-                                    log(&format!("Error: {:?} does not exist.", right_ident));
-                                    return Err("Error");
+                                    return Err(format!("{:?} does not exist.", right_ident));
                                 }
                                 match symbol_table.lookup(&right_ident).unwrap().clone() {
                                     Attribute::Image(right_local_id, right_image_info) => {
@@ -670,12 +770,14 @@ impl Compile for Statement {
                                             builder.local_get(right_local_id);
                                             builder.local_set(left_local_id);
                                         } else {
-                                            return Err("Error");
+                                            return Err(format!(
+                                                "{:?} does not exist.",
+                                                identifier
+                                            ));
                                         }
                                     }
                                     _ => {
-                                        log(&format!("Error: {:?} is not an image.", right_ident));
-                                        return Err("Error");
+                                        return Err(format!("{:?} is not an image.", right_ident));
                                     }
                                 }
                             }
@@ -694,18 +796,20 @@ impl Compile for Statement {
                                                 if update_result.is_ok() {
                                                     builder.local_set(left_local_id);
                                                 } else {
-                                                    return Err("Error");
+                                                    return Err(format!(
+                                                        "{:?} does not exist.",
+                                                        identifier
+                                                    ));
                                                 }
                                             } else {
                                                 return call_compile_result;
                                             }
                                         }
                                         _ => {
-                                            log(&format!(
-                                            "Error: {:?} doesn't exist. Please use another function",
-                                            func_ident
-                                        ));
-                                            return Err("Error");
+                                            return Err(format!(
+                                                "{:?} doesn't exist. Please use another function",
+                                                func_ident
+                                            ));
                                         }
                                     }
                                 } else {
@@ -713,138 +817,204 @@ impl Compile for Statement {
                                 }
                             }
                             _ => {
-                                log(&format!(
-                                    "Error: value of {:?} should be as an image",
+                                return Err(format!(
+                                    "value of {:?} should be as an image",
                                     identifier,
                                 ));
-                                return Err("Error");
                             }
                         },
                         Attribute::Material(mem_id, left_offset, _) => match expr {
                             Expr::Variable(right_ident) => {
-                                if let Some(Attribute::Material(_, right_offset, _)) =
-                                    symbol_table.lookup(right_ident)
+                                if let Some(Attribute::Material(_, right_offset, right_type)) =
+                                    symbol_table.lookup(right_ident).cloned()
                                 {
+                                    let shape = match memories.shape(right_offset) {
+                                        Some(shape) => shape.clone(),
+                                        None => {
+                                            return Err(format!("Please call new_material() on {:?} before assigning it to {:?};",
+                                                right_ident, identifier
+                                            ));
+                                        }
+                                    };
+                                    let size = shape.size();
+                                    let offset = match reallocate(
+                                        memories,
+                                        identifier,
+                                        left_offset,
+                                        shape,
+                                    ) {
+                                        Ok(offset) => offset,
+                                        Err(e) => {
+                                            return Err(e);
+                                        }
+                                    };
+                                    if symbol_table
+                                        .update(
+                                            identifier,
+                                            Attribute::Material(mem_id, offset, right_type),
+                                        )
+                                        .is_err()
+                                    {
+                                        return Err(format!("{:?} does not exist.", identifier));
+                                    }
                                     memories.copy(
                                         builder,
-                                        left_offset.clone(),
-                                        right_offset.clone(),
-                                        32,
+                                        offset,
+                                        right_offset,
+                                        size / u32::pow(2, ALIGN),
                                     );
                                 } else {
-                                    log(&format!(
-                                        "Error: Please define {:?} with a material variable;",
+                                    return Err(format!(
+                                        "Please define {:?} with a material variable;",
                                         identifier
                                     ));
-                                    return Err("Error");
                                 }
                             }
+                            Expr::Call(right_func_ident, right_func_params)
+                                if MATERIAL_OPERATIONS.contains(&right_func_ident.as_str()) =>
+                            {
+                                compile_operation(
+                                    module,
+                                    builder,
+                                    symbol_table,
+                                    memories,
+                                    identifier,
+                                    left_offset,
+                                    right_func_ident,
+                                    right_func_params,
+                                )?;
+                            }
                             Expr::Call(right_func_ident, right_func_params) => {
                                 if *right_func_ident != "new_material".to_string() {
-                                    log(&format!(
-                                    "Error: Please call new_material(material type, width,height) to define {:?};",
+                                    return Err(format!("Please call new_material(material type, width,height) to define {:?};",
                                     identifier
                                 ));
-                                    return Err("Error");
                                 }
                                 if right_func_params.len() != 3 {
-                                    log(&format!(
-                                    "Error: Please call new_material(material type, width,height) to define {:?};",
+                                    return Err(format!("Please call new_material(material type, width,height) to define {:?};",
                                     identifier
                                 ));
-                                    return Err("Error");
                                 }
                                 let material_type = &*right_func_params[0];
                                 let width = &*right_func_params[1];
                                 let height = &*right_func_params[2];
 
-                                if let Expr::Str(t) = material_type {
-                                    let type_name = &t[1..t.len() - 1].to_string();
-                                    let material_type_id = symbol_table
-                                        .library_tracker
-                                        .material_info
-                                        .get_material_type_id(type_name)
-                                        .unwrap();
-
-                                    let update_result = symbol_table.update(
-                                        identifier,
-                                        Attribute::Material(mem_id, left_offset, type_name.clone()),
-                                    );
-                                    if update_result.is_err() {
-                                        return Err("Error");
+                                let type_name = match material_type {
+                                    Expr::Str(t) => t[1..t.len() - 1].to_string(),
+                                    _ => {
+                                        return Err(
+                                            "Please use a string for material's type.".to_string()
+                                        );
                                     }
-
-                                    memories.store(
-                                        builder,
-                                        Some(left_offset),
-                                        vec![MemoryValue::i32(material_type_id)],
-                                    );
-                                } else {
-                                    log(&format!(
-                                        "Error: Please use a string for material's type.",
+                                };
+                                let width = match width {
+                                    Expr::Number(width_i32) => *width_i32,
+                                    _ => {
+                                        return Err(
+                                            "Please use a number for material's width".to_string()
+                                        );
+                                    }
+                                };
+                                let height = match height {
+                                    Expr::Number(height_i32) => *height_i32,
+                                    _ => {
+                                        return Err(
+                                            "Please use a number for material's height".to_string()
+                                        );
+                                    }
+                                };
+                                if width <= 0 || height <= 0 {
+                                    return Err(format!(
+                                        "{:?} needs a positive width and height, not {}x{}",
+                                        identifier, width, height
                                     ));
-                                    return Err("Error");
                                 }
 
-                                if let Expr::Number(width_i32) = width {
-                                    memories.store(
-                                        builder,
-                                        Some(left_offset + u32::pow(2, ALIGN)),
-                                        vec![MemoryValue::i32(*width_i32)],
-                                    );
-                                } else {
-                                    log(&format!(
-                                        "Error: Please use a number for material's width",
-                                    ));
-                                    return Err("Error");
-                                }
+                                let material_info = &symbol_table.library_tracker.material_info;
+                                let (material_type_id, channels) = match (
+                                    material_info.get_material_type_id(&type_name),
+                                    material_info.get_material_channels(&type_name),
+                                ) {
+                                    (Ok(id), Ok(channels)) => (id, channels),
+                                    _ => {
+                                        return Err(format!(
+                                            "{:?} is not a material type.",
+                                            type_name
+                                        ));
+                                    }
+                                };
 
-                                if let Expr::Number(height_i32) = height {
-                                    memories.store(
-                                        builder,
-                                        Some(left_offset + 2 * u32::pow(2, ALIGN)),
-                                        vec![MemoryValue::i32(*height_i32)],
-                                    );
-                                } else {
-                                    log(&format!(
-                                        "Error: Please use a number for material's height",
-                                    ));
-                                    return Err("Error");
+                                let offset = match reallocate(
+                                    memories,
+                                    identifier,
+                                    left_offset,
+                                    material_shape(&channels),
+                                ) {
+                                    Ok(offset) => offset,
+                                    Err(e) => {
+                                        return Err(e);
+                                    }
+                                };
+                                let update_result = symbol_table.update(
+                                    identifier,
+                                    Attribute::Material(mem_id, offset, type_name),
+                                );
+                                if update_result.is_err() {
+                                    return Err(format!("{:?} does not exist.", identifier));
                                 }
+
+                                // [type, width, height, unassigned channels...]
+                                let mut values = vec![
+                                    MemoryValue::i32(material_type_id),
+                                    MemoryValue::i32(width),
+                                    MemoryValue::i32(height),
+                                ];
+                                values.extend(vec![MemoryValue::i32(i32::MAX); channels.len()]);
+                                memories.store(builder, offset, values);
                             }
                             _ => {
-                                log(&format!(
-                                "Error: Please define the material {:?} with another material variable, or call new_material(width,height) function",
+                                return Err(format!("Please define the material {:?} with another material variable, or call new_material(width,height) function",
                                 identifier
                             ));
-                                return Err("Error");
                             }
                         },
                         _ => {}
                     }
                 } else {
-                    log(&format!(
-                        "Error: Please have a variable on the left side = to assign the value to."
-                    ));
+                    return Err(
+                        "Please have a variable on the left side = to assign the value to."
+                            .to_string(),
+                    );
                 }
             }
             Block(_) => {
                 return Ok(());
             }
+            // compiled into functions of their own, see testing::compile_tests
+            Test(_, _) => {
+                return Ok(());
+            }
+            MaterialType(ref identifier, ref channels) => {
+                symbol_table
+                    .library_tracker
+                    .material_info
+                    .add_material_type(identifier, channels)?;
+            }
             Call(ref identifier, ref exprs) => match identifier.as_str() {
                 "logger" => {
                     log(&format!("logging: {:?}", exprs));
                 }
+                "assert" | "assert_similar" => {
+                    return Err(format!("{}() can only be used in a test block", identifier));
+                }
                 "show" => {
                     for expr in exprs {
                         match &**expr {
                             Expr::Variable(expr_ident) => {
                                 if symbol_table.lookup(&expr_ident).is_none() {
-                                    log(&format!(
-                                        "Error: {:?} doesn't exist. Please decalre or define it. Example: var image0:Image; or var image1:Image = file_001;",
+                                    return Err(format!("{:?} doesn't exist. Please decalre or define it. Example: var image0:Image; or var image1:Image = file_001;",
                                         identifier
                                     ));
-                                    return Err("Error");
                                 }
                                 match symbol_table.lookup(&expr_ident).unwrap().clone() {
                                     Attribute::Image(_, _) => {
@@ -854,18 +1024,18 @@ impl Compile for Statement {
                                     }
                                     Attribute::Material(_, _, _) => {}
                                     _ => {
-                                        log(&format!("Error: show() can only be used for image and material. show() is default for material."));
+                                        return Err("show() can only be used for image and material. show() is default for material.".to_string());
                                     }
                                 }
                             }
                             _ => {
-                                log(&format!("Error: show() can only be used for image and material. show() is default for material."));
+                                return Err("show() can only be used for image and material. show() is default for material.".to_string());
                             }
                         }
                     }
                 }
                 _ => {
-                    log(&format!("Error: Please store function {:?}'s return in a variable. Example: var image1:m = grayscale(file_001)", identifier));
+                    return Err(format!("Please store function {:?}'s return in a variable. Example: var image1:m = grayscale(file_001)", identifier));
                 }
             },
         }
@@ -895,7 +1065,7 @@ impl Compile for Prototype {
         _builder: &mut InstrSeqBuilder,
         _symbol_table: &mut SymbolTable,
         _memories: &mut Memory,
-    ) -> Result<(), &'static str> {
+    ) -> Result<(), String> {
         return Ok(());
     }
 }
@@ -904,36 +1074,42 @@ impl Compile for Prototype {
 pub struct Function {
     pub prototype: Prototype,
     pub statements: Vec<Statement>,
+    pub spans: Vec<(usize, usize)>, // byte range of every statement in the source
+    // global set to the index of the statement that runs next, for profiling hosts
+    pub statement_global: Option<GlobalId>,
 }
 
 impl Function {
-    pub fn new(prototype: Prototype, statements: Vec<Statement>) -> Self {
+    pub fn new(prototype: Prototype, statements: Vec<(Statement, usize, usize)>) -> Self {
+        let spans = statements.iter().map(|(_, l, r)| (*l, *r)).collect();
         Function {
             prototype,
-            statements,
+            statements: statements.into_iter().map(|(s, _, _)| s).collect(),
+            spans,
+            statement_global: None,
         }
     }
 }
 
-impl Compile for Function {
-    fn compile(
+impl Function {
+    // main() is compiled statement by statement, an error comes with the byte offset of the
+    // statement it stopped at
+    pub fn compile(
         &self,
         module: &mut walrus::Module,
         builder: &mut InstrSeqBuilder,
         symbol_table: &mut SymbolTable,
         memories: &mut Memory,
-    ) -> Result<(), &'static str> {
-        let _prototype_compile_result =
-            self.prototype
-                .compile(module, builder, symbol_table, memories);
-
-        for statement in &self.statements {
-            let statement_compile_result =
-                statement.compile(module, builder, symbol_table, memories);
-            if !statement_compile_result.is_ok() {
-                return statement_compile_result;
+    ) -> Result<(), (String, usize)> {
+        for (i, (statement, (l, _))) in self.statements.iter().zip(&self.spans).enumerate() {
+            if let Some(global) = self.statement_global {
+                builder.i32_const(i as i32).global_set(global);
             }
+            let statement = statement.lower().map_err(|e| (e, *l))?;
+            statement
+                .compile(module, builder, symbol_table, memories)
+                .map_err(|e| (e, *l))?;
         }
-        return Ok(());
+        Ok(())
     }
 }
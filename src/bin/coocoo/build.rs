@@ -0,0 +1,360 @@
+// coocoo build: compile a script, run it natively and write the results as png files
+//
+// <out>/<texture>.png              every show()n texture
+// <out>/<material>/<channel>.png   every channel and packed texture of a material
+// <out>/manifest.json              which file holds what
+use super::report::{format_diagnostic, format_material_diagnostic};
+use super::{Args, Failure};
+use coocoo_compiler::{
+    encode_png, register_plugin_manifest, CompiledModule, Compiler, ExportOptions, ImageData,
+    Inputs, MaterialTypeRegistry, Output, Session, Severity,
+};
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+pub struct BuildOptions {
+    pub script: PathBuf,
+    pub inputs: Option<PathBuf>,
+    pub out: PathBuf,
+    pub wasm: bool,
+    pub wat: bool,
+    pub interpret: bool,
+    pub types: Option<PathBuf>,
+    pub plugins: Option<PathBuf>,
+}
+
+impl BuildOptions {
+    pub fn from_args(args: &Args) -> Result<Self, Failure> {
+        let script = match args.positional.as_slice() {
+            [script] => PathBuf::from(script),
+            [] => return Err(Failure::Usage("no script given".to_string())),
+            _ => return Err(Failure::Usage("only one script can be built".to_string())),
+        };
+        let interpret = args.flag("--interpret");
+        if interpret && (args.flag("--wasm") || args.flag("--wat")) {
+            return Err(Failure::Usage(
+                "--interpret doesn't compile a module, it can't be used with --wasm or --wat"
+                    .to_string(),
+            ));
+        }
+        Ok(BuildOptions {
+            script,
+            inputs: args.path("--inputs"),
+            out: args.path("--out").unwrap_or_else(|| PathBuf::from("out")),
+            wasm: args.flag("--wasm"),
+            wat: args.flag("--wat"),
+            interpret,
+            types: args.path("--types"),
+            plugins: args.path("--plugins"),
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct BuildManifest {
+    script: String,
+    textures: BTreeMap<String, String>,
+    materials: Vec<MaterialFiles>,
+}
+
+#[derive(Serialize)]
+struct MaterialFiles {
+    name: String,
+    material_type: String,
+    width: i32,
+    height: i32,
+    channels: BTreeMap<String, String>,
+}
+
+// material types and plugins a script may use, registered before compiling it
+pub fn register(types: &Option<PathBuf>, plugins: &Option<PathBuf>) -> Result<(), Failure> {
+    if let Some(types) = types {
+        MaterialTypeRegistry::from_file(types)
+            .and_then(|registry| registry.register())
+            .map_err(Failure::Io)?;
+    }
+    if let Some(plugins) = plugins {
+        register_plugin_manifest(plugins).map_err(Failure::Io)?;
+    }
+    Ok(())
+}
+
+// every image of a directory, sorted by name, named after the file without its extension
+pub fn load_inputs(directory: &Option<PathBuf>) -> Result<Vec<ImageData>, Failure> {
+    let directory = match directory {
+        Some(directory) => directory,
+        None => return Ok(vec![]),
+    };
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) => {
+            return Err(Failure::Io(format!(
+                "failed to read {:?}: {}",
+                directory, e
+            )))
+        }
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && image::ImageFormat::from_path(path).is_ok())
+        .collect();
+    paths.sort();
+
+    let mut images: Vec<ImageData> = vec![];
+    for path in paths {
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if images.iter().any(|image| image.name == name) {
+            return Err(Failure::Io(format!(
+                "there is more than one input image named {:?}",
+                name
+            )));
+        }
+        let image = match image::open(&path) {
+            Ok(image) => image.to_rgba8(),
+            Err(e) => return Err(Failure::Io(format!("failed to read {:?}: {}", path, e))),
+        };
+        images.push(ImageData::new(
+            name,
+            image.width() as i32,
+            image.height() as i32,
+            image.into_raw(),
+        ));
+    }
+    Ok(images)
+}
+
+pub fn read_script(path: &Path) -> Result<String, Failure> {
+    fs::read_to_string(path).map_err(|e| Failure::Io(format!("failed to read {:?}: {}", path, e)))
+}
+
+// print the diagnostics of a script that doesn't compile
+pub fn compile(path: &Path, src: &str, inputs: &[ImageData]) -> Result<CompiledModule, Failure> {
+    let inputs = Inputs {
+        images: inputs.iter().map(|image| image.name.clone()).collect(),
+    };
+    Compiler::compile(src, &inputs).map_err(|diagnostics| {
+        for diagnostic in diagnostics {
+            eprintln!(
+                "{}",
+                format_diagnostic(&path.to_string_lossy(), src, &diagnostic)
+            );
+        }
+        Failure::Script
+    })
+}
+
+// reset the session to the inputs in the order the module expects them, its cache is kept
+pub fn load_session(session: &mut Session, inputs: &[ImageData]) {
+    session.reset();
+    for image in inputs {
+        session.add_image(
+            image.name.clone(),
+            image.width,
+            image.height,
+            image.pixels.clone(),
+        );
+    }
+}
+
+// run the module, the error that stopped it is printed
+pub fn execute(
+    path: &Path,
+    session: &mut Session,
+    module: &CompiledModule,
+    inputs: &[ImageData],
+) -> Result<Output, Failure> {
+    load_session(session, inputs);
+    session.run(module, &ExportOptions::default()).map_err(|e| {
+        eprintln!("{}: error: {}", path.to_string_lossy(), e);
+        Failure::Script
+    })
+}
+
+// files written so far with a hash of their content, files whose content didn't change since
+// are skipped
+pub struct Written {
+    hashes: HashMap<PathBuf, u64>,
+    pub files: usize,
+    pub unchanged: usize,
+}
+
+impl Written {
+    pub fn new() -> Self {
+        Written {
+            hashes: HashMap::new(),
+            files: 0,
+            unchanged: 0,
+        }
+    }
+
+    fn changed<T: Hash>(&mut self, path: &Path, content: T) -> bool {
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+        let hash = hasher.finish();
+        if self.hashes.get(path) == Some(&hash) && path.exists() {
+            self.unchanged += 1;
+            return false;
+        }
+        self.hashes.insert(path.to_path_buf(), hash);
+        self.files += 1;
+        true
+    }
+
+    fn write(&mut self, path: &Path, bytes: &[u8]) -> Result<(), Failure> {
+        if !self.changed(path, bytes) {
+            return Ok(());
+        }
+        fs::write(path, bytes)
+            .map_err(|e| Failure::Io(format!("failed to write {:?}: {}", path, e)))
+    }
+
+    // bit_depth: 8 or 16 bits per channel
+    fn write_png(&mut self, path: &Path, image: &ImageData, bit_depth: u8) -> Result<(), Failure> {
+        if !self.changed(path, (image.width, image.height, &image.pixels, bit_depth)) {
+            return Ok(());
+        }
+        let png = encode_png(&path.to_string_lossy(), image, bit_depth).map_err(Failure::Io)?;
+        fs::write(path, png).map_err(|e| Failure::Io(format!("failed to write {:?}: {}", path, e)))
+    }
+}
+
+fn create_dir(path: &Path) -> Result<(), Failure> {
+    fs::create_dir_all(path).map_err(|e| Failure::Io(format!("failed to create {:?}: {}", path, e)))
+}
+
+// run the script without compiling it, like compile() and execute()
+pub fn interpret(
+    path: &Path,
+    session: &mut Session,
+    src: &str,
+    inputs: &[ImageData],
+) -> Result<Output, Failure> {
+    load_session(session, inputs);
+    let names = Inputs {
+        images: inputs.iter().map(|image| image.name.clone()).collect(),
+    };
+    session
+        .interpret(src, &names, &ExportOptions::default())
+        .map_err(|diagnostics| {
+            for diagnostic in diagnostics {
+                eprintln!(
+                    "{}",
+                    format_diagnostic(&path.to_string_lossy(), src, &diagnostic)
+                );
+            }
+            Failure::Script
+        })
+}
+
+// compile and run the script and write its results, diagnostics are printed
+pub fn render(
+    options: &BuildOptions,
+    session: &mut Session,
+    written: &mut Written,
+) -> Result<Output, Failure> {
+    let src = read_script(&options.script)?;
+    let inputs = load_inputs(&options.inputs)?;
+    let path = options.script.to_string_lossy().to_string();
+    if options.interpret {
+        let output = interpret(&options.script, session, &src, &inputs)?;
+        create_dir(&options.out)?;
+        return write_output(options, path, output, session, written);
+    }
+    let module = compile(&options.script, &src, &inputs)?;
+
+    create_dir(&options.out)?;
+    let stem = options
+        .script
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| "main".to_string());
+    if options.wasm {
+        written.write(&options.out.join(stem.clone() + ".wasm"), &module.wasm)?;
+    }
+    if options.wat {
+        let wat = match wabt::wasm2wat(&module.wasm) {
+            Ok(wat) => wat,
+            Err(e) => return Err(Failure::Io(format!("failed to print the module: {}", e))),
+        };
+        written.write(&options.out.join(stem + ".wat"), wat.as_bytes())?;
+    }
+
+    let output = execute(&options.script, session, &module, &inputs)?;
+    write_output(options, path, output, session, written)
+}
+
+// validate the materials and write the textures, material channels and manifest.json
+fn write_output(
+    options: &BuildOptions,
+    path: String,
+    output: Output,
+    session: &Session,
+    written: &mut Written,
+) -> Result<Output, Failure> {
+    let diagnostics = session.validate_materials(&output.manifest);
+    for diagnostic in &diagnostics {
+        eprintln!("{}", format_material_diagnostic(&path, diagnostic));
+    }
+    if diagnostics.iter().any(|d| d.severity == Severity::Error) {
+        return Err(Failure::Script);
+    }
+
+    let mut manifest = BuildManifest {
+        script: path,
+        textures: BTreeMap::new(),
+        materials: vec![],
+    };
+    for (name, image) in &output.textures {
+        let file = format!("{}.png", name);
+        written.write_png(&options.out.join(&file), image, 8)?;
+        manifest.textures.insert(name.clone(), file);
+    }
+    for material in &output.manifest.materials {
+        create_dir(&options.out.join(&material.name))?;
+        let mut channels: BTreeMap<String, String> = BTreeMap::new();
+        if let Some(images) = output.materials.get(&material.name) {
+            for (channel, image) in images {
+                let file = format!("{}/{}.png", material.name, channel);
+                // packed textures aren't channels and have 8 bits
+                let bit_depth = material
+                    .channels
+                    .iter()
+                    .find(|c| &c.name == channel)
+                    .map_or(8, |c| c.bit_depth);
+                written.write_png(&options.out.join(&file), image, bit_depth)?;
+                channels.insert(channel.clone(), file);
+            }
+        }
+        manifest.materials.push(MaterialFiles {
+            name: material.name.clone(),
+            material_type: material.material_type.clone(),
+            width: material.width,
+            height: material.height,
+            channels,
+        });
+    }
+    written.write(
+        &options.out.join("manifest.json"),
+        serde_json::to_string_pretty(&manifest).unwrap().as_bytes(),
+    )?;
+    Ok(output)
+}
+
+pub fn build(options: &BuildOptions) -> Result<(), Failure> {
+    register(&options.types, &options.plugins)?;
+    let output = render(options, &mut Session::new(), &mut Written::new())?;
+    println!(
+        "{} textures and {} materials written to {}",
+        output.textures.len(),
+        output.manifest.materials.len(),
+        options.out.to_string_lossy()
+    );
+    Ok(())
+}
@@ -0,0 +1,143 @@
+// the coocoo command line, compiles scripts and renders their textures without a browser
+mod build;
+mod repl;
+mod report;
+mod test;
+mod watch;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process;
+
+const USAGE: &str = "usage: coocoo build <script> [options]
+       coocoo watch <script> [options] [--interval <ms>]
+       coocoo test <script> [-i <dir>] [--interpret] [--types <file>] [--plugins <file>]
+       coocoo repl [-i <dir>] [--types <file>] [--plugins <file>]
+
+build: compile a script, run it and write every shown texture and material channel as png
+watch: build again whenever the script or an input changes, only statements and files whose
+       inputs changed are computed and written again, prints the time of every statement
+test:  run the script and then its test blocks, prints which tests pass and why the others fail
+repl:  run statements as they're typed, variables and images stay between lines, see :help
+
+options:
+    -i, --inputs <dir>     images the script reads, named after their file without extension
+    -o, --out <dir>        where to write the results, default: out
+    --wasm                 also write the compiled module
+    --wat                  also write the compiled module as text
+    --interpret            run the script with the interpreter instead of compiling it
+    --types <file>         register the material types of a .json or .toml file
+    --plugins <file>       register the wasm filters of a plugin manifest
+    --interval <ms>        how often watch looks for changes, default: 250
+
+exit codes: 1 the script has errors or a test failed, 2 wrong usage, 3 reading or writing files
+            failed";
+
+// options followed by a value, with their long name
+const VALUE_OPTIONS: [(&str, &str); 7] = [
+    ("-i", "--inputs"),
+    ("-o", "--out"),
+    ("--inputs", "--inputs"),
+    ("--out", "--out"),
+    ("--types", "--types"),
+    ("--plugins", "--plugins"),
+    ("--interval", "--interval"),
+];
+const FLAGS: [&str; 3] = ["--wasm", "--wat", "--interpret"];
+
+pub enum Failure {
+    Script,
+    Usage(String),
+    Io(String),
+}
+
+impl Failure {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Script => 1,
+            Failure::Usage(_) => 2,
+            Failure::Io(_) => 3,
+        }
+    }
+}
+
+pub struct Args {
+    pub positional: Vec<String>,
+    options: HashMap<String, String>,
+    flags: Vec<String>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Self, Failure> {
+        let mut result = Args {
+            positional: vec![],
+            options: HashMap::new(),
+            flags: vec![],
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let name = VALUE_OPTIONS
+                .iter()
+                .find(|(name, _)| name == arg)
+                .map(|(_, long)| *long);
+            if let Some(name) = name {
+                match args.next() {
+                    Some(value) => {
+                        result.options.insert(name.to_string(), value.clone());
+                    }
+                    None => return Err(Failure::Usage(format!("{} needs a value", arg))),
+                }
+            } else if FLAGS.contains(&arg.as_str()) {
+                result.flags.push(arg.clone());
+            } else if arg.starts_with('-') {
+                return Err(Failure::Usage(format!("unknown option {}", arg)));
+            } else {
+                result.positional.push(arg.clone());
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn value(&self, name: &str) -> Option<&String> {
+        self.options.get(name)
+    }
+
+    pub fn path(&self, name: &str) -> Option<PathBuf> {
+        self.options.get(name).map(PathBuf::from)
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|flag| flag == name)
+    }
+}
+
+fn run(args: &[String]) -> Result<(), Failure> {
+    let command = match args.first() {
+        Some(command) => command.as_str(),
+        None => return Err(Failure::Usage("no command given".to_string())),
+    };
+    let args = Args::parse(&args[1..])?;
+    match command {
+        "build" => build::build(&build::BuildOptions::from_args(&args)?),
+        "watch" => watch::watch(&watch::WatchOptions::from_args(&args)?),
+        "test" => test::test(&test::TestOptions::from_args(&args)?),
+        "repl" => repl::repl(&repl::ReplOptions::from_args(&args)?),
+        "help" | "-h" | "--help" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(Failure::Usage(format!("unknown command {:?}", command))),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(failure) = run(&args) {
+        match &failure {
+            Failure::Script => {}
+            Failure::Usage(message) => eprintln!("error: {}\n\n{}", message, USAGE),
+            Failure::Io(message) => eprintln!("error: {}", message),
+        }
+        process::exit(failure.exit_code());
+    }
+}
@@ -0,0 +1,273 @@
+// coocoo repl: run statements one line at a time, variables and images stay between lines
+use super::build::{load_inputs, register};
+use super::{Args, Failure};
+use coocoo_compiler::{Diagnostic, ImageData, Repl, Variable, VariableKind};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+const PROMPT: &str = "> ";
+
+const HELP: &str = "statements run as soon as they're entered, e.g. var b:i = invert_color(rock);
+
+:vars                    every variable and its value
+:info <name>             a variable, with the size and channel statistics of images
+:save <image> <file>     write an image as png, channels of materials are material.channel
+:materials               every material and its channels
+:help                    this text
+:quit                    leave, so does ctrl-d";
+
+pub struct ReplOptions {
+    pub inputs: Option<std::path::PathBuf>,
+    pub types: Option<std::path::PathBuf>,
+    pub plugins: Option<std::path::PathBuf>,
+}
+
+impl ReplOptions {
+    pub fn from_args(args: &Args) -> Result<Self, Failure> {
+        if !args.positional.is_empty() {
+            return Err(Failure::Usage("repl doesn't take a script".to_string()));
+        }
+        Ok(ReplOptions {
+            inputs: args.path("--inputs"),
+            types: args.path("--types"),
+            plugins: args.path("--plugins"),
+        })
+    }
+}
+
+// the caret lines up with the input after the prompt
+fn print_diagnostic(diagnostic: &Diagnostic) {
+    match diagnostic.column {
+        Some(column) => eprintln!(
+            "{:>w$} error: {}",
+            "^",
+            diagnostic.message,
+            w = PROMPT.len() + column
+        ),
+        None => eprintln!("error: {}", diagnostic.message),
+    }
+}
+
+fn describe(repl: &Repl, variable: &Variable) -> String {
+    match &variable.kind {
+        VariableKind::Number => match repl.value(variable) {
+            Some(value) => format!("{} = {}", variable.name, value),
+            None => format!("{}: number", variable.name),
+        },
+        VariableKind::Image => match (repl.value(variable), repl.image(&variable.name)) {
+            (Some(id), Some(image)) => format!(
+                "{}: image #{}, {}x{}",
+                variable.name, id, image.width, image.height
+            ),
+            _ => format!("{}: image, empty", variable.name),
+        },
+        VariableKind::Material(_) => match repl.material(&variable.name) {
+            Some(material) => format!(
+                "{}: {} {}x{}, {} of {} channels assigned",
+                variable.name,
+                material.material_type,
+                material.width,
+                material.height,
+                material
+                    .channels
+                    .iter()
+                    .filter(|c| c.image.is_some())
+                    .count(),
+                material.channels.len()
+            ),
+            None => format!(
+                "{}: material, new_material() wasn't called yet",
+                variable.name
+            ),
+        },
+    }
+}
+
+// min, max and mean of the r, g, b and a bytes
+fn statistics(image: &ImageData) -> String {
+    let mut lines: Vec<String> = vec![];
+    for (i, channel) in ["r", "g", "b", "a"].iter().enumerate() {
+        let values: Vec<u8> = image.pixels.iter().skip(i).step_by(4).cloned().collect();
+        if values.is_empty() {
+            continue;
+        }
+        let sum: u64 = values.iter().map(|v| *v as u64).sum();
+        lines.push(format!(
+            "  {}  min {:>3}  max {:>3}  mean {:>7.2}",
+            channel,
+            values.iter().min().unwrap(),
+            values.iter().max().unwrap(),
+            sum as f64 / values.len() as f64
+        ));
+    }
+    lines.join("\n")
+}
+
+fn info(repl: &Repl, name: &str) -> Result<String, String> {
+    if name.contains('.') {
+        return match repl.image(name) {
+            Some(image) => Ok(format!(
+                "{}: image, {}x{}\n{}",
+                name,
+                image.width,
+                image.height,
+                statistics(image)
+            )),
+            None => Err(format!(
+                "{:?} is not a material channel with an image",
+                name
+            )),
+        };
+    }
+    let variable = match repl.variable(name) {
+        Some(variable) => variable,
+        None => return Err(format!("{:?} doesn't exist", name)),
+    };
+    let mut text = describe(repl, &variable);
+    match &variable.kind {
+        VariableKind::Image => {
+            if let Some(image) = repl.image(name) {
+                text += &format!("\n{}", statistics(image));
+            }
+        }
+        VariableKind::Material(_) => {
+            if let Some(material) = repl.material(name) {
+                for channel in &material.channels {
+                    text += &format!(
+                        "\n  {}",
+                        channel_summary(repl, &channel.name, channel.image)
+                    );
+                }
+            }
+        }
+        VariableKind::Number => {}
+    }
+    Ok(text)
+}
+
+fn channel_summary(repl: &Repl, name: &str, image: Option<i32>) -> String {
+    match image.and_then(|id| repl.session().image(id).map(|image| (id, image))) {
+        Some((id, image)) => format!("{}: image #{}, {}x{}", name, id, image.width, image.height),
+        None => format!("{}: empty", name),
+    }
+}
+
+fn save(repl: &Repl, name: &str, path: &str) -> Result<String, String> {
+    let image = match repl.image(name) {
+        Some(image) => image,
+        None => return Err(format!("{:?} is not an image", name)),
+    };
+    image::save_buffer(
+        path,
+        &image.pixels,
+        image.width as u32,
+        image.height as u32,
+        image::ColorType::Rgba8,
+    )
+    .map_err(|e| format!("failed to write {:?}: {}", path, e))?;
+    Ok(format!("{} written to {}", name, path))
+}
+
+fn materials(repl: &Repl) -> String {
+    let mut lines: Vec<String> = vec![];
+    for material in &repl.manifest().materials {
+        lines.push(format!(
+            "{}: {} {}x{}",
+            material.name, material.material_type, material.width, material.height
+        ));
+        for channel in &material.channels {
+            lines.push(format!(
+                "  {}",
+                channel_summary(repl, &channel.name, channel.image)
+            ));
+        }
+    }
+    if lines.is_empty() {
+        "no materials yet".to_string()
+    } else {
+        lines.join("\n")
+    }
+}
+
+// Ok(None) leaves the repl
+fn command(repl: &Repl, line: &str) -> Result<Option<String>, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        [":quit"] | [":q"] => Ok(None),
+        [":help"] | [":h"] => Ok(Some(HELP.to_string())),
+        [":vars"] => {
+            let lines: Vec<String> = repl
+                .variables()
+                .iter()
+                .map(|variable| describe(repl, variable))
+                .collect();
+            Ok(Some(lines.join("\n")))
+        }
+        [":info", name] => info(repl, name).map(Some),
+        [":save", name, path] => save(repl, name, path).map(Some),
+        [":materials"] => Ok(Some(materials(repl))),
+        _ => Err(format!("unknown command {:?}, see :help", line)),
+    }
+}
+
+// print the variables the line changed
+fn eval(repl: &mut Repl, line: &str) {
+    let before: HashMap<String, String> = repl
+        .variables()
+        .iter()
+        .map(|variable| (variable.name.clone(), describe(repl, variable)))
+        .collect();
+    match repl.eval(line) {
+        Ok(_) => {
+            for variable in repl.variables() {
+                let description = describe(repl, &variable);
+                if before.get(&variable.name) != Some(&description) {
+                    println!("{}", description);
+                }
+            }
+        }
+        Err(diagnostics) => {
+            for diagnostic in &diagnostics {
+                print_diagnostic(diagnostic);
+            }
+        }
+    }
+}
+
+pub fn repl(options: &ReplOptions) -> Result<(), Failure> {
+    register(&options.types, &options.plugins)?;
+    let inputs = load_inputs(&options.inputs)?;
+    if !inputs.is_empty() {
+        let names: Vec<&str> = inputs.iter().map(|image| image.name.as_str()).collect();
+        println!("inputs: {}", names.join(", "));
+    }
+    let mut repl = Repl::new(inputs).map_err(Failure::Io)?;
+    println!("type :help for the commands");
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("{}", PROMPT);
+        io::stdout().flush().ok();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            Some(Err(e)) => return Err(Failure::Io(format!("failed to read the input: {}", e))),
+            None => break,
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with(':') {
+            match command(&repl, line) {
+                Ok(Some(text)) => println!("{}", text),
+                Ok(None) => break,
+                Err(e) => eprintln!("error: {}", e),
+            }
+        } else {
+            eval(&mut repl, line);
+        }
+    }
+    println!();
+    Ok(())
+}
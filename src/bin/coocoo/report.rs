@@ -0,0 +1,45 @@
+// diagnostics in the usual file:line:column format, with the line they point at
+use coocoo_compiler::{Diagnostic, Severity};
+
+pub fn format_diagnostic(path: &str, src: &str, diagnostic: &Diagnostic) -> String {
+    match (diagnostic.line, diagnostic.column) {
+        (Some(line), Some(column)) => {
+            let text = src.lines().nth(line - 1).unwrap_or("");
+            let gutter = line.to_string().len();
+            format!(
+                "{}:{}:{}: error: {}\n{:>w$} |\n{} | {}\n{:>w$} | {:>c$}",
+                path,
+                line,
+                column,
+                diagnostic.message,
+                "",
+                line,
+                text,
+                "",
+                "^",
+                w = gutter,
+                c = column
+            )
+        }
+        _ => format!("{}: error: {}", path, diagnostic.message),
+    }
+}
+
+// a diagnostic of validate_materials
+pub fn format_material_diagnostic(path: &str, diagnostic: &Diagnostic) -> String {
+    let severity = match diagnostic.severity {
+        Severity::Warning => "warning",
+        Severity::Error => "error",
+    };
+    let material = diagnostic.material.clone().unwrap_or_default();
+    match &diagnostic.channel {
+        Some(channel) => format!(
+            "{}: {}: material {:?}, channel {:?}: {}",
+            path, severity, material, channel, diagnostic.message
+        ),
+        None => format!(
+            "{}: {}: material {:?}: {}",
+            path, severity, material, diagnostic.message
+        ),
+    }
+}
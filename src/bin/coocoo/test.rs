@@ -0,0 +1,86 @@
+// coocoo test: run the script and then every test block in it, failed assertions are printed like
+// compile errors
+use super::build::{compile, load_inputs, load_session, read_script, register};
+use super::report::format_diagnostic;
+use super::{Args, Failure};
+use coocoo_compiler::{Inputs, Session, TestResult};
+use std::path::PathBuf;
+
+pub struct TestOptions {
+    pub script: PathBuf,
+    pub inputs: Option<PathBuf>,
+    pub interpret: bool,
+    pub types: Option<PathBuf>,
+    pub plugins: Option<PathBuf>,
+}
+
+impl TestOptions {
+    pub fn from_args(args: &Args) -> Result<Self, Failure> {
+        let script = match args.positional.as_slice() {
+            [script] => PathBuf::from(script),
+            [] => return Err(Failure::Usage("no script given".to_string())),
+            _ => return Err(Failure::Usage("only one script can be tested".to_string())),
+        };
+        if args.flag("--wasm") || args.flag("--wat") {
+            return Err(Failure::Usage(
+                "test doesn't write files, it can't be used with --wasm or --wat".to_string(),
+            ));
+        }
+        Ok(TestOptions {
+            script,
+            inputs: args.path("--inputs"),
+            interpret: args.flag("--interpret"),
+            types: args.path("--types"),
+            plugins: args.path("--plugins"),
+        })
+    }
+}
+
+fn run_tests(options: &TestOptions, src: &str) -> Result<Vec<TestResult>, Failure> {
+    let path = options.script.to_string_lossy().to_string();
+    let inputs = load_inputs(&options.inputs)?;
+    let mut session = Session::new();
+    if options.interpret {
+        load_session(&mut session, &inputs);
+        let names = Inputs {
+            images: inputs.iter().map(|image| image.name.clone()).collect(),
+        };
+        return session.interpret_tests(src, &names).map_err(|diagnostics| {
+            for diagnostic in diagnostics {
+                eprintln!("{}", format_diagnostic(&path, src, &diagnostic));
+            }
+            Failure::Script
+        });
+    }
+    let module = compile(&options.script, src, &inputs)?;
+    load_session(&mut session, &inputs);
+    session.test(&module).map_err(|e| {
+        eprintln!("{}: error: {}", path, e);
+        Failure::Script
+    })
+}
+
+pub fn test(options: &TestOptions) -> Result<(), Failure> {
+    register(&options.types, &options.plugins)?;
+    let src = read_script(&options.script)?;
+    let path = options.script.to_string_lossy().to_string();
+    let results = run_tests(options, &src)?;
+
+    let mut failed = 0;
+    for result in &results {
+        if result.passed() {
+            println!("ok      {}", result.name);
+            continue;
+        }
+        failed += 1;
+        println!("FAILED  {}", result.name);
+        for failure in &result.failures {
+            eprintln!("{}", format_diagnostic(&path, &src, failure));
+        }
+    }
+    println!("{} passed, {} failed", results.len() - failed, failed);
+    if failed > 0 {
+        return Err(Failure::Script);
+    }
+    Ok(())
+}
@@ -0,0 +1,124 @@
+// coocoo watch: render again whenever the script or an input image changes
+//
+// the session keeps the image results of every library call, so only the statements whose
+// inputs changed are computed again, and only files whose content changed are written
+use super::build::{register, render, BuildOptions, Written};
+use super::{Args, Failure};
+use coocoo_compiler::{Session, StatementTiming};
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+const DEFAULT_INTERVAL: u64 = 250; // ms
+
+pub struct WatchOptions {
+    pub build: BuildOptions,
+    pub interval: Duration,
+}
+
+impl WatchOptions {
+    pub fn from_args(args: &Args) -> Result<Self, Failure> {
+        let interval = match args.value("--interval") {
+            Some(interval) => match interval.parse::<u64>() {
+                Ok(interval) => interval,
+                Err(_) => {
+                    return Err(Failure::Usage(format!(
+                        "--interval takes milliseconds, not {:?}",
+                        interval
+                    )))
+                }
+            },
+            None => DEFAULT_INTERVAL,
+        };
+        Ok(WatchOptions {
+            build: BuildOptions::from_args(args)?,
+            interval: Duration::from_millis(interval),
+        })
+    }
+}
+
+// modification times of the script and of every file in the input folder
+fn snapshot(options: &BuildOptions) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let modified = |path: &PathBuf| fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut files = vec![(options.script.clone(), modified(&options.script))];
+    if let Some(inputs) = &options.inputs {
+        if let Ok(entries) = fs::read_dir(inputs) {
+            let mut paths: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .collect();
+            paths.sort();
+            files.extend(paths.into_iter().map(|path| {
+                let time = modified(&path);
+                (path, time)
+            }));
+        }
+    }
+    files
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn print_timings(timings: &[StatementTiming]) {
+    for timing in timings {
+        let cached = if timing.calls > 0 && timing.cached == timing.calls {
+            " (cached)".to_string()
+        } else if timing.cached > 0 {
+            format!(" ({} of {} calls cached)", timing.cached, timing.calls)
+        } else {
+            "".to_string()
+        };
+        println!(
+            "{:>10.2}ms  {:>4}  {}{}",
+            milliseconds(timing.duration),
+            timing.statement.line,
+            timing.statement.text.replace('\n', " "),
+            cached
+        );
+    }
+}
+
+fn rebuild(options: &BuildOptions, session: &mut Session, written: &mut Written) {
+    let start = Instant::now();
+    written.files = 0;
+    written.unchanged = 0;
+    match render(options, session, written) {
+        Ok(output) => {
+            print_timings(&output.timings);
+            println!(
+                "rendered in {:.2}ms, {} files written, {} unchanged",
+                milliseconds(start.elapsed()),
+                written.files,
+                written.unchanged
+            );
+        }
+        // diagnostics are printed already
+        Err(Failure::Script) => {}
+        Err(Failure::Usage(message)) | Err(Failure::Io(message)) => {
+            eprintln!("error: {}", message)
+        }
+    }
+}
+
+pub fn watch(options: &WatchOptions) -> Result<(), Failure> {
+    register(&options.build.types, &options.build.plugins)?;
+    let mut session = Session::with_cache();
+    let mut written = Written::new();
+    let mut last = vec![];
+    loop {
+        let current = snapshot(&options.build);
+        if current != last {
+            // wait for editors that write a file in several steps
+            if !last.is_empty() {
+                thread::sleep(options.interval);
+            }
+            last = snapshot(&options.build);
+            println!("\nrendering {}", options.build.script.to_string_lossy());
+            rebuild(&options.build, &mut session, &mut written);
+            println!("watching for changes, press ctrl-c to stop");
+        }
+        thread::sleep(options.interval);
+    }
+}
@@ -0,0 +1,99 @@
+// image results of library calls, keyed by the function and the content of its arguments, so
+// running a changed program again only computes the calls whose inputs changed
+use super::image_library::{ImageData, ImageLibrary};
+use super::registry::{LibraryFunction, ParamType};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+
+pub struct CallCache {
+    entries: HashMap<u64, ImageData>,
+    used: HashSet<u64>, // keys looked up or stored since the last prune
+}
+
+impl CallCache {
+    pub fn new() -> Self {
+        CallCache {
+            entries: HashMap::new(),
+            used: HashSet::new(),
+        }
+    }
+
+    // None for calls that don't return an image, numbers are cheap and may have side effects
+    pub fn key(library: &ImageLibrary, function: &LibraryFunction, args: &[i32]) -> Option<u64> {
+        match &function.returns {
+            Some(param) if param.param_type == ParamType::Image => {}
+            _ => return None,
+        }
+        let mut hasher = DefaultHasher::new();
+        function.name.hash(&mut hasher);
+        for (param, arg) in function.params.iter().zip(args) {
+            match library.get_image_data(*arg) {
+                Some(image_data) if param.param_type == ParamType::Image => {
+                    image_data.width.hash(&mut hasher);
+                    image_data.height.hash(&mut hasher);
+                    image_data.pixels.hash(&mut hasher);
+                }
+                _ => arg.hash(&mut hasher),
+            }
+        }
+        Some(hasher.finish())
+    }
+
+    pub fn get(&mut self, key: u64) -> Option<&ImageData> {
+        self.used.insert(key);
+        self.entries.get(&key)
+    }
+
+    pub fn insert(&mut self, key: u64, image_data: ImageData) {
+        self.used.insert(key);
+        self.entries.insert(key, image_data);
+    }
+
+    // forget the results the last run didn't need
+    pub fn prune(&mut self) {
+        let used = std::mem::take(&mut self.used);
+        self.entries.retain(|key, _| used.contains(key));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+// call a library function, or take its image from the cache when an equal call was made before,
+// returns the result and whether it came from the cache
+pub fn cached_call(
+    library: &mut ImageLibrary,
+    cache: Option<&mut CallCache>,
+    function: &LibraryFunction,
+    args: &[i32],
+) -> Result<(i32, bool), String> {
+    let key = match &cache {
+        Some(_) => CallCache::key(library, function, args),
+        None => None,
+    };
+    let (key, cache) = match (key, cache) {
+        (Some(key), Some(cache)) => (key, cache),
+        _ => return Ok(((function.call)(library, args)?, false)),
+    };
+    if let Some(image_data) = cache.get(key) {
+        let image_data = image_data.clone();
+        let id = library.add_image(
+            image_data.name,
+            image_data.width,
+            image_data.height,
+            image_data.pixels,
+        );
+        return Ok((id, true));
+    }
+    let result = (function.call)(library, args)?;
+    if let Some(image_data) = library.get_image_data(result) {
+        cache.insert(key, image_data.clone());
+    }
+    Ok((result, false))
+}
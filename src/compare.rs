@@ -0,0 +1,69 @@
+// how much two images differ, channel by channel
+use super::image_library::ImageData;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Difference {
+    pub max: u8,       // largest difference of a channel
+    pub pixels: usize, // pixels with a channel that differs by more than the tolerance
+}
+
+impl Difference {
+    pub fn is_similar(&self) -> bool {
+        self.pixels == 0
+    }
+}
+
+fn check_size(a: &ImageData, b: &ImageData) -> Result<(), String> {
+    if a.width != b.width || a.height != b.height || a.pixels.len() != b.pixels.len() {
+        return Err(format!(
+            "the images have different sizes, {}x{} and {}x{}",
+            a.width, a.height, b.width, b.height
+        ));
+    }
+    Ok(())
+}
+
+// largest difference of the channels of two pixels
+fn pixel_difference(a: &[u8], b: &[u8]) -> u8 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(ca, cb)| ca.max(cb) - ca.min(cb))
+        .max()
+        .unwrap_or(0)
+}
+
+// rgba images of the same size
+pub fn compare_images(a: &ImageData, b: &ImageData, tolerance: u8) -> Result<Difference, String> {
+    check_size(a, b)?;
+    let mut difference = Difference { max: 0, pixels: 0 };
+    for (pa, pb) in a.pixels.chunks(4).zip(b.pixels.chunks(4)) {
+        let max = pixel_difference(pa, pb);
+        difference.max = difference.max.max(max);
+        if max > tolerance {
+            difference.pixels += 1;
+        }
+    }
+    Ok(difference)
+}
+
+// a dimmed gray version of a, with the pixels that differ by more than the tolerance in red,
+// brighter the more they differ
+pub fn diff_image(a: &ImageData, b: &ImageData, tolerance: u8) -> Result<ImageData, String> {
+    check_size(a, b)?;
+    let mut pixels: Vec<u8> = Vec::with_capacity(a.pixels.len());
+    for (pa, pb) in a.pixels.chunks(4).zip(b.pixels.chunks(4)) {
+        let max = pixel_difference(pa, pb);
+        if max > tolerance {
+            pixels.extend(&[128 + max / 2, 0, 0, 255]);
+        } else {
+            let gray = ((pa[0] as u32 + pa[1] as u32 + pa[2] as u32) / 12) as u8;
+            pixels.extend(&[gray, gray, gray, 255]);
+        }
+    }
+    Ok(ImageData::new(
+        format!("{} - {}", a.name, b.name),
+        a.width,
+        a.height,
+        pixels,
+    ))
+}
@@ -1,18 +1,26 @@
-use super::ast::*;
+use super::abi::AbiMetadata;
 use super::coocoo::ProgramParser;
+use super::diagnostic::{to_js_value, Diagnostic, Kind};
+use super::manifest::{write_manifest, MANIFEST_EXPORT_NAME};
+use super::registry::library_functions;
 // use super::image_library::*;
 use super::log_rule;
 use super::symbol::*;
+use super::testing::compile_tests;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 // use walrus::FunctionId;
 use id_arena::*;
+use lalrpop_util::ParseError;
 use walrus::*;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsValue;
 
 log_rule!();
 
-const ALIGN: u32 = 2;
+pub const ALIGN: u32 = 2;
+const PAGE_SIZE: u32 = 65536;
+pub const MAX_PAGES: u32 = 100;
 
 #[derive(Clone)]
 pub enum MemoryValue {
@@ -20,31 +28,316 @@ pub enum MemoryValue {
     i32(i32),
 }
 
+// shape of a record in linear memory
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Shape {
+    I32,
+    Array(Box<Shape>, u32),
+    Struct(Vec<(String, Shape)>),
+}
+
+impl Shape {
+    pub fn size(&self) -> u32 {
+        match self {
+            Shape::I32 => u32::pow(2, ALIGN),
+            Shape::Array(element, length) => element.size() * length,
+            Shape::Struct(fields) => fields.iter().map(|(_, shape)| shape.size()).sum(),
+        }
+    }
+
+    // byte offset of a (nested) struct field or array element, e.g. ["channels", "normal"]
+    pub fn offset_of(&self, path: &[&str]) -> Option<u32> {
+        if path.is_empty() {
+            return Some(0);
+        }
+        match self {
+            Shape::I32 => None,
+            Shape::Array(element, length) => {
+                let index = path[0].parse::<u32>().ok()?;
+                if index >= *length {
+                    return None;
+                }
+                Some(index * element.size() + element.offset_of(&path[1..])?)
+            }
+            Shape::Struct(fields) => {
+                let mut offset = 0;
+                for (name, shape) in fields {
+                    if name == path[0] {
+                        return Some(offset + shape.offset_of(&path[1..])?);
+                    }
+                    offset += shape.size();
+                }
+                None
+            }
+        }
+    }
+
+    // every i32 slot with its dotted path, in memory order
+    fn fields(&self, prefix: &str, offset: u32, result: &mut Vec<FieldLayout>) {
+        match self {
+            Shape::I32 => result.push(FieldLayout {
+                path: prefix.to_string(),
+                offset,
+            }),
+            Shape::Array(element, length) => {
+                for i in 0..*length {
+                    element.fields(
+                        &join_path(prefix, &i.to_string()),
+                        offset + i * element.size(),
+                        result,
+                    );
+                }
+            }
+            Shape::Struct(fields) => {
+                let mut offset = offset;
+                for (name, shape) in fields {
+                    shape.fields(&join_path(prefix, name), offset, result);
+                    offset += shape.size();
+                }
+            }
+        }
+    }
+}
+
+fn join_path(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", prefix, name)
+    }
+}
+
+// offset of a variable that has no record yet
+pub const UNALLOCATED: u32 = u32::MAX;
+
+// a material record: type id, width, height, then one image id per channel
+pub fn material_shape(channels: &Vec<String>) -> Shape {
+    Shape::Struct(vec![
+        ("type".to_string(), Shape::I32),
+        ("width".to_string(), Shape::I32),
+        ("height".to_string(), Shape::I32),
+        (
+            "channels".to_string(),
+            Shape::Struct(channels.iter().map(|c| (c.clone(), Shape::I32)).collect()),
+        ),
+    ])
+}
+
+#[derive(Debug, Clone)]
+struct Record {
+    name: String,
+    offset: u32,
+    shape: Shape,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FieldLayout {
+    pub path: String,
+    pub offset: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordLayout {
+    pub name: String,
+    pub offset: u32,
+    pub size: u32,
+    pub fields: Vec<FieldLayout>,
+}
+
+// where every record lives in the exported "mem", so hosts don't hard-code offsets
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemoryLayout {
+    pub align: u32,
+    pub pages: u32,
+    pub records: Vec<RecordLayout>,
+}
+
+// first-fit allocator over the module's linear memory, all allocations happen at compile time:
+// finish() sizes the initial pages to the records and the module never grows the memory itself
+#[derive(Clone)]
 pub struct Memory {
     pub id: walrus::MemoryId,
-    last_offset: u32,
+    records: Vec<Record>,
+    free: Vec<(u32, u32)>, // offset, size
+    end: u32,
+    data: Vec<(u32, Vec<u8>)>, // offset, bytes to initialize memory with
 }
 
 impl Memory {
     pub fn new(module: &mut walrus::Module) -> Self {
         return Memory {
-            id: module.memories.add_local(false, 10, Some(100)),
-            last_offset: 0,
+            id: module.memories.add_local(false, 1, Some(MAX_PAGES)),
+            records: vec![],
+            free: vec![],
+            end: 0,
+            data: vec![],
+        };
+    }
+
+    // a memory the host passes in as "env" "mem", keeping the records of the previous module
+    pub fn import(module: &mut walrus::Module, previous: Option<&Memory>) -> Self {
+        let (id, _) = module.add_import_memory("env", "mem", false, 1, Some(MAX_PAGES));
+        match previous {
+            Some(previous) => Memory {
+                id,
+                records: previous.records.clone(),
+                free: previous.free.clone(),
+                end: previous.end,
+                data: vec![],
+            },
+            None => Memory {
+                id,
+                records: vec![],
+                free: vec![],
+                end: 0,
+                data: vec![],
+            },
+        }
+    }
+
+    pub fn alloc(&mut self, name: &String, shape: Shape) -> Result<u32, String> {
+        let size = shape.size();
+        let offset = if let Some(i) = self.free.iter().position(|(_, s)| *s >= size) {
+            let (offset, free_size) = self.free[i];
+            if free_size == size {
+                self.free.remove(i);
+            } else {
+                self.free[i] = (offset + size, free_size - size);
+            }
+            offset
+        } else {
+            if self.end + size > MAX_PAGES * PAGE_SIZE {
+                return Err(format!(
+                    "out of memory, {:?} needs {} bytes but only {} of {} are left",
+                    name,
+                    size,
+                    MAX_PAGES * PAGE_SIZE - self.end,
+                    MAX_PAGES * PAGE_SIZE
+                ));
+            }
+            let offset = self.end;
+            self.end += size;
+            offset
+        };
+
+        self.records.push(Record {
+            name: name.clone(),
+            offset,
+            shape,
+        });
+        Ok(offset)
+    }
+
+    // reserve `size` bytes for constant data, see write_data
+    pub fn alloc_data(&mut self, name: &String, size: u32) -> Result<u32, String> {
+        let word = u32::pow(2, ALIGN);
+        self.alloc(
+            name,
+            Shape::Array(Box::new(Shape::I32), (size + word - 1) / word),
+        )
+    }
+
+    // bytes the memory starts with, emitted as a data segment
+    pub fn write_data(&mut self, offset: u32, bytes: Vec<u8>) {
+        self.data.push((offset, bytes));
+    }
+
+    // free ranges stay sorted and merged with their neighbours, so bigger records fit into the
+    // space of smaller ones; a range at the end gives the space back
+    pub fn free(&mut self, offset: u32) {
+        let record = match self.records.iter().position(|r| r.offset == offset) {
+            Some(i) => self.records.remove(i),
+            None => return,
         };
+        let (mut offset, mut size) = (record.offset, record.shape.size());
+        let i = self
+            .free
+            .iter()
+            .position(|(o, _)| *o > offset)
+            .unwrap_or(self.free.len());
+        if i < self.free.len() && offset + size == self.free[i].0 {
+            size += self.free.remove(i).1;
+        }
+        let i = if i > 0 && self.free[i - 1].0 + self.free[i - 1].1 == offset {
+            offset = self.free[i - 1].0;
+            size += self.free.remove(i - 1).1;
+            i - 1
+        } else {
+            i
+        };
+        if offset + size == self.end {
+            self.end = offset;
+        } else {
+            self.free.insert(i, (offset, size));
+        }
+    }
+
+    pub fn shape(&self, offset: u32) -> Option<&Shape> {
+        self.records
+            .iter()
+            .find(|r| r.offset == offset)
+            .map(|r| &r.shape)
+    }
+
+    // absolute offset of a field inside the record starting at record_offset
+    pub fn field(&self, record_offset: u32, path: &[&str]) -> Option<u32> {
+        Some(record_offset + self.shape(record_offset)?.offset_of(path)?)
+    }
+
+    pub fn pages(&self) -> u32 {
+        u32::max(1, (self.end + PAGE_SIZE - 1) / PAGE_SIZE)
+    }
+
+    // grow the initial memory to fit every record and add the constant data
+    pub fn finish(&self, module: &mut walrus::Module) {
+        for (offset, bytes) in &self.data {
+            let data_id = module.data.add(
+                walrus::DataKind::Active(walrus::ActiveData {
+                    memory: self.id,
+                    location: walrus::ActiveDataLocation::Absolute(*offset),
+                }),
+                bytes.clone(),
+            );
+            module
+                .memories
+                .get_mut(self.id)
+                .data_segments
+                .insert(data_id);
+        }
+        module.memories.get_mut(self.id).initial = self.pages();
+    }
+
+    pub fn layout(&self) -> MemoryLayout {
+        let mut records: Vec<RecordLayout> = self
+            .records
+            .iter()
+            .map(|record| {
+                let mut fields: Vec<FieldLayout> = vec![];
+                record.shape.fields("", record.offset, &mut fields);
+                RecordLayout {
+                    name: record.name.clone(),
+                    offset: record.offset,
+                    size: record.shape.size(),
+                    fields,
+                }
+            })
+            .collect();
+        records.sort_by_key(|r| r.offset);
+        MemoryLayout {
+            align: ALIGN,
+            pages: self.pages(),
+            records,
+        }
     }
 
     pub fn store(
         &mut self,
         builder: &mut InstrSeqBuilder,
-        offset: Option<u32>,
+        offset: u32,
         value: Vec<MemoryValue>,
     ) -> (walrus::MemoryId, u32) {
-        let mut offset = if offset.is_some() {
-            offset.unwrap()
-        } else {
-            self.last_offset.clone()
-        };
         let start_offset = offset;
+        let mut offset = offset;
         let n = value.len();
         for i in 0..n {
             builder.i32_const(offset as i32);
@@ -68,7 +361,6 @@ impl Memory {
                 },
             );
         }
-        self.last_offset = self.last_offset.max(offset);
         return (self.id.clone(), start_offset);
     }
 
@@ -119,43 +411,233 @@ impl Memory {
     }
 }
 
+// what a program can refer to besides library functions
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Inputs {
+    // image variables, their ids are the order they're added to the image library in
+    pub images: Vec<String>,
+}
+
+// a top-level statement of the program, main() sets the exported "statement" global to its
+// index before running it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatementSource {
+    pub line: usize,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompiledModule {
+    pub wasm: Vec<u8>,
+    pub textures: HashMap<String, i32>, // name, image_id of every show()n image
+    pub imports: Vec<ImportInfo>,
+    pub layout: MemoryLayout,
+    pub statements: Vec<StatementSource>,
+    pub tests: Vec<String>, // names of the test blocks, exported as "test:<name>"
+}
+
+// a program compiled a piece at a time, e.g. line by line. every piece becomes a module whose
+// main() continues where the previous one stopped: the memory is imported from the host as
+// "env" "mem", and number and image variables are kept in a slot of it between pieces
+#[derive(Clone)]
+pub struct Scope {
+    inputs: Vec<String>,
+    symbol_table: SymbolTable,
+    memory: Option<Memory>,      // None before the first piece
+    slots: HashMap<String, u32>, // variable, offset of the slot holding its value
+    manifest: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum VariableKind {
+    Number,
+    Image,
+    Material(String), // material type, empty until new_material() was called
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Variable {
+    pub name: String,
+    pub kind: VariableKind,
+    // the slot of a number or image, the record of a material
+    pub offset: Option<u32>,
+}
+
+impl Scope {
+    pub fn new(inputs: &Inputs) -> Self {
+        Scope {
+            inputs: inputs.images.clone(),
+            symbol_table: SymbolTable::new(),
+            memory: None,
+            slots: HashMap::new(),
+            manifest: None,
+        }
+    }
+
+    // self is left as it is, the returned scope continues after the piece once it ran
+    pub fn compile(&self, src: &str) -> Result<(CompiledModule, Scope), Vec<Diagnostic>> {
+        let mut scope = self.clone();
+        let mut compiler = Compiler::new();
+        let result = compiler.build(src, &self.inputs, Some(&mut scope));
+        compiler.finish(result).map(|module| (module, scope))
+    }
+
+    pub fn variables(&self) -> Vec<Variable> {
+        self.symbol_table
+            .entries()
+            .into_iter()
+            .filter_map(|(name, attr)| {
+                let (kind, offset) = match attr {
+                    Attribute::Number(_) => (VariableKind::Number, self.slots.get(&name).cloned()),
+                    Attribute::Image(_, _) => (VariableKind::Image, self.slots.get(&name).cloned()),
+                    Attribute::Material(_, offset, material_type) => (
+                        VariableKind::Material(material_type),
+                        Some(offset).filter(|offset| *offset != UNALLOCATED),
+                    ),
+                    _ => return None,
+                };
+                Some(Variable { name, kind, offset })
+            })
+            .collect()
+    }
+
+    // give the variables of the previous pieces a local of the new module
+    pub(crate) fn enter(
+        &mut self,
+        module: &mut walrus::Module,
+        builder: &mut InstrSeqBuilder,
+        symbol_table: &mut SymbolTable,
+        memory: &mut Memory,
+    ) {
+        if let Some(offset) = self.manifest {
+            memory.free(offset);
+        }
+        let memory_id = memory.id;
+        let mut load = |name: &String| {
+            let local_id = module.locals.add(walrus::ValType::I32);
+            if let Some(slot) = self.slots.get(name) {
+                memory.load(builder, *slot);
+                builder.local_set(local_id);
+            }
+            local_id
+        };
+        for (name, attr) in symbol_table.entries() {
+            let attr = match attr {
+                Attribute::Number(_) => Attribute::Number(load(&name)),
+                Attribute::Image(_, image) => Attribute::Image(load(&name), image),
+                Attribute::Material(_, offset, material_type) => {
+                    Attribute::Material(memory_id, offset, material_type)
+                }
+                _ => continue,
+            };
+            symbol_table.rebind(&name, attr);
+        }
+    }
+
+    // keep the value of every number and image in its slot for the next piece
+    pub(crate) fn leave(
+        &mut self,
+        builder: &mut InstrSeqBuilder,
+        symbol_table: &SymbolTable,
+        memory: &mut Memory,
+    ) -> Result<(), String> {
+        for (name, attr) in symbol_table.entries() {
+            let local_id = match attr {
+                Attribute::Number(local_id) | Attribute::Image(local_id, _) => local_id,
+                _ => continue,
+            };
+            let slot = match self.slots.get(&name) {
+                Some(slot) => *slot,
+                None => memory.alloc(&name, Shape::I32)?,
+            };
+            self.slots.insert(name, slot);
+            memory.store(builder, slot, vec![MemoryValue::walrus_id(local_id)]);
+        }
+        Ok(())
+    }
+}
+
+// the program is compiled as the body of main()
+pub const MAIN_PREFIX: &str = "func main(){";
+pub const STATEMENT_EXPORT_NAME: &str = "statement";
+
 pub struct Compiler {
     module: walrus::Module,
     src: String,
     symbol_table: SymbolTable,
     result: Vec<u8>,
+    imports: Vec<ImportInfo>,
+    layout: MemoryLayout,
+    statements: Vec<StatementSource>,
+    tests: Vec<String>,
 }
 
 impl Compiler {
-    pub fn new() -> Compiler {
+    fn new() -> Compiler {
         Compiler {
             module: walrus::Module::with_config(ModuleConfig::new()),
             src: "".to_string(),
             symbol_table: SymbolTable::new(),
             result: vec![],
+            imports: vec![],
+            layout: MemoryLayout {
+                align: ALIGN,
+                pages: 0,
+                records: vec![],
+            },
+            statements: vec![],
+            tests: vec![],
         }
     }
 
+    pub fn compile(src: &str, inputs: &Inputs) -> Result<CompiledModule, Vec<Diagnostic>> {
+        let mut compiler = Compiler::new();
+        let result = compiler.build(src, &inputs.images, None);
+        compiler.finish(result)
+    }
+
+    fn finish(self, result: Result<(), Diagnostic>) -> Result<CompiledModule, Vec<Diagnostic>> {
+        match result {
+            Ok(()) => Ok(CompiledModule {
+                wasm: self.result,
+                textures: self.symbol_table.library_tracker.texture_exports(),
+                imports: self.imports,
+                layout: self.layout,
+                statements: self.statements,
+                tests: self.tests,
+            }),
+            Err(diagnostic) => Err(vec![diagnostic]),
+        }
+    }
+    // import every library function, the ones the compiled program doesn't call are removed
+    // again by prune_imports
     fn import_lib(&mut self) {
-        let lib_func_list = library_function_list();
-        for (name, (args, result)) in lib_func_list.iter() {
-            let type_id = if let Some(t_id) = self.module.types.find(args, result) {
+        for function in library_functions().iter() {
+            let (args, result) = (function.param_types(), function.return_types());
+            let type_id = if let Some(t_id) = self.module.types.find(&args, &result) {
                 t_id
             } else {
-                self.module.types.add(args, result)
+                self.module.types.add(&args, &result)
             };
-            let (func_id, import_id) = self.module.add_import_func("env", name, type_id);
-            self.module
-                .globals
-                .add_import(walrus::ValType::Funcref, false, import_id);
+            let (func_id, _) = self.module.add_import_func("env", &function.name, type_id);
 
             self.symbol_table.insert(
-                name.clone(),
-                Attribute::Func(func_id, args.to_vec(), result.to_vec()),
+                function.name.clone(),
+                Attribute::Func(func_id, args.clone(), result.clone()),
             );
+            self.imports
+                .push(ImportInfo::new(&function.name, &args, &result));
         }
     }
 
+    // only keep the imports of the library functions the compiled code calls
+    fn prune_imports(&mut self) {
+        walrus::passes::gc::run(&mut self.module);
+        let module = &self.module;
+        self.imports
+            .retain(|import| module.imports.find(&import.module, &import.name).is_some());
+    }
+
     fn import_images(&mut self, builder: &mut InstrSeqBuilder, image_names: &Vec<String>) {
         for i in 0..image_names.len() as i32 {
             let image_name = image_names[i as usize].clone().trim().to_string();
@@ -168,134 +650,282 @@ impl Compiler {
         }
     }
 
-    fn compile(&mut self, src: String, image_names: Vec<String>) {
-        // let mut symbol_table: SymbolTable = SymbolTable::new();
+    // the first error is returned, at the statement it's in if it has one
+    // with a scope the program continues the pieces compiled in it before, see Scope
+    fn build(
+        &mut self,
+        src: &str,
+        image_names: &Vec<String>,
+        mut scope: Option<&mut Scope>,
+    ) -> Result<(), Diagnostic> {
+        self.src = MAIN_PREFIX.to_string() + src + "}";
+        let mut functions = parse_program(src)?;
+        // offsets of the statements are into the wrapped source
+        let at = |(message, offset): (String, usize)| {
+            Diagnostic::at(
+                Kind::Compile,
+                message,
+                src,
+                offset.saturating_sub(MAIN_PREFIX.len()),
+            )
+        };
+        if let Some(scope) = &scope {
+            self.symbol_table = scope.symbol_table.clone();
+            // functions of the previous modules, the ones this piece calls are imported again
+            for (name, attr) in self.symbol_table.entries() {
+                if let Attribute::Func(_, _, _) = attr {
+                    self.symbol_table.remove(&name);
+                }
+            }
+        }
         self.import_lib();
 
-        self.src = "func main(){".to_string() + &src + &"}".to_string();
-        let functions = ProgramParser::new().parse(&self.src).unwrap();
+        let statement_global = self.module.globals.add_local(
+            walrus::ValType::I32,
+            true,
+            walrus::InitExpr::Value(walrus::ir::Value::I32(-1)),
+        );
+        self.module
+            .exports
+            .add(STATEMENT_EXPORT_NAME, statement_global);
+        functions[0].statement_global = Some(statement_global);
+        self.statements = statement_sources(src, &functions[0]);
+
         let function = &functions[0];
         let mut function_builder = FunctionBuilder::new(&mut self.module.types, &vec![], &[]);
         let mut builder: InstrSeqBuilder = function_builder.func_body();
-        self.import_images(&mut builder, &image_names);
 
-        let mut memory = Memory::new(&mut self.module);
-        let function_compile_result = function.compile(
+        let mut memory = match &scope {
+            Some(scope) => Memory::import(&mut self.module, scope.memory.as_ref()),
+            None => Memory::new(&mut self.module),
+        };
+        match &mut scope {
+            Some(scope) if scope.memory.is_some() => scope.enter(
+                &mut self.module,
+                &mut builder,
+                &mut self.symbol_table,
+                &mut memory,
+            ),
+            _ => self.import_images(&mut builder, &image_names),
+        }
+
+        function
+            .compile(
+                &mut self.module,
+                &mut builder,
+                &mut self.symbol_table,
+                &mut memory,
+            )
+            .map_err(at)?;
+        if let Some(scope) = &mut scope {
+            if let Err(e) = scope.leave(&mut builder, &self.symbol_table, &mut memory) {
+                return Err(Diagnostic::new(Kind::Compile, e));
+            }
+        }
+        self.tests = compile_tests(
             &mut self.module,
             &mut builder,
-            &mut self.symbol_table,
+            &self.symbol_table,
             &mut memory,
-        );
-        if function_compile_result.is_ok() {
-            let function_id = function_builder.finish(vec![], &mut self.module.funcs);
+            src,
+            function,
+        )
+        .map_err(at)?;
+        let function_id = function_builder.finish(vec![], &mut self.module.funcs);
 
-            self.module
-                .exports
-                .add(&function.prototype.identifier, function_id);
-            self.module.exports.add("mem", memory.id);
+        self.module
+            .exports
+            .add(&function.prototype.identifier, function_id);
+        self.module.exports.add("mem", memory.id);
 
-            self.result = self.module.emit_wasm();
+        let manifest_offset = match write_manifest(&mut memory, &self.symbol_table.library_tracker)
+        {
+            Ok(offset) => offset,
+            Err(e) => return Err(Diagnostic::new(Kind::Compile, e)),
+        };
+        let mut manifest_builder =
+            FunctionBuilder::new(&mut self.module.types, &[], &[walrus::ValType::I32]);
+        manifest_builder
+            .func_body()
+            .i32_const(manifest_offset as i32);
+        let manifest_id = manifest_builder.finish(vec![], &mut self.module.funcs);
+        self.module.exports.add(MANIFEST_EXPORT_NAME, manifest_id);
+        if let Some(scope) = &mut scope {
+            scope.manifest = Some(manifest_offset);
+            scope.memory = Some(memory.clone());
+            scope.symbol_table = self.symbol_table.clone();
         }
+
+        memory.finish(&mut self.module);
+        self.layout = memory.layout();
+        self.prune_imports();
+
+        let metadata = AbiMetadata::new(
+            self.imports.clone(),
+            self.symbol_table.library_tracker.exported_textures(),
+            self.symbol_table.library_tracker.exported_materials(),
+            self.layout.clone(),
+            self.tests.clone(),
+        );
+        self.module.customs.add(metadata.to_custom_section());
+
+        self.result = self.module.emit_wasm();
+        Ok(())
+    }
+}
+
+// the program as the body of main()
+pub fn parse_program(src: &str) -> Result<Vec<super::ast::Function>, Diagnostic> {
+    match ProgramParser::new().parse(&(MAIN_PREFIX.to_string() + src + "}")) {
+        Ok(functions) => Ok(functions),
+        Err(e) => Err(syntax_error(src, e)),
     }
+}
+
+// the top-level statements of main() as written in src
+pub fn statement_sources(src: &str, main: &super::ast::Function) -> Vec<StatementSource> {
+    main.spans
+        .iter()
+        .map(|(l, r)| {
+            let (l, r) = (l - MAIN_PREFIX.len(), r - MAIN_PREFIX.len());
+            StatementSource {
+                line: src[..l].matches('\n').count() + 1,
+                text: src[l..r].trim().to_string(),
+            }
+        })
+        .collect()
+}
 
-    pub fn export(&mut self) -> Vec<JsValue> {
-        let e_src = JsValue::from_serde(&self.result).unwrap();
-        let e_textures = self.symbol_table.library_tracker.export_textures();
-        let e_materials = self.symbol_table.library_tracker.export_materials();
-        vec![e_src, e_textures, e_materials]
+// the regex terminals of coocoo.lalrpop by what they match
+fn token_name(token: &str) -> &str {
+    if !token.starts_with("r#") {
+        token
+    } else if token.contains("`'") {
+        "a string"
+    } else if token.contains("[0-9]*\"") {
+        "a number"
+    } else {
+        "a name"
     }
 }
 
-fn parse_image_names(image_file_names: &JsValue) -> Vec<String> {
-    match image_file_names.into_serde::<Vec<String>>() {
-        Ok(names) => names,
-        Err(e) => {
-            log(&format!(
-                "Err: {:?}, failed to pass image file names from js to wasm",
-                e
-            ));
-            vec![]
+// offsets of the parser are into the wrapped source
+fn syntax_error<T: std::fmt::Display>(src: &str, error: ParseError<usize, T, &str>) -> Diagnostic {
+    let offset = |location: usize| location.saturating_sub(MAIN_PREFIX.len());
+    let expected = |expected: &Vec<String>| {
+        if expected.is_empty() {
+            "".to_string()
+        } else {
+            let names: Vec<&str> = expected.iter().map(|token| token_name(token)).collect();
+            format!(", expected one of {}", names.join(", "))
         }
+    };
+    match error {
+        ParseError::InvalidToken { location } => Diagnostic::at(
+            Kind::Syntax,
+            "invalid token".to_string(),
+            src,
+            offset(location),
+        ),
+        ParseError::UnrecognizedEOF {
+            location,
+            expected: e,
+        } => Diagnostic::at(
+            Kind::Syntax,
+            format!("unexpected end of program{}", expected(&e)),
+            src,
+            src.trim_end().len(),
+        ),
+        // the closing brace of main()
+        ParseError::UnrecognizedToken {
+            token: (start, _, _),
+            expected: e,
+        } if offset(start) >= src.len() => Diagnostic::at(
+            Kind::Syntax,
+            format!("unexpected end of program{}", expected(&e)),
+            src,
+            src.trim_end().len(),
+        ),
+        ParseError::UnrecognizedToken {
+            token: (start, token, _),
+            expected: e,
+        } => Diagnostic::at(
+            Kind::Syntax,
+            format!("unexpected \"{}\"{}", token, expected(&e)),
+            src,
+            offset(start),
+        ),
+        ParseError::ExtraToken {
+            token: (start, token, _),
+        } => Diagnostic::at(
+            Kind::Syntax,
+            format!("unexpected \"{}\"", token),
+            src,
+            offset(start),
+        ),
+        ParseError::User { error } => Diagnostic::new(Kind::Syntax, error.to_string()),
     }
 }
 
-fn library_function_list() -> HashMap<String, (Vec<walrus::ValType>, Vec<walrus::ValType>)> {
-    let mut lib_func_list: HashMap<String, (Vec<walrus::ValType>, Vec<walrus::ValType>)> =
-        HashMap::new();
-    lib_func_list.insert("logger".to_string(), (vec![ValType::I32], vec![]));
-    lib_func_list.insert(
-        "resize".to_string(),
-        (vec![ValType::I32; 3], vec![ValType::I32]),
-    );
-    lib_func_list.insert(
-        "darken".to_string(),
-        (vec![ValType::I32; 2], vec![ValType::I32]),
-    );
-    lib_func_list.insert(
-        "lighten".to_string(),
-        (vec![ValType::I32; 2], vec![ValType::I32]),
-    );
-    lib_func_list.insert(
-        "blank_image".to_string(),
-        (vec![ValType::I32; 6], vec![ValType::I32]),
-    );
-    lib_func_list.insert(
-        "grayscale".to_string(),
-        (vec![ValType::I32], vec![ValType::I32]),
-    );
-    lib_func_list.insert(
-        "invert_color".to_string(),
-        (vec![ValType::I32], vec![ValType::I32]),
-    );
-    lib_func_list.insert(
-        "flip_horizontal".to_string(),
-        (vec![ValType::I32], vec![ValType::I32]),
-    );
-    lib_func_list.insert(
-        "flip_vertical".to_string(),
-        (vec![ValType::I32], vec![ValType::I32]),
-    );
-    lib_func_list.insert(
-        "mask_channel_r".to_string(),
-        (vec![ValType::I32], vec![ValType::I32]),
-    );
-    lib_func_list.insert(
-        "mask_channel_g".to_string(),
-        (vec![ValType::I32], vec![ValType::I32]),
-    );
-    lib_func_list.insert(
-        "mask_channel_b".to_string(),
-        (vec![ValType::I32], vec![ValType::I32]),
-    );
-    lib_func_list.insert("blur".to_string(), (vec![ValType::I32], vec![ValType::I32]));
-    lib_func_list.insert(
-        "blur_gaussian".to_string(),
-        (vec![ValType::I32; 2], vec![ValType::I32]),
-    );
-    lib_func_list.insert(
-        "noise_perlin".to_string(),
-        (vec![ValType::I32], vec![ValType::I32]),
-    );
-    lib_func_list.insert(
-        "sharpen".to_string(),
-        (vec![ValType::I32], vec![ValType::I32]),
-    );
-    lib_func_list.insert(
-        "overlay".to_string(),
-        (vec![ValType::I32; 2], vec![ValType::I32]),
-    );
-    lib_func_list.insert(
-        "multiply".to_string(),
-        (vec![ValType::I32; 2], vec![ValType::I32]),
-    );
-    return lib_func_list;
+// a host function the compiled module expects under the "env" namespace
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportInfo {
+    pub module: String,
+    pub name: String,
+    pub params: Vec<String>,
+    pub results: Vec<String>,
 }
 
+impl ImportInfo {
+    pub fn new(
+        name: &String,
+        params: &Vec<walrus::ValType>,
+        results: &Vec<walrus::ValType>,
+    ) -> Self {
+        ImportInfo {
+            module: "env".to_string(),
+            name: name.clone(),
+            params: params.iter().map(|t| t.to_string()).collect(),
+            results: results.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+}
+
+// returns [wasm bytes, {name: image_id}, imports, layout], the bytes are empty if compiling failed
 #[wasm_bindgen]
 pub fn code_to_wasm(src: String, image_names: &JsValue) -> Vec<JsValue> {
-    let mut compiler = Compiler::new();
-    compiler.compile(src, parse_image_names(image_names));
-    compiler.export()
+    let inputs = Inputs {
+        images: match image_names.into_serde::<Vec<String>>() {
+            Ok(names) => names,
+            Err(e) => {
+                log(&format!(
+                    "Err: {:?}, failed to pass image file names from js to wasm",
+                    e
+                ));
+                vec![]
+            }
+        },
+    };
+    let module = match Compiler::compile(&src, &inputs) {
+        Ok(module) => module,
+        Err(diagnostics) => {
+            for diagnostic in diagnostics {
+                log(&diagnostic.to_string());
+            }
+            return vec![
+                to_js_value(&Vec::<u8>::new()),
+                to_js_value(&HashMap::<String, i32>::new()),
+                to_js_value(&Vec::<ImportInfo>::new()),
+                JsValue::NULL,
+            ];
+        }
+    };
+    vec![
+        to_js_value(&module.wasm),
+        to_js_value(&module.textures),
+        to_js_value(&module.imports),
+        to_js_value(&module.layout),
+    ]
 }
 
 // #[cfg(test)]
@@ -424,3 +1054,196 @@ pub fn code_to_wasm(src: String, image_names: &JsValue) -> Vec<JsValue> {
 //     };
 // }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Statement;
+
+    fn words(n: u32) -> Shape {
+        Shape::Array(Box::new(Shape::I32), n)
+    }
+
+    #[test]
+    fn alloc_places_records_one_after_another() {
+        let mut memory = Memory::new(&mut walrus::Module::default());
+        assert_eq!(memory.alloc(&"a".to_string(), words(2)), Ok(0));
+        assert_eq!(memory.alloc(&"b".to_string(), Shape::I32), Ok(8));
+        assert_eq!(memory.shape(8), Some(&Shape::I32));
+        assert_eq!(memory.pages(), 1);
+        assert!(memory
+            .alloc(&"c".to_string(), words(MAX_PAGES * PAGE_SIZE / 4))
+            .is_err());
+    }
+
+    #[test]
+    fn free_space_is_reused() {
+        let mut memory = Memory::new(&mut walrus::Module::default());
+        let a = memory.alloc(&"a".to_string(), words(4)).unwrap();
+        memory.alloc(&"b".to_string(), Shape::I32).unwrap();
+        memory.free(a);
+        assert_eq!(memory.shape(a), None);
+        assert_eq!(memory.alloc(&"c".to_string(), words(3)), Ok(0));
+        assert_eq!(memory.alloc(&"d".to_string(), Shape::I32), Ok(12));
+        assert_eq!(memory.alloc(&"e".to_string(), Shape::I32), Ok(20));
+    }
+
+    #[test]
+    fn free_merges_adjacent_ranges() {
+        let mut memory = Memory::new(&mut walrus::Module::default());
+        let offsets: Vec<u32> = ["a", "b", "c", "d"]
+            .iter()
+            .map(|name| memory.alloc(&name.to_string(), words(2)).unwrap())
+            .collect();
+        // free b and the one after it, then the one before, in a different order
+        memory.free(offsets[2]);
+        memory.free(offsets[0]);
+        memory.free(offsets[1]);
+        assert_eq!(memory.free, vec![(0, 24)]);
+        assert_eq!(memory.alloc(&"e".to_string(), words(6)), Ok(0));
+    }
+
+    #[test]
+    fn freeing_the_last_record_gives_back_its_pages() {
+        let mut memory = Memory::new(&mut walrus::Module::default());
+        memory.alloc(&"a".to_string(), Shape::I32).unwrap();
+        let b = memory
+            .alloc(&"b".to_string(), words(PAGE_SIZE / 4))
+            .unwrap();
+        assert_eq!(memory.pages(), 2);
+        memory.free(b);
+        assert_eq!(memory.pages(), 1);
+        assert!(memory.free.is_empty());
+        memory.free(b);
+        assert_eq!(memory.alloc(&"c".to_string(), Shape::I32), Ok(4));
+    }
+
+    fn compile_error(src: &str) -> Diagnostic {
+        let inputs = Inputs {
+            images: vec!["rock".to_string()],
+        };
+        match Compiler::compile(src, &inputs) {
+            Ok(_) => panic!("{:?} compiled", src),
+            Err(diagnostics) => {
+                assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+                diagnostics[0].clone()
+            }
+        }
+    }
+
+    #[test]
+    fn compile_errors_are_at_their_statement() {
+        let diagnostic = compile_error("var a:i = rock;\n  var b:i = sparkle(a);");
+        assert_eq!(diagnostic.kind, Kind::Compile);
+        assert_eq!((diagnostic.line, diagnostic.column), (Some(2), Some(3)));
+        assert!(diagnostic.message.contains("\"sparkle\" does not exist"));
+
+        let diagnostic = compile_error(
+            "var wall:m = new_material(\"PBRMetalness\", 4, 4);\nwall.shininess = rock;",
+        );
+        assert_eq!(diagnostic.kind, Kind::Compile);
+        assert_eq!((diagnostic.line, diagnostic.column), (Some(2), Some(1)));
+        assert!(diagnostic.message.contains("channel \"shininess\""));
+    }
+
+    #[test]
+    fn syntax_errors_are_told_apart() {
+        let diagnostic = compile_error("var a:i = ;");
+        assert_eq!(diagnostic.kind, Kind::Syntax);
+        assert_eq!(diagnostic.line, Some(1));
+    }
+
+    #[test]
+    fn pack_is_lowered_inside_blocks() {
+        let lower = |src: &str| {
+            let main = parse_program(src).unwrap().remove(0);
+            main.statements[0]
+                .lower()
+                .map(|statement| format!("{:?}", statement))
+        };
+        let lowered = lower("{ var orm:i = pack(g: rock, r: rock); };").unwrap();
+        assert!(!lowered.contains("Pack"), "{}", lowered);
+        assert!(lowered.contains("\"pack\""), "{}", lowered);
+        assert_eq!(
+            lower("{ var orm:i = pack(x: rock); };"),
+            Err("pack() takes the channels r, g, b and a, not \"x\"".to_string())
+        );
+    }
+
+    #[test]
+    fn only_called_library_functions_are_imported() {
+        let inputs = Inputs {
+            images: vec!["rock".to_string()],
+        };
+        let src = "var stone:m = new_material(\"PBRMetalness\", 4, 4);
+stone.diffuse = invert_color(rock);
+var specular:m = convert_material(stone, \"PBRSpecular\");";
+        let module = Compiler::compile(src, &inputs).unwrap();
+        let mut names: Vec<&str> = module.imports.iter().map(|i| i.name.as_str()).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "invert_color",
+                "metalness_to_albedo",
+                "metalness_to_specular",
+                "resize"
+            ]
+        );
+
+        let wasm = walrus::Module::from_buffer(&module.wasm).unwrap();
+        let mut imported: Vec<&str> = wasm.imports.iter().map(|i| i.name.as_str()).collect();
+        imported.sort();
+        assert_eq!(imported, names);
+        let metadata = AbiMetadata::from_wasm(&module.wasm).unwrap();
+        assert_eq!(metadata.imports.len(), names.len());
+    }
+
+    #[test]
+    fn material_types_are_parsed() {
+        let main = parse_program("material_type Foliage { albedo, translucency };")
+            .unwrap()
+            .remove(0);
+        match &main.statements[0] {
+            Statement::MaterialType(name, channels) => {
+                assert_eq!(name, "Foliage");
+                assert_eq!(
+                    channels,
+                    &vec!["albedo".to_string(), "translucency".to_string()]
+                );
+            }
+            statement => panic!("parsed as {:?}", statement),
+        }
+        let diagnostic = compile_error("material_type { albedo };");
+        assert_eq!(diagnostic.kind, Kind::Syntax);
+    }
+
+    #[test]
+    fn declared_material_types_can_be_used() {
+        let inputs = Inputs {
+            images: vec!["rock".to_string()],
+        };
+        let src = "material_type Foliage { albedo, translucency };
+var leaf:m = new_material(\"Foliage\", 4, 4);
+leaf.translucency = rock;";
+        assert!(Compiler::compile(src, &inputs).is_ok());
+
+        let diagnostic = compile_error(
+            "material_type Foliage { albedo };\nmaterial_type Foliage { translucency };",
+        );
+        assert_eq!(diagnostic.kind, Kind::Compile);
+        assert_eq!(diagnostic.line, Some(2));
+        assert!(diagnostic
+            .message
+            .contains("material type \"Foliage\" is already defined"));
+
+        let diagnostic = compile_error(
+            "material_type Foliage { albedo };
+var leaf:m = new_material(\"Foliage\", 4, 4);
+leaf.roughness = rock;",
+        );
+        assert_eq!(diagnostic.kind, Kind::Compile);
+        assert_eq!(diagnostic.line, Some(3));
+        assert!(diagnostic.message.contains("channel \"roughness\""));
+    }
+}
@@ -0,0 +1,193 @@
+// errors and warnings of compiling, running and exporting a program
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::fmt;
+use wasm_bindgen::JsValue;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+// diagnostics serialized before they had a severity were all errors
+fn default_severity() -> Severity {
+    Severity::Error
+}
+
+// where a diagnostic comes from
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Syntax,    // the program doesn't parse
+    Compile,   // it parses but can't be compiled, e.g. an unknown variable
+    Runtime,   // main() or a test trapped or a library call failed
+    Assertion, // an assertion of a test failed
+    Material,  // validate_materials found a problem of a material
+}
+
+// before diagnostics had a kind only the compiler returned them
+fn default_kind() -> Kind {
+    Kind::Compile
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    #[serde(default = "default_severity")]
+    pub severity: Severity,
+    #[serde(default = "default_kind")]
+    pub kind: Kind,
+    pub message: String,
+    // 1-based position in the source passed to the compiler, if the error has one
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    // what a diagnostic of validate_materials is about
+    #[serde(default)]
+    pub material: Option<String>,
+    #[serde(default)]
+    pub channel: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(kind: Kind, message: String) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            kind,
+            message,
+            line: None,
+            column: None,
+            material: None,
+            channel: None,
+        }
+    }
+
+    // position of a byte offset into src
+    pub fn at(kind: Kind, message: String, src: &str, offset: usize) -> Self {
+        let (line, column) = position(src, offset);
+        Diagnostic {
+            line: Some(line),
+            column: Some(column),
+            ..Diagnostic::new(kind, message)
+        }
+    }
+
+    // a problem of a material or one of its channels found after the run
+    pub fn material(
+        severity: Severity,
+        material: &str,
+        channel: Option<&str>,
+        message: String,
+    ) -> Self {
+        Diagnostic {
+            severity,
+            material: Some(material.to_string()),
+            channel: channel.map(|c| c.to_string()),
+            ..Diagnostic::new(Kind::Material, message)
+        }
+    }
+}
+
+// 1-based line and column of a byte offset into src
+pub fn position(src: &str, offset: usize) -> (usize, usize) {
+    let offset = offset.min(src.len());
+    let before = &src[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+    (line, column)
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "Warning",
+            Severity::Error => "Error",
+        };
+        match (&self.material, &self.channel, self.line, self.column) {
+            (Some(material), Some(channel), _, _) => write!(
+                f,
+                "{}: {:?}.{}: {}",
+                severity, material, channel, self.message
+            ),
+            (Some(material), None, _, _) => {
+                write!(f, "{}: {:?}: {}", severity, material, self.message)
+            }
+            (None, _, Some(line), Some(column)) => {
+                write!(f, "{}: {}:{}: {}", severity, line, column, self.message)
+            }
+            _ => write!(f, "{}: {}", severity, self.message),
+        }
+    }
+}
+
+thread_local! {
+    // Some while collect() runs, see report_at
+    static COLLECTED: RefCell<Option<Vec<Diagnostic>>> = RefCell::new(None);
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(test)] {
+        fn print(s: &str) {
+            println!("{:?}", s);
+        }
+    } else if #[cfg(target_arch = "wasm32")] {
+        use wasm_bindgen::prelude::*;
+        #[wasm_bindgen]
+        extern "C" {
+            #[wasm_bindgen(js_namespace = console, js_name = log)]
+            fn print(s: &str);
+        }
+    } else {
+        fn print(s: &str) {
+            eprintln!("{}", s);
+        }
+    }
+}
+
+// the log() of every module, the compiler returns its errors as diagnostics instead
+pub fn report(s: &str) {
+    print(s);
+}
+
+// the result of a wasm-bindgen function as a JsValue, results that can't be serialized are
+// reported and undefined
+pub fn to_js_value<T: Serialize + ?Sized>(value: &T) -> JsValue {
+    match JsValue::from_serde(value) {
+        Ok(value) => value,
+        Err(e) => {
+            report(&format!("Error: failed to serialize the result: {}", e));
+            JsValue::UNDEFINED
+        }
+    }
+}
+
+// a failed assertion at a position of the source, kept as a diagnostic while collecting and
+// printed otherwise. assertions are library calls, they can't return it to the test
+pub fn report_at(message: &str, line: usize, column: usize) {
+    let diagnostic = Diagnostic {
+        line: Some(line),
+        column: Some(column),
+        ..Diagnostic::new(Kind::Assertion, message.to_string())
+    };
+    let collected = COLLECTED.with(|collected| match collected.borrow_mut().as_mut() {
+        Some(diagnostics) => {
+            diagnostics.push(diagnostic.clone());
+            true
+        }
+        None => false,
+    });
+    if !collected {
+        print(&diagnostic.to_string());
+    }
+}
+
+// run f and return the assertions that failed in it
+pub fn collect<T, F>(f: F) -> (T, Vec<Diagnostic>)
+where
+    F: FnOnce() -> T,
+{
+    let outer = COLLECTED.with(|collected| collected.replace(Some(vec![])));
+    let result = f();
+    let diagnostics = COLLECTED.with(|collected| collected.replace(outer));
+    (result, diagnostics.unwrap_or_default())
+}
@@ -0,0 +1,858 @@
+// export profiles writing ready-to-import engine assets next to the textures
+//
+// unity:  <name>.mat with the Standard or Standard (Specular setup) shader, .meta files with
+//         guids derived from the file names so the material finds its textures
+// godot:  <name>.tres StandardMaterial3D referencing res:// textures
+// unreal: T_<Name>_<Suffix> textures with occlusion, roughness and metalness packed into ORM,
+//         normals flipped to DirectX and a json import preset with the texture settings
+use super::diagnostic::to_js_value;
+use super::export::{encode_png, fill_defaults};
+use super::image_functions::{invert_color, pack};
+use super::image_library::ImageLibrary;
+use super::log_rule;
+use super::manifest::MaterialManifest;
+use super::session::shared_session;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::path::Path;
+use wasm_bindgen::prelude::*;
+
+log_rule!();
+
+const UNITY_STANDARD_SHADER: u32 = 46;
+const UNITY_STANDARD_SPECULAR_SHADER: u32 = 45;
+const UNITY_BUILTIN_GUID: &str = "0000000000000000f000000000000000";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportProfile {
+    Unity,
+    Godot,
+    Unreal,
+}
+
+impl ExportProfile {
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name {
+            "unity" => Ok(ExportProfile::Unity),
+            "godot" => Ok(ExportProfile::Godot),
+            "unreal" => Ok(ExportProfile::Unreal),
+            _ => Err(format!(
+                "{:?} is not an export profile, please use unity, godot or unreal",
+                name
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProfileOptions {
+    // folder the files go to inside the project, used for godot's res:// paths
+    #[serde(default)]
+    pub directory: String,
+}
+
+// a file relative to the export folder
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportFile {
+    pub path: String,
+    pub bytes: Vec<u8>,
+}
+
+impl ExportFile {
+    fn text(path: String, text: String) -> Self {
+        ExportFile {
+            path,
+            bytes: text.into_bytes(),
+        }
+    }
+
+    fn image(
+        library: &ImageLibrary,
+        path: String,
+        image_id: i32,
+        bit_depth: u8,
+    ) -> Result<Self, String> {
+        match library.get_image_data(image_id) {
+            Some(image_data) => Ok(ExportFile {
+                bytes: encode_png(&path, image_data, bit_depth)?,
+                path,
+            }),
+            None => Err(format!("image {:?} of {:?} doesn't exist", image_id, path)),
+        }
+    }
+}
+
+// the channels of a material, with and without default colors filled in
+struct Channels {
+    assigned: MaterialManifest,
+    filled: MaterialManifest,
+}
+
+impl Channels {
+    fn new(library: &mut ImageLibrary, material: &MaterialManifest) -> Self {
+        let mut filled = material.clone();
+        fill_defaults(library, &mut filled);
+        Channels {
+            assigned: material.clone(),
+            filled,
+        }
+    }
+
+    fn find(material: &MaterialManifest, channel: &str) -> Option<i32> {
+        material
+            .channels
+            .iter()
+            .find(|c| c.name == channel)
+            .and_then(|c| c.image)
+    }
+
+    // image the program assigned to the channel
+    fn assigned(&self, channel: &str) -> Option<i32> {
+        Self::find(&self.assigned, channel)
+    }
+
+    // the assigned image or the default color, -1 if there is neither
+    fn filled(&self, channel: &str) -> i32 {
+        Self::find(&self.filled, channel).unwrap_or(-1)
+    }
+
+    // bits per channel of the file a channel is exported to on its own
+    fn bit_depth(&self, channel: &str) -> u8 {
+        self.assigned
+            .channels
+            .iter()
+            .find(|c| c.name == channel)
+            .map_or(8, |c| c.bit_depth)
+    }
+}
+
+// rgb of one image with the brightness of another one as alpha
+fn with_alpha(library: &mut ImageLibrary, rgb: i32, alpha: i32) -> i32 {
+    let alpha = pack(library, alpha, -1, -1, -1);
+    let (mut image_data, alpha) = match (library.get_image_data(rgb), library.get_image_data(alpha))
+    {
+        (Some(rgb), Some(alpha)) => (rgb.clone(), alpha.pixels.clone()),
+        _ => return rgb,
+    };
+    for i in (0..image_data.pixels.len()).step_by(4) {
+        image_data.pixels[i + 3] = alpha[i];
+    }
+    library.add_image(
+        "".to_string(),
+        image_data.width,
+        image_data.height,
+        image_data.pixels,
+    )
+}
+
+// OpenGL (y up) normal maps to DirectX (y down)
+fn flip_green(library: &mut ImageLibrary, image_id: i32) -> i32 {
+    let mut image_data = match library.get_image_data(image_id) {
+        Some(image_data) => image_data.clone(),
+        None => return image_id,
+    };
+    for i in (0..image_data.pixels.len()).step_by(4) {
+        image_data.pixels[i + 1] = 255 - image_data.pixels[i + 1];
+    }
+    library.add_image(
+        "".to_string(),
+        image_data.width,
+        image_data.height,
+        image_data.pixels,
+    )
+}
+
+// stable 32 hex digit guid of a path, two fnv-1a hashes with different offsets
+fn unity_guid(path: &str) -> String {
+    let hash = |offset: u64| {
+        path.bytes().fold(offset, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01B3)
+        })
+    };
+    format!(
+        "{:016x}{:016x}",
+        hash(0xcbf2_9ce4_8422_2325),
+        hash(0x8422_2325_cbf2_9ce4)
+    )
+}
+
+fn unity_texture_meta(guid: &str, srgb: bool, normal_map: bool) -> String {
+    format!(
+        "fileFormatVersion: 2
+guid: {}
+TextureImporter:
+  serializedVersion: 11
+  mipmaps:
+    enableMipMap: 1
+    sRGBTexture: {}
+  textureType: {}
+  alphaIsTransparency: 0
+  userData:
+  assetBundleName:
+  assetBundleVariant:
+",
+        guid,
+        srgb as u8,
+        if normal_map { 1 } else { 0 }
+    )
+}
+
+fn unity_color(name: &str, color: [f32; 4]) -> String {
+    format!(
+        "    - {}: {{r: {}, g: {}, b: {}, a: {}}}\n",
+        name, color[0], color[1], color[2], color[3]
+    )
+}
+
+fn unity(
+    library: &mut ImageLibrary,
+    material: &MaterialManifest,
+) -> Result<Vec<ExportFile>, String> {
+    let channels = Channels::new(library, material);
+    let specular_setup = material.material_type != "PBRMetalness";
+    let (albedo, height) = match material.material_type.as_str() {
+        "PBRMetalness" => ("diffuse", "displacement"),
+        "PBRSpecular" => ("albedo", "displacement"),
+        "UnityStandardSpecular" => ("albedo", "height"),
+        _ => {
+            return Err(format!(
+                "Unity materials can't be made from {:?}, please convert_material {:?} first",
+                material.material_type, material.name
+            ))
+        }
+    };
+
+    // property, image, srgb, normal map, bit depth
+    let mut textures: Vec<(&str, i32, bool, bool, u8)> = vec![];
+    let mut floats: Vec<(&str, f32)> = vec![("_BumpScale", 1.0), ("_OcclusionStrength", 1.0)];
+    let mut keywords: Vec<&str> = vec![];
+    if let Some(id) = channels.assigned(albedo) {
+        textures.push(("_MainTex", id, true, false, channels.bit_depth(albedo)));
+    }
+    if specular_setup {
+        // smoothness is read from the alpha of the specular map
+        let gloss = channels.assigned("glosiness");
+        match (channels.assigned("specular"), gloss) {
+            (None, None) => floats.push(("_Glossiness", 0.5)),
+            (specular, _) => {
+                let specular = specular.unwrap_or(channels.filled("specular"));
+                let id = with_alpha(library, specular, channels.filled("glosiness"));
+                textures.push(("_SpecGlossMap", id, true, false, 8));
+                keywords.push("_SPECGLOSSMAP");
+                // without a glosiness channel the alpha is opaque, scale it to the default
+                floats.push(("_GlossMapScale", if gloss.is_some() { 1.0 } else { 0.5 }));
+            }
+        }
+    } else {
+        match (
+            channels.assigned("metalness"),
+            channels.assigned("roughness"),
+        ) {
+            (None, None) => {
+                floats.push(("_Metallic", 0.0));
+                floats.push(("_Glossiness", 0.5));
+            }
+            _ => {
+                let smoothness = match channels.filled("roughness") {
+                    -1 => -1,
+                    roughness => invert_color(library, roughness),
+                };
+                let id = pack(library, channels.filled("metalness"), -1, -1, smoothness);
+                textures.push(("_MetallicGlossMap", id, false, false, 8));
+                keywords.push("_METALLICGLOSSMAP");
+                floats.push(("_GlossMapScale", 1.0));
+            }
+        }
+    }
+    if let Some(id) = channels.assigned("normal") {
+        textures.push(("_BumpMap", id, false, true, channels.bit_depth("normal")));
+        keywords.push("_NORMALMAP");
+    }
+    if let Some(id) = channels.assigned(height) {
+        textures.push(("_ParallaxMap", id, false, false, channels.bit_depth(height)));
+        keywords.push("_PARALLAXMAP");
+        floats.push(("_Parallax", 0.02));
+    }
+    if let Some(id) = channels.assigned("ao") {
+        textures.push(("_OcclusionMap", id, false, false, channels.bit_depth("ao")));
+    }
+    let emissive = channels.assigned("emissive");
+    if let Some(id) = emissive {
+        textures.push((
+            "_EmissionMap",
+            id,
+            true,
+            false,
+            channels.bit_depth("emissive"),
+        ));
+        keywords.push("_EMISSION");
+    }
+
+    let mut files: Vec<ExportFile> = vec![];
+    let mut tex_envs = String::new();
+    for (property, id, srgb, normal_map, bit_depth) in &textures {
+        let path = format!("{}{}.png", material.name, property);
+        let guid = unity_guid(&path);
+        tex_envs += &format!(
+            "    - {}:\n        m_Texture: {{fileID: 2800000, guid: {}, type: 3}}\n        m_Scale: {{x: 1, y: 1}}\n        m_Offset: {{x: 0, y: 0}}\n",
+            property, guid
+        );
+        files.push(ExportFile::text(
+            format!("{}.meta", path),
+            unity_texture_meta(&guid, *srgb, *normal_map),
+        ));
+        files.push(ExportFile::image(library, path, *id, *bit_depth)?);
+    }
+    let mut float_values = String::new();
+    for (property, value) in &floats {
+        float_values += &format!("    - {}: {}\n", property, value);
+    }
+    let mut colors = unity_color("_Color", [1.0, 1.0, 1.0, 1.0]);
+    if emissive.is_some() {
+        colors += &unity_color("_EmissionColor", [1.0, 1.0, 1.0, 1.0]);
+    }
+    if specular_setup {
+        let specular = material
+            .channels
+            .iter()
+            .find(|c| c.name == "specular")
+            .and_then(|c| c.default_color)
+            .unwrap_or([56, 56, 56, 255]);
+        let value = |c: u8| c as f32 / 255.0;
+        colors += &unity_color(
+            "_SpecColor",
+            [
+                value(specular[0]),
+                value(specular[1]),
+                value(specular[2]),
+                1.0,
+            ],
+        );
+    }
+
+    let shader = if specular_setup {
+        UNITY_STANDARD_SPECULAR_SHADER
+    } else {
+        UNITY_STANDARD_SHADER
+    };
+    let mat = format!(
+        "%YAML 1.1
+%TAG !u! tag:unity3d.com,2011:
+--- !u!21 &2100000
+Material:
+  serializedVersion: 6
+  m_ObjectHideFlags: 0
+  m_CorrespondingSourceObject: {{fileID: 0}}
+  m_PrefabInstance: {{fileID: 0}}
+  m_PrefabAsset: {{fileID: 0}}
+  m_Name: {}
+  m_Shader: {{fileID: {}, guid: {}, type: 0}}
+  m_ShaderKeywords: {}
+  m_LightmapFlags: 4
+  m_EnableInstancingVariants: 0
+  m_DoubleSidedGI: 0
+  m_CustomRenderQueue: -1
+  stringTagMap: {{}}
+  disabledShaderPasses: []
+  m_SavedProperties:
+    serializedVersion: 3
+    m_TexEnvs:
+{}    m_Floats:
+{}    m_Colors:
+{}",
+        material.name,
+        shader,
+        UNITY_BUILTIN_GUID,
+        keywords.join(" "),
+        tex_envs,
+        float_values,
+        colors
+    );
+    let path = format!("{}.mat", material.name);
+    files.push(ExportFile::text(
+        format!("{}.meta", path),
+        format!(
+            "fileFormatVersion: 2
+guid: {}
+NativeFormatImporter:
+  externalObjects: {{}}
+  mainObjectFileID: 2100000
+  userData:
+  assetBundleName:
+  assetBundleVariant:
+",
+            unity_guid(&path)
+        ),
+    ));
+    files.push(ExportFile::text(path, mat));
+    Ok(files)
+}
+
+fn godot(
+    library: &mut ImageLibrary,
+    material: &MaterialManifest,
+    options: &ProfileOptions,
+) -> Result<Vec<ExportFile>, String> {
+    if material.material_type != "PBRMetalness" {
+        return Err(format!(
+            "Godot materials need a PBRMetalness material, please convert_material {:?} first",
+            material.name
+        ));
+    }
+    let channels = Channels::new(library, material);
+    let directory = options.directory.trim_matches('/');
+    let resource_path = |file: &String| {
+        if directory.is_empty() {
+            format!("res://{}", file)
+        } else {
+            format!("res://{}/{}", directory, file)
+        }
+    };
+
+    // channel, property, lines enabling it
+    let properties: [(&str, &str, &str); 6] = [
+        ("diffuse", "albedo_texture", ""),
+        (
+            "metalness",
+            "metallic_texture",
+            "metallic = 1.0\nmetallic_texture_channel = 0\n",
+        ),
+        (
+            "roughness",
+            "roughness_texture",
+            "roughness = 1.0\nroughness_texture_channel = 0\n",
+        ),
+        ("normal", "normal_texture", "normal_enabled = true\n"),
+        (
+            "ao",
+            "ao_texture",
+            "ao_enabled = true\nao_texture_channel = 0\n",
+        ),
+        ("emissive", "emission_texture", "emission_enabled = true\n"),
+    ];
+    let mut files: Vec<ExportFile> = vec![];
+    let mut ext_resources = String::new();
+    let mut resource = format!("resource_name = \"{}\"\n", material.name);
+    let mut add_texture =
+        |channel: &str, property: &str, settings: &str, id: i32| -> Result<(), String> {
+            let file = format!("{}_{}.png", material.name, channel);
+            let index = files.len() + 1;
+            ext_resources += &format!(
+                "[ext_resource type=\"Texture2D\" path=\"{}\" id=\"{}\"]\n",
+                resource_path(&file),
+                index
+            );
+            resource += settings;
+            resource += &format!("{} = ExtResource(\"{}\")\n", property, index);
+            files.push(ExportFile::image(
+                library,
+                file,
+                id,
+                channels.bit_depth(channel),
+            )?);
+            Ok(())
+        };
+    for (channel, property, settings) in properties.iter() {
+        if let Some(id) = channels.assigned(channel) {
+            add_texture(channel, property, settings, id)?;
+        }
+    }
+    if let Some(id) = channels.assigned("displacement") {
+        add_texture(
+            "displacement",
+            "heightmap_texture",
+            "heightmap_enabled = true\nheightmap_scale = 1.0\n",
+            id,
+        )?;
+    }
+    if channels.assigned("metalness").is_none() {
+        resource += "metallic = 0.0\n";
+    }
+    if channels.assigned("roughness").is_none() {
+        resource += "roughness = 0.5\n";
+    }
+
+    let tres = format!(
+        "[gd_resource type=\"StandardMaterial3D\" load_steps={} format=3]\n\n{}{}[resource]\n{}",
+        files.len() + 1,
+        ext_resources,
+        if files.is_empty() { "" } else { "\n" },
+        resource
+    );
+    files.push(ExportFile::text(format!("{}.tres", material.name), tres));
+    Ok(files)
+}
+
+// stone_wall -> StoneWall
+fn pascal_case(name: &str) -> String {
+    name.split(|c: char| c == '_' || c == '-' || c == ' ')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().collect::<String>() + chars.as_str(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+fn unreal(
+    library: &mut ImageLibrary,
+    material: &MaterialManifest,
+) -> Result<Vec<ExportFile>, String> {
+    if material.material_type != "PBRMetalness" {
+        return Err(format!(
+            "Unreal textures need a PBRMetalness material, please convert_material {:?} first",
+            material.name
+        ));
+    }
+    let channels = Channels::new(library, material);
+    let name = pascal_case(&material.name);
+
+    // suffix, image, srgb, compression setting, bit depth
+    let mut textures: Vec<(&str, i32, bool, &str, u8)> = vec![];
+    if let Some(id) = channels.assigned("diffuse") {
+        let bit_depth = channels.bit_depth("diffuse");
+        textures.push(("D", id, true, "TC_Default", bit_depth));
+    }
+    if let Some(id) = channels.assigned("normal") {
+        let bit_depth = channels.bit_depth("normal");
+        textures.push((
+            "N",
+            flip_green(library, id),
+            false,
+            "TC_Normalmap",
+            bit_depth,
+        ));
+    }
+    let orm_channels = ["ao", "roughness", "metalness"];
+    if orm_channels.iter().any(|c| channels.assigned(c).is_some()) {
+        let id = pack(
+            library,
+            channels.filled("ao"),
+            channels.filled("roughness"),
+            channels.filled("metalness"),
+            -1,
+        );
+        textures.push(("ORM", id, false, "TC_Masks", 8));
+    }
+    if let Some(id) = channels.assigned("emissive") {
+        let bit_depth = channels.bit_depth("emissive");
+        textures.push(("E", id, true, "TC_Default", bit_depth));
+    }
+    if let Some(id) = channels.assigned("displacement") {
+        let bit_depth = channels.bit_depth("displacement");
+        textures.push(("H", id, false, "TC_Grayscale", bit_depth));
+    }
+    if let Some(id) = channels.assigned("transparency") {
+        let bit_depth = channels.bit_depth("transparency");
+        textures.push(("O", id, false, "TC_Grayscale", bit_depth));
+    }
+
+    let mut files: Vec<ExportFile> = vec![];
+    let mut preset: Vec<serde_json::Value> = vec![];
+    for (suffix, id, srgb, compression, bit_depth) in &textures {
+        let texture = format!("T_{}_{}", name, suffix);
+        preset.push(json!({
+            "name": texture,
+            "file": format!("{}.png", texture),
+            "srgb": srgb,
+            "compression_settings": compression,
+        }));
+        files.push(ExportFile::image(
+            library,
+            format!("{}.png", texture),
+            *id,
+            *bit_depth,
+        )?);
+    }
+    let preset = json!({
+        "material": format!("M_{}", name),
+        "packing": { "ORM": { "r": "ao", "g": "roughness", "b": "metalness" } },
+        "normal_map_convention": "directx",
+        "textures": preset,
+    });
+    let preset = match serde_json::to_string_pretty(&preset) {
+        Ok(preset) => preset,
+        Err(e) => return Err(format!("failed to write the material preset: {}", e)),
+    };
+    files.push(ExportFile::text(format!("M_{}.json", name), preset));
+    Ok(files)
+}
+
+pub fn export_profile(
+    library: &mut ImageLibrary,
+    material: &MaterialManifest,
+    profile: ExportProfile,
+    options: &ProfileOptions,
+) -> Result<Vec<ExportFile>, String> {
+    match profile {
+        ExportProfile::Unity => unity(library, material),
+        ExportProfile::Godot => godot(library, material, options),
+        ExportProfile::Unreal => unreal(library, material),
+    }
+}
+
+// write the files of a profile into a folder
+pub fn save_profile(
+    library: &mut ImageLibrary,
+    material: &MaterialManifest,
+    profile: ExportProfile,
+    options: &ProfileOptions,
+    directory: &Path,
+) -> Result<(), String> {
+    for file in export_profile(library, material, profile, options)? {
+        let path = directory.join(&file.path);
+        if let Err(e) = std::fs::write(&path, &file.bytes) {
+            return Err(format!("failed to write {:?}: {}", path, e));
+        }
+    }
+    Ok(())
+}
+
+// material: one material of decode_manifest, profile: "unity", "godot" or "unreal",
+// options: {directory} or undefined, returns [{path, bytes}]
+#[wasm_bindgen]
+pub fn library_export_profile(material: &JsValue, profile: &str, options: &JsValue) -> JsValue {
+    let material: MaterialManifest = match material.into_serde() {
+        Ok(material) => material,
+        Err(e) => {
+            log(&format!("Error: malformed material: {}", e));
+            return JsValue::NULL;
+        }
+    };
+    let options: ProfileOptions = if options.is_undefined() || options.is_null() {
+        ProfileOptions::default()
+    } else {
+        match options.into_serde() {
+            Ok(options) => options,
+            Err(e) => {
+                log(&format!("Error: malformed export profile options: {}", e));
+                return JsValue::NULL;
+            }
+        }
+    };
+
+    let files = ExportProfile::from_name(profile)
+        .and_then(|profile| shared_session().export_profile(&material, profile, &options));
+    match files {
+        Ok(files) => to_js_value(&files),
+        Err(e) => {
+            log(&format!("Error: {}", e));
+            JsValue::NULL
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::ChannelManifest;
+    use crate::material_types::ColorSpace;
+    use serde_json::Value;
+
+    // a 1x1 PBRMetalness material with the given channels set to solid colors
+    fn material(
+        library: &mut ImageLibrary,
+        name: &str,
+        colors: &[(&str, [u8; 4])],
+    ) -> MaterialManifest {
+        let defaults = [
+            ("diffuse", None),
+            ("metalness", Some([0, 0, 0, 255])),
+            ("normal", Some([128, 128, 255, 255])),
+            ("roughness", Some([128, 128, 128, 255])),
+            ("ao", Some([255, 255, 255, 255])),
+            ("displacement", Some([128, 128, 128, 255])),
+            ("emissive", Some([0, 0, 0, 255])),
+        ];
+        let channels = defaults
+            .iter()
+            .map(|(name, default_color)| ChannelManifest {
+                name: name.to_string(),
+                image: colors
+                    .iter()
+                    .find(|(channel, _)| channel == name)
+                    .map(|(_, color)| library.add_image(name.to_string(), 1, 1, color.to_vec())),
+                default_color: *default_color,
+                required: false,
+                color_space: ColorSpace::Linear,
+                bit_depth: if *name == "displacement" { 16 } else { 8 },
+            })
+            .collect();
+        MaterialManifest {
+            name: name.to_string(),
+            material_type: "PBRMetalness".to_string(),
+            width: 1,
+            height: 1,
+            channels,
+            packing: vec![],
+        }
+    }
+
+    fn paths(files: &[ExportFile]) -> Vec<&str> {
+        files.iter().map(|file| file.path.as_str()).collect()
+    }
+
+    fn file<'a>(files: &'a [ExportFile], path: &str) -> &'a ExportFile {
+        files.iter().find(|file| file.path == path).unwrap()
+    }
+
+    fn text(files: &[ExportFile], path: &str) -> String {
+        String::from_utf8(file(files, path).bytes.clone()).unwrap()
+    }
+
+    fn pixel(files: &[ExportFile], path: &str) -> [u8; 4] {
+        let png = image::load_from_memory(&file(files, path).bytes).unwrap();
+        png.to_rgba8().get_pixel(0, 0).0
+    }
+
+    #[test]
+    fn profiles_are_found_by_name() {
+        assert_eq!(ExportProfile::from_name("godot"), Ok(ExportProfile::Godot));
+        assert_eq!(
+            ExportProfile::from_name("cryengine"),
+            Err(
+                "\"cryengine\" is not an export profile, please use unity, godot or unreal"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn unity_packs_metalness_and_smoothness() {
+        let mut library = ImageLibrary::new();
+        let stone = material(
+            &mut library,
+            "stone",
+            &[
+                ("diffuse", [10, 20, 30, 255]),
+                ("metalness", [200, 200, 200, 255]),
+                ("roughness", [40, 40, 40, 255]),
+                ("normal", [120, 130, 250, 255]),
+            ],
+        );
+        let files = unity(&mut library, &stone).unwrap();
+        assert_eq!(
+            paths(&files),
+            vec![
+                "stone_MainTex.png.meta",
+                "stone_MainTex.png",
+                "stone_MetallicGlossMap.png.meta",
+                "stone_MetallicGlossMap.png",
+                "stone_BumpMap.png.meta",
+                "stone_BumpMap.png",
+                "stone.mat.meta",
+                "stone.mat",
+            ]
+        );
+        let metallic_gloss = pixel(&files, "stone_MetallicGlossMap.png");
+        assert_eq!((metallic_gloss[0], metallic_gloss[3]), (200, 215));
+        assert_eq!(pixel(&files, "stone_BumpMap.png"), [120, 130, 250, 255]);
+        assert!(text(&files, "stone_BumpMap.png.meta").contains("textureType: 1"));
+        assert!(text(&files, "stone_MainTex.png.meta").contains("sRGBTexture: 1"));
+
+        let mat = text(&files, "stone.mat");
+        assert!(mat.contains("m_ShaderKeywords: _METALLICGLOSSMAP _NORMALMAP\n"));
+        assert!(mat.contains(&format!(
+            "m_Texture: {{fileID: 2800000, guid: {}, type: 3}}",
+            unity_guid("stone_MetallicGlossMap.png")
+        )));
+    }
+
+    #[test]
+    fn godot_references_the_textures_in_the_project() {
+        let mut library = ImageLibrary::new();
+        let stone = material(
+            &mut library,
+            "stone",
+            &[("diffuse", [10, 20, 30, 255]), ("ao", [90, 90, 90, 255])],
+        );
+        let options = ProfileOptions {
+            directory: "/materials/".to_string(),
+        };
+        let files = godot(&mut library, &stone, &options).unwrap();
+        assert_eq!(
+            paths(&files),
+            vec!["stone_diffuse.png", "stone_ao.png", "stone.tres"]
+        );
+        assert_eq!(pixel(&files, "stone_ao.png"), [90, 90, 90, 255]);
+        let tres = text(&files, "stone.tres");
+        assert!(tres.starts_with("[gd_resource type=\"StandardMaterial3D\" load_steps=3"));
+        assert!(tres.contains(
+            "[ext_resource type=\"Texture2D\" path=\"res://materials/stone_ao.png\" id=\"2\"]"
+        ));
+        assert!(tres.contains("ao_enabled = true\nao_texture_channel = 0\n"));
+        assert!(tres.contains("metallic = 0.0\n"));
+        assert!(tres.contains("roughness = 0.5\n"));
+    }
+
+    #[test]
+    fn unreal_packs_orm_and_flips_normals() {
+        let mut library = ImageLibrary::new();
+        let stone = material(
+            &mut library,
+            "stone_wall",
+            &[
+                ("normal", [120, 130, 250, 255]),
+                ("roughness", [40, 40, 40, 255]),
+                ("displacement", [60, 60, 60, 255]),
+            ],
+        );
+        let files = unreal(&mut library, &stone).unwrap();
+        assert_eq!(
+            paths(&files),
+            vec![
+                "T_StoneWall_N.png",
+                "T_StoneWall_ORM.png",
+                "T_StoneWall_H.png",
+                "M_StoneWall.json",
+            ]
+        );
+        assert_eq!(pixel(&files, "T_StoneWall_N.png"), [120, 125, 250, 255]);
+        assert_eq!(pixel(&files, "T_StoneWall_ORM.png"), [255, 40, 0, 255]);
+        let height = image::load_from_memory(&file(&files, "T_StoneWall_H.png").bytes).unwrap();
+        assert_eq!(height.color(), image::ColorType::Rgba16);
+
+        let preset: Value = serde_json::from_str(&text(&files, "M_StoneWall.json")).unwrap();
+        assert_eq!(preset["material"], json!("M_StoneWall"));
+        assert_eq!(preset["normal_map_convention"], json!("directx"));
+        assert_eq!(
+            preset["textures"][1],
+            json!({
+                "name": "T_StoneWall_ORM",
+                "file": "T_StoneWall_ORM.png",
+                "srgb": false,
+                "compression_settings": "TC_Masks",
+            })
+        );
+        assert_eq!(
+            preset["textures"][0]["compression_settings"],
+            json!("TC_Normalmap")
+        );
+    }
+
+    #[test]
+    fn profiles_need_a_material_type_they_know() {
+        let mut library = ImageLibrary::new();
+        let mut stone = material(&mut library, "stone", &[]);
+        stone.material_type = "PBRSpecular".to_string();
+        let options = ProfileOptions::default();
+        assert!(export_profile(&mut library, &stone, ExportProfile::Unity, &options).is_ok());
+        assert_eq!(
+            export_profile(&mut library, &stone, ExportProfile::Godot, &options).unwrap_err(),
+            "Godot materials need a PBRMetalness material, please convert_material \"stone\" first"
+        );
+        assert_eq!(
+            export_profile(&mut library, &stone, ExportProfile::Unreal, &options).unwrap_err(),
+            "Unreal textures need a PBRMetalness material, please convert_material \"stone\" first"
+        );
+        stone.material_type = "Toon".to_string();
+        assert_eq!(
+            export_profile(&mut library, &stone, ExportProfile::Unity, &options).unwrap_err(),
+            "Unity materials can't be made from \"Toon\", please convert_material \"stone\" first"
+        );
+    }
+}
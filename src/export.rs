@@ -0,0 +1,297 @@
+// turns the manifest of a finished run into the images handed to the host
+use super::diagnostic::to_js_value;
+use super::image_functions::{blank_image, invert_color, pack};
+use super::image_library::{ImageData, ImageLibrary};
+use super::log_rule;
+use super::manifest::{Manifest, MaterialManifest};
+use super::session::shared_session;
+use image::png::PngEncoder;
+use image::ColorType;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use wasm_bindgen::prelude::*;
+
+log_rule!();
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportOptions {
+    // give empty channels a solid image of their default color
+    #[serde(default = "default_true")]
+    pub fill_defaults: bool,
+    // add the packed textures of the material type next to its channels
+    #[serde(default = "default_true")]
+    pub pack_channels: bool,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        ExportOptions {
+            fill_defaults: default_true(),
+            pack_channels: default_true(),
+        }
+    }
+}
+
+// add a solid image at the material's resolution for every empty channel with a default color,
+// materials without a valid resolution are left as they are
+pub fn fill_defaults(library: &mut ImageLibrary, material: &mut MaterialManifest) {
+    if material.width <= 0 || material.height <= 0 {
+        return;
+    }
+    for channel in material.channels.iter_mut() {
+        if let (None, Some(color)) = (channel.image, channel.default_color) {
+            channel.image = Some(blank_image(
+                library,
+                color[0] as i32,
+                color[1] as i32,
+                color[2] as i32,
+                color[3] as i32,
+                material.width,
+                material.height,
+            ));
+        }
+    }
+}
+
+// packed texture name -> image id, channels without an image stay empty
+pub fn pack_channels(
+    library: &mut ImageLibrary,
+    material: &MaterialManifest,
+) -> Vec<(String, i32)> {
+    let mut packed_images: Vec<(String, i32)> = vec![];
+    for packed in &material.packing {
+        let mut ids = [-1; 4];
+        for (i, source) in packed.sources().iter().enumerate() {
+            if let Some(source) = source {
+                if let Some(channel) = material.channels.iter().find(|c| &c.name == source) {
+                    ids[i] = match channel.image {
+                        Some(id)
+                            if packed.inverted.contains(source)
+                                && library.get_image_data(id).is_some() =>
+                        {
+                            invert_color(library, id)
+                        }
+                        Some(id) => id,
+                        None => -1,
+                    };
+                }
+            }
+        }
+        let id = pack(library, ids[0], ids[1], ids[2], ids[3]);
+        if id >= 0 {
+            packed_images.push((packed.name.clone(), id));
+        }
+    }
+    packed_images
+}
+
+// the png file of an image with 8 or 16 bits per channel, name is only used for the error
+pub fn encode_png(name: &str, image_data: &ImageData, bit_depth: u8) -> Result<Vec<u8>, String> {
+    let mut png: Vec<u8> = vec![];
+    let result = if bit_depth == 16 {
+        // v * 257 spreads the 8 bits over 16, both bytes are v so the byte order doesn't matter
+        let pixels: Vec<u8> = image_data
+            .pixels
+            .iter()
+            .flat_map(|v| vec![*v, *v])
+            .collect();
+        PngEncoder::new(&mut png).encode(
+            &pixels,
+            image_data.width as u32,
+            image_data.height as u32,
+            ColorType::Rgba16,
+        )
+    } else {
+        PngEncoder::new(&mut png).encode(
+            &image_data.pixels,
+            image_data.width as u32,
+            image_data.height as u32,
+            ColorType::Rgba8,
+        )
+    };
+    match result {
+        Ok(_) => Ok(png),
+        Err(e) => Err(format!("failed to encode {:?} as png: {}", name, e)),
+    }
+}
+
+// channel (or packed texture) name -> image of every channel that has one
+pub fn export_material(
+    library: &mut ImageLibrary,
+    material: &MaterialManifest,
+    options: &ExportOptions,
+) -> HashMap<String, ImageData> {
+    let mut material = material.clone();
+    if options.fill_defaults {
+        fill_defaults(library, &mut material);
+    }
+
+    let mut images: Vec<(String, i32)> = material
+        .channels
+        .iter()
+        .filter_map(|channel| channel.image.map(|id| (channel.name.clone(), id)))
+        .collect();
+    if options.pack_channels {
+        images.extend(pack_channels(library, &material));
+    }
+
+    let mut result: HashMap<String, ImageData> = HashMap::new();
+    for (name, id) in images {
+        if let Some(data) = library.get_image_data(id) {
+            let mut data = data.clone();
+            data.name = name.clone();
+            result.insert(name, data);
+        }
+    }
+    result
+}
+
+// material name -> channel name -> image
+pub fn export_materials(
+    library: &mut ImageLibrary,
+    manifest: &Manifest,
+    options: &ExportOptions,
+) -> HashMap<String, HashMap<String, ImageData>> {
+    manifest
+        .materials
+        .iter()
+        .map(|material| {
+            (
+                material.name.clone(),
+                export_material(library, material, options),
+            )
+        })
+        .collect()
+}
+
+// manifest: the result of decode_manifest, options: {fill_defaults, pack_channels} or undefined
+#[wasm_bindgen]
+pub fn library_export_materials(manifest: &JsValue, options: &JsValue) -> JsValue {
+    let manifest: Manifest = match manifest.into_serde() {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            log(&format!("Error: malformed manifest: {}", e));
+            return JsValue::NULL;
+        }
+    };
+    let options: ExportOptions = if options.is_undefined() || options.is_null() {
+        ExportOptions::default()
+    } else {
+        match options.into_serde() {
+            Ok(options) => options,
+            Err(e) => {
+                log(&format!("Error: malformed export options: {}", e));
+                return JsValue::NULL;
+            }
+        }
+    };
+
+    let result = shared_session().export_materials(&manifest, &options);
+    to_js_value(&result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::ChannelManifest;
+    use crate::material_types::{ColorSpace, PackedTexture};
+
+    fn material(width: i32, height: i32) -> MaterialManifest {
+        MaterialManifest {
+            name: "stone".to_string(),
+            material_type: "PBRMetalness".to_string(),
+            width,
+            height,
+            channels: vec![ChannelManifest {
+                name: "roughness".to_string(),
+                image: None,
+                default_color: Some([128, 128, 128, 255]),
+                required: false,
+                color_space: ColorSpace::Linear,
+                bit_depth: 8,
+            }],
+            packing: vec![],
+        }
+    }
+
+    #[test]
+    fn fill_defaults_adds_solid_images() {
+        let mut library = ImageLibrary::new();
+        let mut stone = material(4, 2);
+        fill_defaults(&mut library, &mut stone);
+        let image = library
+            .get_image_data(stone.channels[0].image.unwrap())
+            .unwrap();
+        assert_eq!((image.width, image.height), (4, 2));
+        assert_eq!(&image.pixels[..4], &[128, 128, 128, 255]);
+    }
+
+    #[test]
+    fn fill_defaults_skips_materials_without_a_resolution() {
+        let mut library = ImageLibrary::new();
+        let mut stone = material(-8, 8);
+        fill_defaults(&mut library, &mut stone);
+        assert_eq!(stone.channels[0].image, None);
+    }
+
+    #[test]
+    fn encode_png_writes_8_or_16_bits() {
+        let image_data = ImageData {
+            name: "".to_string(),
+            width: 1,
+            height: 1,
+            pixels: vec![0, 128, 255, 255],
+        };
+        let png = encode_png("height", &image_data, 8).unwrap();
+        let image = image::load_from_memory(&png).unwrap();
+        assert_eq!(image.color(), ColorType::Rgba8);
+
+        let png = encode_png("height", &image_data, 16).unwrap();
+        let image = image::load_from_memory(&png).unwrap();
+        assert_eq!(image.color(), ColorType::Rgba16);
+        let pixels: Vec<u16> = image.to_rgba16().into_raw();
+        assert_eq!(pixels, vec![0, 128 * 257, 65535, 65535]);
+    }
+
+    #[test]
+    fn mask_maps_store_smoothness() {
+        let mut library = ImageLibrary::new();
+        let mut gray = |value: u8| {
+            Some(library.add_image("".to_string(), 1, 1, vec![value, value, value, 255]))
+        };
+        let (metalness, ao, roughness) = (gray(255), gray(100), gray(40));
+        let channel = |name: &str, image: Option<i32>| ChannelManifest {
+            name: name.to_string(),
+            image,
+            default_color: None,
+            required: false,
+            color_space: ColorSpace::Linear,
+            bit_depth: 8,
+        };
+        let stone = MaterialManifest {
+            channels: vec![
+                channel("metalness", metalness),
+                channel("ao", ao),
+                channel("roughness", roughness),
+            ],
+            packing: vec![PackedTexture {
+                name: "mask_map".to_string(),
+                r: Some("metalness".to_string()),
+                g: Some("ao".to_string()),
+                b: None,
+                a: Some("roughness".to_string()),
+                inverted: vec!["roughness".to_string()],
+            }],
+            ..material(1, 1)
+        };
+
+        let packed = pack_channels(&mut library, &stone);
+        assert_eq!(packed.len(), 1);
+        let image = library.get_image_data(packed[0].1).unwrap();
+        assert_eq!(image.pixels, vec![255, 100, 0, 215]);
+    }
+}
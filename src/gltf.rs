@@ -0,0 +1,585 @@
+// glTF 2.0 files of PBRMetalness materials, as .gltf json with the buffer embedded as a data uri
+// or as binary .glb
+//
+// textures are png images stored in the buffer: diffuse as base color, roughness and metalness
+// packed into the g and b of metallicRoughness, ao, normal and emissive as their own textures
+use super::export::{encode_png, fill_defaults};
+use super::image_functions::pack;
+use super::image_library::{ImageData, ImageLibrary};
+use super::log_rule;
+use super::manifest::MaterialManifest;
+use super::session::shared_session;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::f32::consts::PI;
+use std::path::Path;
+use wasm_bindgen::prelude::*;
+
+log_rule!();
+
+const GLB_MAGIC: u32 = 0x4654_6C67; // "glTF"
+const GLB_VERSION: u32 = 2;
+const CHUNK_JSON: u32 = 0x4E4F_534A;
+const CHUNK_BIN: u32 = 0x004E_4942;
+
+// accessor component types and buffer view targets
+const FLOAT: u32 = 5126;
+const UNSIGNED_SHORT: u32 = 5123;
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+
+const SPHERE_SEGMENTS: usize = 32;
+const SPHERE_RINGS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PreviewMesh {
+    Plane,
+    Sphere,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GltfOptions {
+    // .glb instead of .gltf
+    #[serde(default)]
+    pub binary: bool,
+    // a mesh using the material, so viewers have something to show
+    #[serde(default)]
+    pub preview_mesh: Option<PreviewMesh>,
+}
+
+struct Mesh {
+    positions: Vec<[f32; 3]>,
+    normals: Vec<[f32; 3]>,
+    tangents: Vec<[f32; 4]>,
+    uvs: Vec<[f32; 2]>,
+    indices: Vec<u16>,
+}
+
+impl Mesh {
+    // unit quad facing +z
+    fn plane() -> Self {
+        Mesh {
+            positions: vec![
+                [-0.5, -0.5, 0.0],
+                [0.5, -0.5, 0.0],
+                [0.5, 0.5, 0.0],
+                [-0.5, 0.5, 0.0],
+            ],
+            normals: vec![[0.0, 0.0, 1.0]; 4],
+            tangents: vec![[1.0, 0.0, 0.0, 1.0]; 4],
+            uvs: vec![[0.0, 1.0], [1.0, 1.0], [1.0, 0.0], [0.0, 0.0]],
+            indices: vec![0, 1, 2, 0, 2, 3],
+        }
+    }
+
+    // uv sphere of diameter 1
+    fn sphere() -> Self {
+        let mut mesh = Mesh {
+            positions: vec![],
+            normals: vec![],
+            tangents: vec![],
+            uvs: vec![],
+            indices: vec![],
+        };
+        for ring in 0..=SPHERE_RINGS {
+            let v = ring as f32 / SPHERE_RINGS as f32;
+            let theta = v * PI;
+            for segment in 0..=SPHERE_SEGMENTS {
+                let u = segment as f32 / SPHERE_SEGMENTS as f32;
+                let phi = u * 2.0 * PI;
+                let normal = [
+                    -phi.cos() * theta.sin(),
+                    theta.cos(),
+                    phi.sin() * theta.sin(),
+                ];
+                mesh.positions
+                    .push([normal[0] * 0.5, normal[1] * 0.5, normal[2] * 0.5]);
+                mesh.normals.push(normal);
+                mesh.tangents.push([phi.sin(), 0.0, phi.cos(), 1.0]);
+                mesh.uvs.push([u, v]);
+            }
+        }
+        let row = SPHERE_SEGMENTS + 1;
+        for ring in 0..SPHERE_RINGS {
+            for segment in 0..SPHERE_SEGMENTS {
+                let a = (ring * row + segment) as u16;
+                let b = a + row as u16;
+                mesh.indices.extend(&[a, b, a + 1, a + 1, b, b + 1]);
+            }
+        }
+        mesh
+    }
+}
+
+// the json document and the binary buffer it points into
+struct Document {
+    buffer: Vec<u8>,
+    buffer_views: Vec<Value>,
+    accessors: Vec<Value>,
+    images: Vec<Value>,
+    textures: Vec<Value>,
+}
+
+impl Document {
+    fn add_buffer_view(&mut self, bytes: &[u8], target: Option<u32>) -> usize {
+        while self.buffer.len() % 4 != 0 {
+            self.buffer.push(0);
+        }
+        let mut view = json!({
+            "buffer": 0,
+            "byteOffset": self.buffer.len(),
+            "byteLength": bytes.len(),
+        });
+        if let Some(target) = target {
+            view["target"] = json!(target);
+        }
+        self.buffer.extend(bytes);
+        self.buffer_views.push(view);
+        self.buffer_views.len() - 1
+    }
+
+    fn add_accessor(
+        &mut self,
+        view: usize,
+        component_type: u32,
+        count: usize,
+        kind: &str,
+    ) -> usize {
+        self.accessors.push(json!({
+            "bufferView": view,
+            "componentType": component_type,
+            "count": count,
+            "type": kind,
+        }));
+        self.accessors.len() - 1
+    }
+
+    // count vectors of type `kind` laid out one after the other
+    fn add_floats(&mut self, values: &[f32], count: usize, kind: &str) -> usize {
+        let bytes: Vec<u8> = values
+            .iter()
+            .flat_map(|f| f.to_le_bytes().to_vec())
+            .collect();
+        let view = self.add_buffer_view(&bytes, Some(ARRAY_BUFFER));
+        self.add_accessor(view, FLOAT, count, kind)
+    }
+
+    fn add_texture(&mut self, name: &str, image_data: &ImageData) -> Result<usize, String> {
+        let png = encode_png(name, image_data, 8)?;
+        let view = self.add_buffer_view(&png, None);
+        self.images.push(json!({
+            "name": name,
+            "bufferView": view,
+            "mimeType": "image/png",
+        }));
+        self.textures.push(json!({
+            "sampler": 0,
+            "source": self.images.len() - 1,
+        }));
+        Ok(self.textures.len() - 1)
+    }
+
+    fn add_mesh(&mut self, mesh: &Mesh) -> Value {
+        let count = mesh.positions.len();
+        let position = self.add_floats(&mesh.positions.concat(), count, "VEC3");
+        let mut min = [f32::MAX; 3];
+        let mut max = [f32::MIN; 3];
+        for p in &mesh.positions {
+            for i in 0..3 {
+                min[i] = min[i].min(p[i]);
+                max[i] = max[i].max(p[i]);
+            }
+        }
+        self.accessors[position]["min"] = json!(min);
+        self.accessors[position]["max"] = json!(max);
+
+        let normal = self.add_floats(&mesh.normals.concat(), count, "VEC3");
+        let tangent = self.add_floats(&mesh.tangents.concat(), count, "VEC4");
+        let uv = self.add_floats(&mesh.uvs.concat(), count, "VEC2");
+        let bytes: Vec<u8> = mesh
+            .indices
+            .iter()
+            .flat_map(|i| i.to_le_bytes().to_vec())
+            .collect();
+        let view = self.add_buffer_view(&bytes, Some(ELEMENT_ARRAY_BUFFER));
+        let indices = self.add_accessor(view, UNSIGNED_SHORT, mesh.indices.len(), "SCALAR");
+        json!({
+            "attributes": {
+                "POSITION": position,
+                "NORMAL": normal,
+                "TANGENT": tangent,
+                "TEXCOORD_0": uv,
+            },
+            "indices": indices,
+            "material": 0,
+        })
+    }
+}
+
+fn image<'a>(
+    library: &'a ImageLibrary,
+    material: &MaterialManifest,
+    channel: &str,
+) -> Option<&'a ImageData> {
+    let channel = material.channels.iter().find(|c| c.name == channel)?;
+    library.get_image_data(channel.image?)
+}
+
+// the bytes of a .gltf or .glb file holding the material
+pub fn write_gltf(
+    library: &mut ImageLibrary,
+    material: &MaterialManifest,
+    options: &GltfOptions,
+) -> Result<Vec<u8>, String> {
+    if material.material_type != "PBRMetalness" {
+        return Err(format!(
+            "glTF needs a PBRMetalness material, please convert_material {:?} first",
+            material.name
+        ));
+    }
+    // metallicRoughness is packed from defaults where one of both channels is empty
+    let assigned = |channel: &str| image(library, material, channel).is_some();
+    let has_metallic_roughness = assigned("metalness") || assigned("roughness");
+    let has_occlusion = assigned("ao");
+    let has_normal = assigned("normal");
+    let has_emissive = assigned("emissive");
+    let has_base_color = assigned("diffuse");
+
+    let mut filled = material.clone();
+    fill_defaults(library, &mut filled);
+    let channel_id = |channel: &str| {
+        filled
+            .channels
+            .iter()
+            .find(|c| c.name == channel)
+            .and_then(|c| c.image)
+            .unwrap_or(-1)
+    };
+    let metallic_roughness = if has_metallic_roughness {
+        pack(
+            library,
+            -1,
+            channel_id("roughness"),
+            channel_id("metalness"),
+            -1,
+        )
+    } else {
+        -1
+    };
+
+    let mut document = Document {
+        buffer: vec![],
+        buffer_views: vec![],
+        accessors: vec![],
+        images: vec![],
+        textures: vec![],
+    };
+    let mut pbr = json!({ "metallicFactor": 1.0, "roughnessFactor": 1.0 });
+    let mut gltf_material = json!({ "name": material.name });
+    let texture = |document: &mut Document, name: &str, id: i32| match library.get_image_data(id) {
+        Some(image_data) => document.add_texture(name, image_data),
+        None => Err(format!(
+            "the {} image of {:?} doesn't exist",
+            name, material.name
+        )),
+    };
+    if has_base_color {
+        let index = texture(&mut document, "diffuse", channel_id("diffuse"))?;
+        pbr["baseColorTexture"] = json!({ "index": index });
+    }
+    if has_metallic_roughness {
+        let index = texture(&mut document, "metallicRoughness", metallic_roughness)?;
+        pbr["metallicRoughnessTexture"] = json!({ "index": index });
+    } else {
+        // the default roughness of the material type
+        pbr["metallicFactor"] = json!(0.0);
+        pbr["roughnessFactor"] = json!(0.5);
+    }
+    if has_normal {
+        let index = texture(&mut document, "normal", channel_id("normal"))?;
+        gltf_material["normalTexture"] = json!({ "index": index });
+    }
+    if has_occlusion {
+        let index = texture(&mut document, "ao", channel_id("ao"))?;
+        gltf_material["occlusionTexture"] = json!({ "index": index });
+    }
+    if has_emissive {
+        let index = texture(&mut document, "emissive", channel_id("emissive"))?;
+        gltf_material["emissiveTexture"] = json!({ "index": index });
+        gltf_material["emissiveFactor"] = json!([1.0, 1.0, 1.0]);
+    }
+    gltf_material["pbrMetallicRoughness"] = pbr;
+
+    let mut root = json!({
+        "asset": { "version": "2.0", "generator": "coocoo" },
+        "materials": [gltf_material],
+    });
+    if let Some(preview_mesh) = options.preview_mesh {
+        let mesh = match preview_mesh {
+            PreviewMesh::Plane => Mesh::plane(),
+            PreviewMesh::Sphere => Mesh::sphere(),
+        };
+        let primitive = document.add_mesh(&mesh);
+        root["meshes"] = json!([{ "name": material.name, "primitives": [primitive] }]);
+        root["nodes"] = json!([{ "name": material.name, "mesh": 0 }]);
+        root["scenes"] = json!([{ "nodes": [0] }]);
+        root["scene"] = json!(0);
+    }
+    if !document.textures.is_empty() {
+        // linear filtering with mipmaps, repeat
+        root["samplers"] =
+            json!([{ "magFilter": 9729, "minFilter": 9987, "wrapS": 10497, "wrapT": 10497 }]);
+        root["images"] = json!(document.images);
+        root["textures"] = json!(document.textures);
+    }
+    while document.buffer.len() % 4 != 0 {
+        document.buffer.push(0);
+    }
+    if !document.buffer.is_empty() {
+        let mut buffer = json!({ "byteLength": document.buffer.len() });
+        if !options.binary {
+            buffer["uri"] = json!(format!(
+                "data:application/octet-stream;base64,{}",
+                base64::encode(&document.buffer)
+            ));
+        }
+        root["buffers"] = json!([buffer]);
+        root["bufferViews"] = json!(document.buffer_views);
+        if !document.accessors.is_empty() {
+            root["accessors"] = json!(document.accessors);
+        }
+    }
+
+    if !options.binary {
+        return match serde_json::to_vec_pretty(&root) {
+            Ok(bytes) => Ok(bytes),
+            Err(e) => Err(format!("failed to write glTF json: {}", e)),
+        };
+    }
+    let mut json_chunk = match serde_json::to_vec(&root) {
+        Ok(bytes) => bytes,
+        Err(e) => return Err(format!("failed to write glTF json: {}", e)),
+    };
+    while json_chunk.len() % 4 != 0 {
+        json_chunk.push(b' ');
+    }
+    let mut glb: Vec<u8> = vec![];
+    let mut length = 12 + 8 + json_chunk.len();
+    if !document.buffer.is_empty() {
+        length += 8 + document.buffer.len();
+    }
+    for word in &[GLB_MAGIC, GLB_VERSION, length as u32] {
+        glb.extend(&word.to_le_bytes());
+    }
+    glb.extend(&(json_chunk.len() as u32).to_le_bytes());
+    glb.extend(&CHUNK_JSON.to_le_bytes());
+    glb.extend(json_chunk);
+    if !document.buffer.is_empty() {
+        glb.extend(&(document.buffer.len() as u32).to_le_bytes());
+        glb.extend(&CHUNK_BIN.to_le_bytes());
+        glb.extend(document.buffer);
+    }
+    Ok(glb)
+}
+
+// .glb files are written binary, everything else as .gltf json
+pub fn save_gltf(
+    library: &mut ImageLibrary,
+    material: &MaterialManifest,
+    preview_mesh: Option<PreviewMesh>,
+    path: &Path,
+) -> Result<(), String> {
+    let options = GltfOptions {
+        binary: path
+            .extension()
+            .map_or(false, |extension| extension == "glb"),
+        preview_mesh,
+    };
+    let bytes = write_gltf(library, material, &options)?;
+    match std::fs::write(path, bytes) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("failed to write {:?}: {}", path, e)),
+    }
+}
+
+// material: one material of decode_manifest, options: {binary, preview_mesh: "plane" | "sphere"}
+// or undefined, returns the file's bytes, empty on failure
+#[wasm_bindgen]
+pub fn library_export_gltf(material: &JsValue, options: &JsValue) -> Vec<u8> {
+    let material: MaterialManifest = match material.into_serde() {
+        Ok(material) => material,
+        Err(e) => {
+            log(&format!("Error: malformed material: {}", e));
+            return vec![];
+        }
+    };
+    let options: GltfOptions = if options.is_undefined() || options.is_null() {
+        GltfOptions::default()
+    } else {
+        match options.into_serde() {
+            Ok(options) => options,
+            Err(e) => {
+                log(&format!("Error: malformed glTF options: {}", e));
+                return vec![];
+            }
+        }
+    };
+
+    match shared_session().export_gltf(&material, &options) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            log(&format!("Error: {}", e));
+            vec![]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::ChannelManifest;
+    use crate::material_types::ColorSpace;
+
+    // a 1x1 PBRMetalness material with the given channels set to solid colors
+    fn material(library: &mut ImageLibrary, colors: &[(&str, [u8; 4])]) -> MaterialManifest {
+        let defaults = [
+            ("diffuse", None),
+            ("metalness", Some([0, 0, 0, 255])),
+            ("normal", Some([128, 128, 255, 255])),
+            ("roughness", Some([128, 128, 128, 255])),
+            ("ao", Some([255, 255, 255, 255])),
+            ("emissive", Some([0, 0, 0, 255])),
+        ];
+        let channels = defaults
+            .iter()
+            .map(|(name, default_color)| ChannelManifest {
+                name: name.to_string(),
+                image: colors
+                    .iter()
+                    .find(|(channel, _)| channel == name)
+                    .map(|(_, color)| library.add_image(name.to_string(), 1, 1, color.to_vec())),
+                default_color: *default_color,
+                required: false,
+                color_space: ColorSpace::Linear,
+                bit_depth: 8,
+            })
+            .collect();
+        MaterialManifest {
+            name: "stone".to_string(),
+            material_type: "PBRMetalness".to_string(),
+            width: 1,
+            height: 1,
+            channels,
+            packing: vec![],
+        }
+    }
+
+    fn write(library: &mut ImageLibrary, material: &MaterialManifest) -> Value {
+        let bytes = write_gltf(library, material, &GltfOptions::default()).unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    // the first pixel of the texture a material slot points to
+    fn texture_pixel(root: &Value, texture: &Value) -> [u8; 4] {
+        let uri = root["buffers"][0]["uri"].as_str().unwrap();
+        let buffer = base64::decode(&uri[uri.find(',').unwrap() + 1..]).unwrap();
+        let source = &root["textures"][texture["index"].as_u64().unwrap() as usize]["source"];
+        let image = &root["images"][source.as_u64().unwrap() as usize];
+        let view = &root["bufferViews"][image["bufferView"].as_u64().unwrap() as usize];
+        let offset = view["byteOffset"].as_u64().unwrap() as usize;
+        let length = view["byteLength"].as_u64().unwrap() as usize;
+        let png = image::load_from_memory(&buffer[offset..offset + length]).unwrap();
+        png.to_rgba8().get_pixel(0, 0).0
+    }
+
+    #[test]
+    fn metallic_roughness_packs_roughness_into_g_and_metalness_into_b() {
+        let mut library = ImageLibrary::new();
+        let stone = material(
+            &mut library,
+            &[
+                ("roughness", [40, 40, 40, 255]),
+                ("metalness", [200, 200, 200, 255]),
+            ],
+        );
+        let root = write(&mut library, &stone);
+        let pbr = &root["materials"][0]["pbrMetallicRoughness"];
+        let pixel = texture_pixel(&root, &pbr["metallicRoughnessTexture"]);
+        assert_eq!((pixel[1], pixel[2]), (40, 200));
+        assert_eq!(pbr["metallicFactor"], json!(1.0));
+        assert_eq!(pbr["roughnessFactor"], json!(1.0));
+    }
+
+    #[test]
+    fn metallic_roughness_uses_defaults_for_empty_channels() {
+        let mut library = ImageLibrary::new();
+        let stone = material(&mut library, &[("roughness", [40, 40, 40, 255])]);
+        let root = write(&mut library, &stone);
+        let pbr = &root["materials"][0]["pbrMetallicRoughness"];
+        let pixel = texture_pixel(&root, &pbr["metallicRoughnessTexture"]);
+        assert_eq!((pixel[1], pixel[2]), (40, 0));
+
+        let stone = material(&mut library, &[]);
+        let root = write(&mut library, &stone);
+        let pbr = &root["materials"][0]["pbrMetallicRoughness"];
+        assert_eq!(pbr.get("metallicRoughnessTexture"), None);
+        assert_eq!(pbr["metallicFactor"], json!(0.0));
+        assert_eq!(pbr["roughnessFactor"], json!(0.5));
+        assert_eq!(root.get("textures"), None);
+    }
+
+    #[test]
+    fn assigned_channels_get_their_own_textures() {
+        let mut library = ImageLibrary::new();
+        let stone = material(
+            &mut library,
+            &[
+                ("diffuse", [10, 20, 30, 255]),
+                ("normal", [120, 130, 250, 255]),
+                ("ao", [90, 90, 90, 255]),
+                ("emissive", [255, 0, 0, 255]),
+            ],
+        );
+        let root = write(&mut library, &stone);
+        let gltf_material = &root["materials"][0];
+        let pbr = &gltf_material["pbrMetallicRoughness"];
+        assert_eq!(pbr["baseColorTexture"], json!({ "index": 0 }));
+        assert_eq!(gltf_material["normalTexture"], json!({ "index": 1 }));
+        assert_eq!(gltf_material["occlusionTexture"], json!({ "index": 2 }));
+        assert_eq!(gltf_material["emissiveTexture"], json!({ "index": 3 }));
+        assert_eq!(gltf_material["emissiveFactor"], json!([1.0, 1.0, 1.0]));
+        assert_eq!(root["textures"].as_array().unwrap().len(), 4);
+
+        assert_eq!(
+            texture_pixel(&root, &pbr["baseColorTexture"]),
+            [10, 20, 30, 255]
+        );
+        assert_eq!(
+            texture_pixel(&root, &gltf_material["normalTexture"]),
+            [120, 130, 250, 255]
+        );
+        assert_eq!(
+            texture_pixel(&root, &gltf_material["occlusionTexture"]),
+            [90, 90, 90, 255]
+        );
+        assert_eq!(
+            texture_pixel(&root, &gltf_material["emissiveTexture"]),
+            [255, 0, 0, 255]
+        );
+    }
+
+    #[test]
+    fn other_material_types_need_a_conversion() {
+        let mut library = ImageLibrary::new();
+        let mut stone = material(&mut library, &[]);
+        stone.material_type = "PBRSpecular".to_string();
+        assert_eq!(
+            write_gltf(&mut library, &stone, &GltfOptions::default()),
+            Err(
+                "glTF needs a PBRMetalness material, please convert_material \"stone\" first"
+                    .to_string()
+            )
+        );
+    }
+}
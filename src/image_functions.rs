@@ -1,6 +1,5 @@
-use super::image_library::IMAGE_LIBRARY;
+use super::image_library::ImageLibrary;
 use super::log_rule;
-use wasm_bindgen::prelude::*;
 
 use ::noise::*;
 use photon_rs::*;
@@ -11,14 +10,13 @@ use rgb::RGBA8;
 
 log_rule!();
 
-#[wasm_bindgen]
-pub fn resize_(image_id: i32, new_width: i32, new_height: i32) -> i32 {
-    let image_data = IMAGE_LIBRARY
-        .lock()
-        .unwrap()
-        .get_image_data(image_id)
-        .unwrap()
-        .clone();
+pub fn logger(_library: &mut ImageLibrary, value: i32) -> i32 {
+    log(&format!("{}", value));
+    return 0;
+}
+
+pub fn resize(library: &mut ImageLibrary, image_id: i32, new_width: i32, new_height: i32) -> i32 {
+    let image_data = library.get_image_data(image_id).unwrap().clone();
 
     let w1 = image_data.width as usize;
     let h1 = image_data.height as usize;
@@ -38,10 +36,7 @@ pub fn resize_(image_id: i32, new_width: i32, new_height: i32) -> i32 {
             let mut resizer = resize::new(w1, h1, w2, h2, RGBA8, Mitchell).unwrap();
             resizer.resize(src.as_rgba(), dst.as_rgba_mut()).unwrap();
 
-            IMAGE_LIBRARY
-                .lock()
-                .unwrap()
-                .add_image("".to_string(), new_width, new_height, dst)
+            library.add_image("".to_string(), new_width, new_height, dst)
         } else {
             return -1;
         }
@@ -51,24 +46,15 @@ pub fn resize_(image_id: i32, new_width: i32, new_height: i32) -> i32 {
             let mut resizer = resize::new(w1, h1, w2, h2, RGBA8, Lanczos3).unwrap();
             resizer.resize(src.as_rgba(), dst.as_rgba_mut()).unwrap();
 
-            IMAGE_LIBRARY
-                .lock()
-                .unwrap()
-                .add_image("".to_string(), new_width, new_height, dst)
+            library.add_image("".to_string(), new_width, new_height, dst)
         } else {
             return -1;
         }
     }
 }
 
-#[wasm_bindgen]
-pub fn darken(image_id: i32, value: i32) -> i32 {
-    let mut image_data = IMAGE_LIBRARY
-        .lock()
-        .unwrap()
-        .get_image_data(image_id)
-        .unwrap()
-        .clone();
+pub fn darken(library: &mut ImageLibrary, image_id: i32, value: i32) -> i32 {
+    let mut image_data = library.get_image_data(image_id).unwrap().clone();
     for i in 0..image_data.pixels.len() {
         if (i + 1) / 4 == 0 {
             continue;
@@ -78,7 +64,7 @@ pub fn darken(image_id: i32, value: i32) -> i32 {
             image_data.pixels[i] = 0;
         }
     }
-    IMAGE_LIBRARY.lock().unwrap().add_image(
+    library.add_image(
         "".to_string(),
         image_data.width,
         image_data.height,
@@ -86,14 +72,8 @@ pub fn darken(image_id: i32, value: i32) -> i32 {
     )
 }
 
-#[wasm_bindgen]
-pub fn lighten_(image_id: i32, value: i32) -> i32 {
-    let mut image_data = IMAGE_LIBRARY
-        .lock()
-        .unwrap()
-        .get_image_data(image_id)
-        .unwrap()
-        .clone();
+pub fn lighten(library: &mut ImageLibrary, image_id: i32, value: i32) -> i32 {
+    let mut image_data = library.get_image_data(image_id).unwrap().clone();
     let value = value as u8;
     for i in 0..image_data.pixels.len() {
         if (i + 1) / 4 == 0 {
@@ -104,15 +84,22 @@ pub fn lighten_(image_id: i32, value: i32) -> i32 {
             image_data.pixels[i] = 255;
         }
     }
-    IMAGE_LIBRARY.lock().unwrap().add_image(
+    library.add_image(
         "".to_string(),
         image_data.width,
         image_data.height,
         image_data.pixels,
     )
 }
-#[wasm_bindgen]
-pub fn blank_image(r: i32, g: i32, b: i32, a: i32, width: i32, height: i32) -> i32 {
+pub fn blank_image(
+    library: &mut ImageLibrary,
+    r: i32,
+    g: i32,
+    b: i32,
+    a: i32,
+    width: i32,
+    height: i32,
+) -> i32 {
     let pixel_total: usize = (width * height * 4) as usize;
     let mut image_data: Vec<u8> = vec![0; pixel_total];
     for i in (0..pixel_total).step_by(4) {
@@ -121,20 +108,11 @@ pub fn blank_image(r: i32, g: i32, b: i32, a: i32, width: i32, height: i32) -> i
         image_data[i + 2] = b as u8;
         image_data[i + 3] = a as u8;
     }
-    IMAGE_LIBRARY
-        .lock()
-        .unwrap()
-        .add_image("".to_string(), width, height, image_data)
+    library.add_image("".to_string(), width, height, image_data)
 }
 
-#[wasm_bindgen]
-pub fn grayscale_(image_id: i32) -> i32 {
-    let mut image_data = IMAGE_LIBRARY
-        .lock()
-        .unwrap()
-        .get_image_data(image_id)
-        .unwrap()
-        .clone();
+pub fn grayscale(library: &mut ImageLibrary, image_id: i32) -> i32 {
+    let mut image_data = library.get_image_data(image_id).unwrap().clone();
     for i in (0..image_data.pixels.len()).step_by(4) {
         let avg = ((image_data.pixels[i] as f32 * 0.2989) as i32
             + (image_data.pixels[i + 1] as f32 * 0.5870) as i32
@@ -144,7 +122,7 @@ pub fn grayscale_(image_id: i32) -> i32 {
         image_data.pixels[i + 2] = avg;
     }
 
-    IMAGE_LIBRARY.lock().unwrap().add_image(
+    library.add_image(
         "".to_string(),
         image_data.width,
         image_data.height,
@@ -152,21 +130,15 @@ pub fn grayscale_(image_id: i32) -> i32 {
     )
 }
 
-#[wasm_bindgen]
-pub fn invert_color(image_id: i32) -> i32 {
-    let mut image_data = IMAGE_LIBRARY
-        .lock()
-        .unwrap()
-        .get_image_data(image_id)
-        .unwrap()
-        .clone();
+pub fn invert_color(library: &mut ImageLibrary, image_id: i32) -> i32 {
+    let mut image_data = library.get_image_data(image_id).unwrap().clone();
     for i in (0..image_data.pixels.len()).step_by(4) {
         image_data.pixels[i] = 255 - image_data.pixels[i];
         image_data.pixels[i + 1] = 255 - image_data.pixels[i + 1];
         image_data.pixels[i + 2] = 255 - image_data.pixels[i + 2];
     }
 
-    IMAGE_LIBRARY.lock().unwrap().add_image(
+    library.add_image(
         "".to_string(),
         image_data.width,
         image_data.height,
@@ -174,14 +146,8 @@ pub fn invert_color(image_id: i32) -> i32 {
     )
 }
 
-#[wasm_bindgen]
-pub fn flip_horizontal(image_id: i32) -> i32 {
-    let mut image_data = IMAGE_LIBRARY
-        .lock()
-        .unwrap()
-        .get_image_data(image_id)
-        .unwrap()
-        .clone();
+pub fn flip_horizontal(library: &mut ImageLibrary, image_id: i32) -> i32 {
+    let mut image_data = library.get_image_data(image_id).unwrap().clone();
     let width = image_data.width as usize;
     let height = image_data.width as usize;
     for i in 0..height {
@@ -192,7 +158,7 @@ pub fn flip_horizontal(image_id: i32) -> i32 {
         }
     }
 
-    IMAGE_LIBRARY.lock().unwrap().add_image(
+    library.add_image(
         "".to_string(),
         image_data.width,
         image_data.height,
@@ -200,14 +166,8 @@ pub fn flip_horizontal(image_id: i32) -> i32 {
     )
 }
 
-#[wasm_bindgen]
-pub fn flip_vertical(image_id: i32) -> i32 {
-    let mut image_data = IMAGE_LIBRARY
-        .lock()
-        .unwrap()
-        .get_image_data(image_id)
-        .unwrap()
-        .clone();
+pub fn flip_vertical(library: &mut ImageLibrary, image_id: i32) -> i32 {
+    let mut image_data = library.get_image_data(image_id).unwrap().clone();
     let width = image_data.height as usize;
     let height = image_data.height as usize;
     for i in 0..width {
@@ -218,7 +178,7 @@ pub fn flip_vertical(image_id: i32) -> i32 {
         }
     }
 
-    IMAGE_LIBRARY.lock().unwrap().add_image(
+    library.add_image(
         "".to_string(),
         image_data.width,
         image_data.height,
@@ -226,19 +186,13 @@ pub fn flip_vertical(image_id: i32) -> i32 {
     )
 }
 
-#[wasm_bindgen]
-pub fn mask_channel_r(image_id: i32) -> i32 {
-    let mut image_data = IMAGE_LIBRARY
-        .lock()
-        .unwrap()
-        .get_image_data(image_id)
-        .unwrap()
-        .clone();
+pub fn mask_channel_r(library: &mut ImageLibrary, image_id: i32) -> i32 {
+    let mut image_data = library.get_image_data(image_id).unwrap().clone();
     for i in (0..image_data.pixels.len()).step_by(4) {
         image_data.pixels[i] = 0;
     }
 
-    IMAGE_LIBRARY.lock().unwrap().add_image(
+    library.add_image(
         "".to_string(),
         image_data.width,
         image_data.height,
@@ -246,19 +200,13 @@ pub fn mask_channel_r(image_id: i32) -> i32 {
     )
 }
 
-#[wasm_bindgen]
-pub fn mask_channel_g(image_id: i32) -> i32 {
-    let mut image_data = IMAGE_LIBRARY
-        .lock()
-        .unwrap()
-        .get_image_data(image_id)
-        .unwrap()
-        .clone();
+pub fn mask_channel_g(library: &mut ImageLibrary, image_id: i32) -> i32 {
+    let mut image_data = library.get_image_data(image_id).unwrap().clone();
     for i in (0..image_data.pixels.len()).step_by(4) {
         image_data.pixels[i + 1] = 0;
     }
 
-    IMAGE_LIBRARY.lock().unwrap().add_image(
+    library.add_image(
         "".to_string(),
         image_data.width,
         image_data.height,
@@ -266,19 +214,13 @@ pub fn mask_channel_g(image_id: i32) -> i32 {
     )
 }
 
-#[wasm_bindgen]
-pub fn mask_channel_b(image_id: i32) -> i32 {
-    let mut image_data = IMAGE_LIBRARY
-        .lock()
-        .unwrap()
-        .get_image_data(image_id)
-        .unwrap()
-        .clone();
+pub fn mask_channel_b(library: &mut ImageLibrary, image_id: i32) -> i32 {
+    let mut image_data = library.get_image_data(image_id).unwrap().clone();
     for i in (0..image_data.pixels.len()).step_by(4) {
         image_data.pixels[i + 2] = 0;
     }
 
-    IMAGE_LIBRARY.lock().unwrap().add_image(
+    library.add_image(
         "".to_string(),
         image_data.width,
         image_data.height,
@@ -286,14 +228,8 @@ pub fn mask_channel_b(image_id: i32) -> i32 {
     )
 }
 
-#[wasm_bindgen]
-pub fn blur(image_id: i32) -> i32 {
-    let mut image_data = IMAGE_LIBRARY
-        .lock()
-        .unwrap()
-        .get_image_data(image_id)
-        .unwrap()
-        .clone();
+pub fn blur(library: &mut ImageLibrary, image_id: i32) -> i32 {
+    let mut image_data = library.get_image_data(image_id).unwrap().clone();
 
     let mut photon_image = PhotonImage::new(
         image_data.pixels,
@@ -303,7 +239,7 @@ pub fn blur(image_id: i32) -> i32 {
 
     conv::box_blur(&mut photon_image);
 
-    IMAGE_LIBRARY.lock().unwrap().add_image(
+    library.add_image(
         "".to_string(),
         image_data.width,
         image_data.height,
@@ -311,14 +247,8 @@ pub fn blur(image_id: i32) -> i32 {
     )
 }
 
-#[wasm_bindgen]
-pub fn blur_gaussian(image_id: i32, radius: i32) -> i32 {
-    let mut image_data = IMAGE_LIBRARY
-        .lock()
-        .unwrap()
-        .get_image_data(image_id)
-        .unwrap()
-        .clone();
+pub fn blur_gaussian(library: &mut ImageLibrary, image_id: i32, radius: i32) -> i32 {
+    let mut image_data = library.get_image_data(image_id).unwrap().clone();
 
     let mut photon_image = PhotonImage::new(
         image_data.pixels,
@@ -328,7 +258,7 @@ pub fn blur_gaussian(image_id: i32, radius: i32) -> i32 {
 
     conv::gaussian_blur(&mut photon_image, radius);
 
-    IMAGE_LIBRARY.lock().unwrap().add_image(
+    library.add_image(
         "".to_string(),
         image_data.width,
         image_data.height,
@@ -336,14 +266,8 @@ pub fn blur_gaussian(image_id: i32, radius: i32) -> i32 {
     )
 }
 
-#[wasm_bindgen]
-pub fn noise_perlin(image_id: i32) -> i32 {
-    let mut image_data = IMAGE_LIBRARY
-        .lock()
-        .unwrap()
-        .get_image_data(image_id)
-        .unwrap()
-        .clone();
+pub fn noise_perlin(library: &mut ImageLibrary, image_id: i32) -> i32 {
+    let mut image_data = library.get_image_data(image_id).unwrap().clone();
     let perlin = Perlin::new();
 
     for i in (0..image_data.pixels.len()).step_by(4) {
@@ -358,7 +282,7 @@ pub fn noise_perlin(image_id: i32) -> i32 {
         image_data.pixels[i + 2] = val as u8;
     }
 
-    IMAGE_LIBRARY.lock().unwrap().add_image(
+    library.add_image(
         "".to_string(),
         image_data.width,
         image_data.height,
@@ -366,14 +290,8 @@ pub fn noise_perlin(image_id: i32) -> i32 {
     )
 }
 
-#[wasm_bindgen]
-pub fn sharpen_(image_id: i32) -> i32 {
-    let mut image_data = IMAGE_LIBRARY
-        .lock()
-        .unwrap()
-        .get_image_data(image_id)
-        .unwrap()
-        .clone();
+pub fn sharpen(library: &mut ImageLibrary, image_id: i32) -> i32 {
+    let mut image_data = library.get_image_data(image_id).unwrap().clone();
 
     let mut photon_image = PhotonImage::new(
         image_data.pixels,
@@ -383,7 +301,7 @@ pub fn sharpen_(image_id: i32) -> i32 {
 
     conv::sharpen(&mut photon_image);
 
-    IMAGE_LIBRARY.lock().unwrap().add_image(
+    library.add_image(
         "".to_string(),
         image_data.width,
         image_data.height,
@@ -391,26 +309,15 @@ pub fn sharpen_(image_id: i32) -> i32 {
     )
 }
 
-#[wasm_bindgen]
-pub fn overlay(image_id_1: i32, image_id_2: i32) -> i32 {
-    let mut image_data_1 = IMAGE_LIBRARY
-        .lock()
-        .unwrap()
-        .get_image_data(image_id_1)
-        .unwrap()
-        .clone();
+pub fn overlay(library: &mut ImageLibrary, image_id_1: i32, image_id_2: i32) -> i32 {
+    let mut image_data_1 = library.get_image_data(image_id_1).unwrap().clone();
     let mut photon_image_1 = PhotonImage::new(
         image_data_1.pixels,
         image_data_1.width as u32,
         image_data_1.height as u32,
     );
 
-    let mut image_data_2 = IMAGE_LIBRARY
-        .lock()
-        .unwrap()
-        .get_image_data(image_id_2)
-        .unwrap()
-        .clone();
+    let mut image_data_2 = library.get_image_data(image_id_2).unwrap().clone();
     let mut photon_image_2 = PhotonImage::new(
         image_data_2.pixels,
         image_data_2.width as u32,
@@ -419,33 +326,22 @@ pub fn overlay(image_id_1: i32, image_id_2: i32) -> i32 {
 
     multiple::blend(&mut photon_image_1, &photon_image_2, "overlay");
 
-    IMAGE_LIBRARY.lock().unwrap().add_image(
+    library.add_image(
         "".to_string(),
         photon_image_1.get_width() as i32,
         photon_image_1.get_height() as i32,
         photon_image_1.get_raw_pixels(),
     )
 }
-#[wasm_bindgen]
-pub fn multiply(image_id_1: i32, image_id_2: i32) -> i32 {
-    let mut image_data_1 = IMAGE_LIBRARY
-        .lock()
-        .unwrap()
-        .get_image_data(image_id_1)
-        .unwrap()
-        .clone();
+pub fn multiply(library: &mut ImageLibrary, image_id_1: i32, image_id_2: i32) -> i32 {
+    let mut image_data_1 = library.get_image_data(image_id_1).unwrap().clone();
     let mut photon_image_1 = PhotonImage::new(
         image_data_1.pixels,
         image_data_1.width as u32,
         image_data_1.height as u32,
     );
 
-    let mut image_data_2 = IMAGE_LIBRARY
-        .lock()
-        .unwrap()
-        .get_image_data(image_id_2)
-        .unwrap()
-        .clone();
+    let mut image_data_2 = library.get_image_data(image_id_2).unwrap().clone();
     let mut photon_image_2 = PhotonImage::new(
         image_data_2.pixels,
         image_data_2.width as u32,
@@ -454,10 +350,86 @@ pub fn multiply(image_id_1: i32, image_id_2: i32) -> i32 {
 
     multiple::blend(&mut photon_image_1, &photon_image_2, "multiply");
 
-    IMAGE_LIBRARY.lock().unwrap().add_image(
+    library.add_image(
         "".to_string(),
         photon_image_1.get_width() as i32,
         photon_image_1.get_height() as i32,
         photon_image_1.get_raw_pixels(),
     )
 }
+
+// put the brightness of up to four images into the r, g, b and a of one image,
+// -1 leaves a channel empty (0, or 255 for alpha). Sources are resized to the first one.
+pub fn pack(library: &mut ImageLibrary, r: i32, g: i32, b: i32, a: i32) -> i32 {
+    let sources = [r, g, b, a];
+    // every source has to exist, resize() expects an image
+    if sources
+        .iter()
+        .any(|id| *id >= 0 && library.get_image_data(*id).is_none())
+    {
+        return -1;
+    }
+    let (width, height) = match sources.iter().find(|id| **id >= 0) {
+        Some(id) => match library.get_image_data(*id) {
+            Some(image_data) => (image_data.width, image_data.height),
+            None => return -1,
+        },
+        None => return -1,
+    };
+
+    let mut pixels: Vec<u8> = vec![0; (width * height * 4) as usize];
+    for i in (3..pixels.len()).step_by(4) {
+        pixels[i] = 255;
+    }
+    for (channel, id) in sources.iter().enumerate() {
+        if *id < 0 {
+            continue;
+        }
+        let id = match library.get_image_data(*id) {
+            Some(image_data) if image_data.width == width && image_data.height == height => *id,
+            _ => resize(library, *id, width, height),
+        };
+        let source = match library.get_image_data(id) {
+            Some(image_data) => &image_data.pixels,
+            None => return -1,
+        };
+        for i in (0..pixels.len()).step_by(4) {
+            let luminance = 0.299 * source[i] as f32
+                + 0.587 * source[i + 1] as f32
+                + 0.114 * source[i + 2] as f32;
+            pixels[i + channel] = luminance.round() as u8;
+        }
+    }
+
+    library.add_image("".to_string(), width, height, pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray(library: &mut ImageLibrary, value: u8, width: i32, height: i32) -> i32 {
+        let pixels = [value, value, value, 255].repeat((width * height) as usize);
+        library.add_image("".to_string(), width, height, pixels)
+    }
+
+    #[test]
+    fn pack_rejects_missing_images() {
+        let mut library = ImageLibrary::new();
+        let rock = gray(&mut library, 10, 4, 4);
+        assert_eq!(pack(&mut library, rock, 99, -1, -1), -1);
+        assert_eq!(pack(&mut library, -1, -1, -1, -1), -1);
+    }
+
+    #[test]
+    fn pack_resizes_to_the_first_image() {
+        let mut library = ImageLibrary::new();
+        let r = gray(&mut library, 10, 4, 4);
+        let g = gray(&mut library, 20, 4, 4);
+        let b = gray(&mut library, 30, 8, 8);
+        let packed = pack(&mut library, r, g, b, -1);
+        let image = library.get_image_data(packed).unwrap();
+        assert_eq!((image.width, image.height), (4, 4));
+        assert_eq!(&image.pixels[..4], &[10, 20, 30, 255]);
+    }
+}
@@ -1,15 +1,9 @@
-use super::log_rule;
-// use super::symbol;
-use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::Mutex;
-use wasm_bindgen::prelude::*;
-
-log_rule!();
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ImageData {
+    #[serde(default)]
     pub name: String,
     pub width: i32,
     pub height: i32,
@@ -75,40 +69,16 @@ impl ImageLibrary {
         self.export_items.clear();
     }
 
-    // return [name] [ImageData]
-    pub fn export(&mut self, export_info: &JsValue) -> JsValue {
-        let export_info: HashMap<String, i32> = export_info.into_serde().unwrap();
+    // name -> image of every (name, image id) that exists
+    pub fn export(&self, export_info: &HashMap<String, i32>) -> HashMap<String, ImageData> {
         let mut result: HashMap<String, ImageData> = HashMap::new();
         for (name, id) in export_info.iter() {
-            if let Some(data) = self.content.get_mut(id) {
+            if let Some(data) = self.content.get(id) {
                 let mut new_data = data.clone();
                 new_data.name = name.clone();
                 result.insert(name.clone(), new_data);
             }
         }
-        return JsValue::from_serde(&result).unwrap();
+        result
     }
 }
-
-lazy_static! {
-    #[wasm_bindgen]
-    pub static ref IMAGE_LIBRARY: Mutex<ImageLibrary> = Mutex::new(ImageLibrary::new());
-}
-
-#[wasm_bindgen]
-pub fn library_add_image(name: String, width: i32, height: i32, pixels: Vec<u8>) {
-    IMAGE_LIBRARY
-        .lock()
-        .unwrap()
-        .add_image(name, width, height, pixels);
-}
-
-#[wasm_bindgen]
-pub fn library_export(export_info: &JsValue) -> JsValue {
-    IMAGE_LIBRARY.lock().unwrap().export(export_info)
-}
-
-#[wasm_bindgen]
-pub fn library_reset() {
-    IMAGE_LIBRARY.lock().unwrap().reset();
-}
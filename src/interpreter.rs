@@ -0,0 +1,1042 @@
+// evaluates the ast directly against the image library, without a wasm module. the semantics
+// are the ones of the compiled module: errors the compiler reports stop the program before any
+// statement runs, runtime errors (a division by zero, a library call that fails) stop it
+// after the statements before them ran.
+use super::ast::{Expr, Function, Opcode, Statement};
+use super::cache::{cached_call, CallCache};
+use super::compiler::{parse_program, statement_sources, Inputs, MAIN_PREFIX};
+use super::diagnostic::{collect, position, to_js_value, Diagnostic, Kind};
+use super::export::{export_materials, ExportOptions};
+use super::image_library::ImageLibrary;
+use super::log_rule;
+use super::manifest::{ChannelManifest, Manifest, MaterialManifest, MANIFEST_VERSION};
+use super::material_ops::{
+    channel_blend, channel_source, check_arguments, check_blend_types, check_value, derivations,
+    material_variable, string_arg, ChannelSource, MATERIAL_OPERATIONS, UNASSIGNED,
+};
+use super::material_types::PackedTexture;
+use super::registry::{library_functions, LibraryFunction};
+use super::session::{shared_session, Output, Session, StatementTiming};
+use super::symbol::MaterialInfo;
+use super::testing::{TestResult, ASSERTIONS, TEST_EXPORT_PREFIX};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use wasm_bindgen::prelude::*;
+
+log_rule!();
+
+// what a material variable's record in memory holds
+#[derive(Debug, Clone)]
+struct Record {
+    material_type: String,
+    width: i32,
+    height: i32,
+    channels: Vec<(String, i32)>, // channel, image id or UNASSIGNED
+}
+
+impl Record {
+    fn channel(&self, channel: &str) -> Result<i32, String> {
+        match self.channels.iter().find(|(name, _)| name == channel) {
+            Some((_, image)) => Ok(*image),
+            None => Err(format!(
+                "material type {:?} has no channel {:?}",
+                self.material_type, channel
+            )),
+        }
+    }
+
+    fn set_channel(&mut self, channel: &str, image: i32) {
+        if let Some(slot) = self.channels.iter_mut().find(|(name, _)| name == channel) {
+            slot.1 = image;
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Value {
+    Number(i32),
+    Image(i32),
+    Material(Option<Record>), // None until new_material() was called
+    Function(LibraryFunction),
+}
+
+enum Error {
+    Program(String), // the compiler rejects the program
+    Runtime(String), // the compiled module traps
+}
+
+type Eval<T> = Result<T, Error>;
+
+fn error<T>(message: String) -> Eval<T> {
+    Err(Error::Program(message))
+}
+
+struct Interpreter<'a> {
+    library: &'a mut ImageLibrary,
+    cache: Option<&'a mut CallCache>,
+    // false only checks the program, library functions aren't called
+    execute: bool,
+    variables: HashMap<String, Value>,
+    material_info: MaterialInfo,
+    textures: HashMap<String, i32>,
+    timings: Vec<StatementTiming>,
+    statement: usize,
+}
+
+impl<'a> Interpreter<'a> {
+    // the library functions and the inputs, like the start of main()
+    fn new(
+        library: &'a mut ImageLibrary,
+        cache: Option<&'a mut CallCache>,
+        execute: bool,
+        inputs: &Inputs,
+    ) -> Self {
+        let mut variables: HashMap<String, Value> = HashMap::new();
+        for function in library_functions().iter() {
+            variables.insert(function.name.clone(), Value::Function(function.clone()));
+        }
+        for (i, name) in inputs.images.iter().enumerate() {
+            variables.insert(name.trim().to_string(), Value::Image(i as i32));
+        }
+        Interpreter {
+            library,
+            cache,
+            execute,
+            variables,
+            material_info: MaterialInfo::new(),
+            textures: HashMap::new(),
+            timings: vec![],
+            statement: 0,
+        }
+    }
+
+    fn lookup(&self, identifier: &str) -> Option<Value> {
+        self.variables.get(identifier).cloned()
+    }
+
+    fn function(&self, name: &str) -> Eval<LibraryFunction> {
+        match self.lookup(name) {
+            Some(Value::Function(function)) => Ok(function),
+            _ => error(format!("library function {:?} is not available", name)),
+        }
+    }
+
+    fn call(&mut self, function: &LibraryFunction, args: &[i32]) -> Eval<i32> {
+        if !self.execute {
+            return Ok(0);
+        }
+        let start = Instant::now();
+        // a failed call traps like in the compiled module
+        let (result, cached) = cached_call(
+            &mut *self.library,
+            self.cache.as_deref_mut(),
+            function,
+            args,
+        )
+        .map_err(Error::Runtime)?;
+        if let Some(timing) = self.timings.get_mut(self.statement) {
+            timing.duration += start.elapsed();
+            timing.calls += 1;
+            if cached {
+                timing.cached += 1;
+            }
+        }
+        Ok(result)
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Eval<i32> {
+        match expr {
+            Expr::Number(n) => Ok(*n),
+            Expr::Op(l, op, r) => {
+                let (l, r) = (self.eval(l)?, self.eval(r)?);
+                match op {
+                    Opcode::Add => Ok(l.wrapping_add(r)),
+                    Opcode::Sub => Ok(l.wrapping_sub(r)),
+                    Opcode::Mul => Ok(l.wrapping_mul(r)),
+                    Opcode::Eq => Ok((l == r) as i32),
+                    Opcode::Ne => Ok((l != r) as i32),
+                    Opcode::Lt => Ok((l < r) as i32),
+                    Opcode::Gt => Ok((l > r) as i32),
+                    Opcode::Le => Ok((l <= r) as i32),
+                    Opcode::Ge => Ok((l >= r) as i32),
+                    Opcode::Div if !self.execute => Ok(0),
+                    Opcode::Div => match l.checked_div(r) {
+                        Some(result) => Ok(result),
+                        None if r == 0 => Err(Error::Runtime("integer divide by zero".to_string())),
+                        None => Err(Error::Runtime("integer overflow".to_string())),
+                    },
+                }
+            }
+            Expr::Variable(identifier) => match self.lookup(identifier) {
+                Some(Value::Image(value)) | Some(Value::Number(value)) => Ok(value),
+                Some(_) => error(format!(
+                    "variable {:?} is neither an image nor a number.",
+                    identifier
+                )),
+                None => error(format!("variable {:?} doesn't exist.", identifier)),
+            },
+            Expr::Call(identifier, exprs) => {
+                let function = match self.lookup(identifier) {
+                    Some(Value::Function(function)) => function,
+                    Some(_) => return error(format!("{:?} is not a function.", identifier)),
+                    None => {
+                        return error(format!(
+                            "function {:?} doesn't exist. Please try using an existing function from the library.",
+                            identifier
+                        ))
+                    }
+                };
+                if exprs.len() != function.params.len() {
+                    return error(format!(
+                        "function {:?} should take {:?} parameters instead of {:?} ",
+                        identifier,
+                        function.params.len(),
+                        exprs.len()
+                    ));
+                }
+                let mut args: Vec<i32> = vec![];
+                for expr in exprs {
+                    match &**expr {
+                        Expr::Number(_) | Expr::Call(_, _) | Expr::Op(_, _, _) => {}
+                        Expr::Variable(var_ident) => match self.lookup(var_ident) {
+                            Some(Value::Image(_)) | Some(Value::Number(_)) => {}
+                            Some(_) => return error(format!("{:?} has a wrong type", expr)),
+                            None => {
+                                return error(format!("Variable {:?} does not exist", var_ident))
+                            }
+                        },
+                        _ => return error(format!("{:?} can't be passed to a function", expr)),
+                    }
+                    args.push(self.eval(expr)?);
+                }
+                self.call(&function, &args)
+            }
+            _ => error(format!("{:?} can't be used as a value", expr)),
+        }
+    }
+
+    fn set(&mut self, identifier: &str, value: Value) {
+        self.variables.insert(identifier.to_string(), value);
+    }
+
+    fn statement(&mut self, statement: &Statement) -> Eval<()> {
+        match statement {
+            Statement::Declare(identifier, var_type, expr) => {
+                self.declare(identifier, var_type, expr.as_deref())
+            }
+            Statement::Assignment(identifiers, expr) => match identifiers.as_slice() {
+                [identifier] => self.assign(identifier, expr),
+                [identifier, channel] => self.assign_channel(identifier, channel, expr),
+                _ => error(
+                    "Please have a variable on the left side = to assign the value to.".to_string(),
+                ),
+            },
+            Statement::Block(_) => Ok(()),
+            // run after main(), see run_test
+            Statement::Test(_, _) => Ok(()),
+            Statement::MaterialType(identifier, channels) => {
+                match self.material_info.add_material_type(identifier, channels) {
+                    Ok(_) => Ok(()),
+                    Err(e) => error(e),
+                }
+            }
+            Statement::Call(identifier, exprs) => match identifier.as_str() {
+                "logger" => {
+                    if self.execute {
+                        log(&format!("logging: {:?}", exprs));
+                    }
+                    Ok(())
+                }
+                "assert" | "assert_similar" => error(format!(
+                    "{}() can only be used in a test block",
+                    identifier
+                )),
+                "show" => {
+                    for expr in exprs {
+                        let expr_ident = match &**expr {
+                            Expr::Variable(expr_ident) => expr_ident,
+                            _ => return error("show() can only be used for image and material. show() is default for material.".to_string()),
+                        };
+                        match self.lookup(expr_ident) {
+                            Some(Value::Image(id)) => {
+                                self.textures.insert(expr_ident.clone(), id);
+                            }
+                            Some(Value::Material(_)) => {}
+                            Some(_) => return error("show() can only be used for image and material. show() is default for material.".to_string()),
+                            None => return error(format!("{:?} doesn't exist. Please decalre or define it. Example: var image0:Image; or var image1:Image = file_001;", expr_ident)),
+                        }
+                    }
+                    Ok(())
+                }
+                _ => error(format!("Please store function {:?}'s return in a variable. Example: var image1:m = grayscale(file_001)", identifier)),
+            },
+        }
+    }
+
+    fn declare(&mut self, identifier: &str, var_type: &str, expr: Option<&Expr>) -> Eval<()> {
+        if identifier.parse::<i32>().is_ok() {
+            return error("please use a non-numeric name for a variable.".to_string());
+        }
+        match var_type {
+            "N" | "Number" | "n" | "number" => {
+                // a new variable, even if one of the same name existed before
+                self.set(identifier, Value::Number(0));
+                let value = match expr {
+                    None => return Ok(()),
+                    Some(Expr::Variable(right_ident)) => match self.lookup(right_ident) {
+                        Some(Value::Number(value)) => value,
+                        Some(_) => return error(format!("{:?} is not a number.", right_ident)),
+                        None => return error(format!("{:?} does not exist.", right_ident)),
+                    },
+                    Some(expr @ Expr::Number(_)) | Some(expr @ Expr::Op(_, _, _)) => {
+                        self.eval(expr)?
+                    }
+                    Some(expr) => {
+                        return error(format!(
+                            "{:?} and {:?} has different type.",
+                            identifier, expr
+                        ))
+                    }
+                };
+                self.set(identifier, Value::Number(value));
+                Ok(())
+            }
+            "I" | "Image" | "i" | "image" => {
+                match expr {
+                    None => self.set(identifier, Value::Image(0)),
+                    Some(Expr::Variable(right_ident)) => match self.lookup(right_ident) {
+                        Some(Value::Image(id)) => self.set(identifier, Value::Image(id)),
+                        Some(_) => return error(format!("{:?} is not an image.", right_ident)),
+                        None => return error(format!("{:?} does not exist.", right_ident)),
+                    },
+                    Some(expr @ Expr::Call(right_ident, _)) => match self.lookup(right_ident) {
+                        Some(Value::Function(_)) => {
+                            self.set(identifier, Value::Image(0));
+                            self.assign(identifier, expr)?;
+                        }
+                        Some(_) => return error(format!("{:?} is not a function.", right_ident)),
+                        None => return error(format!("{:?} does not exist.", right_ident)),
+                    },
+                    Some(expr) => {
+                        return error(format!(
+                            "{:?} and {:?} has different type.",
+                            identifier, expr
+                        ))
+                    }
+                }
+                Ok(())
+            }
+            "M" | "Material" | "m" | "material" => {
+                self.set(identifier, Value::Material(None));
+                match expr {
+                    Some(expr) => self.assign(identifier, expr),
+                    None => Ok(()),
+                }
+            }
+            _ => error(format!("type {:?} not supported.", var_type)),
+        }
+    }
+
+    fn assign(&mut self, identifier: &str, expr: &Expr) -> Eval<()> {
+        match self.lookup(identifier) {
+            None => error(format!(
+                "{:?} doesn't exist. Please decalre or define it. Example: var image0:Image; or var image1:Image = file_001;",
+                identifier
+            )),
+            Some(Value::Number(_)) => {
+                let value = match expr {
+                    Expr::Variable(right_ident) => match self.lookup(right_ident) {
+                        Some(Value::Number(value)) => value,
+                        Some(_) => return error(format!("{:?} is not a number.", expr)),
+                        None => return error(format!("{:?} does not exist.", right_ident)),
+                    },
+                    Expr::Number(_) | Expr::Op(_, _, _) => self.eval(expr)?,
+                    _ => {
+                        return error(format!(
+                            "value of {:?} should be as a number",
+                            identifier
+                        ))
+                    }
+                };
+                self.set(identifier, Value::Number(value));
+                Ok(())
+            }
+            Some(Value::Image(_)) => match expr {
+                Expr::Variable(right_ident) => match self.lookup(right_ident) {
+                    Some(Value::Image(id)) => {
+                        self.set(identifier, Value::Image(id));
+                        Ok(())
+                    }
+                    Some(_) => error(format!("{:?} is not an image.", right_ident)),
+                    None => error(format!("{:?} does not exist.", right_ident)),
+                },
+                Expr::Call(func_ident, _) => {
+                    let id = self.eval(expr)?;
+                    match self.lookup(func_ident) {
+                        // functions without a result only run
+                        Some(Value::Function(function)) if function.returns.is_none() => Ok(()),
+                        Some(Value::Function(_)) => {
+                            self.set(identifier, Value::Image(id));
+                            Ok(())
+                        }
+                        _ => error(format!(
+                            "{:?} doesn't exist. Please use another function",
+                            func_ident
+                        )),
+                    }
+                }
+                _ => error(format!(
+                    "value of {:?} should be as an image",
+                    identifier
+                )),
+            },
+            Some(Value::Material(_)) => match expr {
+                Expr::Variable(right_ident) => match self.lookup(right_ident) {
+                    Some(Value::Material(Some(record))) => {
+                        self.set(identifier, Value::Material(Some(record)));
+                        Ok(())
+                    }
+                    Some(Value::Material(None)) => error(format!(
+                        "Please call new_material() on {:?} before assigning it to {:?};",
+                        right_ident, identifier
+                    )),
+                    _ => error(format!(
+                        "Please define {:?} with a material variable;",
+                        identifier
+                    )),
+                },
+                Expr::Call(operation, params)
+                    if MATERIAL_OPERATIONS.contains(&operation.as_str()) =>
+                {
+                    let record = match operation.as_str() {
+                        "convert_material" => self.convert_material(params)?,
+                        _ => self.blend_material(operation, params)?,
+                    };
+                    self.set(identifier, Value::Material(Some(record)));
+                    Ok(())
+                }
+                Expr::Call(func_ident, params) => {
+                    let usage = format!(
+                        "Please call new_material(material type, width,height) to define {:?};",
+                        identifier
+                    );
+                    if func_ident != "new_material" || params.len() != 3 {
+                        return error(usage);
+                    }
+                    let material_type = match &*params[0] {
+                        Expr::Str(t) => t[1..t.len() - 1].to_string(),
+                        _ => return error("Please use a string for material's type.".to_string()),
+                    };
+                    let width = match &*params[1] {
+                        Expr::Number(width) => *width,
+                        _ => return error("Please use a number for material's width".to_string()),
+                    };
+                    let height = match &*params[2] {
+                        Expr::Number(height) => *height,
+                        _ => return error("Please use a number for material's height".to_string()),
+                    };
+                    if width <= 0 || height <= 0 {
+                        return error(format!(
+                            "{:?} needs a positive width and height, not {}x{}",
+                            identifier, width, height
+                        ));
+                    }
+                    let record = self.new_record(&material_type, width, height)?;
+                    self.set(identifier, Value::Material(Some(record)));
+                    Ok(())
+                }
+                _ => error(format!(
+                    "Please define the material {:?} with another material variable, or call new_material(width,height) function",
+                    identifier
+                )),
+            },
+            Some(Value::Function(_)) => Ok(()),
+        }
+    }
+
+    // the image is resized to the material's resolution
+    fn assign_channel(&mut self, identifier: &str, channel: &str, expr: &Expr) -> Eval<()> {
+        let mut record = match self.lookup(identifier) {
+            Some(Value::Material(Some(record))) if record.channel(channel).is_ok() => record,
+            Some(Value::Material(record)) => {
+                return error(format!(
+                    "Can't find channel {:?} of material type {:?} for variable {:?}",
+                    channel,
+                    record.map(|r| r.material_type).unwrap_or_default(),
+                    identifier
+                ))
+            }
+            Some(_) => return error("Please use a material.".to_string()),
+            None => return error(format!("{:?} doesn't exist.", identifier)),
+        };
+        let id = match expr {
+            Expr::Variable(right_ident) => match self.lookup(right_ident) {
+                Some(Value::Image(id)) => id,
+                Some(_) => return error(format!("{:?} is not an image.", right_ident)),
+                None => return error(format!("{:?} does not exist.", right_ident)),
+            },
+            Expr::Call(func_ident, _) => match self.lookup(func_ident) {
+                Some(Value::Function(function)) if function.returns.is_some() => self.eval(expr)?,
+                Some(Value::Function(_)) => return Ok(()),
+                _ => {
+                    return error(format!(
+                        "{:?} doesn't exist. Please use another function",
+                        func_ident
+                    ))
+                }
+            },
+            _ => return Ok(()),
+        };
+        let id = match self.lookup("resize") {
+            Some(Value::Function(resize)) => {
+                self.call(&resize, &[id, record.width, record.height])?
+            }
+            _ => id,
+        };
+        record.set_channel(channel, id);
+        self.set(identifier, Value::Material(Some(record)));
+        Ok(())
+    }
+
+    fn new_record(&self, material_type: &str, width: i32, height: i32) -> Eval<Record> {
+        let material_type = material_type.to_string();
+        match (
+            self.material_info.get_material_type_id(&material_type),
+            self.material_info.get_material_channels(&material_type),
+        ) {
+            (Ok(_), Ok(channels)) => Ok(Record {
+                material_type,
+                width,
+                height,
+                channels: channels.into_iter().map(|c| (c, UNASSIGNED)).collect(),
+            }),
+            _ => error(format!("{:?} is not a material type.", material_type)),
+        }
+    }
+
+    fn material_arg(&self, expr: &Expr) -> Eval<Record> {
+        let identifier = material_variable(expr).map_err(Error::Program)?;
+        match self.lookup(identifier) {
+            Some(Value::Material(Some(record))) => Ok(record),
+            Some(Value::Material(None)) => error(format!(
+                "Please call new_material() on {:?} before using it",
+                identifier
+            )),
+            _ => error(format!("{:?} is not a material", identifier)),
+        }
+    }
+
+    fn value_arg(&mut self, expr: &Expr) -> Eval<i32> {
+        check_value(expr).map_err(Error::Program)?;
+        if let Expr::Variable(identifier) = expr {
+            match self.lookup(identifier) {
+                Some(Value::Image(_)) | Some(Value::Number(_)) => {}
+                _ => return error(format!("{:?} should be an image or a number", identifier)),
+            }
+        }
+        self.eval(expr)
+    }
+
+    // see material_ops::convert_material
+    fn convert_material(&mut self, params: &[Box<Expr>]) -> Eval<Record> {
+        check_arguments("convert_material", params).map_err(Error::Program)?;
+        let source = self.material_arg(&params[0])?;
+        let target_type = string_arg(&params[1]).map_err(Error::Program)?;
+        let derivations =
+            derivations(&source.material_type, &target_type).map_err(Error::Program)?;
+        let source_channels: Vec<String> = source.channels.iter().map(|(c, _)| c.clone()).collect();
+
+        let mut result = self.new_record(&target_type, source.width, source.height)?;
+        let channels: Vec<String> = result.channels.iter().map(|(c, _)| c.clone()).collect();
+        for channel in &channels {
+            match channel_source(&derivations, &source_channels, channel) {
+                ChannelSource::Derived(derivation) => {
+                    let function = self.function(derivation.function)?;
+                    let mut args: Vec<i32> = vec![];
+                    for arg in derivation.args {
+                        args.push(source.channel(arg).map_err(Error::Program)?);
+                    }
+                    if args[0] != UNASSIGNED {
+                        let id = self.call(&function, &args)?;
+                        result.set_channel(channel, id);
+                    }
+                }
+                ChannelSource::Copied => {
+                    result.set_channel(channel, source.channel(channel).map_err(Error::Program)?);
+                }
+                ChannelSource::Empty => {}
+            }
+        }
+        Ok(result)
+    }
+
+    // see material_ops::blend_material
+    fn blend_material(&mut self, operation: &str, params: &[Box<Expr>]) -> Eval<Record> {
+        check_arguments(operation, params).map_err(Error::Program)?;
+        let a = self.material_arg(&params[0])?;
+        let b = self.material_arg(&params[1])?;
+        check_blend_types(operation, &a.material_type, &b.material_type).map_err(Error::Program)?;
+
+        let mask = if operation == "height_blend" {
+            let height_a = self.value_arg(&params[2])?;
+            let height_b = self.value_arg(&params[3])?;
+            let contrast = self.value_arg(&params[4])?;
+            let height_mask = self.function("height_mask")?;
+            self.call(&height_mask, &[height_a, height_b, contrast])?
+        } else {
+            self.value_arg(&params[2])?
+        };
+
+        let mut result = self.new_record(&a.material_type, a.width, a.height)?;
+        let channels: Vec<String> = result.channels.iter().map(|(c, _)| c.clone()).collect();
+        for channel in &channels {
+            let function = self.function(channel_blend(operation, channel))?;
+            let slots = [
+                a.channel(channel).map_err(Error::Program)?,
+                b.channel(channel).map_err(Error::Program)?,
+            ];
+            if slots.iter().any(|slot| *slot != UNASSIGNED) {
+                let id = self.call(&function, &[slots[0], slots[1], mask])?;
+                result.set_channel(channel, id);
+            }
+        }
+        Ok(result)
+    }
+
+    // assert() and assert_similar() of a test, with their position as the last arguments
+    fn assertion(&mut self, identifier: &str, exprs: &[Box<Expr>], at: (usize, usize)) -> Eval<()> {
+        let function = self.function(identifier)?;
+        if exprs.len() + 2 != function.params.len() {
+            return error(format!(
+                "{}() should take {} parameters instead of {}",
+                identifier,
+                function.params.len() - 2,
+                exprs.len()
+            ));
+        }
+        let mut args: Vec<i32> = vec![];
+        for expr in exprs {
+            let valid = match &**expr {
+                Expr::Number(_) | Expr::Call(_, _) | Expr::Op(_, _, _) => true,
+                Expr::Variable(name) => {
+                    matches!(
+                        self.lookup(name),
+                        Some(Value::Number(_)) | Some(Value::Image(_))
+                    )
+                }
+                _ => false,
+            };
+            if !valid {
+                return error(format!(
+                    "{:?} can't be passed to {}(), it takes numbers and images",
+                    expr, identifier
+                ));
+            }
+            args.push(self.eval(expr)?);
+        }
+        args.extend(&[at.0 as i32, at.1 as i32]);
+        self.call(&function, &args)?;
+        Ok(())
+    }
+
+    // every material with a record, like write_manifest
+    fn manifest(&self) -> Manifest {
+        let mut names: Vec<&String> = self.variables.keys().collect();
+        names.sort();
+        let mut materials: Vec<MaterialManifest> = vec![];
+        for name in names {
+            let record = match &self.variables[name] {
+                Value::Material(Some(record)) => record,
+                _ => continue,
+            };
+            let definition = match self.material_info.get_material_type(&record.material_type) {
+                Some(definition) => definition,
+                None => continue,
+            };
+            let has = |channel: &Option<String>| {
+                channel
+                    .clone()
+                    .filter(|c| definition.channels.iter().any(|d| &d.name == c))
+            };
+            materials.push(MaterialManifest {
+                name: name.clone(),
+                material_type: record.material_type.clone(),
+                width: record.width,
+                height: record.height,
+                channels: definition
+                    .channels
+                    .iter()
+                    .map(|channel| ChannelManifest {
+                        name: channel.name.clone(),
+                        image: Some(record.channel(&channel.name).unwrap_or(UNASSIGNED))
+                            .filter(|id| *id != UNASSIGNED),
+                        default_color: channel.default_color,
+                        required: channel.required,
+                        color_space: channel.color_space,
+                        bit_depth: channel.bit_depth,
+                    })
+                    .collect(),
+                packing: definition
+                    .packing
+                    .iter()
+                    .map(|packed| PackedTexture {
+                        name: packed.name.clone(),
+                        r: has(&packed.r),
+                        g: has(&packed.g),
+                        b: has(&packed.b),
+                        a: has(&packed.a),
+                        inverted: packed
+                            .sources()
+                            .iter()
+                            .filter_map(|source| has(source))
+                            .filter(|source| packed.inverted.contains(source))
+                            .collect(),
+                    })
+                    .collect(),
+            });
+        }
+        Manifest {
+            version: MANIFEST_VERSION,
+            materials,
+        }
+    }
+}
+
+// main() of a program, with the error of a statement at its position
+fn run_main(interpreter: &mut Interpreter, main: &Function) -> Result<(), (Error, usize)> {
+    for (i, statement) in main.statements.iter().enumerate() {
+        let offset = main.spans[i].0 - MAIN_PREFIX.len();
+        interpreter.statement = i;
+        let statement = match statement.lower() {
+            Ok(statement) => statement,
+            Err(e) => return Err((Error::Program(e), offset)),
+        };
+        if let Err(e) = interpreter.statement(&statement) {
+            return Err((e, offset));
+        }
+    }
+    Ok(())
+}
+
+// a test block, with the error of a statement at its position. it runs on the variables main()
+// left and may not change the materials of the script, the same checks as testing::compile_tests
+fn run_test(
+    interpreter: &mut Interpreter,
+    name: &str,
+    statements: &[(Statement, usize, usize)],
+    src: &str,
+) -> Result<(), (Error, usize)> {
+    let mut script_materials: Vec<String> = interpreter
+        .variables
+        .iter()
+        .filter(|(_, value)| matches!(value, Value::Material(_)))
+        .map(|(name, _)| name.clone())
+        .collect();
+    for (statement, l, _) in statements {
+        let offset = l - MAIN_PREFIX.len();
+        let statement = statement.lower().map_err(|e| (Error::Program(e), offset))?;
+        let result = match &statement {
+            Statement::Test(_, _) => error(format!("test {:?} can't contain another test", name)),
+            Statement::Call(identifier, exprs) if ASSERTIONS.contains(&identifier.as_str()) => {
+                interpreter.assertion(identifier, exprs, position(src, offset))
+            }
+            Statement::Declare(identifier, _, _) => {
+                script_materials.retain(|material| material != identifier);
+                interpreter.statement(&statement)
+            }
+            Statement::Assignment(identifiers, _) if matches!(identifiers.first(), Some(identifier) if script_materials.contains(identifier)) => {
+                error(format!(
+                    "test {:?} can't assign to {:?}, it belongs to the script; declare a \
+                     variable of the test instead",
+                    name, identifiers[0]
+                ))
+            }
+            _ => interpreter.statement(&statement),
+        };
+        result.map_err(|e| (e, offset))?;
+    }
+    Ok(())
+}
+
+// every test block of main(), each one starts from the state main() left. failed assertions are
+// collected like the ones of the compiled tests, errors the compiler reports stop checking
+fn run_tests(
+    interpreter: &mut Interpreter,
+    main: &Function,
+    src: &str,
+) -> Result<Vec<TestResult>, Diagnostic> {
+    let mut results: Vec<TestResult> = vec![];
+    for (i, statement) in main.statements.iter().enumerate() {
+        let (name, statements) = match statement {
+            Statement::Test(name, statements) => (name, statements),
+            _ => continue,
+        };
+        let offset = main.spans[i].0 - MAIN_PREFIX.len();
+        if results.iter().any(|result| &result.name == name) {
+            return Err(Diagnostic::at(
+                Kind::Compile,
+                format!("there are two tests named {:?}", name),
+                src,
+                offset,
+            ));
+        }
+        let state = (
+            interpreter.variables.clone(),
+            interpreter.material_info.clone(),
+            interpreter.textures.clone(),
+        );
+        // calls of the tests aren't timed
+        interpreter.statement = usize::MAX;
+        let (result, mut failures) = collect(|| run_test(interpreter, name, statements, src));
+        let (variables, material_info, textures) = state;
+        interpreter.variables = variables;
+        interpreter.material_info = material_info;
+        interpreter.textures = textures;
+        match result {
+            Ok(()) => {}
+            Err((Error::Program(message), offset)) => {
+                return Err(Diagnostic::at(Kind::Compile, message, src, offset))
+            }
+            Err((Error::Runtime(message), offset)) => failures.push(Diagnostic::at(
+                Kind::Runtime,
+                format!("{}{}() failed: {}", TEST_EXPORT_PREFIX, name, message),
+                src,
+                offset,
+            )),
+        }
+        results.push(TestResult {
+            name: name.clone(),
+            failures,
+        });
+    }
+    Ok(results)
+}
+
+// what a program run on the library gives
+pub(crate) struct Interpreted {
+    pub textures: HashMap<String, i32>, // name -> image id of every show()n image
+    pub manifest: Manifest,
+    pub timings: Vec<StatementTiming>,
+    pub tests: Vec<TestResult>, // empty unless the tests were run
+}
+
+// input images have to be in the library already, with tests the test blocks run after main()
+pub(crate) fn interpret(
+    library: &mut ImageLibrary,
+    cache: Option<&mut CallCache>,
+    src: &str,
+    inputs: &Inputs,
+    tests: bool,
+) -> Result<Interpreted, Vec<Diagnostic>> {
+    let functions = parse_program(src).map_err(|diagnostic| vec![diagnostic])?;
+    let main = &functions[0];
+
+    let mut check = Interpreter::new(library, None, false, inputs);
+    if let Err((Error::Program(message), offset)) = run_main(&mut check, main) {
+        return Err(vec![Diagnostic::at(Kind::Compile, message, src, offset)]);
+    }
+    run_tests(&mut check, main, src).map_err(|diagnostic| vec![diagnostic])?;
+
+    let mut interpreter = Interpreter::new(library, cache, true, inputs);
+    interpreter.timings = statement_sources(src, main)
+        .into_iter()
+        .map(|statement| StatementTiming {
+            statement,
+            duration: Duration::default(),
+            calls: 0,
+            cached: 0,
+        })
+        .collect();
+    match run_main(&mut interpreter, main) {
+        Ok(()) => {}
+        Err((Error::Program(message), offset)) => {
+            return Err(vec![Diagnostic::at(Kind::Compile, message, src, offset)])
+        }
+        Err((Error::Runtime(message), offset)) => {
+            return Err(vec![Diagnostic::at(
+                Kind::Runtime,
+                format!("main() failed: {}", message),
+                src,
+                offset,
+            )])
+        }
+    }
+    let manifest = interpreter.manifest();
+    let results = if tests {
+        run_tests(&mut interpreter, main, src).map_err(|diagnostic| vec![diagnostic])?
+    } else {
+        vec![]
+    };
+    Ok(Interpreted {
+        textures: std::mem::take(&mut interpreter.textures),
+        manifest,
+        timings: std::mem::take(&mut interpreter.timings),
+        tests: results,
+    })
+}
+
+impl Session {
+    // run a program without compiling it, same as Compiler::compile and run() but without wasm
+    pub fn interpret(
+        &mut self,
+        src: &str,
+        inputs: &Inputs,
+        options: &ExportOptions,
+    ) -> Result<Output, Vec<Diagnostic>> {
+        let mut cache = self.cache_mut().take();
+        let result = interpret(self.library_mut(), cache.as_mut(), src, inputs, false);
+        if let (Ok(_), Some(cache)) = (&result, cache.as_mut()) {
+            cache.prune();
+        }
+        *self.cache_mut() = cache;
+
+        let interpreted = result?;
+        Ok(Output {
+            textures: self.export_textures(&interpreted.textures),
+            materials: export_materials(self.library_mut(), &interpreted.manifest, options),
+            manifest: interpreted.manifest,
+            timings: interpreted.timings,
+        })
+    }
+
+    // the counterpart of test() for a program that isn't compiled
+    pub fn interpret_tests(
+        &mut self,
+        src: &str,
+        inputs: &Inputs,
+    ) -> Result<Vec<TestResult>, Vec<Diagnostic>> {
+        let mut cache = self.cache_mut().take();
+        let result = interpret(self.library_mut(), cache.as_mut(), src, inputs, true);
+        *self.cache_mut() = cache;
+        result.map(|interpreted| interpreted.tests)
+    }
+}
+
+// the counterpart of code_to_wasm and running the module, input images have to be added with
+// library_add_image before. returns [{name: image_id}, manifest], the manifest is null on errors
+#[wasm_bindgen]
+pub fn interpret_code(src: String, image_names: &JsValue) -> Vec<JsValue> {
+    let inputs = Inputs {
+        images: match image_names.into_serde::<Vec<String>>() {
+            Ok(names) => names,
+            Err(e) => {
+                log(&format!(
+                    "Err: {:?}, failed to pass image file names from js to wasm",
+                    e
+                ));
+                vec![]
+            }
+        },
+    };
+    let mut session = shared_session();
+    match interpret(session.library_mut(), None, &src, &inputs, false) {
+        Ok(interpreted) => vec![
+            to_js_value(&interpreted.textures),
+            to_js_value(&interpreted.manifest),
+        ],
+        Err(diagnostics) => {
+            for diagnostic in diagnostics {
+                log(&diagnostic.to_string());
+            }
+            vec![to_js_value(&HashMap::<String, i32>::new()), JsValue::NULL]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // programs both backends have to refuse, whether the compiler rejects them or the module
+    // traps: name, program, part of the error both give
+    const FAILURES: [(&str, &str, &str); 5] = [
+        (
+            "division by zero",
+            "var x:n = 1 / 0;",
+            "integer divide by zero",
+        ),
+        (
+            "wrong arity",
+            "var dark:i = darken(rock);",
+            "\"darken\" should take 2 parameters instead of 1",
+        ),
+        (
+            "unknown channel",
+            "var wall:m = new_material(\"PBRMetalness\", 4, 4); wall.shininess = rock;",
+            "channel \"shininess\"",
+        ),
+        (
+            "unknown image",
+            "var id:n = 7; var x:i = invert_color(id);",
+            "invert_color() got 7, which is not an image",
+        ),
+        (
+            "unknown function",
+            "var x:i = sparkle(rock);",
+            "\"sparkle\" does not exist",
+        ),
+    ];
+
+    fn session() -> Session {
+        let mut session = Session::new();
+        session.add_image("rock".to_string(), 32, 32, vec![128; 32 * 32 * 4]);
+        session
+    }
+
+    fn inputs() -> Inputs {
+        Inputs {
+            images: vec!["rock".to_string()],
+        }
+    }
+
+    fn messages(diagnostics: Vec<Diagnostic>) -> String {
+        let messages: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+        messages.join("\n")
+    }
+
+    fn expect_failure(
+        failures: &mut Vec<String>,
+        name: &str,
+        backend: &str,
+        result: Option<String>,
+        error: &str,
+    ) {
+        match result {
+            Some(message) if message.contains(error) => {}
+            Some(message) => failures.push(format!(
+                "{}: the {} fails with {:?} instead of {:?}",
+                name, backend, message, error
+            )),
+            None => failures.push(format!("{}: the {} doesn't fail", name, backend)),
+        }
+    }
+
+    #[test]
+    fn interpreter_fails() {
+        let mut failures: Vec<String> = vec![];
+        for (name, src, error) in FAILURES.iter() {
+            let result = session()
+                .interpret(src, &inputs(), &ExportOptions::default())
+                .err()
+                .map(messages);
+            expect_failure(&mut failures, name, "interpreter", result, error);
+        }
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn compiled_modules_fail_the_same() {
+        use crate::compiler::Compiler;
+
+        let mut failures: Vec<String> = vec![];
+        for (name, src, error) in FAILURES.iter() {
+            let result = match Compiler::compile(src, &inputs()) {
+                Ok(module) => session().run(&module, &ExportOptions::default()).err(),
+                Err(diagnostics) => Some(messages(diagnostics)),
+            };
+            expect_failure(&mut failures, name, "compiled module", result, error);
+        }
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+}
@@ -1,8 +1,60 @@
+mod abi;
 mod ast;
+mod cache;
+mod compare;
 mod compiler;
+mod diagnostic;
+mod engine_export;
+mod export;
+mod gltf;
 mod image_functions;
 mod image_library;
+mod interpreter;
+mod manifest;
+mod material_functions;
+mod material_ops;
+mod material_types;
+mod materialx;
+mod plugin;
+mod registry;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod repl;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod runtime;
+mod session;
 mod symbol;
+mod testing;
+mod validate;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod wasm_plugin;
+
+// the rust api, the wasm-bindgen functions are thin wrappers around it
+pub use abi::{AbiMetadata, HostAbi, MaterialExport};
+pub use cache::CallCache;
+pub use compare::{compare_images, diff_image, Difference};
+pub use compiler::{
+    CompiledModule, Compiler, FieldLayout, ImportInfo, Inputs, MemoryLayout, RecordLayout, Scope,
+    StatementSource, Variable, VariableKind,
+};
+pub use diagnostic::{Diagnostic, Kind, Severity};
+pub use engine_export::{save_profile, ExportFile, ExportProfile, ProfileOptions};
+pub use export::{encode_png, ExportOptions};
+pub use gltf::{save_gltf, GltfOptions, PreviewMesh};
+pub use image_library::{ImageData, ImageLibrary};
+pub use manifest::{ChannelManifest, Manifest, MaterialManifest};
+pub use material_types::{
+    register_material_type, ChannelInfo, ColorSpace, MaterialType, MaterialTypeRegistry,
+    PackedTexture,
+};
+pub use materialx::{save_materialx, write_materialx, MaterialXOptions, Shader};
+pub use plugin::{register_function, register_functions, unregister_function, ImageFunction};
+pub use registry::{Param, ParamType};
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub use repl::Repl;
+pub use session::{Output, Session, StatementTiming};
+pub use testing::TestResult;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub use wasm_plugin::register_plugin_manifest;
 
 #[macro_use]
 extern crate lalrpop_util;
@@ -11,19 +63,9 @@ lalrpop_mod!(pub coocoo);
 #[macro_export]
 macro_rules! log_rule {
     () => {
-        cfg_if::cfg_if! {
-            if #[cfg(test)] {
-                pub fn log(s: &str) {
-                    println!("{:?}", s);
-                }
-            } else {
-                use wasm_bindgen::prelude::*;
-                #[wasm_bindgen]
-                extern "C" {
-                    #[wasm_bindgen(js_namespace = console)]
-                    fn log(s: &str);
-                }
-            }
+        #[allow(dead_code)]
+        fn log(s: &str) {
+            $crate::diagnostic::report(s);
         }
     };
 }
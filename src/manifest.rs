@@ -0,0 +1,419 @@
+// the table returned by the exported manifest() function
+//
+// every entry is an i32, strings are (pointer, byte length) pairs of utf-8 in the same memory
+//   version, material count,
+//   per material: name, type, width pointer, height pointer, channel count,
+//                 per channel: name, image id pointer, has default (0 / 1), default rgba bytes,
+//                              required (0 / 1), color space (0 linear, 1 srgb), bit depth,
+//                 packed texture count,
+//                 per packed texture: name, channel index for r, g, b and a (-1 if unused),
+//                                     inverted sources (bit 0 r to bit 3 a)
+// pointers lead to the slots main() writes into, so the table itself never changes and hosts
+// read the results without knowing how records are laid out.
+use super::compiler::{Memory, ALIGN};
+use super::diagnostic::to_js_value;
+use super::log_rule;
+use super::material_types::{default_bit_depth, ColorSpace, PackedTexture};
+use super::symbol::LibraryTracker;
+use serde::{Deserialize, Serialize};
+use std::convert::{TryFrom, TryInto};
+use wasm_bindgen::prelude::*;
+
+log_rule!();
+
+pub const MANIFEST_VERSION: i32 = 6;
+pub const MANIFEST_EXPORT_NAME: &str = "manifest";
+
+// image slots nothing was assigned to
+const UNASSIGNED: i32 = i32::MAX;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: i32,
+    pub materials: Vec<MaterialManifest>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MaterialManifest {
+    pub name: String,
+    pub material_type: String,
+    pub width: i32,
+    pub height: i32,
+    pub channels: Vec<ChannelManifest>,
+    pub packing: Vec<PackedTexture>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChannelManifest {
+    pub name: String,
+    pub image: Option<i32>, // image id in the library, None if the channel was never assigned
+    pub default_color: Option<[u8; 4]>, // rgba to fill the channel with when it's empty
+    // exports without an image for this channel fail validation
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default)]
+    pub color_space: ColorSpace,
+    // 8 or 16 bits per channel for the exported file, the image itself has 8
+    #[serde(default = "default_bit_depth")]
+    pub bit_depth: u8,
+}
+
+enum Word {
+    Value(i32),
+    Pointer(u32),
+    Str(u32, u32), // offset into the string area, byte length
+}
+
+// lay out the manifest of every material with a record, returns the table's offset
+pub fn write_manifest(memory: &mut Memory, tracker: &LibraryTracker) -> Result<u32, String> {
+    let mut table: Vec<Word> = vec![];
+    let mut strings: Vec<u8> = vec![];
+    let mut string = |s: &String| {
+        let word = Word::Str(strings.len() as u32, s.len() as u32);
+        strings.extend(s.as_bytes());
+        word
+    };
+
+    let materials = tracker.allocated_materials();
+    table.push(Word::Value(MANIFEST_VERSION));
+    table.push(Word::Value(materials.len() as i32));
+    for (name, offset, material_type) in &materials {
+        let definition = match tracker.material_info.get_material_type(material_type) {
+            Some(definition) => definition.clone(),
+            None => return Err(format!("{:?} is not a material type", material_type)),
+        };
+        let channels = &definition.channels;
+        let field = |path: &[&str]| match memory.field(*offset, path) {
+            Some(field) => Ok(Word::Pointer(field)),
+            None => Err(format!(
+                "material {:?} has no {:?} slot",
+                name,
+                path.join(".")
+            )),
+        };
+
+        table.push(string(name));
+        table.push(string(material_type));
+        table.push(field(&["width"])?);
+        table.push(field(&["height"])?);
+        table.push(Word::Value(channels.len() as i32));
+        for channel in channels {
+            table.push(string(&channel.name));
+            table.push(field(&["channels", &channel.name])?);
+            match channel.default_color {
+                Some(color) => {
+                    table.push(Word::Value(1));
+                    table.push(Word::Value(i32::from_le_bytes(color)));
+                }
+                None => {
+                    table.push(Word::Value(0));
+                    table.push(Word::Value(0));
+                }
+            }
+            table.push(Word::Value(channel.required as i32));
+            table.push(Word::Value(match channel.color_space {
+                ColorSpace::Linear => 0,
+                ColorSpace::Srgb => 1,
+            }));
+            table.push(Word::Value(channel.bit_depth as i32));
+        }
+        table.push(Word::Value(definition.packing.len() as i32));
+        for packed in &definition.packing {
+            table.push(string(&packed.name));
+            let mut inverted = 0;
+            for (i, source) in packed.sources().iter().enumerate() {
+                let index = source
+                    .as_ref()
+                    .and_then(|source| channels.iter().position(|c| &c.name == source));
+                table.push(Word::Value(index.map_or(-1, |i| i as i32)));
+                match source {
+                    Some(source) if index.is_some() && packed.inverted.contains(source) => {
+                        inverted |= 1 << i;
+                    }
+                    _ => {}
+                }
+            }
+            table.push(Word::Value(inverted));
+        }
+    }
+
+    let table_size: u32 = table
+        .iter()
+        .map(|word| match word {
+            Word::Str(_, _) => 2,
+            _ => 1,
+        })
+        .sum::<u32>()
+        * u32::pow(2, ALIGN);
+    let start = memory.alloc_data(
+        &MANIFEST_EXPORT_NAME.to_string(),
+        table_size + strings.len() as u32,
+    )?;
+
+    let mut bytes: Vec<u8> = vec![];
+    for word in table {
+        match word {
+            Word::Value(value) => bytes.extend(&value.to_le_bytes()),
+            Word::Pointer(pointer) => bytes.extend(&pointer.to_le_bytes()),
+            Word::Str(offset, length) => {
+                bytes.extend(&(start + table_size + offset).to_le_bytes());
+                bytes.extend(&length.to_le_bytes());
+            }
+        }
+    }
+    bytes.extend(strings);
+    memory.write_data(start, bytes);
+    Ok(start)
+}
+
+struct Reader<'a> {
+    memory: &'a [u8],
+    position: u32,
+}
+
+impl<'a> Reader<'a> {
+    // the bytes at start..start + length, words come from the module so they can be anything
+    fn bytes(&self, start: i32, length: i32) -> Result<&'a [u8], String> {
+        let range = match (u32::try_from(start), u32::try_from(length)) {
+            (Ok(start), Ok(length)) => start
+                .checked_add(length)
+                .map(|end| start as usize..end as usize),
+            _ => None,
+        };
+        match range.and_then(|range| self.memory.get(range)) {
+            Some(bytes) => Ok(bytes),
+            None => Err(format!(
+                "malformed manifest: {} bytes at {} are outside of memory",
+                length, start
+            )),
+        }
+    }
+
+    fn next(&mut self) -> Result<i32, String> {
+        let bytes = self.bytes(self.position as i32, 4)?;
+        self.position += u32::pow(2, ALIGN);
+        Ok(i32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn next_pointer(&mut self) -> Result<i32, String> {
+        let pointer = self.next()?;
+        let bytes = self.bytes(pointer, 4)?;
+        Ok(i32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn next_string(&mut self) -> Result<String, String> {
+        let start = self.next()?;
+        let length = self.next()?;
+        match String::from_utf8(self.bytes(start, length)?.to_vec()) {
+            Ok(string) => Ok(string),
+            Err(e) => Err(format!(
+                "malformed manifest: string at {} is not utf-8: {}",
+                start, e
+            )),
+        }
+    }
+}
+
+impl Manifest {
+    // read the manifest at `pointer` (the result of manifest()) once main() has run
+    pub fn decode(memory: &[u8], pointer: u32) -> Result<Manifest, String> {
+        let mut reader = Reader {
+            memory,
+            position: pointer,
+        };
+        let version = reader.next()?;
+        if version != MANIFEST_VERSION {
+            return Err(format!(
+                "manifest version {} is not supported, expected {}",
+                version, MANIFEST_VERSION
+            ));
+        }
+
+        let mut materials: Vec<MaterialManifest> = vec![];
+        for _ in 0..reader.next()? {
+            let name = reader.next_string()?;
+            let material_type = reader.next_string()?;
+            let width = reader.next_pointer()?;
+            let height = reader.next_pointer()?;
+            let mut channels: Vec<ChannelManifest> = vec![];
+            for _ in 0..reader.next()? {
+                let name = reader.next_string()?;
+                let image = match reader.next_pointer()? {
+                    UNASSIGNED => None,
+                    image => Some(image),
+                };
+                let default_color = match (reader.next()?, reader.next()?) {
+                    (0, _) => None,
+                    (_, color) => Some(color.to_le_bytes()),
+                };
+                let required = reader.next()? != 0;
+                let color_space = match reader.next()? {
+                    1 => ColorSpace::Srgb,
+                    _ => ColorSpace::Linear,
+                };
+                let bit_depth = match reader.next()? {
+                    16 => 16,
+                    _ => 8,
+                };
+                channels.push(ChannelManifest {
+                    name,
+                    image,
+                    default_color,
+                    required,
+                    color_space,
+                    bit_depth,
+                });
+            }
+            let mut packing: Vec<PackedTexture> = vec![];
+            for _ in 0..reader.next()? {
+                let name = reader.next_string()?;
+                let mut sources: Vec<Option<String>> = vec![];
+                for _ in 0..4 {
+                    let index = reader.next()?;
+                    sources.push(match channels.get(index as usize) {
+                        Some(channel) if index >= 0 => Some(channel.name.clone()),
+                        _ => None,
+                    });
+                }
+                let inverted_bits = reader.next()?;
+                let inverted: Vec<String> = sources
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| inverted_bits & (1 << i) != 0)
+                    .filter_map(|(_, source)| source.clone())
+                    .collect();
+                packing.push(PackedTexture {
+                    name,
+                    r: sources[0].clone(),
+                    g: sources[1].clone(),
+                    b: sources[2].clone(),
+                    a: sources[3].clone(),
+                    inverted,
+                });
+            }
+            materials.push(MaterialManifest {
+                name,
+                material_type,
+                width,
+                height,
+                channels,
+                packing,
+            });
+        }
+
+        Ok(Manifest { version, materials })
+    }
+}
+
+// memory: bytes of the exported "mem", pointer: the value returned by manifest()
+#[wasm_bindgen]
+pub fn decode_manifest(memory: Vec<u8>, pointer: u32) -> JsValue {
+    match Manifest::decode(&memory, pointer) {
+        Ok(manifest) => to_js_value(&manifest),
+        Err(e) => {
+            log(&format!("Error: {}", e));
+            JsValue::NULL
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::material_shape;
+    use crate::symbol::MaterialInfo;
+
+    fn set(memory: &mut [u8], offset: u32, value: i32) {
+        let offset = offset as usize;
+        memory[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    // the memory of a module whose main() made a 64x32 PBRMetalness with one image
+    fn run_main() -> (Vec<u8>, u32, Vec<String>) {
+        let mut module = walrus::Module::default();
+        let mut memory = Memory::new(&mut module);
+        let mut tracker = LibraryTracker::new();
+        let material_type = "PBRMetalness".to_string();
+        let channels = tracker
+            .material_info
+            .get_material_channels(&material_type)
+            .unwrap();
+        let name = "rock".to_string();
+        let offset = memory.alloc(&name, material_shape(&channels)).unwrap();
+        tracker.add_material(name, offset, material_type);
+        let pointer = write_manifest(&mut memory, &tracker).unwrap();
+        memory.finish(&mut module);
+
+        let mut bytes = vec![0; (memory.pages() * 65536) as usize];
+        for data in module.data.iter() {
+            if let walrus::DataKind::Active(walrus::ActiveData {
+                location: walrus::ActiveDataLocation::Absolute(start),
+                ..
+            }) = data.kind
+            {
+                let start = start as usize;
+                bytes[start..start + data.value.len()].copy_from_slice(&data.value);
+            }
+        }
+        let field = |path: &[&str]| memory.field(offset, path).unwrap();
+        set(&mut bytes, field(&["width"]), 64);
+        set(&mut bytes, field(&["height"]), 32);
+        for (i, channel) in channels.iter().enumerate() {
+            let image = if i == 0 { 3 } else { UNASSIGNED };
+            set(&mut bytes, field(&["channels", channel]), image);
+        }
+        (bytes, pointer, channels)
+    }
+
+    #[test]
+    fn decode_reads_what_write_manifest_wrote() {
+        let (memory, pointer, channels) = run_main();
+        let manifest = Manifest::decode(&memory, pointer).unwrap();
+        let definition = MaterialInfo::new()
+            .get_material_type(&"PBRMetalness".to_string())
+            .unwrap()
+            .clone();
+
+        assert_eq!(manifest.version, MANIFEST_VERSION);
+        assert_eq!(manifest.materials.len(), 1);
+        let material = &manifest.materials[0];
+        assert_eq!(material.name, "rock");
+        assert_eq!(material.material_type, "PBRMetalness");
+        assert_eq!((material.width, material.height), (64, 32));
+        assert_eq!(material.packing, definition.packing);
+        let names: Vec<String> = material.channels.iter().map(|c| c.name.clone()).collect();
+        assert_eq!(names, channels);
+        for (i, (channel, info)) in material
+            .channels
+            .iter()
+            .zip(&definition.channels)
+            .enumerate()
+        {
+            assert_eq!(channel.image, if i == 0 { Some(3) } else { None });
+            assert_eq!(channel.default_color, info.default_color);
+            assert_eq!(channel.required, info.required);
+            assert_eq!(channel.color_space, info.color_space);
+            assert_eq!(channel.bit_depth, info.bit_depth);
+        }
+    }
+
+    #[test]
+    fn decode_rejects_words_outside_of_memory() {
+        let (memory, pointer, _) = run_main();
+        // the name of the first material is the (pointer, length) pair after version and count
+        let name = pointer + 2 * 4;
+        for (start, length) in &[(-4, 4), (0, -1), (i32::MAX, i32::MAX), (0, i32::MAX)] {
+            let mut memory = memory.clone();
+            set(&mut memory, name, *start);
+            set(&mut memory, name + 4, *length);
+            let e = Manifest::decode(&memory, pointer).unwrap_err();
+            assert!(e.starts_with("malformed manifest"), "{}", e);
+        }
+        // the width pointer
+        let mut memory = memory.clone();
+        set(&mut memory, name + 4 * 4, -8);
+        assert!(Manifest::decode(&memory, pointer).is_err());
+        assert!(Manifest::decode(&[0; 2], 0).is_err());
+        assert!(Manifest::decode(&[0; 8], u32::MAX).is_err());
+    }
+}
@@ -0,0 +1,533 @@
+// image functions working on whole material channels, the math runs in linear space
+use super::image_functions::resize;
+use super::image_library::{ImageData, ImageLibrary};
+use super::log_rule;
+
+log_rule!();
+
+// reflectance of non-metals at normal incidence
+const DIELECTRIC_SPECULAR: f32 = 0.04;
+const EPSILON: f32 = 1e-6;
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u8 {
+    let value = value.max(0.0).min(1.0);
+    let value = if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+    (value * 255.0).round() as u8
+}
+
+fn to_u8(value: f32) -> u8 {
+    (value.max(0.0).min(1.0) * 255.0).round() as u8
+}
+
+fn perceived_brightness(color: [f32; 3]) -> f32 {
+    (0.299 * color[0] * color[0] + 0.587 * color[1] * color[1] + 0.114 * color[2] * color[2]).sqrt()
+}
+
+// metalness that reproduces a diffuse and specular brightness, see the glTF
+// KHR_materials_pbrSpecularGlossiness conversion
+fn solve_metallic(diffuse: f32, specular: f32, one_minus_specular_strength: f32) -> f32 {
+    if specular < DIELECTRIC_SPECULAR {
+        return 0.0;
+    }
+    let a = DIELECTRIC_SPECULAR;
+    let b = diffuse * one_minus_specular_strength / (1.0 - DIELECTRIC_SPECULAR) + specular
+        - 2.0 * DIELECTRIC_SPECULAR;
+    let c = DIELECTRIC_SPECULAR - specular;
+    let d = (b * b - 4.0 * a * c).max(0.0);
+    ((-b + d.sqrt()) / (2.0 * a)).max(0.0).min(1.0)
+}
+
+fn specular_glossiness_to_metal(albedo: [f32; 3], specular: [f32; 3]) -> ([f32; 3], f32) {
+    let one_minus_specular_strength = 1.0 - specular[0].max(specular[1]).max(specular[2]);
+    let metallic = solve_metallic(
+        perceived_brightness(albedo),
+        perceived_brightness(specular),
+        one_minus_specular_strength,
+    );
+
+    let mut base_color = [0.0; 3];
+    for i in 0..3 {
+        let from_diffuse = albedo[i] * one_minus_specular_strength
+            / (1.0 - DIELECTRIC_SPECULAR)
+            / (1.0 - metallic).max(EPSILON);
+        let from_specular =
+            (specular[i] - DIELECTRIC_SPECULAR * (1.0 - metallic)) / metallic.max(EPSILON);
+        let t = metallic * metallic;
+        base_color[i] = (from_diffuse + (from_specular - from_diffuse) * t)
+            .max(0.0)
+            .min(1.0);
+    }
+    (base_color, metallic)
+}
+
+// a second, optional input of the size of the first one
+fn matching_image(
+    library: &mut ImageLibrary,
+    image_id: i32,
+    like: &ImageData,
+) -> Option<ImageData> {
+    library.get_image_data(image_id)?;
+    let image_id = resize(library, image_id, like.width, like.height);
+    library.get_image_data(image_id).cloned()
+}
+
+fn linear_rgb(pixels: &[u8], i: usize) -> [f32; 3] {
+    [
+        srgb_to_linear(pixels[i]),
+        srgb_to_linear(pixels[i + 1]),
+        srgb_to_linear(pixels[i + 2]),
+    ]
+}
+
+// combine an image with an optional second one pixel by pixel
+fn combine<F>(library: &mut ImageLibrary, function: &str, image_id: i32, other_id: i32, f: F) -> i32
+where
+    F: Fn(&[u8], Option<&[u8]>) -> [u8; 4],
+{
+    let image_data = match library.get_image_data(image_id) {
+        Some(image_data) => image_data.clone(),
+        None => {
+            log(&format!(
+                "Error: image {:?} passed to {:?} doesn't exist.",
+                image_id, function
+            ));
+            return -1;
+        }
+    };
+    let other = matching_image(library, other_id, &image_data);
+
+    let mut pixels: Vec<u8> = vec![0; image_data.pixels.len()];
+    for i in (0..pixels.len()).step_by(4) {
+        let other_pixel = other.as_ref().map(|other| &other.pixels[i..i + 4]);
+        pixels[i..i + 4].copy_from_slice(&f(&image_data.pixels[i..i + 4], other_pixel));
+    }
+    library.add_image("".to_string(), image_data.width, image_data.height, pixels)
+}
+
+pub fn specular_to_base_color(library: &mut ImageLibrary, albedo: i32, specular: i32) -> i32 {
+    combine(
+        library,
+        "specular_to_base_color",
+        albedo,
+        specular,
+        |a, s| {
+            let specular = s.map_or([DIELECTRIC_SPECULAR; 3], |s| linear_rgb(s, 0));
+            let (base_color, _) = specular_glossiness_to_metal(linear_rgb(a, 0), specular);
+            [
+                linear_to_srgb(base_color[0]),
+                linear_to_srgb(base_color[1]),
+                linear_to_srgb(base_color[2]),
+                a[3],
+            ]
+        },
+    )
+}
+
+pub fn specular_to_metalness(library: &mut ImageLibrary, albedo: i32, specular: i32) -> i32 {
+    combine(
+        library,
+        "specular_to_metalness",
+        albedo,
+        specular,
+        |a, s| {
+            let specular = s.map_or([DIELECTRIC_SPECULAR; 3], |s| linear_rgb(s, 0));
+            let (_, metallic) = specular_glossiness_to_metal(linear_rgb(a, 0), specular);
+            let value = to_u8(metallic);
+            [value, value, value, 255]
+        },
+    )
+}
+
+pub fn metalness_to_albedo(library: &mut ImageLibrary, base_color: i32, metalness: i32) -> i32 {
+    combine(
+        library,
+        "metalness_to_albedo",
+        base_color,
+        metalness,
+        |b, m| {
+            let metallic = m.map_or(0.0, |m| m[0] as f32 / 255.0);
+            let base_color = linear_rgb(b, 0);
+            let albedo =
+                |c: f32| linear_to_srgb(c * (1.0 - DIELECTRIC_SPECULAR) * (1.0 - metallic));
+            [
+                albedo(base_color[0]),
+                albedo(base_color[1]),
+                albedo(base_color[2]),
+                b[3],
+            ]
+        },
+    )
+}
+
+pub fn metalness_to_specular(library: &mut ImageLibrary, base_color: i32, metalness: i32) -> i32 {
+    combine(
+        library,
+        "metalness_to_specular",
+        base_color,
+        metalness,
+        |b, m| {
+            let metallic = m.map_or(0.0, |m| m[0] as f32 / 255.0);
+            let base_color = linear_rgb(b, 0);
+            let specular =
+                |c: f32| linear_to_srgb(DIELECTRIC_SPECULAR + (c - DIELECTRIC_SPECULAR) * metallic);
+            [
+                specular(base_color[0]),
+                specular(base_color[1]),
+                specular(base_color[2]),
+                255,
+            ]
+        },
+    )
+}
+
+fn luminance(pixel: &[u8]) -> f32 {
+    (0.299 * pixel[0] as f32 + 0.587 * pixel[1] as f32 + 0.114 * pixel[2] as f32) / 255.0
+}
+
+fn lerp(a: u8, b: u8, t: f32) -> u8 {
+    (a as f32 + (b as f32 - a as f32) * t).round() as u8
+}
+
+fn decode_normal(pixel: &[u8]) -> [f32; 3] {
+    [
+        pixel[0] as f32 / 255.0 * 2.0 - 1.0,
+        pixel[1] as f32 / 255.0 * 2.0 - 1.0,
+        pixel[2] as f32 / 255.0 * 2.0 - 1.0,
+    ]
+}
+
+fn encode_normal(normal: [f32; 3], alpha: u8) -> [u8; 4] {
+    let length = (normal[0] * normal[0] + normal[1] * normal[1] + normal[2] * normal[2])
+        .sqrt()
+        .max(EPSILON);
+    let encode = |c: f32| to_u8((c / length + 1.0) / 2.0);
+    [
+        encode(normal[0]),
+        encode(normal[1]),
+        encode(normal[2]),
+        alpha,
+    ]
+}
+
+// blend an image over another one weighted by the brightness of a mask, an empty mask mixes
+// them evenly, when only one of the images exists it is returned as it is
+fn blend_with_mask<F>(
+    library: &mut ImageLibrary,
+    function: &str,
+    image_id: i32,
+    other_id: i32,
+    mask_id: i32,
+    f: F,
+) -> i32
+where
+    F: Fn(&[u8], &[u8], f32) -> [u8; 4],
+{
+    let image_data = match (
+        library.get_image_data(image_id),
+        library.get_image_data(other_id),
+    ) {
+        (Some(image_data), Some(_)) => image_data.clone(),
+        (Some(_), None) => return image_id,
+        (None, Some(_)) => return other_id,
+        (None, None) => {
+            log(&format!(
+                "Error: images {:?} and {:?} passed to {:?} don't exist.",
+                image_id, other_id, function
+            ));
+            return -1;
+        }
+    };
+    let other = matching_image(library, other_id, &image_data).unwrap();
+    let mask = matching_image(library, mask_id, &image_data);
+
+    let mut pixels: Vec<u8> = vec![0; image_data.pixels.len()];
+    for i in (0..pixels.len()).step_by(4) {
+        let t = mask
+            .as_ref()
+            .map_or(0.5, |mask| luminance(&mask.pixels[i..i + 4]));
+        pixels[i..i + 4].copy_from_slice(&f(
+            &image_data.pixels[i..i + 4],
+            &other.pixels[i..i + 4],
+            t,
+        ));
+    }
+    library.add_image("".to_string(), image_data.width, image_data.height, pixels)
+}
+
+pub fn blend_channel(library: &mut ImageLibrary, image_1: i32, image_2: i32, mask: i32) -> i32 {
+    blend_with_mask(
+        library,
+        "blend_channel",
+        image_1,
+        image_2,
+        mask,
+        |a, b, t| {
+            [
+                lerp(a[0], b[0], t),
+                lerp(a[1], b[1], t),
+                lerp(a[2], b[2], t),
+                lerp(a[3], b[3], t),
+            ]
+        },
+    )
+}
+
+// blend towards the darker of both images, for occlusion
+pub fn blend_min(library: &mut ImageLibrary, image_1: i32, image_2: i32, mask: i32) -> i32 {
+    blend_with_mask(library, "blend_min", image_1, image_2, mask, |a, b, t| {
+        [
+            lerp(a[0], a[0].min(b[0]), t),
+            lerp(a[1], a[1].min(b[1]), t),
+            lerp(a[2], a[2].min(b[2]), t),
+            a[3],
+        ]
+    })
+}
+
+// blend towards the brighter of both images, for heights
+pub fn blend_max(library: &mut ImageLibrary, image_1: i32, image_2: i32, mask: i32) -> i32 {
+    blend_with_mask(library, "blend_max", image_1, image_2, mask, |a, b, t| {
+        [
+            lerp(a[0], a[0].max(b[0]), t),
+            lerp(a[1], a[1].max(b[1]), t),
+            lerp(a[2], a[2].max(b[2]), t),
+            a[3],
+        ]
+    })
+}
+
+// interpolate two normal maps and renormalize, for switching between surfaces
+pub fn blend_normal(library: &mut ImageLibrary, normal_1: i32, normal_2: i32, mask: i32) -> i32 {
+    blend_with_mask(
+        library,
+        "blend_normal",
+        normal_1,
+        normal_2,
+        mask,
+        |a, b, t| {
+            let (n1, n2) = (decode_normal(a), decode_normal(b));
+            encode_normal(
+                [
+                    n1[0] + (n2[0] - n1[0]) * t,
+                    n1[1] + (n2[1] - n1[1]) * t,
+                    n1[2] + (n2[2] - n1[2]) * t,
+                ],
+                a[3],
+            )
+        },
+    )
+}
+
+// reoriented normal blending of a detail normal map over a base one, the mask fades the detail
+// towards a flat normal, see https://blog.selfshadow.com/publications/blending-in-detail/
+pub fn layer_normal(library: &mut ImageLibrary, base: i32, detail: i32, mask: i32) -> i32 {
+    blend_with_mask(library, "layer_normal", base, detail, mask, |a, b, t| {
+        let base = decode_normal(a);
+        let detail = decode_normal(b);
+        let detail = [detail[0] * t, detail[1] * t, 1.0 + (detail[2] - 1.0) * t];
+        let length = (detail[0] * detail[0] + detail[1] * detail[1] + detail[2] * detail[2])
+            .sqrt()
+            .max(EPSILON);
+        let detail = [-detail[0] / length, -detail[1] / length, detail[2] / length];
+        let base = [base[0], base[1], base[2] + 1.0];
+        let d = base[0] * detail[0] + base[1] * detail[1] + base[2] * detail[2];
+        let z = base[2].max(EPSILON);
+        encode_normal(
+            [
+                base[0] * d / z - detail[0],
+                base[1] * d / z - detail[1],
+                base[2] * d / z - detail[2],
+            ],
+            a[3],
+        )
+    })
+}
+
+// mask choosing the higher of two surfaces, contrast sharpens the transition
+pub fn height_mask(library: &mut ImageLibrary, height_1: i32, height_2: i32, contrast: i32) -> i32 {
+    combine(library, "height_mask", height_1, height_2, |h1, h2| {
+        let h2 = h2.map_or(0.5, luminance);
+        let value = to_u8(0.5 + (h2 - luminance(h1)) * contrast.max(1) as f32);
+        [value, value, value, 255]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid(library: &mut ImageLibrary, color: [u8; 4]) -> i32 {
+        library.add_image("".to_string(), 2, 2, color.repeat(4))
+    }
+
+    fn pixel(library: &ImageLibrary, image_id: i32) -> [u8; 4] {
+        let pixels = &library.get_image_data(image_id).unwrap().pixels;
+        [pixels[0], pixels[1], pixels[2], pixels[3]]
+    }
+
+    // 8 bit values can be off by one after a round trip through floats
+    fn near(a: [u8; 4], b: [u8; 4]) -> bool {
+        a.iter()
+            .zip(&b)
+            .all(|(a, b)| (*a as i32 - *b as i32).abs() <= 1)
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    #[test]
+    fn srgb_round_trips() {
+        for value in 0..=255 {
+            assert_eq!(linear_to_srgb(srgb_to_linear(value)), value);
+        }
+    }
+
+    #[test]
+    fn dielectric_specular_keeps_the_albedo() {
+        let albedo = [0.5, 0.2, 0.1];
+        let (base_color, metallic) = specular_glossiness_to_metal(albedo, [DIELECTRIC_SPECULAR; 3]);
+        assert_eq!(metallic, 0.0);
+        for i in 0..3 {
+            assert!(close(base_color[i], albedo[i]), "{:?}", base_color);
+        }
+    }
+
+    #[test]
+    fn metal_specular_becomes_the_base_color() {
+        let specular = [0.9, 0.6, 0.3];
+        let (base_color, metallic) = specular_glossiness_to_metal([0.0; 3], specular);
+        assert!(metallic > 0.99, "{}", metallic);
+        for i in 0..3 {
+            assert!(
+                (base_color[i] - specular[i]).abs() < 0.02,
+                "{:?}",
+                base_color
+            );
+        }
+    }
+
+    #[test]
+    fn metalness_splits_the_base_color() {
+        let mut library = ImageLibrary::new();
+        let base_color = solid(&mut library, [200, 100, 50, 255]);
+        let dielectric = solid(&mut library, [0, 0, 0, 255]);
+        let metal = solid(&mut library, [255, 255, 255, 255]);
+
+        // non-metals reflect 4%, srgb 56, and keep 96% of their color as albedo
+        let specular = metalness_to_specular(&mut library, base_color, dielectric);
+        assert_eq!(pixel(&library, specular), [56, 56, 56, 255]);
+        let albedo = metalness_to_albedo(&mut library, base_color, dielectric);
+        let expected = |c: u8| linear_to_srgb(srgb_to_linear(c) * (1.0 - DIELECTRIC_SPECULAR));
+        assert_eq!(
+            pixel(&library, albedo),
+            [expected(200), expected(100), expected(50), 255]
+        );
+
+        // metals have no albedo and reflect their base color
+        let specular = metalness_to_specular(&mut library, base_color, metal);
+        assert_eq!(pixel(&library, specular), [200, 100, 50, 255]);
+        let albedo = metalness_to_albedo(&mut library, base_color, metal);
+        assert_eq!(pixel(&library, albedo), [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn specular_round_trips_through_metalness() {
+        let mut library = ImageLibrary::new();
+        let albedo = solid(&mut library, [0, 0, 0, 255]);
+        let specular = solid(&mut library, [230, 230, 230, 255]);
+        let base_color = specular_to_base_color(&mut library, albedo, specular);
+        let metalness = specular_to_metalness(&mut library, albedo, specular);
+        assert_eq!(pixel(&library, metalness), [255, 255, 255, 255]);
+        let back = metalness_to_specular(&mut library, base_color, metalness);
+        assert!(near(pixel(&library, back), [230, 230, 230, 255]));
+
+        // without a specular map the albedo is taken as a dielectric
+        let albedo = solid(&mut library, [120, 80, 40, 255]);
+        let metalness = specular_to_metalness(&mut library, albedo, -1);
+        assert_eq!(pixel(&library, metalness), [0, 0, 0, 255]);
+        assert_eq!(specular_to_base_color(&mut library, -1, specular), -1);
+    }
+
+    #[test]
+    fn masks_weight_the_second_image() {
+        let mut library = ImageLibrary::new();
+        let dark = solid(&mut library, [0, 0, 0, 255]);
+        let light = solid(&mut library, [200, 100, 50, 255]);
+        let black = solid(&mut library, [0, 0, 0, 255]);
+        let white = solid(&mut library, [255, 255, 255, 255]);
+
+        let blended = blend_channel(&mut library, dark, light, black);
+        assert_eq!(pixel(&library, blended), [0, 0, 0, 255]);
+        let blended = blend_channel(&mut library, dark, light, white);
+        assert_eq!(pixel(&library, blended), [200, 100, 50, 255]);
+        let blended = blend_channel(&mut library, dark, light, -1);
+        assert_eq!(pixel(&library, blended), [100, 50, 25, 255]);
+
+        // occlusion only gets darker and heights only higher
+        let blended = blend_min(&mut library, light, dark, white);
+        assert_eq!(pixel(&library, blended), [0, 0, 0, 255]);
+        let blended = blend_max(&mut library, dark, light, white);
+        assert_eq!(pixel(&library, blended), [200, 100, 50, 255]);
+        let blended = blend_max(&mut library, light, dark, white);
+        assert_eq!(pixel(&library, blended), [200, 100, 50, 255]);
+    }
+
+    #[test]
+    fn missing_images_keep_the_other_one() {
+        let mut library = ImageLibrary::new();
+        let image = solid(&mut library, [10, 20, 30, 255]);
+        assert_eq!(blend_channel(&mut library, image, -1, -1), image);
+        assert_eq!(blend_normal(&mut library, -1, image, -1), image);
+        assert_eq!(layer_normal(&mut library, -1, -1, -1), -1);
+    }
+
+    #[test]
+    fn normals_stay_normalized() {
+        let mut library = ImageLibrary::new();
+        let flat = solid(&mut library, [128, 128, 255, 255]);
+        let tilted = solid(&mut library, [255, 128, 128, 255]);
+        let black = solid(&mut library, [0, 0, 0, 255]);
+        let white = solid(&mut library, [255, 255, 255, 255]);
+
+        let blended = blend_normal(&mut library, flat, tilted, -1);
+        let normal = decode_normal(&pixel(&library, blended));
+        let length = (normal[0] * normal[0] + normal[1] * normal[1] + normal[2] * normal[2]).sqrt();
+        assert!((length - 1.0).abs() < 0.02, "{:?}", normal);
+        assert!((normal[0] - normal[2]).abs() < 0.02, "{:?}", normal);
+
+        // a flat base shows the detail, a black mask hides it
+        let layered = layer_normal(&mut library, flat, tilted, white);
+        assert!(near(pixel(&library, layered), [255, 128, 128, 255]));
+        let layered = layer_normal(&mut library, tilted, flat, white);
+        assert!(near(pixel(&library, layered), [255, 128, 128, 255]));
+        let layered = layer_normal(&mut library, tilted, tilted, black);
+        assert!(near(pixel(&library, layered), [255, 128, 128, 255]));
+    }
+
+    #[test]
+    fn height_masks_pick_the_higher_surface() {
+        let mut library = ImageLibrary::new();
+        let low = solid(&mut library, [50, 50, 50, 255]);
+        let high = solid(&mut library, [200, 200, 200, 255]);
+        let mask = height_mask(&mut library, low, high, 8);
+        assert_eq!(pixel(&library, mask), [255, 255, 255, 255]);
+        let mask = height_mask(&mut library, high, low, 8);
+        assert_eq!(pixel(&library, mask), [0, 0, 0, 255]);
+        let mask = height_mask(&mut library, low, low, 8);
+        assert_eq!(pixel(&library, mask), [128, 128, 128, 255]);
+    }
+}
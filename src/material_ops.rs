@@ -0,0 +1,656 @@
+// operations on whole materials, compiled into per-channel calls of library functions
+use super::ast::{Compile, Expr};
+use super::compiler::{material_shape, Memory, MemoryValue, ALIGN};
+use super::symbol::{Attribute, SymbolTable};
+use walrus::ir::BinaryOp;
+use walrus::{FunctionId, InstrSeqBuilder, LocalId, ValType};
+
+pub const MATERIAL_OPERATIONS: [&str; 4] = [
+    "convert_material",
+    "blend_material",
+    "height_blend",
+    "layer_material",
+];
+
+// value of a channel slot nothing was assigned to
+pub const UNASSIGNED: i32 = i32::MAX;
+
+// the usage of an operation when it's called with the wrong number of arguments
+pub fn check_arguments(operation: &str, params: &[Box<Expr>]) -> Result<(), String> {
+    let (usage, arguments) = match operation {
+        "convert_material" => ("convert_material(material, material type)", 2),
+        "blend_material" => ("blend_material(material, material, mask)", 3),
+        "height_blend" => (
+            "height_blend(material, material, height, height, contrast)",
+            5,
+        ),
+        _ => ("layer_material(base material, top material, mask)", 3),
+    };
+    if params.len() != arguments {
+        return Err(format!("Please call {}", usage));
+    }
+    Ok(())
+}
+
+// the variable a material argument names, the backends look it up themselves
+pub fn material_variable(expr: &Expr) -> Result<&String, String> {
+    match expr {
+        Expr::Variable(identifier) => Ok(identifier),
+        _ => Err(format!("{:?} should be a material variable", expr)),
+    }
+}
+
+// expressions an image or number argument may be, variables have to be checked by the caller
+pub fn check_value(expr: &Expr) -> Result<(), String> {
+    match expr {
+        Expr::Variable(_) | Expr::Number(_) | Expr::Call(_, _) | Expr::Op(_, _, _) => Ok(()),
+        _ => Err(format!("{:?} should be an image or a number", expr)),
+    }
+}
+
+pub fn string_arg(expr: &Expr) -> Result<String, String> {
+    match expr {
+        Expr::Str(s) => Ok(s[1..s.len() - 1].to_string()),
+        _ => Err(format!("{:?} should be a string", expr)),
+    }
+}
+
+struct MaterialArg {
+    offset: u32,
+    material_type: String,
+    channels: Vec<String>,
+}
+
+impl MaterialArg {
+    fn channel(&self, memories: &Memory, channel: &str) -> Result<u32, String> {
+        match memories.field(self.offset, &["channels", channel]) {
+            Some(offset) => Ok(offset),
+            None => Err(format!(
+                "material type {:?} has no channel {:?}",
+                self.material_type, channel
+            )),
+        }
+    }
+}
+
+fn material_arg(
+    symbol_table: &SymbolTable,
+    memories: &Memory,
+    expr: &Expr,
+) -> Result<MaterialArg, String> {
+    let identifier = material_variable(expr)?;
+    match symbol_table.lookup(identifier) {
+        Some(Attribute::Material(_, offset, material_type))
+            if memories.shape(*offset).is_some() =>
+        {
+            let channels = symbol_table
+                .library_tracker
+                .material_info
+                .get_material_channels(material_type)
+                .unwrap_or(vec![]);
+            Ok(MaterialArg {
+                offset: *offset,
+                material_type: material_type.clone(),
+                channels,
+            })
+        }
+        Some(Attribute::Material(_, _, _)) => Err(format!(
+            "Please call new_material() on {:?} before using it",
+            identifier
+        )),
+        _ => Err(format!("{:?} is not a material", identifier)),
+    }
+}
+
+// evaluate an image or number argument once into a local
+fn value_arg(
+    module: &mut walrus::Module,
+    builder: &mut InstrSeqBuilder,
+    symbol_table: &mut SymbolTable,
+    memories: &mut Memory,
+    expr: &Expr,
+) -> Result<LocalId, String> {
+    check_value(expr)?;
+    if let Expr::Variable(identifier) = expr {
+        match symbol_table.lookup(identifier) {
+            Some(Attribute::Image(_, _)) | Some(Attribute::Number(_)) => {}
+            _ => return Err(format!("{:?} should be an image or a number", identifier)),
+        }
+    }
+    expr.compile(module, builder, symbol_table, memories)?;
+    let local_id = module.locals.add(ValType::I32);
+    builder.local_set(local_id);
+    Ok(local_id)
+}
+
+fn function(symbol_table: &SymbolTable, name: &str) -> Result<FunctionId, String> {
+    match symbol_table.lookup(&name.to_string()) {
+        Some(Attribute::Func(function_id, _, _)) => Ok(*function_id),
+        _ => Err(format!("library function {:?} is not available", name)),
+    }
+}
+
+// store the i32 on top of the stack at the address below it
+fn store(builder: &mut InstrSeqBuilder, memories: &Memory) {
+    builder.store(
+        memories.id,
+        walrus::ir::StoreKind::I32 { atomic: false },
+        walrus::ir::MemArg {
+            align: ALIGN,
+            offset: 0,
+        },
+    );
+}
+
+// run `body` only when one of the image slots holds an image
+fn if_assigned<F>(builder: &mut InstrSeqBuilder, memories: &mut Memory, slots: &[u32], body: F)
+where
+    F: FnOnce(&mut InstrSeqBuilder, &mut Memory),
+{
+    for (i, slot) in slots.iter().enumerate() {
+        memories.load(builder, *slot);
+        builder.i32_const(UNASSIGNED);
+        builder.binop(BinaryOp::I32Ne);
+        if i > 0 {
+            builder.binop(BinaryOp::I32Or);
+        }
+    }
+    builder.if_else(None, |then| body(then, memories), |_| {});
+}
+
+// give `identifier` a new empty record of `material_type` at the resolution of `size_of`
+fn new_result(
+    builder: &mut InstrSeqBuilder,
+    symbol_table: &mut SymbolTable,
+    memories: &mut Memory,
+    identifier: &String,
+    material_type: &String,
+    size_of: &MaterialArg,
+) -> Result<(u32, Vec<String>), String> {
+    let material_info = &symbol_table.library_tracker.material_info;
+    let (material_type_id, channels) = match (
+        material_info.get_material_type_id(material_type),
+        material_info.get_material_channels(material_type),
+    ) {
+        (Ok(id), Ok(channels)) => (id, channels),
+        _ => return Err(format!("{:?} is not a material type.", material_type)),
+    };
+
+    // the old record is released by the caller once the operands have been read
+    let offset = memories.alloc(identifier, material_shape(&channels))?;
+    if symbol_table
+        .update(
+            identifier,
+            Attribute::Material(memories.id, offset, material_type.clone()),
+        )
+        .is_err()
+    {
+        return Err(format!("{:?} is not defined", identifier));
+    }
+
+    memories.store(builder, offset, vec![MemoryValue::i32(material_type_id)]);
+    let size_offset = memories.field(offset, &["width"]).unwrap();
+    let source_size_offset = memories.field(size_of.offset, &["width"]).unwrap();
+    memories.copy(builder, size_offset, source_size_offset, 2);
+    let channels_offset = memories.field(offset, &["channels"]).unwrap();
+    memories.store(
+        builder,
+        channels_offset,
+        vec![MemoryValue::i32(UNASSIGNED); channels.len()],
+    );
+    Ok((offset, channels))
+}
+
+// `identifier = operation(params)`, `old_offset` is the record identifier had before
+pub fn compile_operation(
+    module: &mut walrus::Module,
+    builder: &mut InstrSeqBuilder,
+    symbol_table: &mut SymbolTable,
+    memories: &mut Memory,
+    identifier: &String,
+    old_offset: u32,
+    operation: &str,
+    params: &Vec<Box<Expr>>,
+) -> Result<(), String> {
+    match operation {
+        "convert_material" => {
+            convert_material(builder, symbol_table, memories, identifier, params)?
+        }
+        "blend_material" | "height_blend" | "layer_material" => blend_material(
+            module,
+            builder,
+            symbol_table,
+            memories,
+            identifier,
+            operation,
+            params,
+        )?,
+        _ => return Err(format!("{:?} is not a material operation", operation)),
+    }
+    if memories.shape(old_offset).is_some() {
+        memories.free(old_offset);
+    }
+    Ok(())
+}
+
+// a channel of the target type computed by a library function from channels of the source type,
+// the first argument has to be assigned, the others may be empty
+#[derive(Clone, Copy)]
+pub struct Derivation {
+    pub channel: &'static str,
+    pub function: &'static str,
+    pub args: &'static [&'static str],
+}
+
+pub fn conversion(from: &str, to: &str) -> Option<Vec<Derivation>> {
+    match (from, to) {
+        _ if from == to => Some(vec![]),
+        ("PBRSpecular", "PBRMetalness") => Some(vec![
+            Derivation {
+                channel: "diffuse",
+                function: "specular_to_base_color",
+                args: &["albedo", "specular"],
+            },
+            Derivation {
+                channel: "metalness",
+                function: "specular_to_metalness",
+                args: &["albedo", "specular"],
+            },
+            Derivation {
+                channel: "roughness",
+                function: "invert_color",
+                args: &["glosiness"],
+            },
+        ]),
+        ("PBRMetalness", "PBRSpecular") => Some(vec![
+            Derivation {
+                channel: "albedo",
+                function: "metalness_to_albedo",
+                args: &["diffuse", "metalness"],
+            },
+            Derivation {
+                channel: "specular",
+                function: "metalness_to_specular",
+                args: &["diffuse", "metalness"],
+            },
+            Derivation {
+                channel: "glosiness",
+                function: "invert_color",
+                args: &["roughness"],
+            },
+        ]),
+        _ => None,
+    }
+}
+
+// the derivations of a conversion the built-in types support
+pub fn derivations(from: &str, to: &str) -> Result<Vec<Derivation>, String> {
+    match conversion(from, to) {
+        Some(derivations) => Ok(derivations),
+        None => Err(format!(
+            "{:?} materials can't be converted to {:?}",
+            from, to
+        )),
+    }
+}
+
+// where convert_material gets a channel of the target type from
+pub enum ChannelSource {
+    Derived(Derivation),
+    Copied,
+    Empty,
+}
+
+pub fn channel_source(
+    derivations: &[Derivation],
+    source_channels: &[String],
+    channel: &str,
+) -> ChannelSource {
+    match derivations.iter().find(|d| d.channel == channel) {
+        Some(derivation) => ChannelSource::Derived(*derivation),
+        None if source_channels.iter().any(|c| c == channel) => ChannelSource::Copied,
+        None => ChannelSource::Empty,
+    }
+}
+
+// convert_material(material, "type"): derive the channels of another workflow and copy the shared ones
+fn convert_material(
+    builder: &mut InstrSeqBuilder,
+    symbol_table: &mut SymbolTable,
+    memories: &mut Memory,
+    identifier: &String,
+    params: &Vec<Box<Expr>>,
+) -> Result<(), String> {
+    check_arguments("convert_material", params)?;
+    let source = material_arg(symbol_table, memories, &params[0])?;
+    let target_type = string_arg(&params[1])?;
+    let derivations = derivations(&source.material_type, &target_type)?;
+
+    let (offset, channels) = new_result(
+        builder,
+        symbol_table,
+        memories,
+        identifier,
+        &target_type,
+        &source,
+    )?;
+    for channel in &channels {
+        let target = memories.field(offset, &["channels", channel]).unwrap();
+        match channel_source(&derivations, &source.channels, channel) {
+            ChannelSource::Derived(derivation) => {
+                let function_id = function(symbol_table, derivation.function)?;
+                let mut args: Vec<u32> = vec![];
+                for arg in derivation.args {
+                    args.push(source.channel(memories, arg)?);
+                }
+                if_assigned(builder, memories, &args[..1], |builder, memories| {
+                    builder.i32_const(target as i32);
+                    for arg in &args {
+                        memories.load(builder, *arg);
+                    }
+                    builder.call(function_id);
+                    store(builder, memories);
+                });
+            }
+            ChannelSource::Copied => {
+                let slot = source.channel(memories, channel)?;
+                memories.copy(builder, target, slot, 1);
+            }
+            ChannelSource::Empty => {}
+        }
+    }
+    Ok(())
+}
+
+// library function blending a channel, by the meaning its name has in the built-in types:
+// normals are reoriented, occlusion keeps the darker one and layering keeps the higher surface
+pub fn channel_blend(operation: &str, channel: &str) -> &'static str {
+    match (operation, channel) {
+        (_, "normal") => "layer_normal",
+        (_, "ao") | (_, "cavity") => "blend_min",
+        ("layer_material", "displacement") | ("layer_material", "height") => "blend_max",
+        _ => "blend_channel",
+    }
+}
+
+pub fn check_blend_types(operation: &str, a: &str, b: &str) -> Result<(), String> {
+    if a != b {
+        return Err(format!(
+            "{} needs two materials of the same type instead of {:?} and {:?}",
+            operation, a, b
+        ));
+    }
+    Ok(())
+}
+
+// blend_material(a, b, mask), height_blend(a, b, height_a, height_b, contrast) and
+// layer_material(base, top, mask): blend every channel of two materials of the same type
+fn blend_material(
+    module: &mut walrus::Module,
+    builder: &mut InstrSeqBuilder,
+    symbol_table: &mut SymbolTable,
+    memories: &mut Memory,
+    identifier: &String,
+    operation: &str,
+    params: &Vec<Box<Expr>>,
+) -> Result<(), String> {
+    check_arguments(operation, params)?;
+    let a = material_arg(symbol_table, memories, &params[0])?;
+    let b = material_arg(symbol_table, memories, &params[1])?;
+    check_blend_types(operation, &a.material_type, &b.material_type)?;
+
+    let mask = if operation == "height_blend" {
+        let height_a = value_arg(module, builder, symbol_table, memories, &params[2])?;
+        let height_b = value_arg(module, builder, symbol_table, memories, &params[3])?;
+        let contrast = value_arg(module, builder, symbol_table, memories, &params[4])?;
+        builder.local_get(height_a);
+        builder.local_get(height_b);
+        builder.local_get(contrast);
+        builder.call(function(symbol_table, "height_mask")?);
+        let mask = module.locals.add(ValType::I32);
+        builder.local_set(mask);
+        mask
+    } else {
+        value_arg(module, builder, symbol_table, memories, &params[2])?
+    };
+
+    let material_type = a.material_type.clone();
+    let (offset, channels) = new_result(
+        builder,
+        symbol_table,
+        memories,
+        identifier,
+        &material_type,
+        &a,
+    )?;
+    for channel in &channels {
+        let target = memories.field(offset, &["channels", channel]).unwrap();
+        let function_id = function(symbol_table, channel_blend(operation, channel))?;
+        let slots = [a.channel(memories, channel)?, b.channel(memories, channel)?];
+        if_assigned(builder, memories, &slots, |builder, memories| {
+            builder.i32_const(target as i32);
+            memories.load(builder, slots[0]);
+            memories.load(builder, slots[1]);
+            builder.local_get(mask);
+            builder.call(function_id);
+            store(builder, memories);
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Inputs;
+    use crate::export::ExportOptions;
+    use crate::image_library::{ImageData, ImageLibrary};
+    use crate::material_functions::layer_normal;
+    use crate::registry::find_library_function;
+    use crate::session::Session;
+    use crate::symbol::MaterialInfo;
+    use std::collections::HashMap;
+
+    type Materials = HashMap<String, HashMap<String, ImageData>>;
+
+    // the assigned channels of every material, the compiled module has to agree with the
+    // interpreter
+    fn run(src: &str) -> Materials {
+        let inputs = Inputs { images: vec![] };
+        let options = ExportOptions {
+            fill_defaults: false,
+            pack_channels: false,
+        };
+        let interpreted = Session::new()
+            .interpret(src, &inputs, &options)
+            .unwrap()
+            .materials;
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        {
+            let module = crate::compiler::Compiler::compile(src, &inputs).unwrap();
+            let compiled = Session::new().run(&module, &options).unwrap().materials;
+            assert_eq!(compiled.len(), interpreted.len());
+            for (name, channels) in &compiled {
+                let other = &interpreted[name];
+                assert_eq!(channels.len(), other.len(), "{}", name);
+                for (channel, image) in channels {
+                    assert_eq!(image.pixels, other[channel].pixels, "{}.{}", name, channel);
+                }
+            }
+        }
+        interpreted
+    }
+
+    fn pixel(materials: &Materials, material: &str, channel: &str) -> [u8; 4] {
+        let pixels = &materials[material][channel].pixels;
+        [pixels[0], pixels[1], pixels[2], pixels[3]]
+    }
+
+    #[test]
+    fn conversions_only_use_channels_of_both_types() {
+        let info = MaterialInfo::new();
+        for (from, to) in &[
+            ("PBRSpecular", "PBRMetalness"),
+            ("PBRMetalness", "PBRSpecular"),
+        ] {
+            let from_channels = info.get_material_channels(&from.to_string()).unwrap();
+            let to_channels = info.get_material_channels(&to.to_string()).unwrap();
+            for derivation in conversion(from, to).unwrap() {
+                assert!(to_channels.contains(&derivation.channel.to_string()));
+                assert!(derivation
+                    .args
+                    .iter()
+                    .all(|arg| from_channels.contains(&arg.to_string())));
+                assert!(find_library_function(derivation.function).is_some());
+            }
+        }
+        assert!(conversion("PBRMetalness", "PBRMetalness")
+            .unwrap()
+            .is_empty());
+        assert!(conversion("PBRMetalness", "UnityStandardSpecular").is_none());
+    }
+
+    #[test]
+    fn convert_material_derives_metalness_and_copies_shared_channels() {
+        let materials = run(r#"
+            var scan:m = new_material("PBRSpecular", 2, 2);
+            scan.albedo = blank_image(0, 0, 0, 255, 2, 2);
+            scan.specular = blank_image(230, 230, 230, 255, 2, 2);
+            scan.glosiness = blank_image(200, 200, 200, 255, 2, 2);
+            scan.normal = blank_image(120, 130, 250, 255, 2, 2);
+            var metal:m = convert_material(scan, "PBRMetalness");
+        "#);
+        let metal = &materials["metal"];
+        let mut channels: Vec<&String> = metal.keys().collect();
+        channels.sort();
+        assert_eq!(channels, ["diffuse", "metalness", "normal", "roughness"]);
+        for (a, b) in pixel(&materials, "metal", "diffuse")
+            .iter()
+            .zip(&[230, 230, 230, 255])
+        {
+            assert!((*a as i32 - b).abs() <= 1);
+        }
+        assert_eq!(
+            pixel(&materials, "metal", "metalness"),
+            [255, 255, 255, 255]
+        );
+        assert_eq!(pixel(&materials, "metal", "roughness"), [55, 55, 55, 255]);
+        assert_eq!(pixel(&materials, "metal", "normal"), [120, 130, 250, 255]);
+    }
+
+    const TWO_STONES: &str = r#"
+        var stone:m = new_material("PBRMetalness", 2, 2);
+        stone.diffuse = blank_image(0, 0, 0, 255, 2, 2);
+        stone.ao = blank_image(200, 200, 200, 255, 2, 2);
+        stone.displacement = blank_image(50, 50, 50, 255, 2, 2);
+        var moss:m = new_material("PBRMetalness", 2, 2);
+        moss.diffuse = blank_image(200, 100, 50, 255, 2, 2);
+        moss.roughness = blank_image(90, 90, 90, 255, 2, 2);
+        moss.ao = blank_image(100, 100, 100, 255, 2, 2);
+        moss.displacement = blank_image(150, 150, 150, 255, 2, 2);
+        var white:i = blank_image(255, 255, 255, 255, 2, 2);
+        var low:i = blank_image(50, 50, 50, 255, 2, 2);
+        var high:i = blank_image(150, 150, 150, 255, 2, 2);
+    "#;
+
+    #[test]
+    fn blend_material_blends_every_channel() {
+        let materials = run(&format!(
+            "{}var half:m = blend_material(stone, moss, -1);
+             var top:m = blend_material(stone, moss, white);",
+            TWO_STONES
+        ));
+        assert_eq!(pixel(&materials, "half", "diffuse"), [100, 50, 25, 255]);
+        assert_eq!(pixel(&materials, "half", "ao"), [150, 150, 150, 255]);
+        // a channel only one of them has is kept as it is
+        assert_eq!(pixel(&materials, "half", "roughness"), [90, 90, 90, 255]);
+        assert_eq!(materials["half"].len(), 4);
+        assert_eq!(pixel(&materials, "top", "diffuse"), [200, 100, 50, 255]);
+        assert_eq!(
+            pixel(&materials, "top", "displacement"),
+            [150, 150, 150, 255]
+        );
+    }
+
+    #[test]
+    fn height_blend_shows_the_higher_material() {
+        let materials = run(&format!(
+            "{}var blended:m = height_blend(stone, moss, low, high, 8);
+             var swapped:m = height_blend(moss, stone, high, low, 8);",
+            TWO_STONES
+        ));
+        assert_eq!(pixel(&materials, "blended", "diffuse"), [200, 100, 50, 255]);
+        assert_eq!(pixel(&materials, "swapped", "diffuse"), [200, 100, 50, 255]);
+    }
+
+    #[test]
+    fn layer_material_keeps_occlusion_and_heights() {
+        let materials = run(&format!(
+            "{}var layered:m = layer_material(stone, moss, white);",
+            TWO_STONES
+        ));
+        assert_eq!(pixel(&materials, "layered", "diffuse"), [200, 100, 50, 255]);
+        assert_eq!(pixel(&materials, "layered", "ao"), [100, 100, 100, 255]);
+        assert_eq!(
+            pixel(&materials, "layered", "displacement"),
+            [150, 150, 150, 255]
+        );
+        assert_eq!(channel_blend("layer_material", "ao"), "blend_min");
+        assert_eq!(channel_blend("layer_material", "normal"), "layer_normal");
+        assert_eq!(channel_blend("blend_material", "normal"), "layer_normal");
+        assert_eq!(channel_blend("height_blend", "ao"), "blend_min");
+        assert_eq!(
+            channel_blend("height_blend", "displacement"),
+            "blend_channel"
+        );
+    }
+
+    #[test]
+    fn every_operation_reorients_normals_and_keeps_the_darker_occlusion() {
+        let materials = run(&format!(
+            "{}stone.normal = blank_image(128, 180, 240, 255, 2, 2);
+             moss.normal = blank_image(200, 128, 230, 255, 2, 2);
+             var blended:m = blend_material(stone, moss, white);
+             var height_blended:m = height_blend(stone, moss, low, high, 8);
+             var layered:m = layer_material(stone, moss, white);",
+            TWO_STONES
+        ));
+
+        let mut library = ImageLibrary::new();
+        let mut solid = |color: [u8; 4]| library.add_image("".to_string(), 2, 2, color.repeat(4));
+        let (base, detail, white) = (
+            solid([128, 180, 240, 255]),
+            solid([200, 128, 230, 255]),
+            solid([255, 255, 255, 255]),
+        );
+        let layered = layer_normal(&mut library, base, detail, white);
+        let pixels = &library.get_image_data(layered).unwrap().pixels;
+        let expected = [pixels[0], pixels[1], pixels[2], pixels[3]];
+        // a lerp would end at the second normal
+        assert_ne!(expected, [200, 128, 230, 255]);
+
+        for name in &["blended", "height_blended", "layered"] {
+            assert_eq!(pixel(&materials, name, "normal"), expected, "{}", name);
+            assert_eq!(
+                pixel(&materials, name, "ao"),
+                [100, 100, 100, 255],
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn blending_needs_materials_of_one_type() {
+        let src = r#"
+            var stone:m = new_material("PBRMetalness", 2, 2);
+            var scan:m = new_material("PBRSpecular", 2, 2);
+            var mixed:m = blend_material(stone, scan, -1);
+        "#;
+        let inputs = Inputs { images: vec![] };
+        assert!(Session::new()
+            .interpret(src, &inputs, &ExportOptions::default())
+            .is_err());
+        assert!(crate::compiler::Compiler::compile(src, &inputs).is_err());
+    }
+}
@@ -0,0 +1,394 @@
+// material type definitions, the built-in ones live in material_types.json
+//
+// a registry file lists types in the same format, as json
+// {"material_types": [{"name": "Toon", "channels": [{"name": "albedo", "color_space": "srgb"}]}]}
+// or as toml
+// [[material_types]]
+// name = "Toon"
+// channels = [
+//     { name = "albedo", color_space = "srgb", required = true },
+//     { name = "outline_mask", default_color = [255, 255, 255, 255] },
+// ]
+// packing = [{ name = "mask", r = "ao", g = "outline_mask", inverted = ["outline_mask"] }]
+use super::log_rule;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::path::Path;
+use wasm_bindgen::prelude::*;
+
+log_rule!();
+
+const BUILTIN_MATERIAL_TYPES: &str = include_str!("material_types.json");
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorSpace {
+    Srgb,
+    Linear,
+}
+
+impl Default for ColorSpace {
+    fn default() -> Self {
+        ColorSpace::Linear
+    }
+}
+
+pub fn default_bit_depth() -> u8 {
+    8
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChannelInfo {
+    pub name: String,
+    #[serde(default)]
+    pub default_color: Option<[u8; 4]>, // rgba
+    #[serde(default = "default_bit_depth")]
+    pub bit_depth: u8,
+    #[serde(default)]
+    pub color_space: ColorSpace,
+    // exports without an image for this channel fail validation
+    #[serde(default)]
+    pub required: bool,
+}
+
+impl ChannelInfo {
+    pub fn new(name: &str) -> Self {
+        ChannelInfo {
+            name: name.to_string(),
+            default_color: None,
+            bit_depth: default_bit_depth(),
+            color_space: ColorSpace::default(),
+            required: false,
+        }
+    }
+}
+
+// several grayscale channels stored in the r, g, b and a of one texture
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PackedTexture {
+    pub name: String,
+    #[serde(default)]
+    pub r: Option<String>,
+    #[serde(default)]
+    pub g: Option<String>,
+    #[serde(default)]
+    pub b: Option<String>,
+    #[serde(default)]
+    pub a: Option<String>,
+    // sources stored inverted, e.g. roughness as the smoothness of a mask map
+    #[serde(default)]
+    pub inverted: Vec<String>,
+}
+
+impl PackedTexture {
+    pub fn sources(&self) -> [&Option<String>; 4] {
+        [&self.r, &self.g, &self.b, &self.a]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MaterialType {
+    pub name: String,
+    pub channels: Vec<ChannelInfo>,
+    #[serde(default)]
+    pub packing: Vec<PackedTexture>,
+}
+
+impl MaterialType {
+    pub fn new(name: &str, channels: &Vec<String>) -> Self {
+        MaterialType {
+            name: name.to_string(),
+            channels: channels.iter().map(|c| ChannelInfo::new(c)).collect(),
+            packing: vec![],
+        }
+    }
+
+    pub fn channel_names(&self) -> Vec<String> {
+        self.channels.iter().map(|c| c.name.clone()).collect()
+    }
+
+    pub fn channel(&self, name: &str) -> Option<&ChannelInfo> {
+        self.channels.iter().find(|c| c.name == name)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty()
+            || self.name.starts_with(|c: char| c.is_ascii_digit())
+            || !self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(format!("{:?} is not a valid material type name", self.name));
+        }
+        if self.channels.is_empty() {
+            return Err(format!(
+                "material type {:?} needs at least one channel",
+                self.name
+            ));
+        }
+        for (i, channel) in self.channels.iter().enumerate() {
+            if self.channels[..i].iter().any(|c| c.name == channel.name) {
+                return Err(format!(
+                    "channel {:?} appears twice in material type {:?}",
+                    channel.name, self.name
+                ));
+            }
+            if channel.bit_depth != 8 && channel.bit_depth != 16 {
+                return Err(format!(
+                    "channel {:?} of {:?} has bit depth {}, only 8 and 16 are supported",
+                    channel.name, self.name, channel.bit_depth
+                ));
+            }
+        }
+        for packed in &self.packing {
+            for source in packed.sources().iter() {
+                if let Some(source) = source {
+                    if self.channel(source).is_none() {
+                        return Err(format!(
+                            "packed texture {:?} of {:?} uses unknown channel {:?}",
+                            packed.name, self.name, source
+                        ));
+                    }
+                }
+            }
+            for inverted in &packed.inverted {
+                if !packed.sources().contains(&&Some(inverted.clone())) {
+                    return Err(format!(
+                        "packed texture {:?} of {:?} inverts {:?}, which it doesn't pack",
+                        packed.name, self.name, inverted
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaterialTypeRegistry {
+    pub material_types: Vec<MaterialType>,
+}
+
+impl MaterialTypeRegistry {
+    pub fn from_json(src: &str) -> Result<Self, String> {
+        match serde_json::from_str(src) {
+            Ok(registry) => Ok(registry),
+            Err(e) => Err(format!("malformed material type registry: {}", e)),
+        }
+    }
+
+    pub fn from_toml(src: &str) -> Result<Self, String> {
+        match toml::from_str(src) {
+            Ok(registry) => Ok(registry),
+            Err(e) => Err(format!("malformed material type registry: {}", e)),
+        }
+    }
+
+    // format: "json" or "toml"
+    pub fn from_source(src: &str, format: &str) -> Result<Self, String> {
+        match format {
+            "json" => Self::from_json(src),
+            "toml" => Self::from_toml(src),
+            _ => Err(format!(
+                "{:?} is not a registry format, please use json or toml",
+                format
+            )),
+        }
+    }
+
+    // .toml files are read as toml, everything else as json
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let src = match std::fs::read_to_string(path) {
+            Ok(src) => src,
+            Err(e) => return Err(format!("failed to read {:?}: {}", path, e)),
+        };
+        match path.extension() {
+            Some(extension) if extension == "toml" => Self::from_toml(&src),
+            _ => Self::from_json(&src),
+        }
+    }
+
+    // register every type for all following compilations, returns their names
+    pub fn register(self) -> Result<Vec<String>, String> {
+        for material_type in &self.material_types {
+            material_type.validate()?;
+        }
+        let mut names: Vec<String> = vec![];
+        for material_type in self.material_types {
+            names.push(material_type.name.clone());
+            register_material_type(material_type)?;
+        }
+        Ok(names)
+    }
+}
+
+pub fn builtin_material_types() -> Vec<MaterialType> {
+    MaterialTypeRegistry::from_json(BUILTIN_MATERIAL_TYPES)
+        .unwrap()
+        .material_types
+}
+
+thread_local! {
+    static MATERIAL_TYPES: RefCell<Vec<MaterialType>> = RefCell::new(vec![]);
+}
+
+// a registered type with the name of a built-in one replaces it
+pub fn register_material_type(material_type: MaterialType) -> Result<(), String> {
+    material_type.validate()?;
+    MATERIAL_TYPES.with(|types| {
+        let mut types = types.borrow_mut();
+        types.retain(|t| t.name != material_type.name);
+        types.push(material_type);
+    });
+    Ok(())
+}
+
+pub fn registered_material_types() -> Vec<MaterialType> {
+    MATERIAL_TYPES.with(|types| types.borrow().clone())
+}
+
+pub fn clear_material_types() {
+    MATERIAL_TYPES.with(|types| types.borrow_mut().clear());
+}
+
+// definitions: a registry object, or its source as a string in `format`, "json" or "toml"
+#[wasm_bindgen]
+pub fn register_material_types(definitions: &JsValue, format: &str) -> bool {
+    let registry = match definitions.as_string() {
+        Some(src) => MaterialTypeRegistry::from_source(&src, format),
+        None => match definitions.into_serde::<MaterialTypeRegistry>() {
+            Ok(registry) => Ok(registry),
+            Err(e) => Err(format!("malformed material type registry: {}", e)),
+        },
+    };
+
+    match registry.and_then(|registry| registry.register()) {
+        Ok(_) => true,
+        Err(e) => {
+            log(&format!("Error: {}", e));
+            false
+        }
+    }
+}
+
+#[wasm_bindgen]
+pub fn unregister_material_types() {
+    clear_material_types();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbol::MaterialInfo;
+
+    const TOON_JSON: &str = r#"{"material_types": [{
+        "name": "Toon",
+        "channels": [
+            {"name": "albedo", "color_space": "srgb", "required": true},
+            {"name": "ramp", "bit_depth": 16, "default_color": [255, 255, 255, 255]}
+        ],
+        "packing": [{"name": "mask", "r": "ramp"}]
+    }]}"#;
+
+    const TOON_TOML: &str = r#"
+        [[material_types]]
+        name = "Toon"
+        channels = [
+            { name = "albedo", color_space = "srgb", required = true },
+            { name = "ramp", bit_depth = 16, default_color = [255, 255, 255, 255] },
+        ]
+        packing = [{ name = "mask", r = "ramp" }]
+    "#;
+
+    fn toon() -> MaterialType {
+        MaterialType {
+            name: "Toon".to_string(),
+            channels: vec![
+                ChannelInfo {
+                    color_space: ColorSpace::Srgb,
+                    required: true,
+                    ..ChannelInfo::new("albedo")
+                },
+                ChannelInfo {
+                    bit_depth: 16,
+                    default_color: Some([255, 255, 255, 255]),
+                    ..ChannelInfo::new("ramp")
+                },
+            ],
+            packing: vec![PackedTexture {
+                name: "mask".to_string(),
+                r: Some("ramp".to_string()),
+                g: None,
+                b: None,
+                a: None,
+                inverted: vec![],
+            }],
+        }
+    }
+
+    #[test]
+    fn json_and_toml_read_the_same_types() {
+        let json = MaterialTypeRegistry::from_json(TOON_JSON).unwrap();
+        let toml = MaterialTypeRegistry::from_toml(TOON_TOML).unwrap();
+        assert_eq!(json.material_types, vec![toon()]);
+        assert_eq!(toml.material_types, vec![toon()]);
+        assert_eq!(
+            MaterialTypeRegistry::from_source(TOON_TOML, "toml")
+                .unwrap()
+                .material_types,
+            vec![toon()]
+        );
+        assert!(MaterialTypeRegistry::from_source(TOON_JSON, "toml").is_err());
+        assert!(MaterialTypeRegistry::from_source(TOON_JSON, "yaml").is_err());
+        assert!(
+            MaterialTypeRegistry::from_json(r#"{"material_types": [{"name": "Toon"}]}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn validate_rejects_broken_types() {
+        assert_eq!(toon().validate(), Ok(()));
+        let broken = |change: fn(&mut MaterialType)| {
+            let mut material_type = toon();
+            change(&mut material_type);
+            material_type.validate()
+        };
+        assert!(broken(|t| t.name = "2Toon".to_string()).is_err());
+        assert!(broken(|t| t.channels.clear()).is_err());
+        assert!(broken(|t| t.channels[1].name = "albedo".to_string()).is_err());
+        assert!(broken(|t| t.channels[1].bit_depth = 12).is_err());
+        assert!(broken(|t| t.packing[0].r = Some("outline".to_string())).is_err());
+        assert!(broken(|t| t.packing[0].inverted = vec!["albedo".to_string()]).is_err());
+    }
+
+    #[test]
+    fn registered_types_are_material_types() {
+        let mut broken = toon();
+        broken.name = "Broken".to_string();
+        broken.channels.clear();
+        let registry = MaterialTypeRegistry {
+            material_types: vec![toon(), broken],
+        };
+        // nothing is registered when one of the types is invalid
+        assert!(registry.register().is_err());
+        assert!(registered_material_types().is_empty());
+
+        let registry = MaterialTypeRegistry::from_json(TOON_JSON).unwrap();
+        assert_eq!(registry.register(), Ok(vec!["Toon".to_string()]));
+        let material_info = MaterialInfo::new();
+        assert_eq!(
+            material_info.get_material_type(&"Toon".to_string()),
+            Some(&toon())
+        );
+        assert_eq!(
+            material_info.get_material_channels(&"Toon".to_string()),
+            Ok(vec!["albedo".to_string(), "ramp".to_string()])
+        );
+
+        clear_material_types();
+        assert!(MaterialInfo::new()
+            .get_material_type(&"Toon".to_string())
+            .is_none());
+    }
+}
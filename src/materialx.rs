@@ -0,0 +1,506 @@
+// MaterialX 1.38 documents of exported materials, for DCC tools
+//
+// every material becomes a standard_surface or UsdPreviewSurface shader with image nodes reading
+// the texture files the host saves, e.g. stone_diffuse.png, empty channels with a default color
+// are set as constant inputs instead
+use super::log_rule;
+use super::manifest::{ChannelManifest, Manifest, MaterialManifest};
+use super::material_types::ColorSpace;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use wasm_bindgen::prelude::*;
+
+log_rule!();
+
+const MATERIALX_VERSION: &str = "1.38";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Shader {
+    StandardSurface,
+    UsdPreviewSurface,
+}
+
+impl Default for Shader {
+    fn default() -> Self {
+        Shader::StandardSurface
+    }
+}
+
+fn default_texture_path() -> String {
+    "{material}_{channel}.png".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaterialXOptions {
+    #[serde(default)]
+    pub shader: Shader,
+    // file of a channel's texture, {material} and {channel} are replaced by their names
+    #[serde(default = "default_texture_path")]
+    pub texture_path: String,
+}
+
+impl Default for MaterialXOptions {
+    fn default() -> Self {
+        MaterialXOptions {
+            shader: Shader::default(),
+            texture_path: default_texture_path(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Conversion {
+    Direct,
+    Invert, // glosiness as roughness
+    Normal, // tangent space normal map
+}
+
+// shader input fed by a channel
+struct Connection {
+    input: &'static str,
+    input_type: &'static str,
+    conversion: Conversion,
+}
+
+fn connection(
+    input: &'static str,
+    input_type: &'static str,
+    conversion: Conversion,
+) -> Option<Connection> {
+    Some(Connection {
+        input,
+        input_type,
+        conversion,
+    })
+}
+
+// the input a channel of the built-in types maps to, displacement goes to its own shader
+fn surface_input(shader: Shader, channel: &str) -> Option<Connection> {
+    use Conversion::*;
+    match (shader, channel) {
+        (Shader::StandardSurface, "diffuse") | (Shader::StandardSurface, "albedo") => {
+            connection("base_color", "color3", Direct)
+        }
+        (Shader::StandardSurface, "metalness") => connection("metalness", "float", Direct),
+        (Shader::StandardSurface, "roughness") => connection("specular_roughness", "float", Direct),
+        (Shader::StandardSurface, "glosiness") => connection("specular_roughness", "float", Invert),
+        (Shader::StandardSurface, "specular") => connection("specular_color", "color3", Direct),
+        (Shader::StandardSurface, "normal") => connection("normal", "vector3", Normal),
+        (Shader::StandardSurface, "transparency") => connection("opacity", "color3", Direct),
+        (Shader::StandardSurface, "emissive") => connection("emission_color", "color3", Direct),
+        (Shader::StandardSurface, "subsurfance_scattering") => {
+            connection("subsurface_color", "color3", Direct)
+        }
+        (Shader::UsdPreviewSurface, "diffuse") | (Shader::UsdPreviewSurface, "albedo") => {
+            connection("diffuseColor", "color3", Direct)
+        }
+        (Shader::UsdPreviewSurface, "metalness") => connection("metallic", "float", Direct),
+        (Shader::UsdPreviewSurface, "roughness") => connection("roughness", "float", Direct),
+        (Shader::UsdPreviewSurface, "glosiness") => connection("roughness", "float", Invert),
+        (Shader::UsdPreviewSurface, "specular") => connection("specularColor", "color3", Direct),
+        (Shader::UsdPreviewSurface, "normal") => connection("normal", "vector3", Normal),
+        (Shader::UsdPreviewSurface, "transparency") => connection("opacity", "float", Direct),
+        (Shader::UsdPreviewSurface, "emissive") => connection("emissiveColor", "color3", Direct),
+        (Shader::UsdPreviewSurface, "ao") => connection("occlusion", "float", Direct),
+        (Shader::UsdPreviewSurface, "displacement") | (Shader::UsdPreviewSurface, "height") => {
+            connection("displacement", "float", Direct)
+        }
+        _ => None,
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn colorspace(color_space: ColorSpace) -> &'static str {
+    match color_space {
+        ColorSpace::Srgb => "srgb_texture",
+        ColorSpace::Linear => "lin_rec709",
+    }
+}
+
+// "r, g, b" or "v" of a default color
+fn constant(color: [u8; 4], value_type: &str) -> String {
+    let value = |c: u8| format!("{:.4}", c as f32 / 255.0);
+    match value_type {
+        "color3" | "vector3" => format!(
+            "{}, {}, {}",
+            value(color[0]),
+            value(color[1]),
+            value(color[2])
+        ),
+        _ => value(color[0]),
+    }
+}
+
+struct Writer {
+    lines: Vec<String>,
+}
+
+impl Writer {
+    fn line(&mut self, indent: usize, line: String) {
+        self.lines.push(format!("{}{}", "  ".repeat(indent), line));
+    }
+
+    // the node giving a channel's value, returns its name
+    fn image_node(
+        &mut self,
+        material: &MaterialManifest,
+        channel: &ChannelManifest,
+        connection: &Connection,
+        options: &MaterialXOptions,
+    ) -> String {
+        let name = format!("{}_{}", material.name, channel.name);
+        let file = options
+            .texture_path
+            .replace("{material}", &material.name)
+            .replace("{channel}", &channel.name);
+        // normal maps are read as vectors, color spaces only apply to colors
+        let file_colorspace = if connection.input_type == "color3" {
+            format!(" colorspace=\"{}\"", colorspace(channel.color_space))
+        } else {
+            "".to_string()
+        };
+        self.line(
+            1,
+            format!(
+                "<image name=\"{}\" type=\"{}\">",
+                name, connection.input_type
+            ),
+        );
+        self.line(
+            2,
+            format!(
+                "<input name=\"file\" type=\"filename\" value=\"{}\"{} />",
+                escape(&file),
+                file_colorspace
+            ),
+        );
+        self.line(1, "</image>".to_string());
+
+        match connection.conversion {
+            Conversion::Direct => name,
+            Conversion::Invert => {
+                let inverted = format!("{}_inverted", name);
+                self.line(1, format!("<invert name=\"{}\" type=\"float\">", inverted));
+                self.line(
+                    2,
+                    format!("<input name=\"in\" type=\"float\" nodename=\"{}\" />", name),
+                );
+                self.line(1, "</invert>".to_string());
+                inverted
+            }
+            Conversion::Normal if options.shader == Shader::StandardSurface => {
+                let normal = format!("{}_normalmap", name);
+                self.line(
+                    1,
+                    format!("<normalmap name=\"{}\" type=\"vector3\">", normal),
+                );
+                self.line(
+                    2,
+                    format!(
+                        "<input name=\"in\" type=\"vector3\" nodename=\"{}\" />",
+                        name
+                    ),
+                );
+                self.line(1, "</normalmap>".to_string());
+                normal
+            }
+            Conversion::Normal => {
+                // UsdPreviewSurface takes tangent space normals in [-1, 1]
+                let normal = format!("{}_remap", name);
+                self.line(1, format!("<remap name=\"{}\" type=\"vector3\">", normal));
+                self.line(
+                    2,
+                    format!(
+                        "<input name=\"in\" type=\"vector3\" nodename=\"{}\" />",
+                        name
+                    ),
+                );
+                self.line(
+                    2,
+                    "<input name=\"outlow\" type=\"float\" value=\"-1\" />".to_string(),
+                );
+                self.line(
+                    2,
+                    "<input name=\"outhigh\" type=\"float\" value=\"1\" />".to_string(),
+                );
+                self.line(1, "</remap>".to_string());
+                normal
+            }
+        }
+    }
+
+    fn material(&mut self, material: &MaterialManifest, options: &MaterialXOptions) {
+        let mut inputs: Vec<String> = vec![];
+        let mut displacement: Option<String> = None;
+        for channel in &material.channels {
+            let is_displacement = channel.name == "displacement" || channel.name == "height";
+            let connection = match surface_input(options.shader, &channel.name) {
+                Some(connection) => connection,
+                None if is_displacement => Connection {
+                    input: "displacement",
+                    input_type: "float",
+                    conversion: Conversion::Direct,
+                },
+                None => continue,
+            };
+            let input = match (channel.image, channel.default_color) {
+                (Some(_), _) => {
+                    let node = self.image_node(material, channel, &connection, options);
+                    format!(
+                        "<input name=\"{}\" type=\"{}\" nodename=\"{}\" />",
+                        connection.input, connection.input_type, node
+                    )
+                }
+                // flat normals and default heights are what the shaders assume anyway
+                (None, Some(_)) if connection.conversion != Conversion::Direct => continue,
+                (None, Some(_)) if is_displacement => continue,
+                (None, Some(color)) => {
+                    let value_colorspace = if connection.input_type == "color3" {
+                        format!(" colorspace=\"{}\"", colorspace(channel.color_space))
+                    } else {
+                        "".to_string()
+                    };
+                    format!(
+                        "<input name=\"{}\" type=\"{}\" value=\"{}\"{} />",
+                        connection.input,
+                        connection.input_type,
+                        constant(color, connection.input_type),
+                        value_colorspace
+                    )
+                }
+                (None, None) => continue,
+            };
+            if is_displacement && options.shader == Shader::StandardSurface {
+                displacement = Some(input);
+            } else {
+                inputs.push(input);
+            }
+        }
+
+        let shader_name = format!("SR_{}", material.name);
+        match options.shader {
+            Shader::StandardSurface => {
+                self.line(
+                    1,
+                    format!(
+                        "<standard_surface name=\"{}\" type=\"surfaceshader\">",
+                        shader_name
+                    ),
+                );
+                if inputs.iter().any(|i| {
+                    i.starts_with("<input name=\"emission_color\"") && i.contains("nodename")
+                }) {
+                    inputs
+                        .push("<input name=\"emission\" type=\"float\" value=\"1\" />".to_string());
+                }
+            }
+            Shader::UsdPreviewSurface => {
+                self.line(
+                    1,
+                    format!(
+                        "<UsdPreviewSurface name=\"{}\" type=\"surfaceshader\">",
+                        shader_name
+                    ),
+                );
+                if inputs.iter().any(|i| i.contains("\"specularColor\"")) {
+                    inputs.push(
+                        "<input name=\"useSpecularWorkflow\" type=\"integer\" value=\"1\" />"
+                            .to_string(),
+                    );
+                }
+            }
+        }
+        for input in inputs {
+            self.line(2, input);
+        }
+        match options.shader {
+            Shader::StandardSurface => self.line(1, "</standard_surface>".to_string()),
+            Shader::UsdPreviewSurface => self.line(1, "</UsdPreviewSurface>".to_string()),
+        }
+
+        let displacement_name = format!("DS_{}", material.name);
+        if let Some(input) = &displacement {
+            self.line(
+                1,
+                format!(
+                    "<displacement name=\"{}\" type=\"displacementshader\">",
+                    displacement_name
+                ),
+            );
+            self.line(2, input.clone());
+            self.line(1, "</displacement>".to_string());
+        }
+
+        self.line(
+            1,
+            format!(
+                "<surfacematerial name=\"{}\" type=\"material\">",
+                material.name
+            ),
+        );
+        self.line(
+            2,
+            format!(
+                "<input name=\"surfaceshader\" type=\"surfaceshader\" nodename=\"{}\" />",
+                shader_name
+            ),
+        );
+        if displacement.is_some() {
+            self.line(
+                2,
+                format!(
+                    "<input name=\"displacementshader\" type=\"displacementshader\" nodename=\"{}\" />",
+                    displacement_name
+                ),
+            );
+        }
+        self.line(1, "</surfacematerial>".to_string());
+    }
+}
+
+// one document with every material of the manifest
+pub fn write_materialx(manifest: &Manifest, options: &MaterialXOptions) -> String {
+    let mut writer = Writer { lines: vec![] };
+    writer.line(0, "<?xml version=\"1.0\"?>".to_string());
+    writer.line(
+        0,
+        format!(
+            "<materialx version=\"{}\" colorspace=\"lin_rec709\">",
+            MATERIALX_VERSION
+        ),
+    );
+    for material in &manifest.materials {
+        writer.material(material, options);
+    }
+    writer.line(0, "</materialx>".to_string());
+    writer.lines.join("\n") + "\n"
+}
+
+pub fn save_materialx(
+    manifest: &Manifest,
+    options: &MaterialXOptions,
+    path: &Path,
+) -> Result<(), String> {
+    match std::fs::write(path, write_materialx(manifest, options)) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("failed to write {:?}: {}", path, e)),
+    }
+}
+
+// manifest: the result of decode_manifest,
+// options: {shader: "standard_surface" | "usd_preview_surface", texture_path} or undefined
+#[wasm_bindgen]
+pub fn export_materialx(manifest: &JsValue, options: &JsValue) -> String {
+    let manifest: Manifest = match manifest.into_serde() {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            log(&format!("Error: malformed manifest: {}", e));
+            return "".to_string();
+        }
+    };
+    let options: MaterialXOptions = if options.is_undefined() || options.is_null() {
+        MaterialXOptions::default()
+    } else {
+        match options.into_serde() {
+            Ok(options) => options,
+            Err(e) => {
+                log(&format!("Error: malformed MaterialX options: {}", e));
+                return "".to_string();
+            }
+        }
+    };
+    write_materialx(&manifest, &options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(name: &str, image: Option<i32>, default_color: Option<[u8; 4]>) -> ChannelManifest {
+        ChannelManifest {
+            name: name.to_string(),
+            image,
+            default_color,
+            required: false,
+            color_space: ColorSpace::default(),
+            bit_depth: 8,
+        }
+    }
+
+    fn manifest(channels: Vec<ChannelManifest>) -> Manifest {
+        Manifest {
+            version: crate::manifest::MANIFEST_VERSION,
+            materials: vec![MaterialManifest {
+                name: "wall".to_string(),
+                material_type: "PBRMetalness".to_string(),
+                width: 4,
+                height: 4,
+                channels,
+                packing: vec![],
+            }],
+        }
+    }
+
+    #[test]
+    fn assigned_channels_read_their_textures() {
+        let document = write_materialx(
+            &manifest(vec![
+                ChannelManifest {
+                    color_space: ColorSpace::Srgb,
+                    ..channel("diffuse", Some(0), None)
+                },
+                channel("roughness", Some(1), Some([128, 128, 128, 255])),
+                channel("displacement", Some(2), Some([128, 128, 128, 255])),
+            ]),
+            &MaterialXOptions::default(),
+        );
+        assert!(document.contains("value=\"wall_diffuse.png\" colorspace=\"srgb_texture\""));
+        assert!(document.contains(
+            "<input name=\"specular_roughness\" type=\"float\" nodename=\"wall_roughness\" />"
+        ));
+        assert!(document.contains("<displacement name=\"DS_wall\""));
+        assert!(document.contains("nodename=\"DS_wall\""));
+    }
+
+    #[test]
+    fn missing_channels_are_left_out_or_set_as_constants() {
+        let document = write_materialx(
+            &manifest(vec![
+                channel("diffuse", None, None),
+                channel("metalness", None, Some([0, 0, 0, 255])),
+                channel("normal", None, Some([128, 128, 255, 255])),
+                channel("displacement", None, Some([128, 128, 128, 255])),
+                channel("subsurfance_scattering", None, None),
+            ]),
+            &MaterialXOptions::default(),
+        );
+        assert!(!document.contains("<image"));
+        assert!(!document.contains("base_color"));
+        assert!(!document.contains("subsurface_color"));
+        assert!(document.contains("<input name=\"metalness\" type=\"float\" value=\"0.0000\" />"));
+        // flat normals and default heights are left to the shader
+        assert!(!document.contains("name=\"normal\""));
+        assert!(!document.contains("<displacement"));
+        assert!(document.contains("<surfacematerial name=\"wall\" type=\"material\">"));
+    }
+
+    #[test]
+    fn color_spaces_come_from_the_manifest() {
+        let options = MaterialXOptions::default();
+        let diffuse = |color_space| ChannelManifest {
+            color_space,
+            ..channel("diffuse", Some(0), None)
+        };
+        let srgb = write_materialx(&manifest(vec![diffuse(ColorSpace::Srgb)]), &options);
+        assert!(srgb.contains("colorspace=\"srgb_texture\""));
+        let linear = write_materialx(&manifest(vec![diffuse(ColorSpace::Linear)]), &options);
+        assert!(!linear.contains("srgb_texture"));
+    }
+}
@@ -0,0 +1,381 @@
+use super::image_library::{ImageData, ImageLibrary};
+use super::log_rule;
+use super::material_ops::MATERIAL_OPERATIONS;
+use super::registry::{
+    builtin_functions, invalidate_library_functions, LibraryFunction, Param, ParamType,
+};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+
+log_rule!();
+
+// calls the compiler handles itself
+const COMPILER_CALLS: [&str; 2] = ["show", "new_material"];
+
+// keywords of the language, a call can't have their names
+const KEYWORDS: [&str; 3] = ["pack", "material_type", "test"];
+
+// names a plugin can't take: the keywords, the calls the compiler handles, the material
+// operations and the built-in functions
+pub fn reserved_names() -> Vec<String> {
+    let mut names: Vec<String> = KEYWORDS
+        .iter()
+        .chain(COMPILER_CALLS.iter())
+        .chain(MATERIAL_OPERATIONS.iter())
+        .map(|name| name.to_string())
+        .collect();
+    names.extend(builtin_functions().into_iter().map(|f| f.name));
+    names
+}
+
+// an image function provided by the host at runtime
+pub trait ImageFunction {
+    fn name(&self) -> String;
+    fn params(&self) -> Vec<Param>;
+    fn returns(&self) -> Option<Param>;
+
+    fn explanation(&self) -> String {
+        "".to_string()
+    }
+
+    fn usage(&self) -> String {
+        "".to_string()
+    }
+
+    // images are passed as ids into the library, an image result is returned as a new id
+    fn call(&self, library: &mut ImageLibrary, args: &[i32]) -> i32;
+}
+
+thread_local! {
+    static PLUGINS: RefCell<Vec<Rc<dyn ImageFunction>>> = RefCell::new(vec![]);
+}
+
+// register a function for all following compilations, a plugin has to be unregistered before
+// another one can take its name
+pub fn register_function(function: Rc<dyn ImageFunction>) -> Result<(), String> {
+    register_functions(vec![function])
+}
+
+// register several functions at once, either all of them or none when one can't be registered
+pub fn register_functions(functions: Vec<Rc<dyn ImageFunction>>) -> Result<(), String> {
+    let reserved = reserved_names();
+    for function in &functions {
+        let name = function.name();
+        if name.is_empty()
+            || name.starts_with(|c: char| c.is_ascii_digit())
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(format!("{:?} is not a valid function name", name));
+        }
+        if reserved.contains(&name) {
+            return Err(format!("{:?} is already a built-in function", name));
+        }
+    }
+
+    PLUGINS.with(|plugins| {
+        let mut plugins = plugins.borrow_mut();
+        for (i, function) in functions.iter().enumerate() {
+            let name = function.name();
+            if plugins
+                .iter()
+                .chain(&functions[..i])
+                .any(|f| f.name() == name)
+            {
+                return Err(format!("a plugin {:?} is already registered", name));
+            }
+        }
+        plugins.extend(functions);
+        Ok(())
+    })?;
+    invalidate_library_functions();
+    Ok(())
+}
+
+pub fn unregister_function(name: &str) {
+    PLUGINS.with(|plugins| plugins.borrow_mut().retain(|f| f.name() != name));
+    invalidate_library_functions();
+}
+
+pub fn registered_functions() -> Vec<LibraryFunction> {
+    PLUGINS.with(|plugins| {
+        plugins
+            .borrow()
+            .iter()
+            .map(|plugin| {
+                let plugin = plugin.clone();
+                LibraryFunction {
+                    name: plugin.name(),
+                    params: plugin.params(),
+                    returns: plugin.returns(),
+                    explanation: plugin.explanation(),
+                    usage: plugin.usage(),
+                    call: Rc::new(move |library: &mut ImageLibrary, args: &[i32]| {
+                        Ok(plugin.call(library, args))
+                    }),
+                }
+            })
+            .collect()
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginSignature {
+    pub params: Vec<Param>,
+    #[serde(default)]
+    pub returns: Option<Param>,
+    #[serde(default)]
+    pub explanation: String,
+    #[serde(default)]
+    pub usage: String,
+}
+
+// a js callback, images are handed over as {width, height, pixels} objects
+pub struct JsImageFunction {
+    name: String,
+    signature: PluginSignature,
+    callback: js_sys::Function,
+}
+
+impl ImageFunction for JsImageFunction {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn params(&self) -> Vec<Param> {
+        self.signature.params.clone()
+    }
+
+    fn returns(&self) -> Option<Param> {
+        self.signature.returns.clone()
+    }
+
+    fn explanation(&self) -> String {
+        self.signature.explanation.clone()
+    }
+
+    fn usage(&self) -> String {
+        self.signature.usage.clone()
+    }
+
+    fn call(&self, library: &mut ImageLibrary, args: &[i32]) -> i32 {
+        let js_args = js_sys::Array::new();
+        for (param, arg) in self.signature.params.iter().zip(args) {
+            match param.param_type {
+                ParamType::Image => match library.get_image_data(*arg) {
+                    Some(image_data) => match JsValue::from_serde(image_data) {
+                        Ok(image_data) => {
+                            js_args.push(&image_data);
+                        }
+                        Err(e) => {
+                            log(&format!(
+                                "Error: failed to pass image {:?} to {:?}: {}",
+                                arg, self.name, e
+                            ));
+                            return -1;
+                        }
+                    },
+                    None => {
+                        log(&format!(
+                            "Error: image {:?} passed to {:?} doesn't exist.",
+                            arg, self.name
+                        ));
+                        return -1;
+                    }
+                },
+                ParamType::Number => {
+                    js_args.push(&JsValue::from(*arg));
+                }
+            }
+        }
+
+        let result = match self.callback.apply(&JsValue::NULL, &js_args) {
+            Ok(result) => result,
+            Err(e) => {
+                log(&format!("Error: function {:?} failed: {:?}", self.name, e));
+                return -1;
+            }
+        };
+
+        match &self.signature.returns {
+            Some(param) if param.param_type == ParamType::Image => {
+                match result.into_serde::<ImageData>() {
+                    Ok(image_data) => library.add_image(
+                        self.name.clone(),
+                        image_data.width,
+                        image_data.height,
+                        image_data.pixels,
+                    ),
+                    Err(e) => {
+                        log(&format!(
+                            "Error: function {:?} should return {{width, height, pixels}}: {}",
+                            self.name, e
+                        ));
+                        -1
+                    }
+                }
+            }
+            Some(_) => result.as_f64().unwrap_or(-1.0) as i32,
+            None => 0,
+        }
+    }
+}
+
+// signature: {params: [{param_type: "Image" | "Number", description}], returns, explanation, usage}
+#[wasm_bindgen]
+pub fn register_image_function(
+    name: String,
+    signature: &JsValue,
+    callback: js_sys::Function,
+) -> bool {
+    let signature: PluginSignature = match signature.into_serde() {
+        Ok(signature) => signature,
+        Err(e) => {
+            log(&format!(
+                "Error: failed to read the signature of {:?}: {}",
+                name, e
+            ));
+            return false;
+        }
+    };
+    let function = JsImageFunction {
+        name,
+        signature,
+        callback,
+    };
+    match register_function(Rc::new(function)) {
+        Ok(()) => true,
+        Err(e) => {
+            log(&format!("Error: {}", e));
+            false
+        }
+    }
+}
+
+#[wasm_bindgen]
+pub fn unregister_image_function(name: String) {
+    unregister_function(&name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::find_library_function;
+
+    // inverts the red channel of its image
+    struct InvertRed(&'static str);
+
+    impl ImageFunction for InvertRed {
+        fn name(&self) -> String {
+            self.0.to_string()
+        }
+
+        fn params(&self) -> Vec<Param> {
+            vec![Param::new(ParamType::Image, "an image id")]
+        }
+
+        fn returns(&self) -> Option<Param> {
+            Some(Param::new(ParamType::Image, "an image id"))
+        }
+
+        fn call(&self, library: &mut ImageLibrary, args: &[i32]) -> i32 {
+            let mut image_data = library.get_image_data(args[0]).unwrap().clone();
+            for i in (0..image_data.pixels.len()).step_by(4) {
+                image_data.pixels[i] = 255 - image_data.pixels[i];
+            }
+            library.add_image(
+                "".to_string(),
+                image_data.width,
+                image_data.height,
+                image_data.pixels,
+            )
+        }
+    }
+
+    #[test]
+    fn registered_functions_are_library_functions() {
+        register_function(Rc::new(InvertRed("invert_red"))).unwrap();
+        let function = find_library_function("invert_red").unwrap();
+        let mut library = ImageLibrary::new();
+        let image = library.add_image("".to_string(), 1, 1, vec![10, 20, 30, 255]);
+        let inverted = function.dispatch(&mut library, &[image]).unwrap();
+        assert_eq!(
+            library.get_image_data(inverted).unwrap().pixels,
+            vec![245, 20, 30, 255]
+        );
+
+        unregister_function("invert_red");
+        assert!(find_library_function("invert_red").is_none());
+    }
+
+    #[test]
+    fn reserved_names_are_rejected() {
+        for name in &[
+            "show",
+            "new_material",
+            "blend_material",
+            "invert_color",
+            "pack",
+            "material_type",
+            "test",
+        ] {
+            assert_eq!(
+                register_function(Rc::new(InvertRed(name))),
+                Err(format!("{:?} is already a built-in function", name))
+            );
+        }
+    }
+
+    #[test]
+    fn a_name_is_registered_once() {
+        register_function(Rc::new(InvertRed("tint"))).unwrap();
+        assert_eq!(
+            register_function(Rc::new(InvertRed("tint"))),
+            Err("a plugin \"tint\" is already registered".to_string())
+        );
+        unregister_function("tint");
+        assert!(register_function(Rc::new(InvertRed("tint"))).is_ok());
+        unregister_function("tint");
+    }
+
+    #[test]
+    fn functions_are_registered_all_or_none() {
+        let functions: Vec<Rc<dyn ImageFunction>> =
+            vec![Rc::new(InvertRed("glow")), Rc::new(InvertRed("pack"))];
+        assert!(register_functions(functions).is_err());
+        let functions: Vec<Rc<dyn ImageFunction>> =
+            vec![Rc::new(InvertRed("glow")), Rc::new(InvertRed("glow"))];
+        assert_eq!(
+            register_functions(functions),
+            Err("a plugin \"glow\" is already registered".to_string())
+        );
+        assert!(find_library_function("glow").is_none());
+
+        let functions: Vec<Rc<dyn ImageFunction>> =
+            vec![Rc::new(InvertRed("glow")), Rc::new(InvertRed("dim"))];
+        register_functions(functions).unwrap();
+        assert!(find_library_function("glow").is_some());
+        assert!(find_library_function("dim").is_some());
+        unregister_function("glow");
+        unregister_function("dim");
+    }
+
+    #[test]
+    fn bad_signatures_are_rejected() {
+        for name in &["", "2x", "blur-more", "café"] {
+            assert!(register_function(Rc::new(InvertRed(name))).is_err());
+        }
+
+        // what register_image_function reads the js signature object as
+        let signature: PluginSignature =
+            serde_json::from_str(r#"{"params": [{"param_type": "Image", "description": ""}]}"#)
+                .unwrap();
+        assert!(signature.returns.is_none());
+        assert!(serde_json::from_str::<PluginSignature>(
+            r#"{"params": [{"param_type": "Color", "description": ""}]}"#
+        )
+        .is_err());
+        assert!(serde_json::from_str::<PluginSignature>(r#"{"returns": null}"#).is_err());
+    }
+}
@@ -0,0 +1,462 @@
+use super::abi::{HostAbi, ABI_VERSION};
+use super::compiler::ImportInfo;
+use super::diagnostic::to_js_value;
+use super::image_functions;
+use super::image_library::ImageLibrary;
+use super::log_rule;
+use super::material_functions;
+use super::plugin::registered_functions;
+use super::session::shared_session;
+use super::testing;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+
+log_rule!();
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ParamType {
+    Image,
+    Number,
+}
+
+impl ParamType {
+    // placeholder used in the reference signatures, <img1> or <n1>
+    fn label(&self, index: usize) -> String {
+        match self {
+            Self::Image => format!("<img{}>", index),
+            Self::Number => format!("<n{}>", index),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Param {
+    pub param_type: ParamType,
+    pub description: String,
+}
+
+impl Param {
+    pub fn new(param_type: ParamType, description: &str) -> Self {
+        Param {
+            param_type,
+            description: description.to_string(),
+        }
+    }
+}
+
+// a host function callable from coocoo programs, either built in or registered as a plugin
+#[derive(Clone)]
+pub struct LibraryFunction {
+    pub name: String,
+    pub params: Vec<Param>,
+    pub returns: Option<Param>,
+    pub explanation: String,
+    pub usage: String,
+    // expects one argument per param, see dispatch()
+    pub call: Rc<dyn Fn(&mut ImageLibrary, &[i32]) -> Result<i32, String>>,
+}
+
+impl LibraryFunction {
+    pub fn param_types(&self) -> Vec<walrus::ValType> {
+        vec![walrus::ValType::I32; self.params.len()]
+    }
+
+    pub fn return_types(&self) -> Vec<walrus::ValType> {
+        match self.returns {
+            Some(_) => vec![walrus::ValType::I32],
+            None => vec![],
+        }
+    }
+
+    pub fn import_info(&self) -> ImportInfo {
+        ImportInfo::new(&self.name, &self.param_types(), &self.return_types())
+    }
+
+    // call with arguments that don't come from a module whose imports were checked
+    pub fn dispatch(&self, library: &mut ImageLibrary, args: &[i32]) -> Result<i32, String> {
+        if args.len() != self.params.len() {
+            return Err(format!(
+                "{}() takes {} arguments, not {}",
+                self.name,
+                self.params.len(),
+                args.len()
+            ));
+        }
+        (self.call)(library, args)
+    }
+
+    // entry in the same format as references.json
+    pub fn reference(&self) -> Reference {
+        let mut image_count = 0;
+        let mut number_count = 0;
+        let mut next_label = |param_type: ParamType| match param_type {
+            ParamType::Image => {
+                image_count += 1;
+                param_type.label(image_count)
+            }
+            ParamType::Number => {
+                number_count += 1;
+                param_type.label(number_count)
+            }
+        };
+
+        let mut labels: Vec<String> = vec![];
+        let mut parameters: Vec<String> = vec![];
+        for param in &self.params {
+            let label = next_label(param.param_type);
+            parameters.push(format!("{}: {}", label, param.description));
+            labels.push(label);
+        }
+        let mut signature = format!("{}({})", self.name, labels.join(", "));
+        let mut returns: Vec<String> = vec![];
+        if let Some(param) = &self.returns {
+            let label = next_label(param.param_type);
+            signature += &format!(" -> {}", label);
+            returns.push(format!("{}: {}", label, param.description));
+        }
+
+        Reference {
+            name: self.name.clone(),
+            signature,
+            parameters,
+            returns,
+            explanation: self.explanation.clone(),
+            usage: self.usage.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reference {
+    pub name: String,
+    pub signature: String,
+    pub parameters: Vec<String>,
+    #[serde(rename = "return")]
+    pub returns: Vec<String>,
+    pub explanation: String,
+    pub usage: String,
+}
+
+// the implementations take their images for granted, except the ones of params marked with
+// `or -1` which may be left out
+fn check_images(
+    library: &ImageLibrary,
+    name: &str,
+    params: &[(ParamType, bool)],
+    args: &[i32],
+) -> Result<(), String> {
+    for ((param_type, optional), arg) in params.iter().zip(args) {
+        if *param_type == ParamType::Image && !optional && library.get_image_data(*arg).is_none() {
+            return Err(format!("{}() got {}, which is not an image", name, arg));
+        }
+    }
+    Ok(())
+}
+
+// declares every built-in function once, everything else is derived from this list
+macro_rules! library_functions {
+    ($(
+        $name:ident ( $( $param:ident : $param_type:ident $( or $none:literal )? = $param_doc:literal ),* )
+            $( -> $return_type:ident = $return_doc:literal )?,
+            explanation: $explanation:literal,
+            usage: $usage:literal
+            => $implementation:path;
+    )*) => {
+        pub fn builtin_functions() -> Vec<LibraryFunction> {
+            vec![$(
+                LibraryFunction {
+                    name: stringify!($name).to_string(),
+                    params: vec![$( Param::new(ParamType::$param_type, $param_doc) ),*],
+                    returns: library_functions!(@returns $( $return_type, $return_doc )?),
+                    explanation: $explanation.to_string(),
+                    usage: $usage.to_string(),
+                    call: Rc::new(|library: &mut ImageLibrary, args: &[i32]| {
+                        check_images(
+                            library,
+                            stringify!($name),
+                            &[$( (ParamType::$param_type, library_functions!(@optional $( $none )?)) ),*],
+                            args,
+                        )?;
+                        let mut args = args.iter();
+                        Ok($implementation(library, $( library_functions!(@arg args, $param) ),*))
+                    }),
+                },
+            )*]
+        }
+    };
+    (@returns) => { None };
+    (@returns $return_type:ident, $return_doc:literal) => {
+        Some(Param::new(ParamType::$return_type, $return_doc))
+    };
+    (@optional) => { false };
+    (@optional $none:literal) => { true };
+    (@arg $args:ident, $param:ident) => {
+        *$args.next().unwrap()
+    };
+}
+
+library_functions! {
+    logger(value: Number = "a number to print"),
+        explanation: "Print a number to the console.",
+        usage: "logger(width);"
+        => image_functions::logger;
+    resize(image: Image = "an image id", width: Number = "new width", height: Number = "new height")
+        -> Image = "an image id",
+        explanation: "Resize an image. Mitchell filter is used for upscaling and Lanczos3 for downscaling.",
+        usage: "var small_image:i = resize(original_image, 128, 128);"
+        => image_functions::resize;
+    blank_image(
+        r: Number = "R channel value of the background color",
+        g: Number = "G channel value of the background color",
+        b: Number = "B channel value of the background color",
+        a: Number = "Alpha channel value of the background color",
+        width: Number = "width of an image",
+        height: Number = "height of an image"
+    ) -> Image = "an image id",
+        explanation: "Generate a black empty image. First four arguments (<number(u8)>) are RGBA values, the last two are width and height. Below is an example to generate a solid black image of size 50 250.",
+        usage: "var width:n = 50;\nvar height:n = 250;\nvar empty_image:i = blank_image(0, 0, 0, 255, width, height);"
+        => image_functions::blank_image;
+    darken(image: Image = "an image id", value: Number = "a brightness value to reduce(0-255)")
+        -> Image = "an image id",
+        explanation: "darken a target image with given a given value by reducing red, green and blue channel all by the given value. The given value should be within 0-255, a channel whose value surpasses the limit will be adjusted to boudary 0 or 255 accordingly",
+        usage: "var darkened_image:i = darken(squirrel_image, 50);"
+        => image_functions::darken;
+    lighten(image: Image = "an image id", value: Number = "a brightness value to add(0-255)")
+        -> Image = "an image id",
+        explanation: "lighten a target image by increasing red, green and blue channel all by the given value. A channel whose value surpasses 255 will be clamped to 255.",
+        usage: "var lightened_image:i = lighten(squirrel_image, 50);"
+        => image_functions::lighten;
+    grayscale(image: Image = "an image id") -> Image = "an image id",
+        explanation: "Convert an image into a grayscale image. The new pixel value becomes the average of the original RGB value each multiplied with a coefficient.",
+        usage: "var grayscale_image:i = grayscale(colorful_image);"
+        => image_functions::grayscale;
+    invert_color(image: Image = "an image id") -> Image = "an image id",
+        explanation: "Invert the color an image. Value in each RGB channel will be 255 - current value.",
+        usage: "var inverted_image:i = invert_color(original_image);"
+        => image_functions::invert_color;
+    flip_horizontal(image: Image = "an image id") -> Image = "an image id",
+        explanation: "Flip the image horizontally.",
+        usage: "var flipped_image:i = flip_horizontal(original_image);"
+        => image_functions::flip_horizontal;
+    flip_vertical(image: Image = "an image id") -> Image = "an image id",
+        explanation: "Flip the image vertically.",
+        usage: "var flipped_image:i = flip_vertical(original_image);"
+        => image_functions::flip_vertical;
+    mask_channel_r(image: Image = "an image id") -> Image = "an image id",
+        explanation: "Mask the red channel to show only blue and green channel.",
+        usage: "var masked_image:i = mask_channel_r(original_image);"
+        => image_functions::mask_channel_r;
+    mask_channel_g(image: Image = "an image id") -> Image = "an image id",
+        explanation: "Mask the green channel to show only blue and red channel.",
+        usage: "var masked_image:i = mask_channel_g(original_image);"
+        => image_functions::mask_channel_g;
+    mask_channel_b(image: Image = "an image id") -> Image = "an image id",
+        explanation: "Mask the blue channel to show only red and green channel.",
+        usage: "var masked_image:i = mask_channel_b(original_image);"
+        => image_functions::mask_channel_b;
+    noise_perlin(image: Image = "an image id") -> Image = "an image id",
+        explanation: "Generate a new image with perlin noise from an existing image.",
+        usage: "var noise_image:i = noise_perlin(original_image);"
+        => image_functions::noise_perlin;
+    blur(image: Image = "an image id") -> Image = "an image id",
+        explanation: "Blur an image with box blur.",
+        usage: "var blur_image:i = blur(original_image);"
+        => image_functions::blur;
+    blur_gaussian(image: Image = "an image id", radius: Number = "blur radius")
+        -> Image = "an image id",
+        explanation: "Performs gaussian blur on an image.",
+        usage: "var blur_image:i = blur_gaussian(original_image, 30);"
+        => image_functions::blur_gaussian;
+    sharpen(image: Image = "an image id") -> Image = "an image id",
+        explanation: "Sharpen an image.",
+        usage: "var sharpen_image:i = sharpen(original_image);"
+        => image_functions::sharpen;
+    overlay(image_1: Image = "an image id", image_2: Image = "an image id to blend on top")
+        -> Image = "an image id",
+        explanation: "Blend two images with the overlay blend mode.",
+        usage: "var blended_image:i = overlay(base_image, detail_image);"
+        => image_functions::overlay;
+    multiply(image_1: Image = "an image id", image_2: Image = "an image id to blend on top")
+        -> Image = "an image id",
+        explanation: "Blend two images with the multiply blend mode.",
+        usage: "var blended_image:i = multiply(base_image, shadow_image);"
+        => image_functions::multiply;
+    pack(
+        r: Image or -1 = "an image id for the red channel, -1 to leave it black",
+        g: Image or -1 = "an image id for the green channel, -1 to leave it black",
+        b: Image or -1 = "an image id for the blue channel, -1 to leave it black",
+        a: Image or -1 = "an image id for the alpha channel, -1 to leave it opaque"
+    ) -> Image = "an image id",
+        explanation: "Pack the brightness of grayscale images into the channels of one texture, e.g. occlusion, roughness and metalness into an ORM map. Channels can be named and left out, the images are resized to the first one.",
+        usage: "var orm:i = pack(r: ao_image, g: roughness_image, b: metalness_image);"
+        => image_functions::pack;
+    specular_to_base_color(albedo: Image = "an albedo image id", specular: Image or -1 = "a specular image id, -1 for a dielectric")
+        -> Image = "an image id",
+        explanation: "Derive the base color of a metalness material from the albedo and specular color of a specular material.",
+        usage: "var base_color:i = specular_to_base_color(albedo_image, specular_image);"
+        => material_functions::specular_to_base_color;
+    specular_to_metalness(albedo: Image = "an albedo image id", specular: Image or -1 = "a specular image id, -1 for a dielectric")
+        -> Image = "an image id",
+        explanation: "Estimate the metalness of a surface from the albedo and specular color of a specular material.",
+        usage: "var metalness:i = specular_to_metalness(albedo_image, specular_image);"
+        => material_functions::specular_to_metalness;
+    metalness_to_albedo(base_color: Image = "a base color image id", metalness: Image or -1 = "a metalness image id, -1 for a dielectric")
+        -> Image = "an image id",
+        explanation: "Derive the albedo of a specular material from the base color and metalness of a metalness material.",
+        usage: "var albedo:i = metalness_to_albedo(base_color_image, metalness_image);"
+        => material_functions::metalness_to_albedo;
+    metalness_to_specular(base_color: Image = "a base color image id", metalness: Image or -1 = "a metalness image id, -1 for a dielectric")
+        -> Image = "an image id",
+        explanation: "Derive the specular color of a specular material from the base color and metalness of a metalness material.",
+        usage: "var specular:i = metalness_to_specular(base_color_image, metalness_image);"
+        => material_functions::metalness_to_specular;
+    blend_channel(image_1: Image or -1 = "an image id", image_2: Image or -1 = "an image id to blend in", mask: Image or -1 = "a mask image id, white picks image_2, -1 to mix evenly")
+        -> Image = "an image id",
+        explanation: "Interpolate between two images, weighted by the brightness of a mask.",
+        usage: "var albedo:i = blend_channel(rock_albedo, moss_albedo, moss_mask);"
+        => material_functions::blend_channel;
+    blend_min(image_1: Image or -1 = "an image id", image_2: Image or -1 = "an image id to blend in", mask: Image or -1 = "a mask image id, -1 to blend halfway")
+        -> Image = "an image id",
+        explanation: "Blend an image towards the darker of both images where the mask is white, e.g. to combine ambient occlusion.",
+        usage: "var ao:i = blend_min(rock_ao, moss_ao, moss_mask);"
+        => material_functions::blend_min;
+    blend_max(image_1: Image or -1 = "an image id", image_2: Image or -1 = "an image id to blend in", mask: Image or -1 = "a mask image id, -1 to blend halfway")
+        -> Image = "an image id",
+        explanation: "Blend an image towards the brighter of both images where the mask is white, e.g. to combine heights.",
+        usage: "var height:i = blend_max(rock_height, moss_height, moss_mask);"
+        => material_functions::blend_max;
+    blend_normal(normal_1: Image or -1 = "a normal map id", normal_2: Image or -1 = "a normal map id to blend in", mask: Image or -1 = "a mask image id, white picks normal_2, -1 to mix evenly")
+        -> Image = "an image id",
+        explanation: "Interpolate between two normal maps and renormalize the result.",
+        usage: "var normal:i = blend_normal(rock_normal, moss_normal, moss_mask);"
+        => material_functions::blend_normal;
+    layer_normal(base: Image or -1 = "a normal map id", detail: Image or -1 = "a detail normal map id", mask: Image or -1 = "a mask image id, white applies the full detail, -1 for half of it")
+        -> Image = "an image id",
+        explanation: "Add the bumps of a detail normal map onto a base normal map with reoriented normal blending.",
+        usage: "var normal:i = layer_normal(rock_normal, scratches_normal, scratches_mask);"
+        => material_functions::layer_normal;
+    height_mask(height_1: Image = "a height map id", height_2: Image or -1 = "a height map id", contrast: Number = "sharpness of the transition, 1 or more")
+        -> Image = "an image id",
+        explanation: "Mask that is white where the second surface is higher than the first one, for height based blending.",
+        usage: "var mask:i = height_mask(rock_height, sand_height, 4);"
+        => material_functions::height_mask;
+    assert(
+        value: Number = "a number, the assertion fails when it's 0",
+        line: Number = "line of the call, added by the compiler",
+        column: Number = "column of the call, added by the compiler"
+    ),
+        explanation: "Only in test blocks: fail the test when the value is 0, e.g. a comparison that doesn't hold. The test goes on after a failed assertion.",
+        usage: "test \"size\" {\n    assert(width == 128);\n};"
+        => testing::assert;
+    assert_similar(
+        image_1: Image = "an image id",
+        image_2: Image = "an image id to compare with",
+        tolerance: Number = "largest difference of a channel that still counts as equal (0-255)",
+        line: Number = "line of the call, added by the compiler",
+        column: Number = "column of the call, added by the compiler"
+    ),
+        explanation: "Only in test blocks: fail the test when the images have different sizes or a channel of a pixel differs by more than the tolerance.",
+        usage: "test \"inverted twice\" {\n    assert_similar(invert_color(invert_color(rock)), rock, 0);\n};"
+        => testing::assert_similar;
+}
+
+thread_local! {
+    // built on first use, and again after the plugins changed
+    static LIBRARY_FUNCTIONS: RefCell<Option<Rc<Vec<LibraryFunction>>>> = RefCell::new(None);
+}
+
+// built-in functions followed by the ones registered at runtime
+pub fn library_functions() -> Rc<Vec<LibraryFunction>> {
+    LIBRARY_FUNCTIONS.with(|functions| {
+        functions
+            .borrow_mut()
+            .get_or_insert_with(|| {
+                let mut functions = builtin_functions();
+                functions.extend(registered_functions());
+                Rc::new(functions)
+            })
+            .clone()
+    })
+}
+
+// called by the plugin registry whenever a plugin is added or removed
+pub fn invalidate_library_functions() {
+    LIBRARY_FUNCTIONS.with(|functions| *functions.borrow_mut() = None);
+}
+
+pub fn find_library_function(name: &str) -> Option<LibraryFunction> {
+    library_functions().iter().find(|f| f.name == name).cloned()
+}
+
+pub fn host_abi() -> HostAbi {
+    HostAbi {
+        abi_version: ABI_VERSION,
+        functions: library_functions()
+            .iter()
+            .map(|f| f.import_info())
+            .collect(),
+    }
+}
+
+// dispatch used by the generated js import object
+#[wasm_bindgen]
+pub fn library_call(name: &str, args: Vec<i32>) -> i32 {
+    match shared_session().call(name, &args) {
+        Ok(result) => result,
+        Err(e) => {
+            log(&format!("Error: {}", e));
+            -1
+        }
+    }
+}
+
+// [{module, name, params, results}] to build the "env" import object from
+#[wasm_bindgen]
+pub fn library_host_abi() -> JsValue {
+    to_js_value(&host_abi())
+}
+
+// image_processing_functions entries of references.json
+#[wasm_bindgen]
+pub fn library_references() -> JsValue {
+    let references: Vec<Reference> = library_functions().iter().map(|f| f.reference()).collect();
+    to_js_value(&references)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dispatch_checks_the_argument_count() {
+        let mut library = ImageLibrary::new();
+        let image = library.add_image("".to_string(), 1, 1, vec![10, 20, 30, 255]);
+        let invert = find_library_function("invert_color").unwrap();
+        assert!(invert.dispatch(&mut library, &[]).is_err());
+        assert!(invert.dispatch(&mut library, &[image, 1]).is_err());
+        let inverted = invert.dispatch(&mut library, &[image]).unwrap();
+        assert_eq!(
+            library.get_image_data(inverted).unwrap().pixels,
+            vec![245, 235, 225, 255]
+        );
+    }
+
+    #[test]
+    fn unknown_images_are_errors() {
+        let mut library = ImageLibrary::new();
+        let image = library.add_image("".to_string(), 1, 1, vec![10, 20, 30, 255]);
+        let invert = find_library_function("invert_color").unwrap();
+        assert_eq!(
+            invert.dispatch(&mut library, &[7]),
+            Err("invert_color() got 7, which is not an image".to_string())
+        );
+        assert!(invert.dispatch(&mut library, &[-1]).is_err());
+
+        // only the params that may be left out take -1
+        let convert = find_library_function("specular_to_base_color").unwrap();
+        assert!(convert.dispatch(&mut library, &[image, -1]).is_ok());
+        assert!(convert.dispatch(&mut library, &[-1, image]).is_err());
+    }
+}
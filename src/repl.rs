@@ -0,0 +1,167 @@
+// runs a program a piece at a time, every piece sees the variables and images of the pieces
+// before it: the compiler continues in a Scope and every module gets the same memory
+use super::compiler::{Inputs, Scope, Variable, VariableKind, MAX_PAGES};
+use super::diagnostic::{Diagnostic, Kind};
+use super::image_library::ImageData;
+use super::manifest::{Manifest, MaterialManifest, MANIFEST_VERSION};
+use super::session::{Session, StatementTiming};
+use super::wasm_plugin::plugin_store;
+use std::convert::TryInto;
+use wasmer::{Memory, MemoryType, Store};
+
+pub struct Repl {
+    session: Session,
+    scope: Scope,
+    store: Store,
+    memory: Memory,
+    manifest: Manifest, // of the last piece, it lists every material so far
+}
+
+impl Repl {
+    // inputs are available to the first piece by their name
+    pub fn new(inputs: Vec<ImageData>) -> Result<Self, String> {
+        let store = plugin_store();
+        let memory = match Memory::new(&store, MemoryType::new(1, Some(MAX_PAGES), false)) {
+            Ok(memory) => memory,
+            Err(e) => return Err(format!("failed to create the memory: {}", e)),
+        };
+        let mut session = Session::new();
+        let scope = Scope::new(&Inputs {
+            images: inputs.iter().map(|image| image.name.clone()).collect(),
+        });
+        for image in inputs {
+            session.add_image(image.name, image.width, image.height, image.pixels);
+        }
+        let mut repl = Repl {
+            session,
+            scope,
+            store,
+            memory,
+            manifest: Manifest {
+                version: MANIFEST_VERSION,
+                materials: vec![],
+            },
+        };
+        // the first piece declares the inputs
+        if let Err(diagnostics) = repl.eval("") {
+            let messages: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+            return Err(messages.join(", "));
+        }
+        Ok(repl)
+    }
+
+    // compile and run the next piece, variables keep their values when either fails
+    pub fn eval(&mut self, src: &str) -> Result<Vec<StatementTiming>, Vec<Diagnostic>> {
+        let (module, scope) = self.scope.compile(src)?;
+        // a piece that traps may already have stored some of its values
+        let snapshot: Vec<u8> = self
+            .memory
+            .view::<u8>()
+            .iter()
+            .map(|cell| cell.get())
+            .collect();
+        match self
+            .session
+            .execute(&self.store, &module, Some(&self.memory), &[])
+        {
+            Ok((manifest, timings, _)) => {
+                self.scope = scope;
+                self.manifest = manifest;
+                Ok(timings)
+            }
+            Err(e) => {
+                for (cell, byte) in self.memory.view::<u8>().iter().zip(snapshot) {
+                    cell.set(byte);
+                }
+                Err(vec![Diagnostic::new(Kind::Runtime, e)])
+            }
+        }
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    pub fn variables(&self) -> Vec<Variable> {
+        self.scope.variables()
+    }
+
+    pub fn variable(&self, name: &str) -> Option<Variable> {
+        self.variables().into_iter().find(|v| v.name == name)
+    }
+
+    // the number or image id a variable holds, None for materials
+    pub fn value(&self, variable: &Variable) -> Option<i32> {
+        match (&variable.kind, variable.offset) {
+            (VariableKind::Material(_), _) | (_, None) => None,
+            (_, Some(offset)) => {
+                let start = offset as usize;
+                let bytes: Vec<u8> = self.memory.view::<u8>()[start..start + 4]
+                    .iter()
+                    .map(|cell| cell.get())
+                    .collect();
+                Some(i32::from_le_bytes(bytes.as_slice().try_into().unwrap()))
+            }
+        }
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    pub fn material(&self, name: &str) -> Option<&MaterialManifest> {
+        self.manifest.materials.iter().find(|m| m.name == name)
+    }
+
+    // an image variable or a channel of a material, e.g. "wall.normal"
+    pub fn image(&self, name: &str) -> Option<&ImageData> {
+        let parts: Vec<&str> = name.splitn(2, '.').collect();
+        let id = match parts.as_slice() {
+            [material, channel] => {
+                self.material(material)?
+                    .channels
+                    .iter()
+                    .find(|c| &c.name == channel)?
+                    .image?
+            }
+            _ => {
+                let variable = self.variable(name)?;
+                if variable.kind != VariableKind::Image {
+                    return None;
+                }
+                self.value(&variable)?
+            }
+        };
+        self.session.image(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diffuse(repl: &Repl) -> Option<i32> {
+        repl.material("wall").unwrap().channels[0].image
+    }
+
+    #[test]
+    fn failed_pieces_leave_the_variables_alone() {
+        let mut repl = Repl::new(vec![]).unwrap();
+        repl.eval(
+            r#"var wall:m = new_material("PBRMetalness", 2, 2);
+               wall.diffuse = blank_image(0, 0, 0, 255, 2, 2);
+               var x:n = 1;"#,
+        )
+        .unwrap();
+        let before = diffuse(&repl);
+        // the channel is stored in memory right away, before the division traps
+        let piece = "wall.diffuse = blank_image(9, 9, 9, 255, 2, 2); var zero:n = 0; x = 3 / zero;";
+        assert!(repl.eval(piece).is_err());
+        assert!(repl.eval("x = undefined;").is_err());
+        repl.eval("x = x + 1;").unwrap();
+        assert_eq!(diffuse(&repl), before);
+        let x = repl.variable("x").unwrap();
+        assert_eq!(repl.value(&x), Some(2));
+        assert!(repl.variable("zero").is_none());
+    }
+}
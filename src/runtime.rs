@@ -0,0 +1,232 @@
+// runs compiled modules natively with wasmer, the headless counterpart of the run button in
+// js/App.js: every "env" import calls the library function of the registry directly, main()
+// runs and the results are read back through the exported manifest.
+use super::abi::{check_host_abi, AbiMetadata};
+use super::cache::{cached_call, CallCache};
+use super::compiler::{CompiledModule, STATEMENT_EXPORT_NAME};
+use super::diagnostic::{collect, Diagnostic, Kind};
+use super::export::{export_materials, ExportOptions};
+use super::image_library::ImageLibrary;
+use super::manifest::{Manifest, MANIFEST_EXPORT_NAME};
+use super::registry::{find_library_function, host_abi, LibraryFunction};
+use super::session::{Output, Session, StatementTiming};
+use super::testing::{TestResult, TEST_EXPORT_PREFIX};
+use super::wasm_plugin::plugin_store;
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use wasmer::{
+    Exports, Function, FunctionType, Global, ImportObject, Instance, Memory, Module, Pages,
+    RuntimeError, Store, Type, Value,
+};
+
+// what the host functions work on while the instance lives
+struct Host {
+    library: ImageLibrary,
+    cache: Option<CallCache>,
+    statement: Option<Global>,
+    timings: Vec<StatementTiming>,
+}
+
+impl Host {
+    fn call(&mut self, function: &LibraryFunction, args: &[i32]) -> Result<i32, String> {
+        let start = Instant::now();
+        let (result, cached) = cached_call(&mut self.library, self.cache.as_mut(), function, args)?;
+
+        let index = match &self.statement {
+            Some(global) => global.get().unwrap_i32(),
+            None => -1,
+        };
+        if let Some(timing) = self.timings.get_mut(index as usize) {
+            timing.duration += start.elapsed();
+            timing.calls += 1;
+            if cached {
+                timing.cached += 1;
+            }
+        }
+        Ok(result)
+    }
+}
+
+fn host_function(store: &Store, name: &str, host: &Rc<RefCell<Host>>) -> Result<Function, String> {
+    let function = match find_library_function(name) {
+        Some(function) => function,
+        None => return Err(format!("host function {:?} doesn't exist", name)),
+    };
+    let signature = FunctionType::new(
+        vec![Type::I32; function.params.len()],
+        if function.returns.is_some() {
+            vec![Type::I32]
+        } else {
+            vec![]
+        },
+    );
+    let host = host.clone();
+    Ok(Function::new(store, &signature, move |args: &[Value]| {
+        let args: Vec<i32> = args.iter().map(|arg| arg.unwrap_i32()).collect();
+        // a panic must not unwind through the module, it traps instead
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            host.borrow_mut().call(&function, &args)
+        }));
+        match result {
+            Ok(Ok(result)) if function.returns.is_some() => Ok(vec![Value::I32(result)]),
+            Ok(Ok(_)) => Ok(vec![]),
+            Ok(Err(e)) => Err(RuntimeError::new(e)),
+            Err(_) => Err(RuntimeError::new(format!(
+                "{}{:?} panicked",
+                function.name, args
+            ))),
+        }
+    }))
+}
+
+// memory: what the module imports as "env" "mem", for modules compiled in a Scope
+// tests: the test blocks to run after main(), each one sees the variables main() left
+fn execute(
+    store: &Store,
+    module: &CompiledModule,
+    host: &Rc<RefCell<Host>>,
+    memory: Option<&Memory>,
+    tests: &[String],
+) -> Result<(Manifest, Vec<TestResult>), String> {
+    let metadata = AbiMetadata::from_wasm(&module.wasm)?;
+    if let Err(mismatches) = check_host_abi(&metadata, &host_abi()) {
+        let mismatches: Vec<String> = mismatches.iter().map(|m| m.to_string()).collect();
+        return Err(mismatches.join(", "));
+    }
+
+    let wasm_module = match Module::new(store, &module.wasm) {
+        Ok(wasm_module) => wasm_module,
+        Err(e) => return Err(format!("failed to compile the module: {}", e)),
+    };
+    let mut env = Exports::new();
+    for import in &module.imports {
+        env.insert(
+            import.name.as_str(),
+            host_function(store, &import.name, host)?,
+        );
+    }
+    if let Some(memory) = memory {
+        let pages = Pages(module.layout.pages);
+        if memory.size() < pages {
+            if let Err(e) = memory.grow(pages - memory.size()) {
+                return Err(format!("failed to grow the memory: {}", e));
+            }
+        }
+        env.insert("mem", memory.clone());
+    }
+    let mut imports = ImportObject::new();
+    imports.register("env", env);
+    let instance = match Instance::new(&wasm_module, &imports) {
+        Ok(instance) => instance,
+        Err(e) => return Err(format!("failed to instantiate the module: {}", e)),
+    };
+    host.borrow_mut().statement = instance
+        .exports
+        .get_global(STATEMENT_EXPORT_NAME)
+        .ok()
+        .cloned();
+
+    let call = |name: &str| match instance.exports.get_function(name) {
+        Ok(function) => match function.call(&[]) {
+            Ok(result) => Ok(result),
+            Err(e) => Err(format!("{}() failed: {}", name, e)),
+        },
+        Err(_) => Err(format!("the module doesn't export {:?}", name)),
+    };
+    call("main")?;
+    let pointer = match call(MANIFEST_EXPORT_NAME)?.first() {
+        Some(Value::I32(pointer)) => *pointer as u32,
+        _ => return Err(format!("{}() should return an i32", MANIFEST_EXPORT_NAME)),
+    };
+    let memory = match instance.exports.get_memory("mem") {
+        Ok(memory) => memory,
+        Err(_) => return Err("the module doesn't export \"mem\"".to_string()),
+    };
+    let bytes: Vec<u8> = memory.view::<u8>().iter().map(|cell| cell.get()).collect();
+    let manifest = Manifest::decode(&bytes, pointer)?;
+
+    // calls of the tests aren't timed
+    host.borrow_mut().statement = None;
+    let mut results: Vec<TestResult> = vec![];
+    for name in tests {
+        let (result, mut failures) = collect(|| call(&format!("{}{}", TEST_EXPORT_PREFIX, name)));
+        if let Err(e) = result {
+            failures.push(Diagnostic::new(Kind::Runtime, e));
+        }
+        results.push(TestResult {
+            name: name.clone(),
+            failures,
+        });
+    }
+    Ok((manifest, results))
+}
+
+impl Session {
+    // input images have to be added beforehand, the images main() creates stay in the session
+    pub fn run(
+        &mut self,
+        module: &CompiledModule,
+        options: &ExportOptions,
+    ) -> Result<Output, String> {
+        let result = self.execute(&plugin_store(), module, None, &[]);
+        if let (Ok(_), Some(cache)) = (&result, self.cache_mut()) {
+            cache.prune();
+        }
+
+        let (manifest, timings, _) = result?;
+        Ok(Output {
+            textures: self.export_textures(&module.textures),
+            materials: export_materials(self.library_mut(), &manifest, options),
+            manifest,
+            timings,
+        })
+    }
+
+    // run main() and then every test block, the images the tests create stay in the session too
+    pub fn test(&mut self, module: &CompiledModule) -> Result<Vec<TestResult>, String> {
+        self.execute(&plugin_store(), module, None, &module.tests)
+            .map(|(_, _, results)| results)
+    }
+
+    // run main() and the given tests with the library and the cache handed to the host functions
+    pub(crate) fn execute(
+        &mut self,
+        store: &Store,
+        module: &CompiledModule,
+        memory: Option<&Memory>,
+        tests: &[String],
+    ) -> Result<(Manifest, Vec<StatementTiming>, Vec<TestResult>), String> {
+        // host functions hold on to the library while the instance lives
+        let host = Rc::new(RefCell::new(Host {
+            library: std::mem::replace(self.library_mut(), ImageLibrary::new()),
+            cache: self.cache_mut().take(),
+            statement: None,
+            timings: module
+                .statements
+                .iter()
+                .map(|statement| StatementTiming {
+                    statement: statement.clone(),
+                    duration: Duration::default(),
+                    calls: 0,
+                    cached: 0,
+                })
+                .collect(),
+        }));
+        let result = execute(store, module, &host, memory, tests);
+        let host = std::mem::replace(
+            &mut *host.borrow_mut(),
+            Host {
+                library: ImageLibrary::new(),
+                cache: None,
+                statement: None,
+                timings: vec![],
+            },
+        );
+        *self.library_mut() = host.library;
+        *self.cache_mut() = host.cache;
+        let timings = host.timings;
+        result.map(|(manifest, results)| (manifest, timings, results))
+    }
+}
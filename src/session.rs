@@ -0,0 +1,194 @@
+// the images of one run and everything that reads or writes them
+use super::cache::CallCache;
+use super::compiler::StatementSource;
+use super::diagnostic::{to_js_value, Diagnostic};
+use super::engine_export::{export_profile, ExportFile, ExportProfile, ProfileOptions};
+use super::export::{export_materials, ExportOptions};
+use super::gltf::{write_gltf, GltfOptions};
+use super::image_library::{ImageData, ImageLibrary};
+use super::log_rule;
+use super::manifest::{Manifest, MaterialManifest};
+use super::registry::find_library_function;
+use super::validate::validate_materials;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use wasm_bindgen::prelude::*;
+
+log_rule!();
+
+// the results of Session::run or Session::interpret
+pub struct Output {
+    // name -> image of every show()n texture
+    pub textures: HashMap<String, ImageData>,
+    pub manifest: Manifest,
+    // material name -> channel name -> image, see export_materials
+    pub materials: HashMap<String, HashMap<String, ImageData>>,
+    // one per top-level statement, in program order
+    pub timings: Vec<StatementTiming>,
+}
+
+// time spent in the library calls of a statement
+#[derive(Debug, Clone)]
+pub struct StatementTiming {
+    pub statement: StatementSource,
+    pub duration: Duration,
+    pub calls: u32,
+    pub cached: u32, // calls answered by the session's cache
+}
+
+pub struct Session {
+    library: ImageLibrary,
+    cache: Option<CallCache>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session {
+            library: ImageLibrary::new(),
+            cache: None,
+        }
+    }
+
+    // a session that keeps the image results of run() across reset(), see CallCache
+    pub fn with_cache() -> Self {
+        Session {
+            library: ImageLibrary::new(),
+            cache: Some(CallCache::new()),
+        }
+    }
+
+    pub fn cache(&self) -> Option<&CallCache> {
+        self.cache.as_ref()
+    }
+
+    pub fn cache_mut(&mut self) -> &mut Option<CallCache> {
+        &mut self.cache
+    }
+
+    pub fn library(&self) -> &ImageLibrary {
+        &self.library
+    }
+
+    pub fn library_mut(&mut self) -> &mut ImageLibrary {
+        &mut self.library
+    }
+
+    // inputs have to be added in the order of Inputs::images, returns the image id
+    pub fn add_image(&mut self, name: String, width: i32, height: i32, pixels: Vec<u8>) -> i32 {
+        self.library.add_image(name, width, height, pixels)
+    }
+
+    pub fn image(&self, id: i32) -> Option<&ImageData> {
+        self.library.get_image_data(id)
+    }
+
+    // the cache is kept
+    pub fn reset(&mut self) {
+        self.library.reset();
+    }
+
+    // run the library function a compiled module imports as "env" "name", also what library_call
+    // dispatches to, so the arguments are checked
+    pub fn call(&mut self, name: &str, args: &[i32]) -> Result<i32, String> {
+        match find_library_function(name) {
+            Some(function) => function.dispatch(&mut self.library, args),
+            None => Err(format!("host function {:?} doesn't exist", name)),
+        }
+    }
+
+    // textures: CompiledModule::textures
+    pub fn export_textures(&self, textures: &HashMap<String, i32>) -> HashMap<String, ImageData> {
+        self.library.export(textures)
+    }
+
+    pub fn validate_materials(&self, manifest: &Manifest) -> Vec<Diagnostic> {
+        validate_materials(&self.library, manifest)
+    }
+
+    pub fn export_materials(
+        &mut self,
+        manifest: &Manifest,
+        options: &ExportOptions,
+    ) -> HashMap<String, HashMap<String, ImageData>> {
+        export_materials(&mut self.library, manifest, options)
+    }
+
+    pub fn export_gltf(
+        &mut self,
+        material: &MaterialManifest,
+        options: &GltfOptions,
+    ) -> Result<Vec<u8>, String> {
+        write_gltf(&mut self.library, material, options)
+    }
+
+    pub fn export_profile(
+        &mut self,
+        material: &MaterialManifest,
+        profile: ExportProfile,
+        options: &ProfileOptions,
+    ) -> Result<Vec<ExportFile>, String> {
+        export_profile(&mut self.library, material, profile, options)
+    }
+}
+
+lazy_static! {
+    // the session behind the wasm-bindgen functions, see shared_session
+    static ref SESSION: Mutex<Session> = Mutex::new(Session::new());
+}
+
+// a binding that panicked while holding the session poisons the lock, the images are still
+// fine though and the next call goes on with them instead of panicking as well
+pub fn shared_session() -> MutexGuard<'static, Session> {
+    SESSION
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[wasm_bindgen]
+pub fn library_add_image(name: String, width: i32, height: i32, pixels: Vec<u8>) {
+    shared_session().add_image(name, width, height, pixels);
+}
+
+// export_info: {name: image_id}, returns {name: {name, width, height, pixels}}
+#[wasm_bindgen]
+pub fn library_export(export_info: &JsValue) -> JsValue {
+    let export_info: HashMap<String, i32> = match export_info.into_serde() {
+        Ok(export_info) => export_info,
+        Err(e) => {
+            log(&format!("Error: malformed export info: {}", e));
+            return JsValue::NULL;
+        }
+    };
+    to_js_value(&shared_session().export_textures(&export_info))
+}
+
+#[wasm_bindgen]
+pub fn library_reset() {
+    shared_session().reset();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn call_checks_the_argument_count() {
+        let mut session = Session::new();
+        assert!(session.call("invert_color", &[]).is_err());
+        assert!(session.call("no_such_function", &[0]).is_err());
+    }
+
+    #[test]
+    fn a_panic_doesnt_lock_out_the_bindings() {
+        let result = std::thread::spawn(|| {
+            let _session = shared_session();
+            panic!("a binding failed");
+        })
+        .join();
+        assert!(result.is_err());
+        assert!(SESSION.is_poisoned());
+        shared_session().reset();
+    }
+}
@@ -1,8 +1,10 @@
+use super::abi::MaterialExport;
+use super::compiler::UNALLOCATED;
+use super::material_types::{builtin_material_types, registered_material_types, MaterialType};
 use super::*;
 use id_arena::*;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use wasm_bindgen::prelude::*;
 
 log_rule!();
 
@@ -57,9 +59,9 @@ impl SymbolTable {
                 self.library_tracker
                     .add_image(Some(ident.clone()), image.clone());
             }
-            Attribute::Material(_, _, new_material_type) => {
+            Attribute::Material(_, offset, new_material_type) => {
                 self.library_tracker
-                    .update_material_type(ident, new_material_type.clone());
+                    .update_material(ident, offset, new_material_type.clone());
             }
             _ => {}
         }
@@ -67,6 +69,21 @@ impl SymbolTable {
         return Ok(());
     }
 
+    // replace an attribute without tracking it, for variables carried over from another module
+    pub fn rebind(&mut self, ident: &String, attr: Attribute) {
+        self.table.insert(ident.clone(), attr);
+    }
+
+    pub fn entries(&self) -> Vec<(String, Attribute)> {
+        let mut entries: Vec<(String, Attribute)> = self
+            .table
+            .iter()
+            .map(|(ident, attr)| (ident.clone(), attr.clone()))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
     pub fn remove(&mut self, ident: &String) {
         self.table.remove(ident);
     }
@@ -163,109 +180,104 @@ impl LibraryTracker {
         }
     }
 
-    pub fn export_textures(&self) -> JsValue {
-        JsValue::from_serde(&self.texture_exports).unwrap()
+    pub fn exported_textures(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.texture_exports.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn texture_exports(&self) -> HashMap<String, i32> {
+        self.texture_exports.clone()
     }
 
     pub fn add_material(&mut self, name: String, offset: u32, material_type: String) {
         self.materials.insert(name, (offset, material_type));
     }
 
-    pub fn update_material_type(&mut self, name: &String, new_material_type: String) {
-        // self.materials.insert(name, (offset, material_type));// name, (starting offset, type)
-        let old_info = self
-            .materials
-            .entry(name.to_string())
-            .or_insert((0, "".to_string()));
-        *old_info = (old_info.0, new_material_type);
+    pub fn update_material(&mut self, name: &String, offset: u32, new_material_type: String) {
+        self.materials
+            .insert(name.to_string(), (offset, new_material_type)); // name, (starting offset, type)
     }
 
-    pub fn export_materials(&mut self) -> JsValue {
-        let mut result: HashMap<i32, Vec<String>> = HashMap::new();
-        let align = 2;
-        for (name, (start, material_type)) in &self.materials {
-            if let Ok(channels) = self.material_info.get_material_channels(material_type) {
-                let mut position = (*start as i32) / u32::pow(2, align) as i32 + 3;
-                for channel in channels {
-                    result.insert(position, vec![name.clone(), channel, material_type.clone()]);
-                    position += 1;
-                }
-            }
-        }
+    pub fn exported_materials(&self) -> Vec<MaterialExport> {
+        let mut materials: Vec<MaterialExport> = self
+            .materials
+            .iter()
+            .map(|(name, (_, material_type))| MaterialExport {
+                name: name.clone(),
+                material_type: material_type.clone(),
+            })
+            .collect();
+        materials.sort_by(|a, b| a.name.cmp(&b.name));
+        materials
+    }
 
-        JsValue::from_serde(&result).unwrap()
+    // name, record offset, type of every material new_material() was called on, by name
+    pub fn allocated_materials(&self) -> Vec<(String, u32, String)> {
+        let mut materials: Vec<(String, u32, String)> = self
+            .materials
+            .iter()
+            .filter(|(_, (offset, _))| *offset != UNALLOCATED)
+            .map(|(name, (offset, material_type))| (name.clone(), *offset, material_type.clone()))
+            .collect();
+        materials.sort();
+        materials
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct MaterialInfo {
-    channel_info: HashMap<String, (i32, Vec<String>)>,
+    channel_info: HashMap<String, (i32, MaterialType)>, // name, (type id, definition)
 }
 
 impl MaterialInfo {
-    fn new() -> Self {
-        let mut channel_info: HashMap<String, (i32, Vec<String>)> = HashMap::new();
-        let mut id = 0;
-        channel_info.insert(
-            "PBRMetalness".to_string(),
-            (
-                id,
-                vec![
-                    "diffuse".to_string(),
-                    "metalness".to_string(),
-                    "normal".to_string(),
-                    "transparency".to_string(),
-                    "roughness".to_string(),
-                    "ao".to_string(),
-                    "displacement".to_string(),
-                    "emissive".to_string(),
-                    "cavity".to_string(),
-                    "subsurfance_scattering".to_string(),
-                ],
-            ),
-        );
-        id += 1;
-        channel_info.insert(
-            "PBRSpecular".to_string(),
-            (
-                id,
-                vec![
-                    "albedo".to_string(),
-                    "specular".to_string(),
-                    "normal".to_string(),
-                    "transparency".to_string(),
-                    "glosiness".to_string(),
-                    "ao".to_string(),
-                    "displacement".to_string(),
-                    "emissive".to_string(),
-                    "cavity".to_string(),
-                    "subsurfance_scattering".to_string(),
-                ],
-            ),
-        );
-        id += 1;
-        channel_info.insert(
-            "UnityStandardSpecular".to_string(),
-            (
-                id,
-                vec![
-                    "albedo".to_string(),
-                    "specular".to_string(),
-                    "normal".to_string(),
-                    "height".to_string(),
-                    "ao".to_string(),
-                    "emissive".to_string(),
-                ],
-            ),
-        );
-        id += 1;
-        return MaterialInfo { channel_info };
+    pub fn new() -> Self {
+        let mut material_info = MaterialInfo {
+            channel_info: HashMap::new(),
+        };
+        for material_type in builtin_material_types()
+            .into_iter()
+            .chain(registered_material_types())
+        {
+            // registered types replace built-in ones but keep their id
+            let id = match material_info.channel_info.get(&material_type.name) {
+                Some((id, _)) => *id,
+                None => material_info.channel_info.len() as i32,
+            };
+            material_info
+                .channel_info
+                .insert(material_type.name.clone(), (id, material_type));
+        }
+        return material_info;
+    }
+
+    // register a material type declared with `material_type Name { channel, ... }`
+    pub fn add_material_type(
+        &mut self,
+        name: &String,
+        channels: &Vec<String>,
+    ) -> Result<i32, String> {
+        if self.channel_info.contains_key(name) {
+            return Err(format!("material type {:?} is already defined", name));
+        }
+        let material_type = MaterialType::new(name, channels);
+        material_type.validate()?;
+
+        let id = self.channel_info.len() as i32;
+        self.channel_info.insert(name.clone(), (id, material_type));
+        Ok(id)
+    }
+
+    pub fn get_material_type(&self, material_type: &String) -> Option<&MaterialType> {
+        self.channel_info
+            .get(material_type)
+            .map(|(_, definition)| definition)
     }
 
     pub fn find_channel_index(&self, material_type: &String, channel: &String) -> Result<u32, ()> {
-        if let Some((_, channels)) = self.channel_info.get(material_type) {
-            for (i, v) in channels.iter().enumerate() {
-                if v == channel {
+        if let Some((_, definition)) = self.channel_info.get(material_type) {
+            for (i, v) in definition.channels.iter().enumerate() {
+                if &v.name == channel {
                     return Ok(i as u32);
                 }
             }
@@ -274,8 +286,8 @@ impl MaterialInfo {
     }
 
     pub fn get_material_channels(&self, material_type: &String) -> Result<Vec<String>, ()> {
-        if let Some((_, channels)) = self.channel_info.get(material_type) {
-            return Ok(channels.to_vec());
+        if let Some((_, definition)) = self.channel_info.get(material_type) {
+            return Ok(definition.channel_names());
         } else {
             return Err(());
         }
@@ -0,0 +1,216 @@
+// test blocks: every top-level `test "name" { ... }` becomes a function of its own, exported as
+// "test:name". tests run after main() and see its variables as main() left them, a failed
+// assertion is reported as a diagnostic at its position and the test goes on
+use super::ast::{Compile, Expr, Function, Statement};
+use super::compare::compare_images;
+use super::compiler::{Inputs, Memory, Scope, MAIN_PREFIX};
+use super::diagnostic::{position, report_at, Diagnostic};
+use super::image_library::ImageLibrary;
+use super::symbol::{Attribute, SymbolTable};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use walrus::{FunctionBuilder, InstrSeqBuilder};
+
+// library functions that can only be called in a test, the compiler adds their position as the
+// last two arguments
+pub const ASSERTIONS: [&str; 2] = ["assert", "assert_similar"];
+pub const TEST_EXPORT_PREFIX: &str = "test:";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TestResult {
+    pub name: String,
+    pub failures: Vec<Diagnostic>, // failed assertions, or why the test stopped
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+pub fn assert(_library: &mut ImageLibrary, value: i32, line: i32, column: i32) -> i32 {
+    if value == 0 {
+        report_at("assertion failed", line as usize, column as usize);
+    }
+    0
+}
+
+pub fn assert_similar(
+    library: &mut ImageLibrary,
+    image_1: i32,
+    image_2: i32,
+    tolerance: i32,
+    line: i32,
+    column: i32,
+) -> i32 {
+    let tolerance = u8::try_from(tolerance.max(0)).unwrap_or(u8::MAX);
+    let message = match (
+        library.get_image_data(image_1),
+        library.get_image_data(image_2),
+    ) {
+        (Some(a), Some(b)) => match compare_images(a, b, tolerance) {
+            Ok(difference) if difference.is_similar() => return 0,
+            Ok(difference) => format!(
+                "the images differ, {} pixels by more than {}, by up to {}",
+                difference.pixels, tolerance, difference.max
+            ),
+            Err(e) => e,
+        },
+        _ => "assert_similar() needs two images".to_string(),
+    };
+    report_at(
+        &format!("assertion failed: {}", message),
+        line as usize,
+        column as usize,
+    );
+    0
+}
+
+// an assertion of a test body, its arguments are checked like the ones of other calls
+fn compile_assertion(
+    module: &mut walrus::Module,
+    builder: &mut InstrSeqBuilder,
+    symbol_table: &mut SymbolTable,
+    memory: &mut Memory,
+    identifier: &str,
+    exprs: &[Box<Expr>],
+    (line, column): (usize, usize),
+) -> Result<(), String> {
+    let (function_id, params) = match symbol_table.lookup(&identifier.to_string()) {
+        Some(Attribute::Func(function_id, params, _)) => (*function_id, params.len()),
+        _ => return Err(format!("{}() isn't available", identifier)),
+    };
+    if exprs.len() + 2 != params {
+        return Err(format!(
+            "{}() should take {} parameters instead of {}",
+            identifier,
+            params - 2,
+            exprs.len()
+        ));
+    }
+    for expr in exprs {
+        let valid = match &**expr {
+            Expr::Number(_) | Expr::Call(_, _) | Expr::Op(_, _, _) => true,
+            Expr::Variable(name) => matches!(
+                symbol_table.lookup(name),
+                Some(Attribute::Number(_)) | Some(Attribute::Image(_, _))
+            ),
+            _ => false,
+        };
+        if !valid {
+            return Err(format!(
+                "{:?} can't be passed to {}(), it takes numbers and images",
+                expr, identifier
+            ));
+        }
+        expr.compile(module, builder, symbol_table, memory)?;
+    }
+    builder
+        .i32_const(line as i32)
+        .i32_const(column as i32)
+        .call(function_id);
+    Ok(())
+}
+
+fn compile_test(
+    module: &mut walrus::Module,
+    scope: &mut Scope,
+    symbol_table: &SymbolTable,
+    memory: &mut Memory,
+    src: &str,
+    name: &str,
+    statements: &[(Statement, usize, usize)],
+) -> Result<walrus::FunctionId, (String, usize)> {
+    let mut function_builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+    let mut builder = function_builder.func_body();
+    let mut symbol_table = symbol_table.clone();
+    scope.enter(module, &mut builder, &mut symbol_table, memory);
+
+    // materials of the script are in its manifest, a test can only change its own ones
+    let mut script_materials: Vec<String> = symbol_table
+        .entries()
+        .into_iter()
+        .filter(|(_, attr)| matches!(attr, Attribute::Material(_, _, _)))
+        .map(|(name, _)| name)
+        .collect();
+    for (statement, l, _) in statements {
+        let statement = statement.lower().map_err(|e| (e, *l))?;
+        match &statement {
+            Statement::Test(_, _) => {
+                return Err((format!("test {:?} can't contain another test", name), *l));
+            }
+            Statement::Call(identifier, exprs) if ASSERTIONS.contains(&identifier.as_str()) => {
+                let at = position(src, l - MAIN_PREFIX.len());
+                compile_assertion(
+                    module,
+                    &mut builder,
+                    &mut symbol_table,
+                    memory,
+                    identifier,
+                    exprs,
+                    at,
+                )
+                .map_err(|e| (e, *l))?;
+                continue;
+            }
+            Statement::Declare(identifier, _, _) => {
+                script_materials.retain(|material| material != identifier);
+            }
+            Statement::Assignment(identifiers, _) if matches!(identifiers.first(), Some(identifier) if script_materials.contains(identifier)) =>
+            {
+                return Err((
+                    format!(
+                        "test {:?} can't assign to {:?}, it belongs to the script; declare a \
+                         variable of the test instead",
+                        name, identifiers[0]
+                    ),
+                    *l,
+                ));
+            }
+            _ => {}
+        }
+        statement
+            .compile(module, &mut builder, &mut symbol_table, memory)
+            .map_err(|e| (e, *l))?;
+    }
+    Ok(function_builder.finish(vec![], &mut module.funcs))
+}
+
+// compile the tests of main after main() itself, builder is the body of main(): it keeps the
+// numbers and images in memory for the tests. returns the names of the tests in program order,
+// an error comes with the byte offset of the statement it's about
+pub fn compile_tests(
+    module: &mut walrus::Module,
+    builder: &mut InstrSeqBuilder,
+    symbol_table: &SymbolTable,
+    memory: &mut Memory,
+    src: &str,
+    main: &Function,
+) -> Result<Vec<String>, (String, usize)> {
+    let mut names: Vec<String> = vec![];
+    let mut scope: Option<Scope> = None;
+    for (statement, (l, _)) in main.statements.iter().zip(&main.spans) {
+        let (name, statements) = match statement {
+            Statement::Test(name, statements) => (name, statements),
+            _ => continue,
+        };
+        if names.contains(name) {
+            return Err((format!("there are two tests named {:?}", name), *l));
+        }
+        // the first test makes main() keep its variables
+        if scope.is_none() {
+            let mut new_scope = Scope::new(&Inputs { images: vec![] });
+            new_scope
+                .leave(builder, symbol_table, memory)
+                .map_err(|e| (e, *l))?;
+            scope = Some(new_scope);
+        }
+        let scope = scope.as_mut().unwrap();
+        let function_id = compile_test(module, scope, symbol_table, memory, src, name, statements)?;
+        module
+            .exports
+            .add(&format!("{}{}", TEST_EXPORT_PREFIX, name), function_id);
+        names.push(name.clone());
+    }
+    Ok(names)
+}
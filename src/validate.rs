@@ -0,0 +1,214 @@
+// checks a finished run's materials before they are exported
+use super::diagnostic::{to_js_value, Diagnostic, Severity};
+use super::image_library::{ImageData, ImageLibrary};
+use super::log_rule;
+use super::manifest::{Manifest, MaterialManifest};
+use super::session::shared_session;
+use wasm_bindgen::prelude::*;
+
+log_rule!();
+
+// normals whose length is further from 1 than this are malformed
+const NORMAL_LENGTH_TOLERANCE: f32 = 0.1;
+// share of malformed normals tolerated, e.g. for filtering artifacts
+const MALFORMED_NORMAL_SHARE: f32 = 0.05;
+// share of metalness pixels that may be neither close to black nor to white
+const PARTIAL_METALNESS_SHARE: f32 = 0.25;
+
+fn normal_length(pixel: &[u8]) -> f32 {
+    let decode = |c: u8| c as f32 / 255.0 * 2.0 - 1.0;
+    let (x, y, z) = (decode(pixel[0]), decode(pixel[1]), decode(pixel[2]));
+    (x * x + y * y + z * z).sqrt()
+}
+
+fn is_grayscale(image_data: &ImageData) -> bool {
+    image_data
+        .pixels
+        .chunks(4)
+        .all(|p| p[0] == p[1] && p[1] == p[2])
+}
+
+fn share<F>(image_data: &ImageData, f: F) -> f32
+where
+    F: Fn(&[u8]) -> bool,
+{
+    let count = (image_data.pixels.len() / 4).max(1);
+    image_data.pixels.chunks(4).filter(|p| f(p)).count() as f32 / count as f32
+}
+
+// value range checks of the channels built-in types share
+fn check_values(material: &str, channel: &str, image_data: &ImageData) -> Option<Diagnostic> {
+    match channel {
+        "normal" if is_grayscale(image_data) => Some(Diagnostic::material(
+            Severity::Warning,
+            material,
+            Some(channel),
+            "the normal map is grayscale, it was probably made from a height or color image"
+                .to_string(),
+        )),
+        "normal" => {
+            let malformed = share(image_data, |p| {
+                (normal_length(p) - 1.0).abs() > NORMAL_LENGTH_TOLERANCE
+            });
+            if malformed > MALFORMED_NORMAL_SHARE {
+                Some(Diagnostic::material(
+                    Severity::Warning,
+                    material,
+                    Some(channel),
+                    format!(
+                        "{:.0}% of the normals are not unit length",
+                        malformed * 100.0
+                    ),
+                ))
+            } else {
+                None
+            }
+        }
+        "metalness" => {
+            let partial = share(image_data, |p| p[0] > 25 && p[0] < 230);
+            if partial > PARTIAL_METALNESS_SHARE {
+                Some(Diagnostic::material(
+                    Severity::Warning,
+                    material,
+                    Some(channel),
+                    format!(
+                        "{:.0}% of the metalness is neither black nor white, surfaces are usually either metal or not",
+                        partial * 100.0
+                    ),
+                ))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+pub fn validate_material(library: &ImageLibrary, material: &MaterialManifest) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = vec![];
+    let name = material.name.as_str();
+
+    let power_of_two = |n: i32| n > 0 && n & (n - 1) == 0;
+    if !power_of_two(material.width) || !power_of_two(material.height) {
+        diagnostics.push(Diagnostic::material(
+            Severity::Warning,
+            name,
+            None,
+            format!(
+                "{}x{} is not a power of two, mipmaps and compression may not work",
+                material.width, material.height
+            ),
+        ));
+    }
+
+    for channel in &material.channels {
+        let image_data = match channel.image.and_then(|id| library.get_image_data(id)) {
+            Some(image_data) => image_data,
+            None => {
+                if let Some(id) = channel.image {
+                    diagnostics.push(Diagnostic::material(
+                        Severity::Error,
+                        name,
+                        Some(&channel.name),
+                        format!("image {:?} doesn't exist", id),
+                    ));
+                } else if channel.required {
+                    diagnostics.push(Diagnostic::material(
+                        Severity::Error,
+                        name,
+                        Some(&channel.name),
+                        format!("{:?} materials need this channel", material.material_type),
+                    ));
+                }
+                continue;
+            }
+        };
+
+        if image_data.width != material.width || image_data.height != material.height {
+            diagnostics.push(Diagnostic::material(
+                Severity::Error,
+                name,
+                Some(&channel.name),
+                format!(
+                    "the image is {}x{}, the material {}x{}",
+                    image_data.width, image_data.height, material.width, material.height
+                ),
+            ));
+        }
+        diagnostics.extend(check_values(name, &channel.name, image_data));
+    }
+    diagnostics
+}
+
+// the manifest says which channels are required, also for types declared in the program
+pub fn validate_materials(library: &ImageLibrary, manifest: &Manifest) -> Vec<Diagnostic> {
+    manifest
+        .materials
+        .iter()
+        .flat_map(|material| validate_material(library, material))
+        .collect()
+}
+
+// manifest: the result of decode_manifest, returns [{severity, material, channel, message}]
+#[wasm_bindgen]
+pub fn library_validate_materials(manifest: &JsValue) -> JsValue {
+    let manifest: Manifest = match manifest.into_serde() {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            log(&format!("Error: malformed manifest: {}", e));
+            return JsValue::NULL;
+        }
+    };
+    let diagnostics = shared_session().validate_materials(&manifest);
+    to_js_value(&diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::ChannelManifest;
+    use crate::material_types::ColorSpace;
+
+    // a type no registry knows, like one declared with material_type
+    fn material(image: Option<i32>, required: bool) -> MaterialManifest {
+        MaterialManifest {
+            name: "wall".to_string(),
+            material_type: "Plaster".to_string(),
+            width: 4,
+            height: 4,
+            channels: vec![ChannelManifest {
+                name: "diffuse".to_string(),
+                image,
+                default_color: None,
+                required,
+                color_space: ColorSpace::Srgb,
+                bit_depth: 8,
+            }],
+            packing: vec![],
+        }
+    }
+
+    #[test]
+    fn required_channels_come_from_the_manifest() {
+        let library = ImageLibrary::new();
+        let diagnostics = validate_material(&library, &material(None, true));
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(diagnostics[0].material.as_deref(), Some("wall"));
+        assert_eq!(diagnostics[0].channel.as_deref(), Some("diffuse"));
+        assert_eq!(diagnostics[0].line, None);
+        assert!(diagnostics[0]
+            .to_string()
+            .starts_with("Error: \"wall\".diffuse: "));
+        assert!(validate_material(&library, &material(None, false)).is_empty());
+    }
+
+    #[test]
+    fn images_of_another_size_are_errors() {
+        let mut library = ImageLibrary::new();
+        let id = library.add_image("".to_string(), 2, 2, vec![0; 16]);
+        let diagnostics = validate_material(&library, &material(Some(id), true));
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].message.contains("2x2"));
+    }
+}
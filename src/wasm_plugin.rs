@@ -0,0 +1,342 @@
+// filters shipped as separate .wasm files, executed natively with wasmer
+//
+// a filter module exports
+//   memory
+//   alloc(size: i32) -> i32                     reserve `size` bytes, return a pointer
+//   process(ptr: i32, width: i32, height: i32, ...numbers)
+// process() works in place on width * height RGBA8 pixels starting at ptr.
+//
+// a plugin manifest (json) describes the filters of a directory:
+// {
+//     "plugins": [
+//         {
+//             "name": "sepia",
+//             "wasm": "sepia.wasm",
+//             "params": [{"param_type": "Number", "description": "strength(0-255)"}],
+//             "explanation": "Tint an image with a sepia tone.",
+//             "usage": "var old_image:i = sepia(original_image, 200);"
+//         }
+//     ]
+// }
+// the image is always the first argument and the result is a new image, "params" lists
+// the extra number arguments that follow it.
+use super::image_library::ImageLibrary;
+use super::log_rule;
+use super::plugin::{register_functions, ImageFunction};
+use super::registry::{Param, ParamType};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use wasmer::{Instance, Memory, Module, Store, Value};
+use wasmer_compiler_cranelift::Cranelift;
+use wasmer_engine_jit::JIT;
+
+log_rule!();
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginManifest {
+    pub plugins: Vec<PluginEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginEntry {
+    pub name: String,
+    pub wasm: PathBuf,
+    #[serde(default)]
+    pub params: Vec<Param>,
+    #[serde(default)]
+    pub explanation: String,
+    #[serde(default)]
+    pub usage: String,
+}
+
+pub struct WasmFilter {
+    entry: PluginEntry,
+    instance: Instance,
+    memory: Memory,
+}
+
+impl WasmFilter {
+    pub fn new(store: &Store, entry: PluginEntry, wasm: &[u8]) -> Result<Self, String> {
+        if entry
+            .params
+            .iter()
+            .any(|param| param.param_type != ParamType::Number)
+        {
+            return Err(format!(
+                "plugin {:?} can only take numbers after the image",
+                entry.name
+            ));
+        }
+
+        let module = match Module::new(store, wasm) {
+            Ok(module) => module,
+            Err(e) => return Err(format!("failed to compile plugin {:?}: {}", entry.name, e)),
+        };
+        let instance = match Instance::new(&module, &wasmer::imports! {}) {
+            Ok(instance) => instance,
+            Err(e) => {
+                return Err(format!(
+                    "failed to instantiate plugin {:?}: {}",
+                    entry.name, e
+                ))
+            }
+        };
+        let memory = match instance.exports.get_memory("memory") {
+            Ok(memory) => memory.clone(),
+            Err(_) => return Err(format!("plugin {:?} doesn't export memory", entry.name)),
+        };
+        for export in ["alloc", "process"].iter() {
+            if instance.exports.get_function(export).is_err() {
+                return Err(format!(
+                    "plugin {:?} doesn't export function {:?}",
+                    entry.name, export
+                ));
+            }
+        }
+
+        Ok(WasmFilter {
+            entry,
+            instance,
+            memory,
+        })
+    }
+
+    fn process(
+        &self,
+        pixels: &[u8],
+        width: i32,
+        height: i32,
+        numbers: &[i32],
+    ) -> Result<Vec<u8>, String> {
+        let alloc = self.instance.exports.get_function("alloc").unwrap();
+        let ptr = match alloc.call(&[Value::I32(pixels.len() as i32)]) {
+            Ok(result) => match result.first() {
+                Some(Value::I32(ptr)) => *ptr,
+                _ => return Err("alloc() should return an i32 pointer".to_string()),
+            },
+            Err(e) => return Err(format!("alloc() failed: {}", e)),
+        };
+        // the plugin can return anything, also a negative pointer or one at the end of memory
+        let range = match usize::try_from(ptr) {
+            Ok(start) => match start.checked_add(pixels.len()) {
+                Some(end) if end <= self.memory.data_size() as usize => start..end,
+                _ => return Err("alloc() returned a pointer outside of memory".to_string()),
+            },
+            Err(_) => return Err(format!("alloc() returned a negative pointer {}", ptr)),
+        };
+
+        let view = self.memory.view::<u8>();
+        for (cell, byte) in view[range.clone()].iter().zip(pixels) {
+            cell.set(*byte);
+        }
+
+        let mut args = vec![Value::I32(ptr), Value::I32(width), Value::I32(height)];
+        args.extend(numbers.iter().map(|n| Value::I32(*n)));
+        let process = self.instance.exports.get_function("process").unwrap();
+        if let Err(e) = process.call(&args) {
+            return Err(format!("process() failed: {}", e));
+        }
+
+        // process() may have grown the memory, take a fresh view
+        let view = self.memory.view::<u8>();
+        match view.get(range) {
+            Some(cells) => Ok(cells.iter().map(|cell| cell.get()).collect()),
+            None => Err("process() left the image outside of memory".to_string()),
+        }
+    }
+}
+
+impl ImageFunction for WasmFilter {
+    fn name(&self) -> String {
+        self.entry.name.clone()
+    }
+
+    fn params(&self) -> Vec<Param> {
+        let mut params = vec![Param::new(ParamType::Image, "an image id")];
+        params.extend(self.entry.params.iter().cloned());
+        params
+    }
+
+    fn returns(&self) -> Option<Param> {
+        Some(Param::new(ParamType::Image, "an image id"))
+    }
+
+    fn explanation(&self) -> String {
+        self.entry.explanation.clone()
+    }
+
+    fn usage(&self) -> String {
+        self.entry.usage.clone()
+    }
+
+    fn call(&self, library: &mut ImageLibrary, args: &[i32]) -> i32 {
+        let image_data = match library.get_image_data(args[0]) {
+            Some(image_data) => image_data.clone(),
+            None => {
+                log(&format!(
+                    "Error: image {:?} passed to {:?} doesn't exist.",
+                    args[0], self.entry.name
+                ));
+                return -1;
+            }
+        };
+
+        match self.process(
+            &image_data.pixels,
+            image_data.width,
+            image_data.height,
+            &args[1..],
+        ) {
+            Ok(pixels) => {
+                library.add_image("".to_string(), image_data.width, image_data.height, pixels)
+            }
+            Err(e) => {
+                log(&format!("Error: plugin {:?}: {}", self.entry.name, e));
+                -1
+            }
+        }
+    }
+}
+
+pub fn plugin_store() -> Store {
+    Store::new(&JIT::new(&Cranelift::default()).engine())
+}
+
+// compile every filter listed in a manifest file, wasm paths are relative to the manifest
+pub fn load_plugin_manifest(
+    store: &Store,
+    manifest_path: &Path,
+) -> Result<Vec<WasmFilter>, String> {
+    let manifest = match std::fs::read_to_string(manifest_path) {
+        Ok(manifest) => manifest,
+        Err(e) => return Err(format!("failed to read {:?}: {}", manifest_path, e)),
+    };
+    let manifest: PluginManifest = match serde_json::from_str(&manifest) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            return Err(format!(
+                "malformed plugin manifest {:?}: {}",
+                manifest_path, e
+            ))
+        }
+    };
+    let base = manifest_path.parent().unwrap_or(Path::new("."));
+
+    let mut filters: Vec<WasmFilter> = vec![];
+    for entry in manifest.plugins {
+        let wasm_path = base.join(&entry.wasm);
+        let wasm = match std::fs::read(&wasm_path) {
+            Ok(wasm) => wasm,
+            Err(e) => return Err(format!("failed to read {:?}: {}", wasm_path, e)),
+        };
+        filters.push(WasmFilter::new(store, entry, &wasm)?);
+    }
+    Ok(filters)
+}
+
+// load a manifest and register its filters as library functions, nothing is registered when
+// one of them can't be
+pub fn register_plugin_manifest(manifest_path: &Path) -> Result<Vec<String>, String> {
+    let store = plugin_store();
+    let filters = load_plugin_manifest(&store, manifest_path)?;
+    let names: Vec<String> = filters.iter().map(|filter| filter.name()).collect();
+    register_functions(
+        filters
+            .into_iter()
+            .map(|filter| Rc::new(filter) as Rc<dyn ImageFunction>)
+            .collect(),
+    )?;
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::unregister_function;
+    use crate::registry::find_library_function;
+
+    fn identity_wat(ptr: i32) -> String {
+        format!(
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "alloc") (param i32) (result i32) i32.const {})
+                (func (export "process") (param i32 i32 i32)))"#,
+            ptr
+        )
+    }
+
+    fn entry(name: &str) -> PluginEntry {
+        PluginEntry {
+            name: name.to_string(),
+            wasm: PathBuf::from(format!("{}.wasm", name)),
+            params: vec![],
+            explanation: String::new(),
+            usage: String::new(),
+        }
+    }
+
+    // a plugin whose alloc() returns `ptr` and whose process() leaves the pixels as they are
+    fn filter(ptr: i32) -> WasmFilter {
+        let wasm = wat::parse_str(identity_wat(ptr)).unwrap();
+        WasmFilter::new(&plugin_store(), entry("identity"), &wasm).unwrap()
+    }
+
+    #[test]
+    fn process_checks_the_pointer_of_alloc() {
+        let pixels = [1, 2, 3, 4];
+        assert_eq!(filter(16).process(&pixels, 1, 1, &[]).unwrap(), pixels);
+        assert_eq!(filter(65532).process(&pixels, 1, 1, &[]).unwrap(), pixels);
+        assert!(filter(-4).process(&pixels, 1, 1, &[]).is_err());
+        assert!(filter(65533).process(&pixels, 1, 1, &[]).is_err());
+        assert!(filter(i32::MAX).process(&pixels, 1, 1, &[]).is_err());
+    }
+
+    #[test]
+    fn plugins_need_alloc_and_process() {
+        let wasm = wat::parse_str(
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "process") (param i32 i32 i32)))"#,
+        )
+        .unwrap();
+        assert_eq!(
+            WasmFilter::new(&plugin_store(), entry("identity"), &wasm).err(),
+            Some("plugin \"identity\" doesn't export function \"alloc\"".to_string())
+        );
+    }
+
+    #[test]
+    fn a_manifest_is_registered_all_or_none() {
+        let directory = std::env::temp_dir().join(format!("coocoo_plugins_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let wasm = wat::parse_str(identity_wat(16)).unwrap();
+        for name in &["keep", "pack"] {
+            std::fs::write(directory.join(format!("{}.wasm", name)), &wasm).unwrap();
+        }
+        let manifest_path = directory.join("plugins.json");
+        let write_manifest = |names: &[&str]| {
+            let manifest = PluginManifest {
+                plugins: names.iter().map(|name| entry(name)).collect(),
+            };
+            std::fs::write(&manifest_path, serde_json::to_string(&manifest).unwrap()).unwrap();
+        };
+
+        write_manifest(&["keep", "pack"]);
+        assert_eq!(
+            register_plugin_manifest(&manifest_path),
+            Err("\"pack\" is already a built-in function".to_string())
+        );
+        assert!(find_library_function("keep").is_none());
+
+        write_manifest(&["keep"]);
+        assert_eq!(
+            register_plugin_manifest(&manifest_path),
+            Ok(vec!["keep".to_string()])
+        );
+        assert!(find_library_function("keep").is_some());
+        unregister_function("keep");
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,235 @@
+// end-to-end tests: every tests/fixtures/<name>.coo is compiled, run natively and its textures
+// and material channels are compared with the pngs in tests/golden/<name>/
+//
+//   COOCOO_UPDATE_GOLDENS=1 cargo test --test golden     write the current outputs as goldens
+//   COOCOO_GOLDEN_TOLERANCE=4 cargo test --test golden   allow larger channel differences
+//
+// on a mismatch the output and a diff image (red where it differs) are written to
+// target/golden/<name>/, images in tests/fixtures/inputs/ are the inputs of every fixture
+#![cfg(any(target_os = "linux", target_os = "macos"))]
+
+use coocoo_compiler::{
+    compare_images, diff_image, Compiler, ExportOptions, ImageData, Inputs, Output, Session,
+};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// largest difference of a channel that still matches, resampling filters may round differently
+// on other platforms
+const TOLERANCE: u8 = 2;
+
+fn root() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+fn tolerance() -> u8 {
+    env::var("COOCOO_GOLDEN_TOLERANCE")
+        .ok()
+        .and_then(|tolerance| tolerance.parse().ok())
+        .unwrap_or(TOLERANCE)
+}
+
+fn read_png(path: &Path) -> Result<ImageData, String> {
+    let image = image::open(path)
+        .map_err(|e| format!("failed to read {:?}: {}", path, e))?
+        .to_rgba8();
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    Ok(ImageData::new(
+        name,
+        image.width() as i32,
+        image.height() as i32,
+        image.into_raw(),
+    ))
+}
+
+fn write_png(path: &Path, image: &ImageData) {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).unwrap();
+    }
+    image::save_buffer(
+        path,
+        &image.pixels,
+        image.width as u32,
+        image.height as u32,
+        image::ColorType::Rgba8,
+    )
+    .unwrap();
+}
+
+// files of a directory and its subdirectories, relative to it
+fn files(directory: &Path, prefix: &str) -> Vec<String> {
+    let mut found: Vec<String> = vec![];
+    if let Ok(entries) = fs::read_dir(directory) {
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+            if entry.path().is_dir() {
+                found.extend(files(&entry.path(), &(name + "/")));
+            } else {
+                found.push(name);
+            }
+        }
+    }
+    found.sort();
+    found
+}
+
+fn inputs() -> Vec<ImageData> {
+    files(&root().join("tests/fixtures/inputs"), "")
+        .iter()
+        .map(|file| read_png(&root().join("tests/fixtures/inputs").join(file)).unwrap())
+        .collect()
+}
+
+fn session(inputs: &[ImageData]) -> Session {
+    let mut session = Session::new();
+    for image in inputs {
+        session.add_image(
+            image.name.clone(),
+            image.width,
+            image.height,
+            image.pixels.clone(),
+        );
+    }
+    session
+}
+
+// file -> image, as coocoo build writes them
+fn images(output: Output) -> BTreeMap<String, ImageData> {
+    let mut images: BTreeMap<String, ImageData> = BTreeMap::new();
+    for (name, image) in output.textures {
+        images.insert(format!("{}.png", name), image);
+    }
+    for (material, channels) in output.materials {
+        for (channel, image) in channels {
+            images.insert(format!("{}/{}.png", material, channel), image);
+        }
+    }
+    images
+}
+
+// the outputs of the compiled module, the interpreter has to give the same ones
+fn render(src: &str, inputs: &[ImageData]) -> Result<BTreeMap<String, ImageData>, String> {
+    let names = Inputs {
+        images: inputs.iter().map(|image| image.name.clone()).collect(),
+    };
+    let module = Compiler::compile(src, &names).map_err(|diagnostics| {
+        let messages: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+        messages.join("\n")
+    })?;
+    let compiled = images(session(inputs).run(&module, &ExportOptions::default())?);
+
+    let interpreted = session(inputs)
+        .interpret(src, &names, &ExportOptions::default())
+        .map_err(|diagnostics| {
+            let messages: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+            format!("the interpreter failed: {}", messages.join("\n"))
+        })?;
+    let interpreted = images(interpreted);
+    for (file, image) in &compiled {
+        match interpreted.get(file) {
+            Some(other) if other.pixels == image.pixels => {}
+            _ => return Err(format!("the interpreter gives another {}", file)),
+        }
+    }
+    if interpreted.len() != compiled.len() {
+        return Err("the interpreter gives other files".to_string());
+    }
+    Ok(compiled)
+}
+
+// the failures of one fixture
+fn check(name: &str, outputs: &BTreeMap<String, ImageData>, tolerance: u8) -> Vec<String> {
+    let golden = root().join("tests/golden").join(name);
+    let failed = root().join("target/golden").join(name);
+    let mut failures: Vec<String> = vec![];
+    for (file, image) in outputs {
+        let expected = match read_png(&golden.join(file)) {
+            Ok(expected) => expected,
+            Err(_) => {
+                failures.push(format!("{}/{} has no golden", name, file));
+                continue;
+            }
+        };
+        let difference = match compare_images(&expected, image, tolerance) {
+            Ok(difference) if difference.is_similar() => continue,
+            Ok(difference) => format!(
+                "{} pixels differ, by up to {}",
+                difference.pixels, difference.max
+            ),
+            Err(e) => e,
+        };
+        let actual = failed.join(file);
+        write_png(&actual, image);
+        if let Ok(diff) = diff_image(&expected, image, tolerance) {
+            write_png(&actual.with_extension("diff.png"), &diff);
+        }
+        failures.push(format!(
+            "{}/{}: {}, see {}",
+            name,
+            file,
+            difference,
+            actual.to_string_lossy()
+        ));
+    }
+    for file in files(&golden, "") {
+        if !outputs.contains_key(&file) {
+            failures.push(format!("{}/{} isn't written anymore", name, file));
+        }
+    }
+    failures
+}
+
+fn update(name: &str, outputs: &BTreeMap<String, ImageData>) {
+    let golden = root().join("tests/golden").join(name);
+    if golden.exists() {
+        fs::remove_dir_all(&golden).unwrap();
+    }
+    for (file, image) in outputs {
+        write_png(&golden.join(file), image);
+    }
+}
+
+#[test]
+fn goldens() {
+    let update_goldens = env::var("COOCOO_UPDATE_GOLDENS").is_ok();
+    let tolerance = tolerance();
+    let inputs = inputs();
+    let mut failures: Vec<String> = vec![];
+    let fixtures: Vec<String> = files(&root().join("tests/fixtures"), "")
+        .into_iter()
+        .filter(|file| file.ends_with(".coo"))
+        .collect();
+    assert!(!fixtures.is_empty(), "tests/fixtures has no .coo files");
+
+    for fixture in &fixtures {
+        let name = fixture.trim_end_matches(".coo");
+        let src = fs::read_to_string(root().join("tests/fixtures").join(fixture)).unwrap();
+        let outputs = match render(&src, &inputs) {
+            Ok(outputs) => outputs,
+            Err(e) => {
+                failures.push(format!("{}: {}", name, e));
+                continue;
+            }
+        };
+        if update_goldens {
+            update(name, &outputs);
+        } else {
+            failures.extend(check(name, &outputs, tolerance));
+        }
+    }
+
+    if !failures.is_empty() {
+        panic!(
+            "{} failures in {} fixtures, run with COOCOO_UPDATE_GOLDENS=1 if the changes are \
+             intended:\n{}",
+            failures.len(),
+            fixtures.len(),
+            failures.join("\n")
+        );
+    }
+}
@@ -0,0 +1,154 @@
+// test blocks: tests/scripts/testing.coo has a passing and a failing test, both backends have to
+// report the same results at the same positions
+#![cfg(any(target_os = "linux", target_os = "macos"))]
+
+use coocoo_compiler::{Compiler, Inputs, Session, TestResult};
+use std::fs;
+use std::path::PathBuf;
+
+fn root() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+fn session() -> Session {
+    let rock = image::open(root().join("tests/fixtures/inputs/rock.png"))
+        .unwrap()
+        .to_rgba8();
+    let mut session = Session::new();
+    session.add_image(
+        "rock".to_string(),
+        rock.width() as i32,
+        rock.height() as i32,
+        rock.into_raw(),
+    );
+    session
+}
+
+fn inputs() -> Inputs {
+    Inputs {
+        images: vec!["rock".to_string()],
+    }
+}
+
+fn compiled(src: &str) -> Result<Vec<TestResult>, String> {
+    let module = Compiler::compile(src, &inputs()).map_err(|diagnostics| {
+        let messages: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+        messages.join("\n")
+    })?;
+    session().test(&module)
+}
+
+fn interpreted(src: &str) -> Result<Vec<TestResult>, String> {
+    session()
+        .interpret_tests(src, &inputs())
+        .map_err(|diagnostics| {
+            let messages: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+            messages.join("\n")
+        })
+}
+
+// name and (line, column, message) of every failure
+fn summary(results: &[TestResult]) -> Vec<(String, Vec<(usize, usize, String)>)> {
+    results
+        .iter()
+        .map(|result| {
+            let failures = result
+                .failures
+                .iter()
+                .map(|d| {
+                    (
+                        d.line.unwrap_or(0),
+                        d.column.unwrap_or(0),
+                        d.message.clone(),
+                    )
+                })
+                .collect();
+            (result.name.clone(), failures)
+        })
+        .collect()
+}
+
+#[test]
+fn failed_assertions_are_reported_where_they_are() {
+    let src = fs::read_to_string(root().join("tests/scripts/testing.coo")).unwrap();
+    let results = compiled(&src).unwrap();
+    assert_eq!(summary(&results), summary(&interpreted(&src).unwrap()));
+
+    // the first test reads the numbers and images main() left behind
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].name, "main keeps its variables");
+    assert!(results[0].passed(), "{:?}", results[0].failures);
+
+    // a failed assertion doesn't stop the test
+    assert_eq!(results[1].name, "fails twice");
+    assert!(!results[1].passed());
+    let failures = &summary(&results)[1].1;
+    assert_eq!(failures.len(), 2);
+    assert_eq!(failures[0], (16, 5, "assertion failed".to_string()));
+    assert_eq!((failures[1].0, failures[1].1), (17, 5));
+    assert!(failures[1]
+        .2
+        .starts_with("assertion failed: the images differ"));
+}
+
+#[test]
+fn a_trap_only_stops_its_own_test() {
+    let src = "var zero:n = 0;
+test \"divides\" { assert(1); var x:n = 4 / zero; assert(x) };
+test \"after\" { assert(zero == 0) };";
+    for results in &[compiled(src).unwrap(), interpreted(src).unwrap()] {
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].failures.len(), 1);
+        assert!(results[0].failures[0].message.contains("divide by zero"));
+        assert!(results[1].passed());
+    }
+}
+
+#[test]
+fn scripts_without_tests_have_no_results() {
+    let src = "var x:n = 1;";
+    assert!(compiled(src).unwrap().is_empty());
+    assert!(interpreted(src).unwrap().is_empty());
+}
+
+// programs whose tests both backends reject, and part of the error
+const REJECTED: [(&str, &str); 4] = [
+    (
+        "var a:n = 1;\ntest \"outer\" { test \"inner\" { assert(a) } };",
+        "can't contain another test",
+    ),
+    (
+        "var stone:m = new_material(\"PBRMetalness\", 32, 32);\ntest \"paint\" { stone.diffuse = rock };",
+        "can't assign to \"stone\"",
+    ),
+    (
+        "var a:n = 1;\ntest \"twice\" { assert(a) };\ntest \"twice\" { assert(a) };",
+        "two tests named \"twice\"",
+    ),
+    ("var a:n = 1;\nassert(a == 1);", "assert"),
+];
+
+#[test]
+fn misplaced_tests_are_rejected() {
+    for (src, error) in REJECTED.iter() {
+        for result in &[compiled(src), interpreted(src)] {
+            match result {
+                Err(message) => assert!(message.contains(error), "{:?}: {}", src, message),
+                Ok(_) => panic!("{:?} should be rejected", src),
+            }
+        }
+    }
+}
+
+#[test]
+fn tests_may_declare_their_own_materials() {
+    let src = "var stone:m = new_material(\"PBRMetalness\", 32, 32);
+test \"shadowed\" {
+    var stone:m = new_material(\"PBRMetalness\", 32, 32);
+    stone.diffuse = rock;
+    assert(1)
+};";
+    for results in &[compiled(src).unwrap(), interpreted(src).unwrap()] {
+        assert!(results[0].passed(), "{:?}", results[0].failures);
+    }
+}